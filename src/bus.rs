@@ -1,4 +1,21 @@
+use crate::cdrom::Cdrom;
+use crate::controller::Controller;
 use crate::cpu::Exception;
+use crate::disc::Disc;
+use crate::dma::{Direction, Dma, Port, Sync};
+use crate::gpu::Gpu;
+use crate::interrupt::{Interrupt, InterruptController};
+use crate::memcard::MemoryCard;
+use crate::scheduler::{Event, Scheduler};
+use crate::sio::Sio;
+use crate::spu::{Spu, SAMPLE_CYCLES};
+use crate::timers::Timers;
+
+// a list that visits more headers than there are words in ram goes in a
+// circle
+const LINKED_LIST_HEADERS: u32 = 0x80000;
+
+const TIMER_INTERRUPTS: [Interrupt; 3] = [Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2];
 
 struct SimpleRam(Vec<u8>);
 
@@ -65,57 +82,233 @@ enum AddressBusDevice {
     Ram(u32),
     Scratchpad(u32),
     Bios(u32),
+    Interrupt(u32),
+    Dma(u32),
+    Gpu(u32),
+    Timers(u32),
+    Cdrom(u32),
+    Spu(u32),
+    Sio(u32),
+    // inside the io and expansion regions, where nothing is emulated yet
     Unknown(u32),
+    // nothing answers, the access ends in a bus error
+    Unmapped,
 }
 
 pub struct Bus {
     ram: SimpleRam,
     scratchpad: SimpleRam,
     bios: SimpleRom,
+    interrupts: InterruptController,
+    dma: Dma,
+    gpu: Gpu,
+    timers: Timers,
+    cdrom: Cdrom,
+    spu: Spu,
+    sio: Sio,
+    scheduler: Scheduler,
+    // a vblank started since the last tick
+    frame: bool,
+    // cycle the devices that catch up on their own were last run to
+    gpu_sync: u64,
+    timers_sync: u64,
+    cdrom_sync: u64,
+    sio_sync: u64,
 }
 
 impl Bus {
     pub fn new(bios: Vec<u8>) -> Self {
-        Bus {
+        let mut bus = Bus {
             ram: SimpleRam::new(0x200000),
             scratchpad: SimpleRam::new(0x400),
             bios: SimpleRom::new(bios),
+            interrupts: InterruptController::new(),
+            dma: Dma::new(),
+            gpu: Gpu::new(),
+            timers: Timers::new(),
+            cdrom: Cdrom::new(None),
+            spu: Spu::new(),
+            sio: Sio::new(),
+            scheduler: Scheduler::new(),
+            frame: false,
+            gpu_sync: 0,
+            timers_sync: 0,
+            cdrom_sync: 0,
+            sio_sync: 0,
+        };
+        // the first line starts inside its hblank
+        bus.timers.hblank(bus.gpu.in_hblank());
+        bus.scheduler.schedule(Event::Hblank, bus.gpu.cycles_to_hblank_edge());
+        bus.scheduler.schedule(Event::Spu, SAMPLE_CYCLES);
+        bus
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupts.pending()
+    }
+
+    // runs the events that came due, true when a frame ended
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        self.scheduler.advance(cycles);
+
+        while let Some(event) = self.scheduler.next() {
+            match event {
+                Event::Hblank => {
+                    self.sync_video();
+                    self.update_timers();
+                }
+                Event::Timers => {
+                    self.sync_timers();
+                    self.update_timers();
+                }
+                Event::Dma(port) => self.complete_dma(port),
+                Event::Cdrom => {
+                    let irq = self.sync_cdrom();
+                    self.update_cdrom(irq);
+                }
+                Event::Spu => {
+                    let irq = self.spu.irq();
+                    let cd = self.cdrom.audio_sample();
+                    self.spu.clock(cd);
+                    self.update_spu(irq);
+                    self.scheduler.schedule(Event::Spu, SAMPLE_CYCLES);
+                }
+                Event::Sio => {
+                    let irq = self.sync_sio();
+                    self.update_sio(irq);
+                }
+            }
         }
+        std::mem::take(&mut self.frame)
     }
 
-    pub fn read_byte(&self, address: u32) -> Result<u8, Exception> {
-        match bus_device_address(address) {
+    pub fn insert_disc(&mut self, disc: Box<dyn Disc>) {
+        self.cdrom.insert_disc(disc);
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn controller(&mut self, slot: usize) -> &mut Controller {
+        self.sio.controller(slot)
+    }
+
+    pub fn insert_memory_card(&mut self, slot: usize, card: MemoryCard) {
+        self.sio.insert_memory_card(slot, card);
+    }
+
+    // the sound output since the last call, interleaved left and right
+    pub fn take_audio(&mut self) -> Vec<i16> {
+        self.spu.take_samples()
+    }
+
+    // copies data to ram without going through the cpu, at any mirror
+    pub fn write_ram(&mut self, address: u32, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let address = address.wrapping_add(i as u32) & 0x001fffff;
+            self.ram.write_byte(address, byte);
+        }
+    }
+
+    pub fn read_byte(&mut self, address: u32) -> Result<u8, Exception> {
+        let device = bus_device_address(address);
+        self.scheduler.advance(access_cycles(&device, 1));
+
+        match device {
             AddressBusDevice::Ram(address) => Ok(self.ram.read_byte(address)),
             AddressBusDevice::Scratchpad(address) => Ok(self.scratchpad.read_byte(address)),
             AddressBusDevice::Bios(address) => Ok(self.bios.read_byte(address)),
+            AddressBusDevice::Interrupt(address) => {
+                let value = self.interrupts.read(address & !3);
+                Ok((value >> ((address & 3) * 8)) as u8)
+            }
+            AddressBusDevice::Dma(address) => {
+                let value = self.dma.read(address & !3);
+                Ok((value >> ((address & 3) * 8)) as u8)
+            }
+            AddressBusDevice::Gpu(address) => {
+                let value = self.read_gpu(address & !3);
+                Ok((value >> ((address & 3) * 8)) as u8)
+            }
+            AddressBusDevice::Timers(address) => {
+                let value = self.read_timers(address & !3);
+                Ok((value >> ((address & 3) * 8)) as u8)
+            }
+            AddressBusDevice::Cdrom(address) => Ok(self.read_cdrom(address)),
+            AddressBusDevice::Spu(address) => {
+                let value = self.spu.read(address & !1);
+                Ok((value >> ((address & 1) * 8)) as u8)
+            }
+            AddressBusDevice::Sio(address) => Ok(self.read_sio(address) as u8),
             AddressBusDevice::Unknown(address) => {
                 println!("Bus read on unknown device on address {:x}", address);
                 Ok(0)
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
         }
     }
 
-    pub fn read_halfword(&self, address: u32) -> Result<u16, Exception> {
-        match bus_device_address(address) {
+    pub fn read_halfword(&mut self, address: u32) -> Result<u16, Exception> {
+        let device = bus_device_address(address);
+        self.scheduler.advance(access_cycles(&device, 2));
+
+        match device {
             AddressBusDevice::Ram(address) => Ok(self.ram.read_halfword(address)),
             AddressBusDevice::Scratchpad(address) => Ok(self.scratchpad.read_halfword(address)),
             AddressBusDevice::Bios(address) => Ok(self.bios.read_halfword(address)),
+            AddressBusDevice::Interrupt(address) => {
+                let value = self.interrupts.read(address & !3);
+                Ok((value >> ((address & 2) * 8)) as u16)
+            }
+            AddressBusDevice::Dma(address) => {
+                let value = self.dma.read(address & !3);
+                Ok((value >> ((address & 2) * 8)) as u16)
+            }
+            AddressBusDevice::Gpu(address) => {
+                let value = self.read_gpu(address & !3);
+                Ok((value >> ((address & 2) * 8)) as u16)
+            }
+            AddressBusDevice::Timers(address) => {
+                let value = self.read_timers(address & !3);
+                Ok((value >> ((address & 2) * 8)) as u16)
+            }
+            // the registers are 8 bit wide
+            AddressBusDevice::Cdrom(address) => Ok(self.read_cdrom(address) as u16),
+            AddressBusDevice::Spu(address) => Ok(self.spu.read(address)),
+            AddressBusDevice::Sio(address) => Ok(self.read_sio(address) as u16),
             AddressBusDevice::Unknown(address) => {
                 println!("Bus read on unknown device on address {:x}", address);
                 Ok(0)
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
         }
     }
 
-    pub fn read_word(&self, address: u32) -> Result<u32, Exception> {
-        match bus_device_address(address) {
+    pub fn read_word(&mut self, address: u32) -> Result<u32, Exception> {
+        let device = bus_device_address(address);
+        self.scheduler.advance(access_cycles(&device, 4));
+
+        match device {
             AddressBusDevice::Ram(address) => Ok(self.ram.read_word(address)),
             AddressBusDevice::Scratchpad(address) => Ok(self.scratchpad.read_word(address)),
             AddressBusDevice::Bios(address) => Ok(self.bios.read_word(address)),
+            AddressBusDevice::Interrupt(address) => Ok(self.interrupts.read(address)),
+            AddressBusDevice::Dma(address) => Ok(self.dma.read(address)),
+            AddressBusDevice::Gpu(address) => Ok(self.read_gpu(address)),
+            AddressBusDevice::Timers(address) => Ok(self.read_timers(address)),
+            AddressBusDevice::Cdrom(address) => Ok(self.read_cdrom(address) as u32),
+            // the registers are 16 bit wide, the word is read in two halves
+            AddressBusDevice::Spu(address) => {
+                let low = self.spu.read(address) as u32;
+                Ok(low | (self.spu.read(address + 2) as u32) << 16)
+            }
+            AddressBusDevice::Sio(address) => Ok(self.read_sio(address)),
             AddressBusDevice::Unknown(address) => {
                 println!("Bus read on unknown device on address {:x}", address);
                 Ok(0)
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
         }
     }
 
@@ -133,10 +326,43 @@ impl Bus {
                 println!("Trying to write to bios that I think is read-only");
                 Ok(())
             }
+            AddressBusDevice::Interrupt(address) => {
+                let shift = (address & 3) * 8;
+                self.write_interrupts(address & !3, (value as u32) << shift, 0xff << shift);
+                Ok(())
+            }
+            AddressBusDevice::Dma(address) => {
+                let shift = (address & 3) * 8;
+                let value = self.dma.merge(address & !3, (value as u32) << shift, 0xff << shift);
+                self.write_dma(address & !3, value);
+                Ok(())
+            }
+            AddressBusDevice::Timers(address) => {
+                let value = (value as u32) << ((address & 3) * 8);
+                self.write_timers(address & !3, value);
+                Ok(())
+            }
+            AddressBusDevice::Cdrom(address) => {
+                self.write_cdrom(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Gpu(_) => {
+                println!("Byte write to the gpu of {:x}", value);
+                Ok(())
+            }
+            AddressBusDevice::Spu(_) => {
+                println!("Byte write to the spu of {:x}", value);
+                Ok(())
+            }
+            AddressBusDevice::Sio(address) => {
+                self.write_sio(address, value as u32);
+                Ok(())
+            }
             AddressBusDevice::Unknown(address) => {
                 println!("Bus write on unknown device of {:x} on address {:x}", value, address);
                 Ok(())
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
         }
     }
 
@@ -154,10 +380,43 @@ impl Bus {
                 println!("Trying to write to bios that I think is read-only");
                 Ok(())
             }
+            AddressBusDevice::Interrupt(address) => {
+                let shift = (address & 2) * 8;
+                self.write_interrupts(address & !3, (value as u32) << shift, 0xffff << shift);
+                Ok(())
+            }
+            AddressBusDevice::Dma(address) => {
+                let shift = (address & 2) * 8;
+                let value = self.dma.merge(address & !3, (value as u32) << shift, 0xffff << shift);
+                self.write_dma(address & !3, value);
+                Ok(())
+            }
+            AddressBusDevice::Timers(address) => {
+                let value = (value as u32) << ((address & 2) * 8);
+                self.write_timers(address & !3, value);
+                Ok(())
+            }
+            AddressBusDevice::Cdrom(address) => {
+                self.write_cdrom(address, value as u8);
+                Ok(())
+            }
+            AddressBusDevice::Spu(address) => {
+                self.write_spu(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Sio(address) => {
+                self.write_sio(address, value as u32);
+                Ok(())
+            }
+            AddressBusDevice::Gpu(_) => {
+                println!("Halfword write to the gpu of {:x}", value);
+                Ok(())
+            }
             AddressBusDevice::Unknown(address) => {
                 println!("Bus write on unknown device of {:x} on address {:x}", value, address);
                 Ok(())
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
         }
     }
 
@@ -175,20 +434,727 @@ impl Bus {
                 println!("Trying to write to bios that I think is read-only");
                 Ok(())
             }
+            AddressBusDevice::Interrupt(address) => {
+                self.interrupts.write(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Dma(address) => {
+                self.write_dma(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Gpu(address) => {
+                self.write_gpu(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Timers(address) => {
+                self.write_timers(address, value);
+                Ok(())
+            }
+            AddressBusDevice::Cdrom(address) => {
+                self.write_cdrom(address, value as u8);
+                Ok(())
+            }
+            AddressBusDevice::Spu(address) => {
+                self.write_spu(address, value as u16);
+                self.write_spu(address + 2, (value >> 16) as u16);
+                Ok(())
+            }
+            AddressBusDevice::Sio(address) => {
+                self.write_sio(address, value);
+                Ok(())
+            }
             AddressBusDevice::Unknown(address) => {
                 println!("Bus write on unknown device of {:x} on address {:x}", value, address);
                 Ok(())
             }
+            AddressBusDevice::Unmapped => Err(Exception::BusErrorData),
+        }
+    }
+
+    // the other bytes of a narrow store keep their value, on I_STAT that
+    // means writing ones so nothing else is acknowledged
+    fn write_interrupts(&mut self, offset: u32, value: u32, lanes: u32) {
+        let current = match offset {
+            0 => 0xffffffff,
+            _ => self.interrupts.read(offset),
+        };
+        self.interrupts.write(offset, (current & !lanes) | value);
+    }
+
+    // the dot and hblank clocks come from the gpu, it catches up first
+    fn read_timers(&mut self, offset: u32) -> u32 {
+        self.sync_video();
+        self.sync_timers();
+        let value = self.timers.read(offset);
+        self.update_timers();
+        value
+    }
+
+    fn write_timers(&mut self, offset: u32, value: u32) {
+        self.sync_video();
+        self.sync_timers();
+        self.timers.write(offset, value);
+        self.update_timers();
+    }
+
+    // counts the cpu cycles since the last time the timers ran
+    fn sync_timers(&mut self) {
+        self.sync_timers_to(self.scheduler.cycles());
+    }
+
+    fn sync_timers_to(&mut self, cycles: u64) {
+        if cycles > self.timers_sync {
+            self.timers.step((cycles - self.timers_sync) as u32);
+            self.timers_sync = cycles;
+        }
+    }
+
+    fn update_timers(&mut self) {
+        for (index, interrupt) in TIMER_INTERRUPTS.into_iter().enumerate() {
+            if self.timers.take_irq(index) {
+                self.interrupts.request(interrupt);
+            }
+        }
+
+        if let Some(cycles) = self.timers.cycles_until_irq() {
+            self.scheduler.schedule(Event::Timers, cycles);
+        }
+    }
+
+    fn read_cdrom(&mut self, offset: u32) -> u8 {
+        let irq = self.sync_cdrom();
+        let value = self.cdrom.read(offset);
+        self.update_cdrom(irq);
+        value
+    }
+
+    fn write_cdrom(&mut self, offset: u32, value: u8) {
+        let irq = self.sync_cdrom();
+        self.cdrom.write(offset, value);
+        self.update_cdrom(irq);
+    }
+
+    // runs the drive up to now, returns the irq line from before
+    fn sync_cdrom(&mut self) -> bool {
+        let irq = self.cdrom.irq();
+        let now = self.scheduler.cycles();
+        self.cdrom.step((now - self.cdrom_sync) as u32);
+        self.cdrom_sync = now;
+        irq
+    }
+
+    fn update_cdrom(&mut self, irq: bool) {
+        if !irq && self.cdrom.irq() {
+            self.interrupts.request(Interrupt::Cdrom);
+        }
+
+        if let Some(cycles) = self.cdrom.cycles_until_event() {
+            self.scheduler.schedule(Event::Cdrom, cycles);
+        }
+    }
+
+    fn read_sio(&mut self, offset: u32) -> u32 {
+        let irq = self.sync_sio();
+        let value = self.sio.read(offset);
+        self.update_sio(irq);
+        value
+    }
+
+    fn write_sio(&mut self, offset: u32, value: u32) {
+        let irq = self.sync_sio();
+        self.sio.write(offset, value);
+        self.update_sio(irq);
+    }
+
+    fn sync_sio(&mut self) -> bool {
+        let irq = self.sio.irq();
+        let now = self.scheduler.cycles();
+        self.sio.step((now - self.sio_sync) as u32);
+        self.sio_sync = now;
+        irq
+    }
+
+    fn update_sio(&mut self, irq: bool) {
+        if !irq && self.sio.irq() {
+            self.interrupts.request(Interrupt::Controller);
+        }
+
+        if let Some(cycles) = self.sio.cycles_until_event() {
+            self.scheduler.schedule(Event::Sio, cycles);
+        }
+    }
+
+    fn write_spu(&mut self, offset: u32, value: u16) {
+        let irq = self.spu.irq();
+        self.spu.write(offset, value);
+        self.update_spu(irq);
+    }
+
+    fn update_spu(&mut self, irq: bool) {
+        if !irq && self.spu.irq() {
+            self.interrupts.request(Interrupt::Spu);
+        }
+    }
+
+    // runs the gpu up to now, the timers see each blank start and end at the
+    // cycle it happens
+    fn sync_video(&mut self) {
+        let now = self.scheduler.cycles();
+        while self.gpu_sync < now {
+            let edge = self.gpu.cycles_to_hblank_edge();
+            let cycles = (now - self.gpu_sync).min(edge as u64) as u32;
+            let video = self.gpu.step(cycles);
+            self.gpu_sync += cycles as u64;
+
+            self.sync_timers_to(self.gpu_sync);
+            self.timers.dot_clock(video.dots);
+            if let Some(active) = video.vblank {
+                self.timers.vblank(active);
+                if active {
+                    self.frame = true;
+                    self.interrupts.request(Interrupt::Vblank);
+                }
+            }
+            if let Some(active) = video.hblank {
+                self.timers.hblank(active);
+            }
+        }
+
+        let next = self.gpu.cycles_to_next_line().min(self.gpu.cycles_to_hblank_edge());
+        self.scheduler.schedule(Event::Hblank, next);
+    }
+
+    fn read_gpu(&mut self, offset: u32) -> u32 {
+        match offset {
+            0 => self.gpu.read(),
+            _ => self.gpu.status(),
+        }
+    }
+
+    fn write_gpu(&mut self, offset: u32, value: u32) {
+        let irq = self.gpu.irq();
+
+        match offset {
+            0 => self.gpu.gp0(value),
+            _ => self.gpu.gp1(value),
+        }
+
+        if !irq && self.gpu.irq() {
+            self.interrupts.request(Interrupt::Gpu);
+        }
+    }
+
+    // transfers happen at once when a channel is started
+    fn write_dma(&mut self, offset: u32, value: u32) {
+        let irq = self.dma.irq();
+        let spu_irq = self.spu.irq();
+
+        self.dma.write(offset, value);
+        while let Some(port) = self.dma.active_port() {
+            let words = match self.dma.channel(port).sync() {
+                Sync::LinkedList => self.dma_linked_list(port),
+                _ => self.dma_block(port),
+            };
+            // the data moves at once but the channel is busy for about a
+            // cycle per word
+            self.scheduler.schedule(Event::Dma(port), words);
+        }
+
+        if !irq && self.dma.irq() {
+            self.interrupts.request(Interrupt::Dma);
+        }
+        // the transfer can go over the spu irq address
+        self.update_spu(spu_irq);
+    }
+
+    fn complete_dma(&mut self, port: Port) {
+        let irq = self.dma.irq();
+
+        self.dma.complete(port);
+
+        if !irq && self.dma.irq() {
+            self.interrupts.request(Interrupt::Dma);
+        }
+    }
+
+    // returns the number of words moved
+    fn dma_block(&mut self, port: Port) -> u32 {
+        let channel = self.dma.channel(port);
+        let mut address = channel.address();
+
+        for remaining in (0..channel.transfer_size()).rev() {
+            let ram_address = address & 0x001ffffc;
+            match channel.direction() {
+                Direction::FromRam => {
+                    let value = self.ram.read_word(ram_address);
+                    self.dma_write_port(port, value);
+                }
+                Direction::ToRam => {
+                    let value = match port {
+                        // each entry points to the previous one, the last
+                        // one is the end marker
+                        Port::Otc if remaining == 0 => 0x00ffffff,
+                        Port::Otc => address.wrapping_sub(4) & 0x001fffff,
+                        _ => self.dma_read_port(port),
+                    };
+                    self.ram.write_word(ram_address, value);
+                }
+            }
+            address = address.wrapping_add(channel.step());
         }
+
+        self.dma.transferred(port, address);
+        channel.transfer_size()
+    }
+
+    fn dma_linked_list(&mut self, port: Port) -> u32 {
+        let mut address = self.dma.channel(port).address() & 0x001ffffc;
+        let mut words = 0;
+
+        for _ in 0..LINKED_LIST_HEADERS {
+            // the high byte is the number of words in the packet, the rest
+            // is the address of the next one
+            let header = self.ram.read_word(address);
+            words += 1 + (header >> 24);
+            for i in 1..=(header >> 24) {
+                let value = self.ram.read_word((address + i * 4) & 0x001ffffc);
+                self.dma_write_port(port, value);
+            }
+
+            // the end marker is usually 0x00ffffff
+            if header & 0x00800000 != 0 {
+                self.dma.transferred(port, 0x00ffffff);
+                return words;
+            }
+            address = header & 0x001ffffc;
+        }
+
+        // the hardware would go around forever, it is ended as if the list
+        // was over so the irq still comes
+        println!("Dma linked list on port {:?} does not end, stopped at {:x}", port, address);
+        self.dma.transferred(port, address);
+        words
+    }
+
+    fn dma_write_port(&mut self, port: Port, value: u32) {
+        match port {
+            Port::Gpu => self.write_gpu(0, value),
+            Port::Spu => self.spu.dma_write(value),
+            _ => println!("Dma write to unknown port {:?} of {:x}", port, value),
+        }
+    }
+
+    fn dma_read_port(&mut self, port: Port) -> u32 {
+        match port {
+            Port::Gpu => self.gpu.read(),
+            Port::Cdrom => self.cdrom.read_word(),
+            Port::Spu => self.spu.dma_read(),
+            _ => {
+                println!("Dma read from unknown port {:?}", port);
+                0
+            }
+        }
+    }
+}
+
+// cpu cycles an access stalls for on top of the one of the instruction,
+// writes go through the write buffer and never stall
+fn access_cycles(device: &AddressBusDevice, bytes: u32) -> u32 {
+    match device {
+        AddressBusDevice::Ram(_) => 4,
+        AddressBusDevice::Scratchpad(_) => 0,
+        // the bios is on an 8 bit bus
+        AddressBusDevice::Bios(_) => 6 * bytes,
+        _ => 2,
     }
 }
 
 // Think of a better name for this
 fn bus_device_address(address: u32) -> AddressBusDevice {
     match address {
-        0x00000000..=0x001fffff => AddressBusDevice::Ram(address),
+        // 2 MiB mirrored over the 8 MiB the memory control sets up
+        0x00000000..=0x007fffff => AddressBusDevice::Ram(address & 0x001fffff),
         0x1f800000..=0x1f8003ff => AddressBusDevice::Scratchpad(address - 0x1f800000),
+        0x1f801040..=0x1f80104f => AddressBusDevice::Sio(address - 0x1f801040),
+        0x1f801070..=0x1f801077 => AddressBusDevice::Interrupt(address - 0x1f801070),
+        0x1f801080..=0x1f8010ff => AddressBusDevice::Dma(address - 0x1f801080),
+        0x1f801100..=0x1f80112f => AddressBusDevice::Timers(address - 0x1f801100),
+        0x1f801800..=0x1f801803 => AddressBusDevice::Cdrom(address - 0x1f801800),
+        0x1f801810..=0x1f801817 => AddressBusDevice::Gpu(address - 0x1f801810),
+        0x1f801c00..=0x1f801fff => AddressBusDevice::Spu(address - 0x1f801c00),
         0x1fc00000..=0x1fc7ffff => AddressBusDevice::Bios(address - 0x1fc00000),
-        _ => AddressBusDevice::Unknown(address),
+        // expansion 1, the io ports and expansion 2, and expansion 3
+        0x1f000000..=0x1f803fff | 0x1fa00000..=0x1fbfffff => AddressBusDevice::Unknown(address),
+        _ => AddressBusDevice::Unmapped,
+    }
+}
+
+#[cfg(test)]
+mod dma {
+    use super::*;
+
+    #[test]
+    fn ordering_table_clear() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f8010f0, 0x08000000).unwrap();
+        bus.write_word(0x1f8010e0, 0x0000010c).unwrap();
+        bus.write_word(0x1f8010e4, 0x00000004).unwrap();
+        bus.write_word(0x1f8010e8, 0x11000002).unwrap();
+        // the channel is busy for the time the transfer takes
+        assert_eq!(bus.read_word(0x1f8010e8), Ok(0x11000002));
+        bus.tick(4);
+
+        assert_eq!(bus.read_word(0x10c), Ok(0x00000108));
+        assert_eq!(bus.read_word(0x108), Ok(0x00000104));
+        assert_eq!(bus.read_word(0x104), Ok(0x00000100));
+        assert_eq!(bus.read_word(0x100), Ok(0x00ffffff));
+        assert_eq!(bus.read_word(0x1f8010e8), Ok(0x00000002));
+        assert_eq!(bus.read_word(0x1f8010e0), Ok(0x0000010c));
+    }
+
+    #[test]
+    fn block_to_device() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f8010f0, 0x00000800).unwrap();
+        bus.write_word(0x1f8010a0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010a4, 0x00020004).unwrap();
+        bus.write_word(0x1f8010a8, 0x01000201).unwrap();
+        bus.tick(8);
+
+        assert_eq!(bus.read_word(0x1f8010a0), Ok(0x00000120));
+        assert_eq!(bus.read_word(0x1f8010a4), Ok(0x00000004));
+        assert_eq!(bus.read_word(0x1f8010a8), Ok(0x00000201));
+    }
+
+    #[test]
+    fn linked_list() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x100, 0x01000200).unwrap();
+        bus.write_word(0x200, 0x00ffffff).unwrap();
+        bus.write_word(0x1f8010f0, 0x00000800).unwrap();
+        bus.write_word(0x1f8010a0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010a8, 0x01000401).unwrap();
+        bus.tick(3);
+
+        assert_eq!(bus.read_word(0x1f8010a0), Ok(0x00ffffff));
+        assert_eq!(bus.read_word(0x1f8010a8), Ok(0x00000401));
+    }
+
+    #[test]
+    fn endless_linked_list() {
+        let mut bus = Bus::new(vec![]);
+        // two headers pointing at each other
+        bus.write_word(0x100, 0x00000200).unwrap();
+        bus.write_word(0x200, 0x00000100).unwrap();
+        bus.write_word(0x1f8010f0, 0x00000800).unwrap();
+        bus.write_word(0x1f8010f4, 0x00840000).unwrap();
+        bus.write_word(0x1f8010a0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010a8, 0x01000401).unwrap();
+        bus.tick(LINKED_LIST_HEADERS);
+
+        assert_eq!(bus.read_word(0x1f8010a8), Ok(0x00000401));
+        assert_eq!(bus.read_word(0x1f8010f4), Ok(0x84840000));
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000008));
+    }
+
+    #[test]
+    fn narrow_writes() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f8010a0, 0x00123456).unwrap();
+        bus.write_byte(0x1f8010a1, 0x78).unwrap();
+        assert_eq!(bus.read_word(0x1f8010a0), Ok(0x00127856));
+        bus.write_halfword(0x1f8010a6, 0x0002).unwrap();
+        bus.write_halfword(0x1f8010a4, 0x0010).unwrap();
+        assert_eq!(bus.read_word(0x1f8010a4), Ok(0x00020010));
+
+        // the flags of DICR stay until a one is written to them
+        bus.write_word(0x1f8010f4, 0x00840000).unwrap();
+        bus.write_word(0x1f8010f0, 0x00000800).unwrap();
+        bus.write_word(0x1f8010a8, 0x01000201).unwrap();
+        bus.tick(0x40);
+        bus.write_byte(0x1f8010f4, 0x01).unwrap();
+        assert_eq!(bus.read_word(0x1f8010f4), Ok(0x84840001));
+        bus.write_byte(0x1f8010f7, 0x04).unwrap();
+        assert_eq!(bus.read_word(0x1f8010f4), Ok(0x00840001));
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000008).unwrap();
+        bus.write_word(0x1f8010f4, 0x00c00000).unwrap();
+        bus.write_word(0x1f8010f0, 0x08000000).unwrap();
+        bus.write_word(0x1f8010e0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010e4, 0x00000001).unwrap();
+        bus.write_word(0x1f8010e8, 0x11000002).unwrap();
+        assert!(!bus.interrupt_pending());
+        bus.tick(1);
+
+        assert_eq!(bus.read_word(0x1f8010f4), Ok(0xc0c00000));
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000008));
+        assert!(bus.interrupt_pending());
+    }
+
+    #[test]
+    fn gpu_linked_list() {
+        let mut bus = Bus::new(vec![]);
+        // fill rectangle packet followed by the end marker
+        bus.write_word(0x100, 0x03ffffff).unwrap();
+        bus.write_word(0x104, 0x020000ff).unwrap();
+        bus.write_word(0x108, 0x00000000).unwrap();
+        bus.write_word(0x10c, 0x00010010).unwrap();
+        bus.write_word(0x1f8010f0, 0x00000800).unwrap();
+        bus.write_word(0x1f8010a0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010a8, 0x01000401).unwrap();
+
+        bus.write_word(0x1f801810, 0xc0000000).unwrap();
+        bus.write_word(0x1f801810, 0x00000000).unwrap();
+        bus.write_word(0x1f801810, 0x00010002).unwrap();
+        assert_eq!(bus.read_word(0x1f801810), Ok(0x001f001f));
+    }
+}
+
+#[cfg(test)]
+mod gpu {
+    use super::*;
+
+    #[test]
+    fn interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000002).unwrap();
+        bus.write_word(0x1f801810, 0x1f000000).unwrap();
+
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000002));
+        assert_eq!(bus.read_word(0x1f801814).unwrap() & 0x01000000, 0x01000000);
+    }
+}
+
+#[cfg(test)]
+mod interrupts {
+    use super::*;
+
+    #[test]
+    fn narrow_writes() {
+        let mut bus = Bus::new(vec![]);
+        bus.interrupts.request(Interrupt::Vblank);
+        bus.interrupts.request(Interrupt::Spu);
+        bus.write_word(0x1f801074, 0x00000201).unwrap();
+
+        // only the bits of the byte written are acknowledged
+        bus.write_byte(0x1f801070, 0x00).unwrap();
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000200));
+        bus.write_halfword(0x1f801070, 0x0000).unwrap();
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000000));
+
+        bus.write_byte(0x1f801075, 0x04).unwrap();
+        assert_eq!(bus.read_word(0x1f801074), Ok(0x00000401));
+        bus.write_halfword(0x1f801074, 0x0008).unwrap();
+        assert_eq!(bus.read_word(0x1f801074), Ok(0x00000008));
+    }
+}
+
+#[cfg(test)]
+mod timers {
+    use super::*;
+
+    #[test]
+    fn interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801128, 0x00000010).unwrap();
+        bus.write_halfword(0x1f801124, 0x0010).unwrap();
+
+        bus.tick(8);
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000000));
+        bus.tick(6);
+        assert_eq!(bus.read_word(0x1f801070), Ok(0x00000040));
+        // the register reads took their own cycles
+        assert_eq!(bus.read_halfword(0x1f801120), Ok(0x0014));
+    }
+
+    #[test]
+    fn hblank() {
+        // the hblank starts at gpu cycle 0xc00, cpu cycle 1955
+        let mut bus = Bus::new(vec![]);
+        bus.write_halfword(0x1f801104, 0x0003).unwrap();
+        bus.write_halfword(0x1f801114, 0x0100).unwrap();
+        bus.tick(1950);
+        // the register accesses take a few cycles of their own
+        assert_eq!(bus.read_halfword(0x1f801100), Ok(1952));
+        assert_eq!(bus.read_halfword(0x1f801110), Ok(0));
+
+        // timer 0 restarted as it began and timer 1 counted it
+        bus.tick(100);
+        assert_eq!(bus.read_halfword(0x1f801100), Ok(101));
+        assert_eq!(bus.read_halfword(0x1f801110), Ok(1));
+
+        // paused through the rest of the line and the start of the next one
+        let mut bus = Bus::new(vec![]);
+        bus.write_halfword(0x1f801104, 0x0001).unwrap();
+        bus.tick(2100);
+        let paused = bus.read_halfword(0x1f801100);
+        bus.tick(180);
+        assert_eq!(bus.read_halfword(0x1f801100), paused);
+    }
+
+    #[test]
+    fn dot_clock() {
+        // read before the hblank ends, a dot takes 10 gpu cycles and 300 cpu
+        // cycles are 471 gpu cycles
+        let mut bus = Bus::new(vec![]);
+        bus.write_halfword(0x1f801104, 0x0100).unwrap();
+        bus.tick(300);
+        assert_eq!(bus.read_halfword(0x1f801100), Ok(47));
+    }
+}
+
+#[cfg(test)]
+mod timing {
+    use super::*;
+
+    #[test]
+    fn wait_states() {
+        let mut bus = Bus::new(vec![0; 16]);
+        bus.read_word(0x1fc00000).unwrap();
+        assert_eq!(bus.scheduler.cycles(), 24);
+        bus.read_byte(0x00000000).unwrap();
+        assert_eq!(bus.scheduler.cycles(), 28);
+        bus.read_word(0x1f800000).unwrap();
+        bus.write_word(0x00000000, 0).unwrap();
+        assert_eq!(bus.scheduler.cycles(), 28);
+    }
+
+    #[test]
+    fn vblank() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000001).unwrap();
+
+        let mut frames = 0;
+        for _ in 0..1_200_000 {
+            frames += bus.tick(1) as u32;
+        }
+        assert_eq!(frames, 2);
+        assert!(bus.interrupt_pending());
+    }
+}
+
+#[cfg(test)]
+mod cdrom {
+    use super::*;
+
+    #[test]
+    fn interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000004).unwrap();
+        bus.write_byte(0x1f801800, 0x01).unwrap();
+        bus.write_byte(0x1f801802, 0x1f).unwrap();
+        bus.write_byte(0x1f801800, 0x00).unwrap();
+        bus.write_byte(0x1f801801, 0x01).unwrap();
+
+        bus.tick(10_000);
+        assert!(!bus.interrupt_pending());
+        bus.tick(10_000);
+        assert!(bus.interrupt_pending());
+        assert_eq!(bus.read_byte(0x1f801801), Ok(0x10));
+
+        // acknowledged on the controller and then on I_STAT
+        bus.write_byte(0x1f801800, 0x01).unwrap();
+        bus.write_byte(0x1f801803, 0x1f).unwrap();
+        bus.write_word(0x1f801070, 0).unwrap();
+        assert!(!bus.interrupt_pending());
+    }
+
+    #[test]
+    fn sector_dma() {
+        let name = format!("psiemu_sector_dma_{}.iso", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let data: Vec<u8> = (0..0x1000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        let mut bus = Bus::new(vec![]);
+        bus.insert_disc(crate::disc::open(&path).unwrap());
+
+        // ReadN from the start and wait for the first sector
+        bus.write_byte(0x1f801801, 0x06).unwrap();
+        bus.write_byte(0x1f801800, 0x01).unwrap();
+        for _ in 0..2 {
+            while bus.read_byte(0x1f801803) == Ok(0xe0) {
+                bus.tick(1000);
+            }
+            bus.write_byte(0x1f801803, 0x1f).unwrap();
+        }
+        bus.write_byte(0x1f801800, 0x00).unwrap();
+        bus.write_byte(0x1f801803, 0x80).unwrap();
+
+        bus.write_word(0x1f8010f0, 0x00008000).unwrap();
+        bus.write_word(0x1f8010b0, 0x00001000).unwrap();
+        bus.write_word(0x1f8010b4, 0x00000200).unwrap();
+        bus.write_word(0x1f8010b8, 0x11000000).unwrap();
+        bus.tick(0x200);
+
+        assert_eq!(bus.read_word(0x1000), Ok(0x03020100));
+        assert_eq!(bus.read_word(0x17fc), Ok(0xfffefdfc));
+        assert_eq!(bus.read_word(0x1f8010b8), Ok(0x00000000));
+    }
+}
+
+#[cfg(test)]
+mod spu {
+    use super::*;
+
+    #[test]
+    fn dma_and_interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000200).unwrap();
+        bus.write_word(0x100, 0x12345678).unwrap();
+        bus.write_word(0x104, 0x9abcdef0).unwrap();
+
+        // dma write mode, with the irq where the transfer starts
+        bus.write_halfword(0x1f801da4, 0x0200).unwrap();
+        bus.write_halfword(0x1f801da6, 0x0200).unwrap();
+        bus.write_halfword(0x1f801daa, 0x8060).unwrap();
+        bus.write_word(0x1f8010f0, 0x00080000).unwrap();
+        bus.write_word(0x1f8010c0, 0x00000100).unwrap();
+        bus.write_word(0x1f8010c4, 0x00010002).unwrap();
+        bus.write_word(0x1f8010c8, 0x01000201).unwrap();
+        bus.tick(2);
+        assert!(bus.interrupt_pending());
+        assert_eq!(bus.read_halfword(0x1f801dae).unwrap() & 0x40, 0x40);
+
+        // and back to ram in dma read mode
+        bus.write_halfword(0x1f801daa, 0x8070).unwrap();
+        bus.write_halfword(0x1f801da6, 0x0200).unwrap();
+        bus.write_word(0x1f8010c0, 0x00000200).unwrap();
+        bus.write_word(0x1f8010c4, 0x00010002).unwrap();
+        bus.write_word(0x1f8010c8, 0x01000200).unwrap();
+        bus.tick(2);
+        assert_eq!(bus.read_word(0x200), Ok(0x12345678));
+        assert_eq!(bus.read_word(0x204), Ok(0x9abcdef0));
+    }
+}
+
+#[cfg(test)]
+mod sio {
+    use super::*;
+
+    #[test]
+    fn pad_interrupt() {
+        let mut bus = Bus::new(vec![]);
+        bus.write_word(0x1f801074, 0x00000080).unwrap();
+        bus.write_halfword(0x1f80104e, 0x0088).unwrap();
+        bus.write_halfword(0x1f80104a, 0x1003).unwrap();
+        bus.write_byte(0x1f801040, 0x01).unwrap();
+        assert_eq!(bus.read_word(0x1f801044).unwrap() & 0x02, 0x00);
+
+        bus.tick(0x88 * 8);
+        assert_eq!(bus.read_word(0x1f801044).unwrap() & 0x02, 0x02);
+        assert_eq!(bus.read_byte(0x1f801040), Ok(0xff));
+        assert!(!bus.interrupt_pending());
+        bus.tick(400);
+        assert!(bus.interrupt_pending());
+        assert_eq!(bus.read_halfword(0x1f801044).unwrap() & 0x200, 0x200);
+
+        bus.write_halfword(0x1f80104a, 0x1013).unwrap();
+        bus.write_word(0x1f801070, 0).unwrap();
+        assert!(!bus.interrupt_pending());
+        assert_eq!(bus.read_halfword(0x1f80104a), Ok(0x1003));
     }
 }
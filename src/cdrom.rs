@@ -0,0 +1,1128 @@
+// CD-ROM controller, the command interface and the drive behind it
+
+use std::collections::VecDeque;
+
+use crate::disc::{self, bcd_to_binary, binary_to_bcd, Disc};
+use crate::xa::XaDecoder;
+
+// cpu cycles, the drive reads 75 sectors a second at single speed
+const SECTOR_CYCLES: u32 = 33_868_800 / 75;
+const FIRST_RESPONSE_CYCLES: u32 = 20_000;
+const INIT_CYCLES: u32 = 80_000;
+const GET_ID_CYCLES: u32 = 19_000;
+// real seeks depend on the distance, a fixed time is enough for the bios
+const SEEK_CYCLES: u32 = 33_868_800 / 100;
+const READ_TOC_CYCLES: u32 = 33_868_800 / 2;
+
+// samples of audio kept for the spu, two 18.9 kHz mono xa sectors, the most
+// any sector decodes to. the drive gets ahead of the spu by a little each
+// sector and the oldest are dropped past this
+const AUDIO_LIMIT: usize = 2 * 4032 * 7 / 3;
+
+// stat bits
+const STAT_ERROR: u8 = 0x01;
+const STAT_MOTOR: u8 = 0x02;
+const STAT_SHELL_OPEN: u8 = 0x10;
+const STAT_READING: u8 = 0x20;
+const STAT_SEEKING: u8 = 0x40;
+const STAT_PLAYING: u8 = 0x80;
+
+// second byte of the INT5 responses
+const ERROR_WRONG_PARAMETERS: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+const ERROR_NOT_READY: u8 = 0x80;
+
+const INT1: u8 = 1;
+const INT2: u8 = 2;
+const INT3: u8 = 3;
+const INT4: u8 = 4;
+const INT5: u8 = 5;
+
+struct Response {
+    interrupt: u8,
+    bytes: Vec<u8>,
+    // counts down once the previous response was acknowledged
+    delay: u32,
+    sector: Option<Vec<u8>>,
+}
+
+// what the drive does once a seek is over
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AfterSeek {
+    Pause,
+    Read,
+    Play,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Drive {
+    Idle,
+    Seeking { after: AfterSeek },
+    Reading,
+    // audio tracks straight to the spu
+    Playing,
+}
+
+pub struct Cdrom {
+    index: u8,
+    parameters: VecDeque<u8>,
+    response: VecDeque<u8>,
+    data: VecDeque<u8>,
+    interrupt_enable: u8,
+    interrupt_flag: u8,
+    // a command was sent and its first response did not arrive yet
+    busy: bool,
+    queued: VecDeque<Response>,
+
+    mode: u8,
+    drive: Drive,
+    drive_cycles: u32,
+    motor: bool,
+    setloc: Option<u32>,
+    position: u32,
+    // last sector read, moved to the data fifo when the cpu asks for it
+    sector: Vec<u8>,
+    // decoded audio waiting for the spu, left and right
+    audio: VecDeque<[i16; 2]>,
+    xa: XaDecoder,
+    // file and channel of the xa sectors to play
+    filter: (u8, u8),
+    muted: bool,
+    adpcm_muted: bool,
+    // 0x80 is full volume, from left and right to left and right of the spu.
+    // they take effect together once the cpu applies them
+    volume: [[u8; 2]; 2],
+    next_volume: [[u8; 2]; 2],
+    // tens of the frame in the last report, one goes out when they change
+    report: Option<u8>,
+    // the SCEx string is being read since Test 0x04
+    scex: bool,
+    disc: Option<Box<dyn Disc>>,
+}
+
+impl Cdrom {
+    pub fn new(disc: Option<Box<dyn Disc>>) -> Self {
+        Cdrom {
+            index: 0,
+            parameters: VecDeque::with_capacity(16),
+            response: VecDeque::with_capacity(16),
+            data: VecDeque::with_capacity(disc::SECTOR_SIZE),
+            interrupt_enable: 0,
+            interrupt_flag: 0,
+            busy: false,
+            queued: VecDeque::new(),
+            mode: 0,
+            drive: Drive::Idle,
+            drive_cycles: 0,
+            motor: disc.is_some(),
+            setloc: None,
+            position: 0,
+            sector: vec![],
+            audio: VecDeque::new(),
+            xa: XaDecoder::new(),
+            filter: (0, 0),
+            muted: false,
+            adpcm_muted: false,
+            volume: [[0x80, 0], [0, 0x80]],
+            next_volume: [[0x80, 0], [0, 0x80]],
+            report: None,
+            scex: false,
+            disc,
+        }
+    }
+
+    // the drive starts spinning as if the lid was just closed
+    pub fn insert_disc(&mut self, disc: Box<dyn Disc>) {
+        self.disc = Some(disc);
+        self.motor = true;
+        self.drive = Drive::Idle;
+        self.position = 0;
+        self.audio.clear();
+    }
+
+    pub fn irq(&self) -> bool {
+        self.interrupt_flag & self.interrupt_enable & 0x1f != 0
+    }
+
+    pub fn read(&mut self, offset: u32) -> u8 {
+        match (offset, self.index) {
+            (0, _) => self.status(),
+            (1, _) => self.response.pop_front().unwrap_or(0),
+            (2, _) => self.data.pop_front().unwrap_or(0),
+            (3, 0 | 2) => self.interrupt_enable | 0xe0,
+            (3, _) => self.interrupt_flag | 0xe0,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u8) {
+        match (offset, self.index) {
+            (0, _) => self.index = value & 3,
+            (1, 0) => self.command(value),
+            (2, 0) if self.parameters.len() < 16 => self.parameters.push_back(value),
+            (2, 1) => self.interrupt_enable = value & 0x1f,
+            (3, 0) => self.request(value),
+            (3, 1) => {
+                // writing one acknowledges, the next response can come in
+                self.interrupt_flag &= !(value & 0x1f);
+                if value & 0x40 != 0 {
+                    self.parameters.clear();
+                }
+            }
+            (1, 3) => self.next_volume[1][1] = value,
+            (2, 2) => self.next_volume[0][0] = value,
+            (2, 3) => self.next_volume[1][0] = value,
+            (3, 2) => self.next_volume[0][1] = value,
+            (3, 3) => {
+                self.adpcm_muted = value & 0x01 != 0;
+                if value & 0x20 != 0 {
+                    self.volume = self.next_volume;
+                }
+            }
+            // the sound map is not emulated
+            _ => (),
+        }
+    }
+
+    // the sector of the last INT1 goes to the data fifo
+    fn request(&mut self, value: u8) {
+        if value & 0x80 == 0 {
+            self.data.clear();
+            return;
+        }
+        if !self.data.is_empty() {
+            return;
+        }
+
+        let range = if self.mode & 0x20 != 0 {
+            // everything after the sync
+            12..0x930
+        } else {
+            24..0x818
+        };
+        if let Some(bytes) = self.sector.get(range) {
+            self.data.extend(bytes);
+        }
+    }
+
+    // cd-da and xa audio go to the spu at its own rate, one stereo sample
+    // for each of its output samples
+    pub fn audio_sample(&mut self) -> [i16; 2] {
+        let Some([left, right]) = self.audio.pop_front() else {
+            return [0; 2];
+        };
+        if self.muted {
+            return [0; 2];
+        }
+        [0, 1].map(|channel| {
+            let [from_left, from_right] = [0, 1].map(|from| self.volume[from][channel] as i32);
+            let sample = (left as i32 * from_left + right as i32 * from_right) >> 7;
+            sample.clamp(-0x8000, 0x7fff) as i16
+        })
+    }
+
+    // data fifo as the dma sees it
+    pub fn read_word(&mut self) -> u32 {
+        let bytes = [0; 4].map(|_| self.data.pop_front().unwrap_or(0));
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut left = cycles;
+        loop {
+            match self.cycles_until_event() {
+                Some(next) if next <= left => {
+                    self.elapse(next);
+                    left -= next;
+                    self.fire();
+                }
+                _ => {
+                    self.elapse(left);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn cycles_until_event(&self) -> Option<u32> {
+        let response = self.next_response().map(|response| response.delay);
+        let drive = (self.drive != Drive::Idle).then_some(self.drive_cycles);
+        [response, drive].into_iter().flatten().min()
+    }
+
+    // only the first response waits, and not while one is unacknowledged
+    fn next_response(&self) -> Option<&Response> {
+        self.queued.front().filter(|_| self.interrupt_flag == 0)
+    }
+
+    fn elapse(&mut self, cycles: u32) {
+        if self.next_response().is_some() {
+            self.queued[0].delay -= cycles;
+        }
+        if self.drive != Drive::Idle {
+            self.drive_cycles -= cycles;
+        }
+    }
+
+    fn fire(&mut self) {
+        if self.next_response().is_some_and(|response| response.delay == 0) {
+            let response = self.queued.pop_front().unwrap();
+            self.interrupt_flag = response.interrupt;
+            self.response = response.bytes.into();
+            if let Some(sector) = response.sector {
+                self.sector = sector;
+            }
+            self.busy = false;
+        }
+
+        if self.drive != Drive::Idle && self.drive_cycles == 0 {
+            self.drive_event();
+        }
+    }
+
+    fn drive_event(&mut self) {
+        match self.drive {
+            Drive::Seeking { after: AfterSeek::Read } => {
+                self.drive = Drive::Reading;
+                self.drive_cycles = self.sector_cycles();
+            }
+            Drive::Seeking { after: AfterSeek::Play } => {
+                self.drive = Drive::Playing;
+                self.drive_cycles = self.sector_cycles();
+            }
+            Drive::Seeking { after: AfterSeek::Pause } => {
+                self.drive = Drive::Idle;
+                self.push(INT2, vec![self.stat()], 0);
+            }
+            Drive::Reading => {
+                self.read_sector();
+                self.drive_cycles = self.sector_cycles();
+            }
+            Drive::Playing => {
+                self.play_sector();
+                self.drive_cycles = self.sector_cycles();
+            }
+            Drive::Idle => (),
+        }
+    }
+
+    fn read_sector(&mut self) {
+        let sector = match self.disc.as_mut() {
+            Some(disc) => disc.read_sector(self.position),
+            None => None,
+        };
+        self.position += 1;
+
+        match sector {
+            Some(sector) if self.is_xa_audio(&sector) => {
+                // real time audio goes to the decoder and not to the cpu
+                let (file, channel) = (sector[16], sector[17]);
+                let filtered = self.mode & 0x08 != 0 && (file, channel) != self.filter;
+                if !filtered && !self.adpcm_muted {
+                    self.xa.decode(&sector, &mut self.audio);
+                    self.limit_audio();
+                }
+            }
+            Some(sector) => {
+                // the cpu missed the previous sector, only the newest is kept
+                self.queued.retain(|response| response.interrupt != INT1);
+                self.queued.push_back(Response {
+                    interrupt: INT1,
+                    bytes: vec![self.stat()],
+                    delay: 0,
+                    sector: Some(sector),
+                });
+            }
+            None => {
+                self.drive = Drive::Idle;
+                self.push(INT4, vec![self.stat()], 0);
+            }
+        }
+    }
+
+    fn limit_audio(&mut self) {
+        let excess = self.audio.len().saturating_sub(AUDIO_LIMIT);
+        self.audio.drain(..excess);
+    }
+
+    fn is_xa_audio(&self, sector: &[u8]) -> bool {
+        // mode 2 sectors with the audio and real time submode bits
+        self.mode & 0x40 != 0 && sector[15] == 2 && sector[18] & 0x44 == 0x44
+    }
+
+    fn play_sector(&mut self) {
+        let lba = self.position;
+        let sector = match self.disc.as_mut() {
+            Some(disc) => disc.read_sector(lba),
+            None => None,
+        };
+        let Some(sector) = sector else {
+            self.drive = Drive::Idle;
+            self.push(INT4, vec![self.stat()], 0);
+            return;
+        };
+        self.position += 1;
+
+        let tracks = self.disc.as_ref().unwrap().tracks();
+        let track = tracks.iter().rev().find(|track| track.start <= lba);
+        let (number, start) = track.map_or((1, 0), |track| (track.number, track.start));
+        let next_track = tracks.iter().any(|track| track.start == self.position);
+
+        let samples = sector.chunks_exact(4).map(|sample| {
+            let left = i16::from_le_bytes([sample[0], sample[1]]);
+            let right = i16::from_le_bytes([sample[2], sample[3]]);
+            [left, right]
+        });
+        let peak = samples.clone().flatten().map(|sample| sample.unsigned_abs()).max();
+        self.audio.extend(samples);
+        self.limit_audio();
+
+        // auto pause stops at the end of the track
+        if self.mode & 0x02 != 0 && next_track {
+            self.drive = Drive::Idle;
+            self.push(INT4, vec![self.stat()], 0);
+            return;
+        }
+
+        let (minute, second, frame) = disc::msf(lba);
+        let nibble = binary_to_bcd(frame) >> 4;
+        if self.mode & 0x04 == 0 || self.report == Some(nibble) {
+            return;
+        }
+        self.report = Some(nibble);
+
+        // absolute time on the even reports, time in the track on the odd
+        // ones with bit 7 of the seconds set
+        let time = match nibble & 1 {
+            0 => [minute, second, frame].map(binary_to_bcd),
+            _ => {
+                let relative = lba - start;
+                let time = [relative / 75 / 60, relative / 75 % 60, relative % 75];
+                let [minute, second, frame] = time.map(|value| binary_to_bcd(value as u8));
+                [minute, second | 0x80, frame]
+            }
+        };
+        let peak = peak.unwrap_or(0).min(0x7fff);
+        let mut bytes = vec![self.stat(), binary_to_bcd(number), 0x01];
+        bytes.extend(time);
+        bytes.extend(peak.to_le_bytes());
+        self.queued.retain(|response| response.interrupt != INT1);
+        self.push(INT1, bytes, 0);
+    }
+
+    fn sector_cycles(&self) -> u32 {
+        if self.mode & 0x80 != 0 {
+            SECTOR_CYCLES / 2
+        } else {
+            SECTOR_CYCLES
+        }
+    }
+
+    fn stat(&self) -> u8 {
+        if self.disc.is_none() {
+            return STAT_SHELL_OPEN;
+        }
+
+        let mut stat = 0;
+        if self.motor {
+            stat |= STAT_MOTOR;
+        }
+        match self.drive {
+            Drive::Seeking { .. } => stat |= STAT_SEEKING,
+            Drive::Reading => stat |= STAT_READING,
+            Drive::Playing => stat |= STAT_PLAYING,
+            Drive::Idle => (),
+        }
+        stat
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.index;
+        status |= (self.parameters.is_empty() as u8) << 3;
+        status |= ((self.parameters.len() < 16) as u8) << 4;
+        status |= (!self.response.is_empty() as u8) << 5;
+        status |= (!self.data.is_empty() as u8) << 6;
+        status |= (self.busy as u8) << 7;
+        status
+    }
+
+    fn push(&mut self, interrupt: u8, bytes: Vec<u8>, delay: u32) {
+        self.queued.push_back(Response {
+            interrupt,
+            bytes,
+            delay,
+            sector: None,
+        });
+    }
+
+    fn acknowledge(&mut self, bytes: Vec<u8>) {
+        self.push(INT3, bytes, FIRST_RESPONSE_CYCLES);
+    }
+
+    fn error(&mut self, code: u8) {
+        self.push(INT5, vec![self.stat() | STAT_ERROR, code], FIRST_RESPONSE_CYCLES);
+    }
+
+    fn command(&mut self, command: u8) {
+        let parameters: Vec<u8> = self.parameters.drain(..).collect();
+        self.busy = true;
+
+        let expected = match command {
+            0x02 => 3..=3,
+            // the track to play is optional
+            0x03 => 0..=1,
+            0x0d => 2..=2,
+            0x0e | 0x14 | 0x19 => 1..=1,
+            _ => 0..=0,
+        };
+        if !expected.contains(&parameters.len()) {
+            self.error(ERROR_WRONG_PARAMETERS);
+            return;
+        }
+
+        // everything that needs the disc fails while the shell is open
+        let needs_disc = matches!(command, 0x03 | 0x06 | 0x13..=0x16 | 0x1b | 0x1e);
+        if needs_disc && self.disc.is_none() {
+            self.error(ERROR_NOT_READY);
+            return;
+        }
+
+        match command {
+            // GetStat
+            0x01 => self.acknowledge(vec![self.stat()]),
+            // Setloc
+            0x02 => {
+                let [minute, second, sector] = [0, 1, 2].map(|i| bcd_to_binary(parameters[i]));
+                self.setloc = Some(disc::lba(minute, second, sector));
+                self.audio.clear();
+                self.acknowledge(vec![self.stat()]);
+            }
+            // Play
+            0x03 => {
+                self.acknowledge(vec![self.stat()]);
+                let track = parameters.first().map(|&number| bcd_to_binary(number));
+                let tracks = self.disc.as_ref().unwrap().tracks();
+                // a track number of zero plays from where the drive is
+                let start = match track {
+                    Some(number) if number != 0 => {
+                        tracks.iter().find(|track| track.number == number).map(|track| track.start)
+                    }
+                    _ => None,
+                };
+                match start.or(self.setloc.take()) {
+                    Some(target) => {
+                        self.position = target;
+                        self.drive = Drive::Seeking {
+                            after: AfterSeek::Play,
+                        };
+                        self.drive_cycles = SEEK_CYCLES;
+                    }
+                    None => {
+                        self.drive = Drive::Playing;
+                        self.drive_cycles = self.sector_cycles();
+                    }
+                }
+                self.report = None;
+                self.motor = true;
+            }
+            // ReadN and ReadS
+            0x06 | 0x1b => {
+                self.acknowledge(vec![self.stat()]);
+                self.xa.reset();
+                match self.setloc.take() {
+                    Some(target) => {
+                        self.position = target;
+                        self.drive = Drive::Seeking {
+                            after: AfterSeek::Read,
+                        };
+                        self.drive_cycles = SEEK_CYCLES;
+                    }
+                    None => {
+                        self.drive = Drive::Reading;
+                        self.drive_cycles = self.sector_cycles();
+                    }
+                }
+                self.motor = true;
+            }
+            // Stop
+            0x08 => {
+                self.acknowledge(vec![self.stat()]);
+                self.drive = Drive::Idle;
+                self.motor = false;
+                self.audio.clear();
+                self.push(INT2, vec![self.stat()], SECTOR_CYCLES);
+            }
+            // Pause
+            0x09 => {
+                let delay = match self.drive {
+                    Drive::Idle => 7000,
+                    _ => self.sector_cycles(),
+                };
+                self.acknowledge(vec![self.stat()]);
+                self.drive = Drive::Idle;
+                self.queued.retain(|response| response.interrupt != INT1);
+                self.audio.clear();
+                self.push(INT2, vec![self.stat()], delay);
+            }
+            // Init
+            0x0a => {
+                self.mode = 0x20;
+                self.drive = Drive::Idle;
+                self.motor = self.disc.is_some();
+                self.queued.clear();
+                self.audio.clear();
+                self.push(INT3, vec![self.stat()], INIT_CYCLES);
+                self.push(INT2, vec![self.stat()], FIRST_RESPONSE_CYCLES);
+            }
+            // Mute and Demute
+            0x0b | 0x0c => {
+                self.muted = command == 0x0b;
+                self.acknowledge(vec![self.stat()]);
+            }
+            // Setfilter
+            0x0d => {
+                self.filter = (parameters[0], parameters[1]);
+                self.acknowledge(vec![self.stat()]);
+            }
+            // Setmode
+            0x0e => {
+                self.mode = parameters[0];
+                self.acknowledge(vec![self.stat()]);
+            }
+            // GetTN
+            0x13 => {
+                let tracks = self.disc.as_ref().unwrap().tracks();
+                let first = tracks.first().map_or(1, |track| track.number);
+                let last = tracks.last().map_or(1, |track| track.number);
+                self.acknowledge(vec![self.stat(), binary_to_bcd(first), binary_to_bcd(last)]);
+            }
+            // GetTD
+            0x14 => {
+                let number = bcd_to_binary(parameters[0]);
+                let disc = self.disc.as_ref().unwrap();
+                let start = match number {
+                    0 => Some(disc.lead_out()),
+                    _ => disc.tracks().iter().find(|t| t.number == number).map(|t| t.start),
+                };
+                match start {
+                    Some(start) => {
+                        let (minute, second, _) = disc::msf(start);
+                        let bytes = vec![self.stat(), binary_to_bcd(minute), binary_to_bcd(second)];
+                        self.acknowledge(bytes);
+                    }
+                    None => self.error(ERROR_WRONG_PARAMETERS),
+                }
+            }
+            // SeekL and SeekP
+            0x15 | 0x16 => {
+                self.acknowledge(vec![self.stat()]);
+                if let Some(target) = self.setloc.take() {
+                    self.position = target;
+                }
+                self.drive = Drive::Seeking {
+                    after: AfterSeek::Pause,
+                };
+                self.drive_cycles = SEEK_CYCLES;
+                self.motor = true;
+            }
+            // Test
+            0x19 => match parameters[0] {
+                // motor and servo adjustments, nothing to do for them
+                0x00..=0x03 => self.acknowledge(vec![self.stat()]),
+                // the drive starts reading the SCEx string over again
+                0x04 => {
+                    self.motor = self.disc.is_some();
+                    self.scex = true;
+                    self.acknowledge(vec![self.stat()]);
+                }
+                // how many times it read the string and how many it was right,
+                // taken as one read since the counters were reset
+                0x05 => {
+                    let read = std::mem::take(&mut self.scex) && self.motor;
+                    let good = read && self.region().is_some();
+                    self.acknowledge(vec![read as u8, good as u8]);
+                }
+                // date and version of the controller firmware
+                0x20 => self.acknowledge(vec![0x94, 0x09, 0x19, 0xc0]),
+                // the head is at the start of the disc and the shell is open
+                0x21 => {
+                    let head = (self.position == 0) as u8;
+                    let shell = (self.disc.is_none() as u8) << 1;
+                    self.acknowledge(vec![head | shell]);
+                }
+                // region of the drive, a north american one
+                0x22 => self.acknowledge(b"for U/C".to_vec()),
+                _ => self.error(ERROR_WRONG_PARAMETERS),
+            },
+            // GetID
+            0x1a => self.get_id(),
+            // ReadTOC
+            0x1e => {
+                self.acknowledge(vec![self.stat()]);
+                self.push(INT2, vec![self.stat()], READ_TOC_CYCLES);
+            }
+            _ => self.error(ERROR_INVALID_COMMAND),
+        }
+    }
+
+    fn get_id(&mut self) {
+        let Some(disc) = self.disc.as_mut() else {
+            self.push(INT5, vec![0x08, 0x40, 0, 0, 0, 0, 0, 0], FIRST_RESPONSE_CYCLES);
+            return;
+        };
+
+        let audio = disc.tracks().first().is_some_and(|track| track.audio);
+        let region = self.region();
+
+        self.acknowledge(vec![self.stat()]);
+        let response = match region {
+            Some(region) => {
+                let mut bytes = vec![self.stat(), 0x00, 0x20, 0x00];
+                bytes.extend(region);
+                (INT2, bytes)
+            }
+            // audio discs and unlicensed ones
+            None if audio => (INT5, vec![self.stat() | 0x08, 0x90, 0, 0, 0, 0, 0, 0]),
+            None => (INT5, vec![self.stat() | 0x08, 0x80, 0, 0, 0, 0, 0, 0]),
+        };
+        self.push(response.0, response.1, GET_ID_CYCLES);
+    }
+
+    // the license text of the system area names the region
+    fn region(&mut self) -> Option<[u8; 4]> {
+        let disc = self.disc.as_mut()?;
+        if disc.tracks().first().is_some_and(|track| track.audio) {
+            return None;
+        }
+
+        let license = disc.read_sector(4).unwrap_or_default();
+        if contains(&license, b"Sony Computer Entertainment Euro") {
+            Some(*b"SCEE")
+        } else if contains(&license, b"Sony Computer Entertainment Amer") {
+            Some(*b"SCEA")
+        } else if contains(&license, b"Sony Computer Entertainment Inc") {
+            Some(*b"SCEI")
+        } else {
+            None
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::disc::{Track, SECTOR_SIZE};
+
+    // 1000 sectors where each byte is its sector number, but for the ones
+    // replaced
+    struct TestDisc {
+        tracks: Vec<Track>,
+        replaced: HashMap<u32, Vec<u8>>,
+    }
+
+    impl Disc for TestDisc {
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn lead_out(&self) -> u32 {
+            1000
+        }
+
+        fn read_sector(&mut self, lba: u32) -> Option<Vec<u8>> {
+            if lba >= 1000 {
+                return None;
+            }
+            if let Some(sector) = self.replaced.get(&lba) {
+                return Some(sector.clone());
+            }
+            let mut sector = vec![lba as u8; SECTOR_SIZE];
+            if lba == 4 {
+                let license = b"Licensed  by  Sony Computer Entertainment Euro pe";
+                sector[24..24 + license.len()].copy_from_slice(license);
+            }
+            Some(sector)
+        }
+    }
+
+    fn with_disc() -> Cdrom {
+        let tracks = vec![
+            Track {
+                number: 1,
+                start: 0,
+                audio: false,
+            },
+            Track {
+                number: 2,
+                start: 600,
+                audio: true,
+            },
+        ];
+        let replaced = HashMap::new();
+        Cdrom::new(Some(Box::new(TestDisc { tracks, replaced })))
+    }
+
+    fn send(cdrom: &mut Cdrom, command: u8, parameters: &[u8]) {
+        cdrom.write(0, 0);
+        for &parameter in parameters {
+            cdrom.write(2, parameter);
+        }
+        cdrom.write(1, command);
+    }
+
+    // waits for the next response, acknowledges it and returns it
+    fn response(cdrom: &mut Cdrom) -> (u8, Vec<u8>) {
+        while cdrom.interrupt_flag == 0 {
+            cdrom.step(cdrom.cycles_until_event().unwrap());
+        }
+        cdrom.write(0, 1);
+        let interrupt = cdrom.read(3) & 0x1f;
+        let mut bytes = vec![];
+        while cdrom.read(0) & 0x20 != 0 {
+            bytes.push(cdrom.read(1));
+        }
+        cdrom.write(3, 0x1f);
+        (interrupt, bytes)
+    }
+
+    #[test]
+    fn status_register() {
+        let mut cdrom = with_disc();
+        assert_eq!(cdrom.read(0), 0x18);
+        cdrom.write(0, 1);
+        assert_eq!(cdrom.read(0), 0x19);
+
+        cdrom.write(0, 0);
+        cdrom.write(2, 0x12);
+        assert_eq!(cdrom.read(0), 0x10);
+        cdrom.write(1, 0x01);
+        // busy until the first response
+        assert_eq!(cdrom.read(0), 0x98);
+        assert_eq!(response(&mut cdrom), (INT5, vec![0x03, ERROR_WRONG_PARAMETERS]));
+        assert_eq!(cdrom.read(0), 0x19);
+    }
+
+    #[test]
+    fn get_stat() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x01, &[]);
+        cdrom.step(FIRST_RESPONSE_CYCLES - 1);
+        assert_eq!(cdrom.interrupt_flag, 0);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+
+        let mut empty = Cdrom::new(None);
+        send(&mut empty, 0x01, &[]);
+        assert_eq!(response(&mut empty), (INT3, vec![STAT_SHELL_OPEN]));
+    }
+
+    #[test]
+    fn irq() {
+        let mut cdrom = with_disc();
+        cdrom.write(0, 1);
+        cdrom.write(2, 0x1f);
+        send(&mut cdrom, 0x01, &[]);
+        assert!(!cdrom.irq());
+
+        cdrom.step(FIRST_RESPONSE_CYCLES);
+        assert!(cdrom.irq());
+        cdrom.write(0, 1);
+        cdrom.write(3, 0x07);
+        assert!(!cdrom.irq());
+    }
+
+    #[test]
+    fn read_sectors() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x10]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+        send(&mut cdrom, 0x06, &[]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+
+        assert_eq!(response(&mut cdrom), (INT1, vec![STAT_MOTOR | STAT_READING]));
+        cdrom.write(0, 0);
+        cdrom.write(3, 0x80);
+        assert_eq!(cdrom.read(0) & 0x40, 0x40);
+        assert_eq!(cdrom.read(2), 10);
+        assert_eq!(cdrom.data.len(), 0x800 - 1);
+
+        cdrom.write(3, 0x00);
+        assert_eq!(response(&mut cdrom), (INT1, vec![STAT_MOTOR | STAT_READING]));
+        cdrom.write(0, 0);
+        cdrom.write(3, 0x80);
+        assert_eq!(cdrom.read_word(), 0x0b0b0b0b);
+    }
+
+    #[test]
+    fn whole_sector_mode() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x0e, &[0xa0]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x1b, &[]);
+        response(&mut cdrom);
+
+        // double speed
+        cdrom.step(SECTOR_CYCLES / 2);
+        assert_eq!(response(&mut cdrom), (INT1, vec![STAT_MOTOR | STAT_READING]));
+        cdrom.write(0, 0);
+        cdrom.write(3, 0x80);
+        assert_eq!(cdrom.data.len(), 0x924);
+    }
+
+    #[test]
+    fn missed_sectors() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x06, &[]);
+        response(&mut cdrom);
+
+        // the cpu does not acknowledge the first sector in time
+        cdrom.step(SECTOR_CYCLES * 3);
+        cdrom.write(0, 1);
+        cdrom.write(3, 0x1f);
+        assert_eq!(response(&mut cdrom).0, INT1);
+        cdrom.write(0, 0);
+        cdrom.write(3, 0x80);
+        assert_eq!(cdrom.read(2), 2);
+    }
+
+    #[test]
+    fn seek_and_pause() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x02, &[0x00, 0x04, 0x00]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x15, &[]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+        assert_eq!(response(&mut cdrom), (INT2, vec![STAT_MOTOR]));
+        assert_eq!(cdrom.position, 150);
+
+        send(&mut cdrom, 0x06, &[]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x09, &[]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR | STAT_READING]));
+        assert_eq!(response(&mut cdrom), (INT2, vec![STAT_MOTOR]));
+        assert_eq!(cdrom.cycles_until_event(), None);
+    }
+
+    #[test]
+    fn init() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x0e, &[0x80]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x0a, &[]);
+
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+        assert_eq!(response(&mut cdrom), (INT2, vec![STAT_MOTOR]));
+        assert_eq!(cdrom.mode, 0x20);
+    }
+
+    #[test]
+    fn get_id() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x1a, &[]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+        let id = vec![STAT_MOTOR, 0x00, 0x20, 0x00, b'S', b'C', b'E', b'E'];
+        assert_eq!(response(&mut cdrom), (INT2, id));
+
+        let mut empty = Cdrom::new(None);
+        send(&mut empty, 0x1a, &[]);
+        assert_eq!(response(&mut empty), (INT5, vec![0x08, 0x40, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn table_of_contents() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x13, &[]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR, 0x01, 0x02]));
+
+        send(&mut cdrom, 0x14, &[0x02]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR, 0x00, 0x10]));
+        send(&mut cdrom, 0x14, &[0x00]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR, 0x00, 0x15]));
+        send(&mut cdrom, 0x14, &[0x03]);
+        assert_eq!(response(&mut cdrom).0, INT5);
+
+        send(&mut cdrom, 0x1e, &[]);
+        assert_eq!(response(&mut cdrom).0, INT3);
+        assert_eq!(response(&mut cdrom).0, INT2);
+    }
+
+    #[test]
+    fn test_command() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x19, &[0x20]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![0x94, 0x09, 0x19, 0xc0]));
+        send(&mut cdrom, 0x19, &[0x22]);
+        assert_eq!(response(&mut cdrom), (INT3, b"for U/C".to_vec()));
+        send(&mut cdrom, 0x19, &[0x21]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![0x01]));
+        send(&mut cdrom, 0x19, &[0x03]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+
+        // nothing counted until the counters are reset, the test disc is licensed
+        send(&mut cdrom, 0x19, &[0x05]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![0, 0]));
+        send(&mut cdrom, 0x19, &[0x04]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+        send(&mut cdrom, 0x19, &[0x05]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![1, 1]));
+
+        let mut empty = Cdrom::new(None);
+        send(&mut empty, 0x19, &[0x21]);
+        assert_eq!(response(&mut empty), (INT3, vec![0x03]));
+        send(&mut empty, 0x19, &[0x30]);
+        assert_eq!(response(&mut empty).0, INT5);
+    }
+
+    #[test]
+    fn errors() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x1f, &[]);
+        assert_eq!(response(&mut cdrom), (INT5, vec![0x03, ERROR_INVALID_COMMAND]));
+
+        let mut empty = Cdrom::new(None);
+        send(&mut empty, 0x06, &[]);
+        assert_eq!(response(&mut empty), (INT5, vec![0x11, ERROR_NOT_READY]));
+    }
+
+    #[test]
+    fn play() {
+        let mut cdrom = with_disc();
+        // reports on
+        send(&mut cdrom, 0x0e, &[0x04]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x03, &[0x02]);
+        assert_eq!(response(&mut cdrom), (INT3, vec![STAT_MOTOR]));
+
+        let playing = STAT_MOTOR | STAT_PLAYING;
+        let report = vec![playing, 0x02, 0x01, 0x00, 0x10, 0x00, 0x58, 0x58];
+        assert_eq!(response(&mut cdrom), (INT1, report));
+        assert_eq!(cdrom.audio.len(), 588);
+        assert_eq!(cdrom.audio_sample(), [0x5858, 0x5858]);
+
+        // the next one is 10 sectors later with the time in the track
+        let report = vec![playing, 0x02, 0x01, 0x00, 0x80, 0x10, 0x62, 0x62];
+        assert_eq!(response(&mut cdrom), (INT1, report));
+        assert_eq!(cdrom.position, 611);
+
+        // the left channel at half volume and muted
+        cdrom.write(0, 2);
+        cdrom.write(2, 0x40);
+        cdrom.write(0, 3);
+        cdrom.write(3, 0x20);
+        cdrom.audio.push_front([0x4000, 0x4000]);
+        assert_eq!(cdrom.audio_sample(), [0x2000, 0x4000]);
+        send(&mut cdrom, 0x0b, &[]);
+        response(&mut cdrom);
+        assert_eq!(cdrom.audio_sample(), [0, 0]);
+    }
+
+    #[test]
+    fn auto_pause() {
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x0e, &[0x02]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x02, &[0x00, 0x09, 0x73]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x03, &[]);
+        response(&mut cdrom);
+
+        // at the start of the second track
+        assert_eq!(response(&mut cdrom), (INT4, vec![STAT_MOTOR]));
+        assert_eq!(cdrom.position, 600);
+        assert_eq!(cdrom.audio.len(), 2 * 588);
+
+        // pausing drops what the spu did not take yet
+        send(&mut cdrom, 0x09, &[]);
+        response(&mut cdrom);
+        assert!(cdrom.audio.is_empty());
+    }
+
+    #[test]
+    fn audio_limit() {
+        // the spu never takes any, only the newest sectors are kept
+        let mut cdrom = with_disc();
+        send(&mut cdrom, 0x03, &[0x02]);
+        response(&mut cdrom);
+        for _ in 0..40 {
+            cdrom.step(SECTOR_CYCLES);
+        }
+        assert_eq!(cdrom.audio.len(), AUDIO_LIMIT);
+        let last = (cdrom.position - 1) as u8 as i16 * 0x101;
+        assert_eq!(cdrom.audio.back(), Some(&[last, last]));
+
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x00]);
+        response(&mut cdrom);
+        assert!(cdrom.audio.is_empty());
+
+        // two whole 18.9 kHz mono xa sectors fit, a third pushes the first out
+        let mut disc = TestDisc {
+            tracks: cdrom.disc.as_ref().unwrap().tracks().to_vec(),
+            replaced: HashMap::new(),
+        };
+        for lba in 20..23 {
+            let mut sector = vec![0; SECTOR_SIZE];
+            sector[15] = 2;
+            sector[16..20].copy_from_slice(&[1, 1, 0x64, 0x04]);
+            sector[24..].fill(0x11);
+            disc.replaced.insert(lba, sector);
+        }
+        cdrom.insert_disc(Box::new(disc));
+        send(&mut cdrom, 0x0e, &[0x48]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x0d, &[0x01, 0x01]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x20]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x06, &[]);
+        response(&mut cdrom);
+        while cdrom.position < 21 {
+            cdrom.step(SECTOR_CYCLES);
+        }
+        assert_eq!(cdrom.audio.len(), 4032 * 7 / 3);
+        cdrom.step(SECTOR_CYCLES);
+        assert_eq!(cdrom.audio.len(), AUDIO_LIMIT);
+        cdrom.step(SECTOR_CYCLES);
+        assert_eq!(cdrom.position, 23);
+        assert_eq!(cdrom.audio.len(), AUDIO_LIMIT);
+    }
+
+    #[test]
+    fn xa_audio() {
+        let mut cdrom = with_disc();
+        let mut disc = TestDisc {
+            tracks: cdrom.disc.as_ref().unwrap().tracks().to_vec(),
+            replaced: HashMap::new(),
+        };
+        for (lba, channel) in [(20, 0), (21, 1)] {
+            let mut sector = vec![0; SECTOR_SIZE];
+            sector[15] = 2;
+            sector[16..20].copy_from_slice(&[1, channel, 0x64, 0x00]);
+            sector[24..].fill(0x11);
+            disc.replaced.insert(lba, sector);
+        }
+        cdrom.insert_disc(Box::new(disc));
+
+        // xa-adpcm on, only file 1 and channel 1
+        send(&mut cdrom, 0x0e, &[0x48]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x0d, &[0x01, 0x01]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x20]);
+        response(&mut cdrom);
+        send(&mut cdrom, 0x06, &[]);
+        response(&mut cdrom);
+
+        // the audio sectors never reach the cpu
+        assert_eq!(response(&mut cdrom).0, INT1);
+        assert_eq!(cdrom.position, 23);
+        cdrom.write(0, 0);
+        cdrom.write(3, 0x80);
+        assert_eq!(cdrom.read(2), 22);
+        assert_eq!(cdrom.audio.len(), 4032 * 7 / 6);
+    }
+}
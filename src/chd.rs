@@ -0,0 +1,1118 @@
+// CHD images, version 5 with the cd codecs. The disc is split in hunks of a
+// few sectors, each stored with its subcode and compressed on its own
+
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use claxon::frame::FrameReader;
+use flate2::read::DeflateDecoder;
+
+use crate::disc::{Disc, Track, SECTOR_SIZE};
+
+const HEADER_SIZE: usize = 124;
+const SUBCODE_SIZE: usize = 96;
+// a sector followed by its subcode, the unit of the hunks
+const FRAME_SIZE: usize = SECTOR_SIZE + SUBCODE_SIZE;
+// the frames of every track are padded to a multiple of this
+const TRACK_PADDING: u32 = 4;
+
+const CODEC_CDLZ: u32 = u32::from_be_bytes(*b"cdlz");
+const CODEC_CDZL: u32 = u32::from_be_bytes(*b"cdzl");
+const CODEC_CDFL: u32 = u32::from_be_bytes(*b"cdfl");
+
+const METADATA_CHT2: u32 = u32::from_be_bytes(*b"CHT2");
+const METADATA_CHTR: u32 = u32::from_be_bytes(*b"CHTR");
+
+// types of the entries in the compressed map, the last ones are shorthands
+// that are turned into the first ones while decoding
+const MAP_CODEC_3: u8 = 3;
+const MAP_NONE: u8 = 4;
+const MAP_SELF: u8 = 5;
+const MAP_PARENT: u8 = 6;
+const MAP_RLE_SMALL: u8 = 7;
+const MAP_RLE_LARGE: u8 = 8;
+const MAP_SELF_0: u8 = 9;
+const MAP_SELF_1: u8 = 10;
+const MAP_PARENT_SELF: u8 = 11;
+const MAP_PARENT_0: u8 = 12;
+const MAP_PARENT_1: u8 = 13;
+
+const SYNC: [u8; 12] = [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0];
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    compressors: [u32; 4],
+    logical_bytes: u64,
+    map_offset: u64,
+    metadata_offset: u64,
+    hunk_bytes: u32,
+    unit_bytes: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hunk {
+    Compressed {
+        codec: usize,
+        offset: u64,
+        length: u32,
+        crc: u16,
+    },
+    Uncompressed {
+        offset: u64,
+        crc: Option<u16>,
+    },
+    // same data as an earlier hunk
+    Copy(u32),
+    // never written, it reads as zeros
+    Unallocated,
+    // the data is in the parent image
+    Parent,
+}
+
+#[derive(Debug, PartialEq)]
+struct ChdTrack {
+    number: u8,
+    audio: bool,
+    subcode: bool,
+    // counting the pregap when it is in the file
+    frames: u32,
+    pregap: u32,
+    pregap_in_file: bool,
+}
+
+// a run of disc sectors that are consecutive frames of the file
+#[derive(Debug, PartialEq)]
+struct Region {
+    start: i64,
+    length: u32,
+    // first frame, none for a pregap that is not in the file
+    frame: Option<u32>,
+    audio: bool,
+    subcode: bool,
+}
+
+pub struct Chd {
+    file: File,
+    header: Header,
+    map: Vec<Hunk>,
+    regions: Vec<Region>,
+    tracks: Vec<Track>,
+    lead_out: u32,
+    // sectors are read in order, keeping the last hunk is enough
+    cache: Option<(u32, Vec<u8>)>,
+}
+
+impl Chd {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut data = [0; HEADER_SIZE];
+        file.read_exact(&mut data)?;
+        let header = parse_header(&data)?;
+
+        let hunks = header.logical_bytes.div_ceil(header.hunk_bytes as u64) as usize;
+        let map = if header.compressors[0] == 0 {
+            let mut data = vec![0; hunks * 4];
+            file.seek(SeekFrom::Start(header.map_offset))?;
+            file.read_exact(&mut data)?;
+            uncompressed_map(&data, header.hunk_bytes)
+        } else {
+            let mut map_header = [0; 16];
+            file.seek(SeekFrom::Start(header.map_offset))?;
+            file.read_exact(&mut map_header)?;
+            let length = u32::from_be_bytes(map_header[0..4].try_into().unwrap());
+            let mut data = vec![0; 16 + length as usize];
+            data[..16].copy_from_slice(&map_header);
+            file.read_exact(&mut data[16..])?;
+            decompress_map(&data, hunks, header.hunk_bytes, header.unit_bytes)?
+        };
+
+        let chd_tracks = read_metadata(&mut file, header.metadata_offset)?;
+        let (regions, tracks, lead_out) = layout(&chd_tracks)?;
+
+        Ok(Chd {
+            file,
+            header,
+            map,
+            regions,
+            tracks,
+            lead_out,
+            cache: None,
+        })
+    }
+
+    // a whole frame of the file, the sector and its subcode
+    fn read_frame(&mut self, lba: u32) -> Option<(Vec<u8>, &Region)> {
+        let lba = lba as i64;
+        let index = self
+            .regions
+            .iter()
+            .position(|region| lba >= region.start && lba < region.start + region.length as i64)?;
+
+        let region = &self.regions[index];
+        let frame = match region.frame {
+            Some(first) => first + (lba - region.start) as u32,
+            None => return Some((vec![0; FRAME_SIZE], &self.regions[index])),
+        };
+
+        let frames_per_hunk = self.header.hunk_bytes / self.header.unit_bytes;
+        let hunk = match self.hunk(frame / frames_per_hunk) {
+            Ok(hunk) => hunk,
+            Err(error) => {
+                println!("Could not read sector {} of the chd image: {}", lba, error);
+                return None;
+            }
+        };
+        let offset = (frame % frames_per_hunk) as usize * FRAME_SIZE;
+        let data = hunk[offset..offset + FRAME_SIZE].to_vec();
+        Some((data, &self.regions[index]))
+    }
+
+    fn hunk(&mut self, mut index: u32) -> anyhow::Result<&[u8]> {
+        while let Some(&Hunk::Copy(other)) = self.map.get(index as usize) {
+            // only earlier hunks, so the chain ends
+            if other >= index {
+                bail!("hunk {} copies hunk {}", index, other);
+            }
+            index = other;
+        }
+
+        if self.cache.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let data = self.read_hunk(index)?;
+            self.cache = Some((index, data));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+
+    fn read_hunk(&mut self, index: u32) -> anyhow::Result<Vec<u8>> {
+        let hunk_bytes = self.header.hunk_bytes as usize;
+        let hunk = *self
+            .map
+            .get(index as usize)
+            .ok_or(anyhow!("hunk {} is out of the map", index))?;
+
+        let (data, crc) = match hunk {
+            Hunk::Compressed {
+                codec,
+                offset,
+                length,
+                crc,
+            } => {
+                let mut compressed = vec![0; length as usize];
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut compressed)?;
+                (decompress(self.header.compressors[codec], &compressed, hunk_bytes)?, Some(crc))
+            }
+            Hunk::Uncompressed { offset, crc } => {
+                let mut data = vec![0; hunk_bytes];
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut data)?;
+                (data, crc)
+            }
+            Hunk::Unallocated => (vec![0; hunk_bytes], None),
+            Hunk::Copy(_) | Hunk::Parent => bail!("hunk {} is in a parent image", index),
+        };
+
+        if crc.is_some_and(|crc| crc != crc16(&data)) {
+            bail!("hunk {} is corrupted", index);
+        }
+        Ok(data)
+    }
+}
+
+impl Disc for Chd {
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.lead_out
+    }
+
+    fn read_sector(&mut self, lba: u32) -> Option<Vec<u8>> {
+        let (mut frame, region) = self.read_frame(lba)?;
+        // the samples are big endian in the image
+        if region.audio {
+            for sample in frame.chunks_exact_mut(2) {
+                sample.swap(0, 1);
+            }
+        }
+        frame.truncate(SECTOR_SIZE);
+        Some(frame)
+    }
+
+    fn read_subcode(&mut self, lba: u32) -> Option<Vec<u8>> {
+        let (frame, region) = self.read_frame(lba)?;
+        match region.subcode {
+            true => Some(frame[SECTOR_SIZE..].to_vec()),
+            false => None,
+        }
+    }
+}
+
+fn parse_header(data: &[u8]) -> anyhow::Result<Header> {
+    let word = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+    let long = |offset: usize| u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap());
+
+    if &data[0..8] != b"MComprHD" {
+        bail!("not a chd image");
+    }
+    if word(12) != 5 {
+        bail!("unsupported chd version {}, only version 5 is supported", word(12));
+    }
+    if data[104..124].iter().any(|&byte| byte != 0) {
+        bail!("chd images that need a parent are not supported");
+    }
+
+    let header = Header {
+        compressors: [word(16), word(20), word(24), word(28)],
+        logical_bytes: long(32),
+        map_offset: long(40),
+        metadata_offset: long(48),
+        hunk_bytes: word(56),
+        unit_bytes: word(60),
+    };
+
+    if header.unit_bytes as usize != FRAME_SIZE
+        || header.hunk_bytes == 0
+        || !header.hunk_bytes.is_multiple_of(header.unit_bytes)
+    {
+        bail!("chd image is not a cd");
+    }
+    for &codec in header.compressors.iter().filter(|&&codec| codec != 0) {
+        if ![CODEC_CDLZ, CODEC_CDZL, CODEC_CDFL].contains(&codec) {
+            let name = String::from_utf8_lossy(&codec.to_be_bytes()).into_owned();
+            bail!("unsupported chd codec {}", name);
+        }
+    }
+    Ok(header)
+}
+
+// the offsets are in hunks, zero when the hunk was never written. images
+// with a parent are refused, so it is not there either
+fn uncompressed_map(data: &[u8], hunk_bytes: u32) -> Vec<Hunk> {
+    data.chunks_exact(4)
+        .map(|entry| match u32::from_be_bytes(entry.try_into().unwrap()) {
+            0 => Hunk::Unallocated,
+            offset => Hunk::Uncompressed {
+                offset: offset as u64 * hunk_bytes as u64,
+                crc: None,
+            },
+        })
+        .collect()
+}
+
+// reads from the most significant bit, with zeros after the end
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn peek(&self, bits: u32) -> u64 {
+        (0..bits as usize).fold(0, |value, i| {
+            let position = self.position + i;
+            let byte = self.data.get(position / 8).copied().unwrap_or(0);
+            value << 1 | ((byte >> (7 - position % 8)) & 1) as u64
+        })
+    }
+
+    fn read(&mut self, bits: u32) -> u64 {
+        let value = self.peek(bits);
+        self.position += bits as usize;
+        value
+    }
+
+    fn overflowed(&self) -> bool {
+        self.position > self.data.len() * 8
+    }
+}
+
+// canonical huffman codes of the map entry types
+struct Huffman {
+    // value and length of the code for every possible next MAX_BITS bits
+    lookup: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    const CODES: usize = 16;
+    const MAX_BITS: u32 = 8;
+
+    // the code lengths come run length encoded
+    fn import(bits: &mut BitReader) -> anyhow::Result<Self> {
+        let mut lengths = Vec::with_capacity(Self::CODES);
+        while lengths.len() < Self::CODES {
+            match bits.read(4) as u8 {
+                1 => match bits.read(4) as u8 {
+                    1 => lengths.push(1),
+                    length => {
+                        let count = bits.read(4) as usize + 3;
+                        lengths.extend(std::iter::repeat_n(length, count));
+                    }
+                },
+                length => lengths.push(length),
+            }
+        }
+        if lengths.len() != Self::CODES
+            || lengths.iter().any(|&length| length as u32 > Self::MAX_BITS)
+        {
+            bail!("invalid huffman tree in the chd map");
+        }
+
+        // first code of every length, the longest codes start at zero
+        let mut starts = [0u32; 33];
+        let mut start = 0;
+        for length in (1..=32).rev() {
+            let count = lengths.iter().filter(|&&code| code as usize == length).count() as u32;
+            if length != 1 && !(start + count).is_multiple_of(2) {
+                bail!("invalid huffman tree in the chd map");
+            }
+            starts[length] = start;
+            start = (start + count) >> 1;
+        }
+
+        let mut lookup = vec![(0, 0); 1 << Self::MAX_BITS];
+        for (value, &length) in lengths.iter().enumerate().filter(|(_, &length)| length > 0) {
+            let code = starts[length as usize];
+            starts[length as usize] += 1;
+            let shift = Self::MAX_BITS - length as u32;
+            let first = (code << shift) as usize;
+            let last = (((code + 1) << shift) - 1) as usize;
+            lookup
+                .get_mut(first..=last)
+                .ok_or(anyhow!("invalid huffman tree in the chd map"))?
+                .fill((value as u8, length));
+        }
+        Ok(Huffman { lookup })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> u8 {
+        let (value, length) = self.lookup[bits.peek(Self::MAX_BITS) as usize];
+        bits.read(length as u32);
+        value
+    }
+}
+
+// the map is a huffman coded list of entry types followed by the lengths,
+// offsets and crcs packed in as few bits as the header says
+fn decompress_map(
+    data: &[u8],
+    hunks: usize,
+    hunk_bytes: u32,
+    unit_bytes: u32,
+) -> anyhow::Result<Vec<Hunk>> {
+    let mut offset = data[4..10].iter().fold(0, |offset, &byte| offset << 8 | byte as u64);
+    let map_crc = u16::from_be_bytes([data[10], data[11]]);
+    let (length_bits, self_bits, parent_bits) = (data[12] as u32, data[13] as u32, data[14] as u32);
+    let mut bits = BitReader::new(&data[16..]);
+
+    let huffman = Huffman::import(&mut bits)?;
+    let mut types = Vec::with_capacity(hunks);
+    let mut last = 0;
+    while types.len() < hunks {
+        let repeat = match huffman.decode(&mut bits) {
+            MAP_RLE_SMALL => 2 + huffman.decode(&mut bits) as usize,
+            MAP_RLE_LARGE => {
+                let high = huffman.decode(&mut bits) as usize;
+                2 + 16 + (high << 4) + huffman.decode(&mut bits) as usize
+            }
+            kind => {
+                last = kind;
+                0
+            }
+        };
+        types.push(last);
+        types.extend(std::iter::repeat_n(last, repeat));
+    }
+    types.truncate(hunks);
+
+    let mut map = Vec::with_capacity(hunks);
+    // the entries as the crc of the map covers them
+    let mut raw = Vec::with_capacity(hunks * 12);
+    let (mut last_self, mut last_parent) = (0, 0);
+    for (hunk, &kind) in types.iter().enumerate() {
+        let (mut kind, mut length, mut position, mut crc) = (kind, 0, offset, 0);
+        match kind {
+            0..=MAP_CODEC_3 => {
+                length = bits.read(length_bits) as u32;
+                offset += length as u64;
+                crc = bits.read(16) as u16;
+            }
+            MAP_NONE => {
+                length = hunk_bytes;
+                offset += length as u64;
+                crc = bits.read(16) as u16;
+            }
+            MAP_SELF => {
+                position = bits.read(self_bits);
+                last_self = position;
+            }
+            MAP_PARENT => {
+                position = bits.read(parent_bits);
+                last_parent = position;
+            }
+            MAP_SELF_0 | MAP_SELF_1 => {
+                if kind == MAP_SELF_1 {
+                    last_self += 1;
+                }
+                kind = MAP_SELF;
+                position = last_self;
+            }
+            MAP_PARENT_SELF => {
+                kind = MAP_PARENT;
+                position = hunk as u64 * hunk_bytes as u64 / unit_bytes as u64;
+                last_parent = position;
+            }
+            MAP_PARENT_0 | MAP_PARENT_1 => {
+                if kind == MAP_PARENT_1 {
+                    last_parent += (hunk_bytes / unit_bytes) as u64;
+                }
+                kind = MAP_PARENT;
+                position = last_parent;
+            }
+            _ => bail!("invalid entry type {} in the chd map", kind),
+        }
+
+        raw.push(kind);
+        raw.extend_from_slice(&length.to_be_bytes()[1..]);
+        raw.extend_from_slice(&position.to_be_bytes()[2..]);
+        raw.extend_from_slice(&crc.to_be_bytes());
+
+        map.push(match kind {
+            0..=MAP_CODEC_3 => Hunk::Compressed {
+                codec: kind as usize,
+                offset: position,
+                length,
+                crc,
+            },
+            MAP_NONE => Hunk::Uncompressed {
+                offset: position,
+                crc: Some(crc),
+            },
+            MAP_SELF if position < hunk as u64 => Hunk::Copy(position as u32),
+            MAP_SELF => bail!("hunk {} copies a later hunk in the chd map", hunk),
+            _ => Hunk::Parent,
+        });
+    }
+
+    if bits.overflowed() || crc16(&raw) != map_crc {
+        bail!("chd map is corrupted");
+    }
+    Ok(map)
+}
+
+// the track list is in the metadata, one text entry per track
+fn read_metadata(file: &mut File, mut offset: u64) -> anyhow::Result<Vec<ChdTrack>> {
+    let mut tracks = vec![];
+    while offset != 0 {
+        let mut entry = [0; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut entry)?;
+        let tag = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes([0, entry[5], entry[6], entry[7]]);
+        offset = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+
+        if tag == METADATA_CHT2 || tag == METADATA_CHTR {
+            let mut text = vec![0; length as usize];
+            file.read_exact(&mut text)?;
+            let text = String::from_utf8_lossy(&text);
+            tracks.push(parse_track(text.trim_end_matches('\0'))?);
+        }
+    }
+
+    if tracks.is_empty() {
+        bail!("chd image has no cd tracks");
+    }
+    tracks.sort_by_key(|track| track.number);
+    Ok(tracks)
+}
+
+// TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1234 PREGAP:0 PGTYPE:MODE1 ...
+fn parse_track(text: &str) -> anyhow::Result<ChdTrack> {
+    let field = |name: &str| {
+        text.split_whitespace()
+            .filter_map(|pair| pair.split_once(':'))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let number = |name: &str| -> anyhow::Result<u32> {
+        Ok(field(name).map(str::parse).transpose()?.unwrap_or(0))
+    };
+
+    let audio = match field("TYPE") {
+        Some("AUDIO") => true,
+        Some("MODE1_RAW" | "MODE2_RAW") => false,
+        _ => bail!("unsupported track type in chd image: {}", text),
+    };
+    Ok(ChdTrack {
+        number: number("TRACK")? as u8,
+        audio,
+        subcode: !matches!(field("SUBTYPE"), None | Some("NONE")),
+        frames: number("FRAMES")?,
+        pregap: number("PREGAP")?,
+        pregap_in_file: field("PGTYPE").is_some_and(|kind| kind.starts_with('V')),
+    })
+}
+
+// places every track and pregap on the disc, the first track starts at 0
+fn layout(chd_tracks: &[ChdTrack]) -> anyhow::Result<(Vec<Region>, Vec<Track>, u32)> {
+    let mut regions = vec![];
+    let mut tracks = vec![];
+    let mut cursor: i64 = 0;
+    let mut frame = 0;
+
+    for track in chd_tracks {
+        let mut frames = track.frames;
+        if track.pregap > 0 {
+            let first = match track.pregap_in_file {
+                true => Some(frame),
+                false => None,
+            };
+            regions.push(Region {
+                start: cursor,
+                length: track.pregap,
+                frame: first,
+                audio: track.audio,
+                subcode: track.subcode && track.pregap_in_file,
+            });
+            if track.pregap_in_file {
+                frames = frames
+                    .checked_sub(track.pregap)
+                    .ok_or(anyhow!("track {} is shorter than its pregap", track.number))?;
+                frame += track.pregap;
+            }
+            cursor += track.pregap as i64;
+        }
+
+        tracks.push(Track {
+            number: track.number,
+            start: cursor as u32,
+            audio: track.audio,
+        });
+        regions.push(Region {
+            start: cursor,
+            length: frames,
+            frame: Some(frame),
+            audio: track.audio,
+            subcode: track.subcode,
+        });
+        cursor += frames as i64;
+        frame = (frame + frames).next_multiple_of(TRACK_PADDING);
+    }
+
+    let shift = tracks[0].start as i64;
+    for region in &mut regions {
+        region.start -= shift;
+    }
+    for track in &mut tracks {
+        track.start -= shift as u32;
+    }
+    Ok((regions, tracks, (cursor - shift) as u32))
+}
+
+// every codec stores the sectors and the subcode of the hunk apart
+fn decompress(codec: u32, data: &[u8], hunk_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let frames = hunk_bytes / FRAME_SIZE;
+
+    let (sectors, subcode) = match codec {
+        CODEC_CDFL => flac(data, frames * SECTOR_SIZE)?,
+        _ => {
+            // a bit per frame for the sectors that had their sync and ecc
+            // taken out, then the length of the compressed sectors
+            let ecc_bytes = frames.div_ceil(8);
+            let length_bytes = if hunk_bytes < 65536 { 2 } else { 3 };
+            let header = ecc_bytes + length_bytes;
+            if data.len() < header {
+                bail!("chd hunk is too short");
+            }
+            let length = data[ecc_bytes..header]
+                .iter()
+                .fold(0, |length, &byte| length << 8 | byte as usize);
+            if data.len() < header + length {
+                bail!("chd hunk is too short");
+            }
+
+            let (sectors, subcode) = data[header..].split_at(length);
+            let mut sectors = match codec {
+                CODEC_CDLZ => lzma(sectors, frames * SECTOR_SIZE, hunk_bytes)?,
+                _ => inflate(sectors, frames * SECTOR_SIZE)?,
+            };
+            for (frame, sector) in sectors.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+                if data[frame / 8] & (1 << (frame % 8)) != 0 {
+                    sector[..12].copy_from_slice(&SYNC);
+                    generate_ecc(sector);
+                }
+            }
+            (sectors, subcode)
+        }
+    };
+    let subcode = inflate(subcode, frames * SUBCODE_SIZE)?;
+
+    let mut hunk = Vec::with_capacity(hunk_bytes);
+    let frames = sectors.chunks_exact(SECTOR_SIZE).zip(subcode.chunks_exact(SUBCODE_SIZE));
+    for (sector, subcode) in frames {
+        hunk.extend_from_slice(sector);
+        hunk.extend_from_slice(subcode);
+    }
+    Ok(hunk)
+}
+
+fn inflate(data: &[u8], size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut output = vec![0; size];
+    DeflateDecoder::new(data).read_exact(&mut output)?;
+    Ok(output)
+}
+
+// raw lzma without its header, the properties are always the same
+fn lzma(data: &[u8], size: usize, hunk_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    // lc 3, lp 0 and pb 2, with the dictionary the encoder picks for the hunk
+    let mut input = vec![0x5d];
+    input.extend_from_slice(&dictionary_size(hunk_bytes as u32).to_le_bytes());
+    input.extend_from_slice(data);
+
+    let mut output = Vec::with_capacity(size);
+    let options = lzma_rs::decompress::Options {
+        unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(size as u64)),
+        ..Default::default()
+    };
+    lzma_rs::lzma_decompress_with_options(&mut &input[..], &mut output, &options)?;
+    Ok(output)
+}
+
+// the smallest 2^n or 3 * 2^n that holds the hunk
+fn dictionary_size(hunk_bytes: u32) -> u32 {
+    for shift in 11..=30 {
+        if hunk_bytes <= 2 << shift {
+            return 2 << shift;
+        }
+        if hunk_bytes <= 3 << shift {
+            return 3 << shift;
+        }
+    }
+    1 << 26
+}
+
+// bare flac frames of 16 bit stereo, followed by the deflated subcode
+fn flac(data: &[u8], size: usize) -> anyhow::Result<(Vec<u8>, &[u8])> {
+    let mut input = Cursor::new(data);
+    let mut reader = FrameReader::new(&mut input);
+    let mut output = Vec::with_capacity(size);
+    let mut buffer = vec![];
+
+    while output.len() < size {
+        let block = reader.read_next_or_eof(buffer)?.ok_or(anyhow!("chd flac data ended early"))?;
+        if block.channels() != 2 {
+            bail!("chd flac data is not stereo");
+        }
+        for sample in 0..block.duration() {
+            for channel in 0..2 {
+                output.extend_from_slice(&(block.sample(channel, sample) as i16).to_be_bytes());
+            }
+        }
+        buffer = block.into_buffer();
+    }
+    output.truncate(size);
+
+    let consumed = input.position() as usize;
+    Ok((output, &data[consumed..]))
+}
+
+const fn ecc_tables() -> ([u8; 256], [u8; 256]) {
+    let mut forward = [0; 256];
+    let mut backward = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let j = (i << 1) ^ if i & 0x80 != 0 { 0x11d } else { 0 };
+        forward[i] = j as u8;
+        backward[i ^ j] = i as u8;
+        i += 1;
+    }
+    (forward, backward)
+}
+
+const ECC_FORWARD: [u8; 256] = ecc_tables().0;
+const ECC_BACKWARD: [u8; 256] = ecc_tables().1;
+
+// the p and q parity of a data sector, mode 2 leaves the header out
+fn generate_ecc(sector: &mut [u8]) {
+    let mut header = [0; 4];
+    header.copy_from_slice(&sector[12..16]);
+    if sector[15] == 2 {
+        sector[12..16].fill(0);
+    }
+
+    ecc_block(sector, 86, 24, 2, 86, 0x81c);
+    ecc_block(sector, 52, 43, 86, 88, 0x8c8);
+    sector[12..16].copy_from_slice(&header);
+}
+
+fn ecc_block(
+    sector: &mut [u8],
+    majors: usize,
+    minors: usize,
+    major_step: usize,
+    minor_step: usize,
+    destination: usize,
+) {
+    let size = majors * minors;
+    for major in 0..majors {
+        let mut index = (major >> 1) * major_step + (major & 1);
+        let (mut a, mut b) = (0, 0);
+        for _ in 0..minors {
+            let byte = sector[12 + index];
+            index += minor_step;
+            if index >= size {
+                index -= size;
+            }
+            a ^= byte;
+            b ^= byte;
+            a = ECC_FORWARD[a as usize];
+        }
+        a = ECC_BACKWARD[(ECC_FORWARD[a as usize] ^ b) as usize];
+        sector[destination + major] = a;
+        sector[destination + major + majors] = a ^ b;
+    }
+}
+
+// crc16 ccitt, for the map and every hunk
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const HUNK_BYTES: u32 = 2 * FRAME_SIZE as u32;
+
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u64, bits: u32) {
+            for i in (0..bits).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                *self.data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+    }
+
+    // every entry type gets a 4 bit code, with the value as the code
+    fn flat_tree(bits: &mut BitWriter) {
+        for _ in 0..Huffman::CODES {
+            bits.write(4, 4);
+        }
+    }
+
+    fn raw_entry(raw: &mut Vec<u8>, kind: u8, length: u32, offset: u64, crc: u16) {
+        raw.push(kind);
+        raw.extend_from_slice(&length.to_be_bytes()[1..]);
+        raw.extend_from_slice(&offset.to_be_bytes()[2..]);
+        raw.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    fn map_header(length: usize, first: u64, crc: u16) -> Vec<u8> {
+        let mut header = (length as u32).to_be_bytes().to_vec();
+        header.extend_from_slice(&first.to_be_bytes()[2..]);
+        header.extend_from_slice(&crc.to_be_bytes());
+        // 24 bits of length, 8 bits of self reference and no parent
+        header.extend_from_slice(&[24, 8, 0, 0]);
+        header
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn lzma_raw(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let options = lzma_rs::compress::Options {
+            unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        };
+        lzma_rs::lzma_compress_with_options(&mut &data[..], &mut output, &options).unwrap();
+        // without the properties and dictionary size
+        output.split_off(5)
+    }
+
+    fn cd_hunk(flags: u8, sectors: Vec<u8>, subcode: &[u8]) -> Vec<u8> {
+        let mut hunk = vec![flags];
+        hunk.extend_from_slice(&(sectors.len() as u16).to_be_bytes());
+        hunk.extend(sectors);
+        hunk.extend(deflate(subcode));
+        hunk
+    }
+
+    fn flac_crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            })
+        })
+    }
+
+    fn flac_crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x8005,
+            })
+        })
+    }
+
+    // a frame with a constant subframe for each channel
+    fn flac_frame(left: i16, right: i16, samples: u16) -> Vec<u8> {
+        // fixed block size, 44100hz, two independent channels of 16 bits
+        let mut frame = vec![0xff, 0xf8, 0x79, 0x18, 0x00];
+        frame.extend_from_slice(&(samples - 1).to_be_bytes());
+        frame.push(flac_crc8(&frame));
+        for value in [left, right] {
+            frame.push(0);
+            frame.extend_from_slice(&value.to_be_bytes());
+        }
+        frame.extend_from_slice(&flac_crc16(&frame).to_be_bytes());
+        frame
+    }
+
+    enum Entry {
+        // codec, compressed data and the crc of the hunk
+        Codec(u8, Vec<u8>, u16),
+        Copy(u32),
+    }
+
+    fn image(entries: &[Entry], tracks: &[&str]) -> Vec<u8> {
+        let mut file = vec![0; HEADER_SIZE];
+        file[0..8].copy_from_slice(b"MComprHD");
+        file[8..12].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+        file[12..16].copy_from_slice(&5u32.to_be_bytes());
+        file[16..28].copy_from_slice(b"cdlzcdzlcdfl");
+        file[32..40].copy_from_slice(&(entries.len() as u64 * HUNK_BYTES as u64).to_be_bytes());
+        file[56..60].copy_from_slice(&HUNK_BYTES.to_be_bytes());
+        file[60..64].copy_from_slice(&(FRAME_SIZE as u32).to_be_bytes());
+
+        let metadata_offset = file.len() as u64;
+        file[48..56].copy_from_slice(&metadata_offset.to_be_bytes());
+        for (i, track) in tracks.iter().enumerate() {
+            let next = match i + 1 < tracks.len() {
+                true => file.len() + 16 + track.len() + 1,
+                false => 0,
+            };
+            // the flags share a word with the length
+            file.extend_from_slice(b"CHT2");
+            file.extend_from_slice(&(0x0100_0000 | (track.len() as u32 + 1)).to_be_bytes());
+            file.extend_from_slice(&(next as u64).to_be_bytes());
+            file.extend_from_slice(track.as_bytes());
+            file.push(0);
+        }
+
+        let first = file.len() as u64;
+        let mut bits = BitWriter::default();
+        let mut raw = vec![];
+        flat_tree(&mut bits);
+        for entry in entries {
+            match entry {
+                Entry::Codec(codec, ..) => bits.write(*codec as u64, 4),
+                Entry::Copy(_) => bits.write(MAP_SELF as u64, 4),
+            }
+        }
+        for entry in entries {
+            match entry {
+                Entry::Codec(codec, data, crc) => {
+                    bits.write(data.len() as u64, 24);
+                    bits.write(*crc as u64, 16);
+                    raw_entry(&mut raw, *codec, data.len() as u32, file.len() as u64, *crc);
+                    file.extend_from_slice(data);
+                }
+                Entry::Copy(hunk) => {
+                    bits.write(*hunk as u64, 8);
+                    raw_entry(&mut raw, MAP_SELF, 0, *hunk as u64, 0);
+                }
+            }
+        }
+
+        let map_offset = file.len() as u64;
+        file[40..48].copy_from_slice(&map_offset.to_be_bytes());
+        file.extend(map_header(bits.data.len(), first, crc16(&raw)));
+        file.extend(bits.data);
+        file
+    }
+
+    #[test]
+    fn track_layout() {
+        let chd_tracks = [
+            "TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1001 PREGAP:150 PGTYPE:MODE1 PGSUB:NONE",
+            "TRACK:2 TYPE:AUDIO SUBTYPE:RW FRAMES:500 PREGAP:150 PGTYPE:VAUDIO PGSUB:RW",
+            "TRACK:3 TYPE:AUDIO SUBTYPE:RW FRAMES:10 PREGAP:0 PGTYPE:VAUDIO PGSUB:RW",
+        ]
+        .map(|text| parse_track(text).unwrap());
+        assert_eq!(
+            chd_tracks[1],
+            ChdTrack {
+                number: 2,
+                audio: true,
+                subcode: true,
+                frames: 500,
+                pregap: 150,
+                pregap_in_file: true,
+            }
+        );
+        assert!(parse_track("TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:10").is_err());
+
+        let (regions, tracks, lead_out) = layout(&chd_tracks).unwrap();
+        let starts: Vec<u32> = tracks.iter().map(|track| track.start).collect();
+        // the pregap of the first track is not on the disc, the second one is
+        // in the file after the padding of the first track
+        assert_eq!(starts, vec![0, 1001 + 150, 1001 + 500]);
+        assert_eq!(lead_out, 1001 + 500 + 10);
+        assert_eq!(regions[0].frame, None);
+        assert_eq!(regions[2].start, 1001);
+        assert_eq!(regions[2].frame, Some(1004));
+        assert_eq!(regions[3].frame, Some(1004 + 150));
+        assert_eq!(regions[4].frame, Some(1004 + 500));
+    }
+
+    #[test]
+    fn map_shorthands() {
+        let mut bits = BitWriter::default();
+        flat_tree(&mut bits);
+        // an uncompressed hunk repeated three more times, then two copies
+        for kind in [MAP_NONE, MAP_RLE_SMALL, 0, MAP_SELF_0, MAP_SELF_1] {
+            bits.write(kind as u64, 4);
+        }
+        let mut raw = vec![];
+        for i in 0..4 {
+            bits.write(0x1111 * i, 16);
+            let offset = 1000 + i * HUNK_BYTES as u64;
+            raw_entry(&mut raw, MAP_NONE, HUNK_BYTES, offset, 0x1111 * i as u16);
+        }
+        raw_entry(&mut raw, MAP_SELF, 0, 0, 0);
+        raw_entry(&mut raw, MAP_SELF, 0, 1, 0);
+
+        let mut data = map_header(bits.data.len(), 1000, crc16(&raw));
+        data.extend_from_slice(&bits.data);
+        let map = decompress_map(&data, 6, HUNK_BYTES, FRAME_SIZE as u32).unwrap();
+        assert_eq!(
+            map[3],
+            Hunk::Uncompressed {
+                offset: 1000 + 3 * HUNK_BYTES as u64,
+                crc: Some(0x3333),
+            }
+        );
+        assert_eq!(map[4..], [Hunk::Copy(0), Hunk::Copy(1)]);
+
+        data[11] ^= 1;
+        assert!(decompress_map(&data, 6, HUNK_BYTES, FRAME_SIZE as u32).is_err());
+    }
+
+    #[test]
+    fn read_image() {
+        let mut data_sector = vec![0; SECTOR_SIZE];
+        data_sector[..12].copy_from_slice(&SYNC);
+        data_sector[12..16].copy_from_slice(&[0x00, 0x02, 0x00, 0x02]);
+        data_sector[18] = 0x08;
+        data_sector[22] = 0x08;
+        for (i, byte) in data_sector[24..2072].iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        generate_ecc(&mut data_sector);
+        assert!(data_sector[0x81c..].iter().any(|&byte| byte != 0));
+
+        // the first sector goes in without its sync and ecc
+        let mut stripped = data_sector.clone();
+        stripped[..12].fill(0);
+        stripped[0x81c..].fill(0);
+        let other_sector: Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+        let subcode: Vec<u8> = (0..2 * SUBCODE_SIZE).map(|i| 0x80 | i as u8).collect();
+        let mut hunk0 = vec![];
+        let frames = [&data_sector, &other_sector].into_iter().zip(subcode.chunks(SUBCODE_SIZE));
+        for (sector, subcode) in frames {
+            hunk0.extend_from_slice(sector);
+            hunk0.extend_from_slice(subcode);
+        }
+        let sectors = [stripped, other_sector.clone()].concat();
+        let lzma_hunk = cd_hunk(0x01, lzma_raw(&sectors), &subcode);
+
+        let mut hunk1 = [vec![0x22; SECTOR_SIZE], vec![0; SUBCODE_SIZE]].concat();
+        hunk1.extend([vec![0x33; SECTOR_SIZE], vec![0; SUBCODE_SIZE]].concat());
+        let sectors = [vec![0x22; SECTOR_SIZE], vec![0x33; SECTOR_SIZE]].concat();
+        let zlib_hunk = cd_hunk(0, deflate(&sectors), &[0; 2 * SUBCODE_SIZE]);
+
+        // audio is big endian in the image
+        let audio = [0x12, 0x34, 0xff, 0xfe].repeat(SECTOR_SIZE / 4);
+        let hunk2 = [audio.clone(), vec![0; SUBCODE_SIZE], audio, vec![0; SUBCODE_SIZE]].concat();
+        let mut flac_hunk = flac_frame(0x1234, -2, (SECTOR_SIZE / 2) as u16);
+        flac_hunk.extend(deflate(&[0; 2 * SUBCODE_SIZE]));
+
+        let mut hunk4 = [[1, 2].repeat(SECTOR_SIZE / 2), vec![0; SUBCODE_SIZE]].concat();
+        hunk4.extend(vec![0; FRAME_SIZE]);
+        let sectors = [[1, 2].repeat(SECTOR_SIZE / 2), vec![0; SECTOR_SIZE]].concat();
+        let last_hunk = cd_hunk(0, deflate(&sectors), &[0; 2 * SUBCODE_SIZE]);
+
+        let entries = [
+            Entry::Codec(0, lzma_hunk, crc16(&hunk0)),
+            Entry::Codec(1, zlib_hunk, crc16(&hunk1)),
+            Entry::Codec(2, flac_hunk, crc16(&hunk2)),
+            Entry::Copy(2),
+            Entry::Codec(1, last_hunk, crc16(&hunk4)),
+        ];
+        let tracks = [
+            "TRACK:1 TYPE:MODE2_RAW SUBTYPE:RW FRAMES:4 PREGAP:0 PGTYPE:MODE1 PGSUB:RW POSTGAP:0",
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:3 PREGAP:1 PGTYPE:VAUDIO PGSUB:NONE POSTGAP:0",
+            "TRACK:3 TYPE:AUDIO SUBTYPE:NONE FRAMES:2 PREGAP:2 PGTYPE:AUDIO PGSUB:NONE POSTGAP:0",
+        ];
+        let path = std::env::temp_dir().join(format!("psiemu_test_{}.chd", std::process::id()));
+        std::fs::write(&path, image(&entries, &tracks)).unwrap();
+
+        let mut chd = Chd::open(&path).unwrap();
+        let starts: Vec<u32> = chd.tracks().iter().map(|track| track.start).collect();
+        assert_eq!(starts, vec![0, 5, 9]);
+        assert_eq!(chd.lead_out(), 11);
+
+        assert_eq!(chd.read_sector(0).unwrap(), data_sector);
+        assert_eq!(chd.read_sector(1).unwrap(), other_sector);
+        assert_eq!(chd.read_subcode(1).unwrap(), subcode[SUBCODE_SIZE..]);
+        assert_eq!(chd.read_sector(3).unwrap(), vec![0x33; SECTOR_SIZE]);
+        // the pregap of track 2 is in the file, the one of track 3 is not
+        assert_eq!(chd.read_sector(4).unwrap()[..4], [0x34, 0x12, 0xfe, 0xff]);
+        assert_eq!(chd.read_sector(6).unwrap()[..4], [0x34, 0x12, 0xfe, 0xff]);
+        assert_eq!(chd.read_subcode(6), None);
+        assert_eq!(chd.read_sector(7).unwrap(), vec![0; SECTOR_SIZE]);
+        assert_eq!(chd.read_sector(9).unwrap()[..4], [2, 1, 2, 1]);
+        assert_eq!(chd.read_sector(11), None);
+
+        // a copy that does not point back is refused instead of followed
+        chd.cache = None;
+        chd.map[3] = Hunk::Copy(3);
+        assert_eq!(chd.read_sector(6), None);
+        chd.map[3] = Hunk::Copy(4);
+        assert_eq!(chd.read_sector(6), None);
+    }
+
+    #[test]
+    fn unallocated_hunks() {
+        let map = uncompressed_map(&[0, 0, 0, 0, 0, 0, 0, 3], HUNK_BYTES);
+        let offset = 3 * HUNK_BYTES as u64;
+        assert_eq!(map, [Hunk::Unallocated, Hunk::Uncompressed { offset, crc: None }]);
+    }
+}
@@ -0,0 +1,239 @@
+// Controllers on the joypad port, the digital pad and the DualShock with its
+// analog sticks, config mode and motors
+
+// bits of the buttons in the order the pad sends them, where 0 is pressed
+const BUTTONS: [&str; 16] = [
+    "select", "l3", "r3", "start", "up", "right", "down", "left", "l2", "r2", "l1", "r1",
+    "triangle", "circle", "cross", "square",
+];
+
+const DIGITAL_ID: u8 = 0x41;
+const ANALOG_ID: u8 = 0x73;
+const CONFIG_ID: u8 = 0xf3;
+
+// a byte of the rumble map that drives no motor
+const NO_MOTOR: u8 = 0xff;
+
+// the bit of a button by its name
+pub fn button(name: &str) -> Option<u16> {
+    BUTTONS.iter().position(|&button| button == name).map(|bit| 1 << bit)
+}
+
+// how fast each motor turns, from 0 to 0xff. the small one is either
+// stopped or at full speed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Motors {
+    pub small: u8,
+    pub large: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Digital,
+    DualShock,
+}
+
+pub struct Controller {
+    kind: Kind,
+    // pressed buttons, one bit each
+    buttons: u16,
+    // right x, right y, left x and left y, 0x80 is the center
+    sticks: [u8; 4],
+    analog: bool,
+    config: bool,
+    // which motor each byte after the command drives, 0x00 the small one
+    // and 0x01 the big one
+    rumble_map: [u8; 6],
+    small_motor: bool,
+    large_motor: u8,
+
+    // byte of the transfer in progress, and what the pad sends back after
+    // the address byte
+    step: usize,
+    command: u8,
+    reply: Vec<u8>,
+}
+
+impl Controller {
+    pub fn new(kind: Kind) -> Self {
+        Controller {
+            kind,
+            buttons: 0,
+            sticks: [0x80; 4],
+            analog: false,
+            config: false,
+            rumble_map: [NO_MOTOR; 6],
+            small_motor: false,
+            large_motor: 0,
+            step: 0,
+            command: 0,
+            reply: vec![],
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.buttons = buttons;
+    }
+
+    pub fn motors(&self) -> Motors {
+        Motors {
+            small: if self.small_motor { 0xff } else { 0x00 },
+            large: self.large_motor,
+        }
+    }
+
+    // the pad was deselected, the next byte starts a new command
+    pub fn end_transfer(&mut self) {
+        self.step = 0;
+    }
+
+    // the byte going back and whether the pad acknowledges it, which it
+    // does for every byte but the last
+    pub fn transfer(&mut self, byte: u8) -> (u8, bool) {
+        let step = self.step;
+        self.step += 1;
+
+        if step == 0 {
+            return (0xff, true);
+        }
+        if step == 1 {
+            self.command = byte;
+            self.reply = self.reply(byte);
+        }
+        if step >= 3 && !self.reply.is_empty() {
+            self.receive(step - 3, byte);
+        }
+        match self.reply.get(step - 1) {
+            Some(&value) => (value, step < self.reply.len()),
+            None => (0xff, false),
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match (self.config, self.analog) {
+            (true, _) => CONFIG_ID,
+            (false, true) => ANALOG_ID,
+            (false, false) => DIGITAL_ID,
+        }
+    }
+
+    fn reply(&self, command: u8) -> Vec<u8> {
+        let mut reply = vec![self.id(), 0x5a];
+        match command {
+            // a digital pad only knows how to read the buttons
+            _ if self.kind == Kind::Digital && command != 0x42 => return vec![],
+            // entering config mode reads the buttons too
+            0x42 | 0x43 if !self.config || command == 0x42 => {
+                reply.extend((!self.buttons).to_le_bytes());
+                if self.analog || self.config {
+                    reply.extend(self.sticks);
+                }
+            }
+            _ if !self.config => return vec![],
+            // status, with the led on in analog mode
+            // 0x01 is a dualshock, 0x03 would be the older analog pad
+            0x45 => reply.extend([0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00]),
+            0x47 => reply.extend([0x00, 0x00, 0x02, 0x00, 0x01, 0x00]),
+            // the rumble map so far
+            0x4d => reply.extend(self.rumble_map),
+            // the rest depend on their parameter, patched once it comes in
+            0x43 | 0x44 | 0x46 | 0x4c => reply.extend([0; 6]),
+            _ => return vec![],
+        }
+        reply
+    }
+
+    // the bytes after the command and the 0x5a, by their index
+    fn receive(&mut self, index: usize, byte: u8) {
+        match (self.command, index) {
+            (0x42, _) => match self.rumble_map.get(index) {
+                Some(0x00) => self.small_motor = byte & 0x01 != 0,
+                Some(0x01) => self.large_motor = byte,
+                _ => (),
+            },
+            (0x43, 0) => self.config = byte == 0x01,
+            // the second byte locks the analog button, which is not there
+            (0x44, 0) => self.analog = byte == 0x01,
+            // the tables of the actuators and the modes
+            (0x46, 0) => {
+                let table = match byte {
+                    0x00 => [0x01, 0x02, 0x00, 0x0a],
+                    _ => [0x01, 0x01, 0x01, 0x14],
+                };
+                self.reply[4..8].copy_from_slice(&table);
+            }
+            (0x4c, 0) => self.reply[5] = if byte == 0x00 { 0x04 } else { 0x07 },
+            (0x4d, index) if index < 6 => self.rumble_map[index] = byte,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the whole exchange, with the bytes after the address
+    fn exchange(pad: &mut Controller, bytes: &[u8]) -> Vec<(u8, bool)> {
+        pad.end_transfer();
+        pad.transfer(0x01);
+        bytes.iter().map(|&byte| pad.transfer(byte)).collect()
+    }
+
+    fn replies(pad: &mut Controller, bytes: &[u8]) -> Vec<u8> {
+        exchange(pad, bytes).into_iter().map(|(byte, _)| byte).collect()
+    }
+
+    #[test]
+    fn digital() {
+        let mut pad = Controller::new(Kind::Digital);
+        pad.set_buttons(button("start").unwrap() | button("cross").unwrap());
+        let reply = exchange(&mut pad, &[0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, [(0x41, true), (0x5a, true), (0xf7, true), (0xbf, false)]);
+
+        // no config mode on a digital pad
+        assert_eq!(exchange(&mut pad, &[0x43, 0x00, 0x01]), [(0xff, false); 3]);
+        assert!(!pad.config);
+        assert_eq!(button("home"), None);
+    }
+
+    #[test]
+    fn analog_mode() {
+        let mut pad = Controller::new(Kind::DualShock);
+        let read = [0x42, 0x00, 0x00, 0x00];
+        assert_eq!(replies(&mut pad, &read), [0x41, 0x5a, 0xff, 0xff]);
+
+        // config mode, analog and locked, then out of config mode
+        assert_eq!(replies(&mut pad, &[0x43, 0x00, 0x01, 0x00, 0x00])[0], 0x41);
+        let reply = replies(&mut pad, &[0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, [0xf3, 0x5a, 0, 0, 0, 0, 0, 0]);
+        let reply = replies(&mut pad, &[0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, [0xf3, 0x5a, 0x01, 0x02, 0x01, 0x02, 0x01, 0x00]);
+        let reply = replies(&mut pad, &[0x46, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, [0xf3, 0x5a, 0x00, 0x00, 0x01, 0x01, 0x01, 0x14]);
+        replies(&mut pad, &[0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert!(!pad.config);
+
+        pad.sticks = [0x10, 0x20, 0x30, 0x40];
+        let reply = exchange(&mut pad, &[0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let bytes: Vec<u8> = reply.iter().map(|&(byte, _)| byte).collect();
+        assert_eq!(bytes, [0x73, 0x5a, 0xff, 0xff, 0x10, 0x20, 0x30, 0x40]);
+        assert!(reply[..7].iter().all(|&(_, ack)| ack));
+        assert!(!reply[7].1);
+    }
+
+    #[test]
+    fn rumble() {
+        let mut pad = Controller::new(Kind::DualShock);
+        replies(&mut pad, &[0x43, 0x00, 0x01, 0x00, 0x00]);
+        // small motor on the first byte and the big one on the second
+        let map = [0x4d, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(replies(&mut pad, &map)[2..], [0xff; 6]);
+        replies(&mut pad, &[0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        replies(&mut pad, &[0x42, 0x00, 0x01, 0xc0]);
+        assert_eq!(pad.motors(), Motors { small: 0xff, large: 0xc0 });
+        replies(&mut pad, &[0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(pad.motors(), Motors::default());
+    }
+}
@@ -5,17 +5,22 @@ use parsmips::MipsI;
 use parsmips::Register as RegisterType;
 
 use crate::bus::Bus;
+use crate::gte::Gte;
 
 #[derive(Debug, PartialEq)]
 pub enum Exception {
     Reset,
-    BusError,
-    AddressError,
+    BusErrorInstruction,
+    BusErrorData,
+    // the address that caused the exception goes to BadVaddr
+    AddressErrorLoad(u32),
+    AddressErrorStore(u32),
     Overflow,
     SystemCall,
     Breakpoint,
     ReservedInstruction,
-    CoprocessorUnusable,
+    // number of the coprocessor goes to the CE field of Cause
+    CoprocessorUnusable(u8),
     Interrupt,
     Debug,
 }
@@ -38,6 +43,47 @@ impl MemorySpace {
     }
 }
 
+// the cache control register lives in kseg2 and is part of the cpu
+const CACHE_CONTROL: u32 = 0xfffe0130;
+
+// 4 KiB direct mapped instruction cache, 256 lines of 4 words
+struct ICache {
+    lines: [ICacheLine; 256],
+}
+
+#[derive(Clone, Copy)]
+struct ICacheLine {
+    // bits 12-28 of the physical address cached on this line
+    tag: u32,
+    // one bit for each word of the line
+    valid: u8,
+    data: [u32; 4],
+}
+
+impl ICache {
+    fn new() -> Self {
+        ICache {
+            lines: [ICacheLine {
+                tag: 0,
+                valid: 0,
+                data: [0; 4],
+            }; 256],
+        }
+    }
+
+    // drops the lines holding any of the physical range
+    fn invalidate(&mut self, address: u32, length: u32) {
+        let start = address & 0x1ffffff0;
+        let end = (address & 0x1fffffff) + length;
+        for address in (start..end).step_by(16) {
+            let line = &mut self.lines[((address >> 4) & 0xff) as usize];
+            if line.tag == address & 0x1ffff000 {
+                line.valid = 0;
+            }
+        }
+    }
+}
+
 enum Register {
     Zero,
     Normal(u32),
@@ -59,57 +105,187 @@ impl Register {
     }
 }
 
+// Only the registers the psx has, the R3000A has no TLB
 struct Cop0 {
-    register_file: [Register; 32],
+    // breakpoint on execute address and mask
+    bpc: u32,
+    bpcm: u32,
+    // breakpoint on data access address and mask
+    bda: u32,
+    bdam: u32,
+    // jump destination, read-only
+    tar: u32,
+    // breakpoint control
+    dcic: u32,
+    bad_vaddr: u32,
+    sr: u32,
+    cause: u32,
+    epc: u32,
 }
 
 impl Cop0 {
+    const BPC: u8 = 3;
+    const BDA: u8 = 5;
+    const TAR: u8 = 6;
+    const DCIC: u8 = 7;
+    const BAD_VADDR: u8 = 8;
+    const BDAM: u8 = 9;
+    const BPCM: u8 = 11;
+    const SR: u8 = 12;
+    const CAUSE: u8 = 13;
+    const EPC: u8 = 14;
+    const PRID: u8 = 15;
+
+    // bits 6-7, 19-21 and 23-24, 26-27 are read-only or always zero
+    const SR_WRITE_MASK: u32 = 0xf247ff3f;
+    // only the two software interrupt bits can be written
+    const CAUSE_WRITE_MASK: u32 = 0x00000300;
+    const DCIC_WRITE_MASK: u32 = 0xff80f03f;
+    // CXD8530BQ (R3000A compatible)
+    const PRID_VALUE: u32 = 0x00000002;
+
     pub fn new() -> Self {
-        let register_file: [Register; 32] = [
-            Register::Zero,
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-            Register::Normal(0),
-        ];
+        Cop0 {
+            bpc: 0,
+            bpcm: 0,
+            bda: 0,
+            bdam: 0,
+            tar: 0,
+            dcic: 0,
+            bad_vaddr: 0,
+            // boots with the bootstrap exception vectors in the bios
+            sr: 0x00400000,
+            cause: 0,
+            epc: 0,
+        }
+    }
+
+    fn read(&self, index: u8) -> Result<u32, Exception> {
+        match index {
+            Cop0::BPC => Ok(self.bpc),
+            Cop0::BDA => Ok(self.bda),
+            Cop0::TAR => Ok(self.tar),
+            Cop0::DCIC => Ok(self.dcic),
+            Cop0::BAD_VADDR => Ok(self.bad_vaddr),
+            Cop0::BDAM => Ok(self.bdam),
+            Cop0::BPCM => Ok(self.bpcm),
+            Cop0::SR => Ok(self.sr),
+            Cop0::CAUSE => Ok(self.cause),
+            Cop0::EPC => Ok(self.epc),
+            Cop0::PRID => Ok(Cop0::PRID_VALUE),
+            // the TLB registers do not exist
+            0 | 1 | 2 | 4 | 10 => Err(Exception::ReservedInstruction),
+            // garbage on real hardware
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, index: u8, value: u32) {
+        match index {
+            Cop0::BPC => self.bpc = value,
+            Cop0::BDA => self.bda = value,
+            Cop0::DCIC => self.dcic = value & Cop0::DCIC_WRITE_MASK,
+            Cop0::BDAM => self.bdam = value,
+            Cop0::BPCM => self.bpcm = value,
+            Cop0::SR => self.sr = value & Cop0::SR_WRITE_MASK,
+            Cop0::CAUSE => {
+                self.cause &= !Cop0::CAUSE_WRITE_MASK;
+                self.cause |= value & Cop0::CAUSE_WRITE_MASK;
+            }
+            // TAR, BadVaddr, EPC and PRId are read-only
+            _ => (),
+        }
+    }
+
+    fn interrupt_enable(&self) -> bool {
+        self.sr & 0x00000001 != 0
+    }
+
+    fn kernel_mode(&self) -> bool {
+        self.sr & 0x00000002 == 0
+    }
+
+    fn interrupt_mask(&self) -> u8 {
+        (self.sr >> 8) as u8
+    }
+
+    fn isolate_cache(&self) -> bool {
+        self.sr & 0x00010000 != 0
+    }
+
+    fn bootstrap_vectors(&self) -> bool {
+        self.sr & 0x00400000 != 0
+    }
+
+    fn coprocessor_usable(&self, cop: u8) -> bool {
+        self.sr & (0x10000000 << cop) != 0
+    }
+
+    // Cause.IP2 follows the interrupt controller output
+    fn set_hardware_interrupt(&mut self, pending: bool) {
+        if pending {
+            self.cause |= 0x00000400;
+        } else {
+            self.cause &= !0x00000400;
+        }
+    }
 
-        Cop0 { register_file }
+    fn interrupt_requested(&self) -> bool {
+        let pending = (self.cause >> 8) as u8;
+        self.interrupt_enable() && pending & self.interrupt_mask() != 0
+    }
+
+    fn enter_exception(&mut self, code: u32, epc: u32, delay_slot: bool) {
+        // push kernel mode with interrupts disabled onto the KU/IE stack
+        self.sr = (self.sr & !0x0000003f) | ((self.sr << 2) & 0x0000003f);
+
+        // keep the interrupt pending bits, only ExcCode, CE and BD change
+        self.cause &= !0xb000007c;
+        self.cause |= code << 2;
+        if delay_slot {
+            self.cause |= 0x80000000;
+        }
+
+        self.epc = epc;
+    }
+
+    fn return_from_exception(&mut self) {
+        // pop the KU/IE stack, the old bits are left untouched
+        self.sr = (self.sr & !0x0000000f) | ((self.sr >> 2) & 0x0000000f);
+    }
+
+    fn reset(&mut self) {
+        // kernel mode, interrupts disabled and bootstrap exception vectors
+        self.sr = (self.sr & !0x00000003) | 0x00400000;
     }
 }
 
 pub struct Cpu {
     cop0: Cop0,
+    gte: Gte,
     register_file: [Register; 32],
     hi: u32,
     lo: u32,
     pc: u32,
+    // address of the instruction after pc, a branch or jump changes this
+    // and not pc so that the instruction in the delay slot is executed
+    next_pc: u32,
+    // address of the instruction being executed
+    current_pc: u32,
+    // the gte commands are taken from the raw instruction word
+    current_instruction: u32,
+    // set by branches and jumps, the next instruction is on a delay slot
+    branch: bool,
+    delay_slot: bool,
+    // a load is only visible after the instruction that follows it,
+    // the load issued by this instruction waits here for one cycle
+    pending_load: Option<(u8, u32)>,
+    // load issued by the previous instruction, written after this one
+    delayed_load: Option<(u8, u32)>,
+    icache: ICache,
+    // the cache is slow to emulate and most software does not notice it
+    icache_emulation: bool,
+    cache_control: u32,
 }
 
 impl Cpu {
@@ -151,17 +327,63 @@ impl Cpu {
 
         Cpu {
             cop0: Cop0::new(),
+            gte: Gte::new(),
             register_file,
             hi: 0,
             lo: 0,
             pc: 0xbfc00000,
+            next_pc: 0xbfc00004,
+            current_pc: 0xbfc00000,
+            current_instruction: 0,
+            branch: false,
+            delay_slot: false,
+            pending_load: None,
+            delayed_load: None,
+            icache: ICache::new(),
+            icache_emulation: false,
+            cache_control: 0,
         }
     }
 
+    pub fn enable_icache(&mut self) {
+        self.icache_emulation = true;
+    }
+
+    // for memory written without going through the cpu
+    pub fn invalidate_icache(&mut self, address: u32, length: u32) {
+        self.icache.invalidate(address, length);
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    // starts executing at address, outside of any branch or load delay
+    pub fn jump(&mut self, address: u32) {
+        self.pc = address;
+        self.next_pc = address.wrapping_add(4);
+        self.branch = false;
+        self.pending_load = None;
+        self.delayed_load = None;
+    }
+
     pub fn cpu_cycle(&mut self, bus: &mut Bus) {
+        self.current_pc = self.pc;
+        self.delay_slot = self.branch;
+        self.branch = false;
+
+        // the interrupt is taken before the instruction at pc is executed
+        self.cop0.set_hardware_interrupt(bus.interrupt_pending());
+        if self.cop0.interrupt_requested() {
+            self.retire_load();
+            self.handle_exception(Exception::Interrupt);
+            return;
+        }
+
         let instr = match self.fetch_decode_instruction(bus) {
             Ok(instr) => instr,
             Err(exception) => {
+                self.retire_load();
                 self.handle_exception(exception);
                 return;
             }
@@ -169,16 +391,25 @@ impl Cpu {
 
         println!("pc:{:x} instr:{:?}", self.pc, instr);
 
-        self.pc += 4;
+        self.pc = self.next_pc;
+        self.next_pc = self.next_pc.wrapping_add(4);
+
+        let result = self.execute_instruction(instr, bus);
+        self.retire_load();
 
-        match self.execute_instruction(instr, bus) {
+        match result {
             Ok(_) => (),
             Err(exception) => self.handle_exception(exception),
         }
     }
 
-    fn fetch_decode_instruction(&mut self, bus: &Bus) -> Result<MipsI, Exception> {
-        let instr = self.read_word(self.pc, bus)?;
+    fn fetch_decode_instruction(&mut self, bus: &mut Bus) -> Result<MipsI, Exception> {
+        // the bus cannot tell a fetch from a load
+        let instr = self.fetch_instruction(bus).map_err(|exception| match exception {
+            Exception::BusErrorData => Exception::BusErrorInstruction,
+            exception => exception,
+        })?;
+        self.current_instruction = instr;
         let instr = match instr.decode() {
             Ok(instr) => instr,
             Err(e) => {
@@ -215,38 +446,14 @@ impl Cpu {
                 self.andi(rs, rt, immediate);
                 Ok(())
             },
-            MipsI::Bc0f(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc0f(immediate);
-                Ok(())
-            },
-            MipsI::Bc0t(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc0t(immediate);
-                Ok(())
-            },
-            MipsI::Bc1f(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc1f(immediate);
-                Ok(())
-            },
-            MipsI::Bc1t(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc1t(immediate);
-                Ok(())
-            },
-            MipsI::Bc2f(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc2f(immediate);
-                Ok(())
-            },
-            MipsI::Bc2t(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc2t(immediate);
-                Ok(())
-            },
-            MipsI::Bc3f(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc3f(immediate);
-                Ok(())
-            },
-            MipsI::Bc3t(ImmediateType { rs: _, rt: _, immediate }) => {
-                self.bc3t(immediate);
-                Ok(())
-            },
+            MipsI::Bc0f(ImmediateType { rs: _, rt: _, immediate }) => self.bc0f(immediate),
+            MipsI::Bc0t(ImmediateType { rs: _, rt: _, immediate }) => self.bc0t(immediate),
+            MipsI::Bc1f(ImmediateType { rs: _, rt: _, immediate }) => self.bc1f(immediate),
+            MipsI::Bc1t(ImmediateType { rs: _, rt: _, immediate }) => self.bc1t(immediate),
+            MipsI::Bc2f(ImmediateType { rs: _, rt: _, immediate }) => self.bc2f(immediate),
+            MipsI::Bc2t(ImmediateType { rs: _, rt: _, immediate }) => self.bc2t(immediate),
+            MipsI::Bc3f(ImmediateType { rs: _, rt: _, immediate }) => self.bc3f(immediate),
+            MipsI::Bc3t(ImmediateType { rs: _, rt: _, immediate }) => self.bc3t(immediate),
             MipsI::Beq(ImmediateType { rs, rt, immediate }) => {
                 self.beq(rs, rt, immediate);
                 Ok(())
@@ -280,16 +487,16 @@ impl Cpu {
                 Ok(())
             },
             MipsI::Break(RegisterType { rs: _, rt: _, rd: _, sa: _ }) => Err(self.r#break()),
-            MipsI::Cfc1(_) => todo!(),
-            MipsI::Cfc2(_) => todo!(),
-            MipsI::Cfc3(_) => todo!(),
-            MipsI::Cop0(_) => todo!(),
-            MipsI::Cop1(_) => todo!(),
-            MipsI::Cop2(_) => todo!(),
-            MipsI::Cop3(_) => todo!(),
-            MipsI::Ctc1(_) => todo!(),
-            MipsI::Ctc2(_) => todo!(),
-            MipsI::Ctc3(_) => todo!(),
+            MipsI::Cfc1(_) => self.check_coprocessor(1),
+            MipsI::Cfc2(RegisterType { rs: _, rt, rd, sa: _ }) => self.cfc2(rt, rd),
+            MipsI::Cfc3(_) => self.check_coprocessor(3),
+            MipsI::Cop0(_) => self.check_coprocessor(0),
+            MipsI::Cop1(_) => self.check_coprocessor(1),
+            MipsI::Cop2(_) => self.cop2(),
+            MipsI::Cop3(_) => self.check_coprocessor(3),
+            MipsI::Ctc1(_) => self.check_coprocessor(1),
+            MipsI::Ctc2(RegisterType { rs: _, rt, rd, sa: _ }) => self.ctc2(rt, rd),
+            MipsI::Ctc3(_) => self.check_coprocessor(3),
             MipsI::Div(RegisterType { rs, rt, rd: _, sa: _ }) => {
                 self.div(rs, rt);
                 Ok(())
@@ -306,8 +513,14 @@ impl Cpu {
                 self.jal(target);
                 Ok(())
             },
-            MipsI::Jalr(RegisterType { rs, rt: _, rd, sa: _ }) => self.jalr(rs, rd),
-            MipsI::Jr(RegisterType { rs, rt: _, rd: _, sa: _ }) => self.jr(rs),
+            MipsI::Jalr(RegisterType { rs, rt: _, rd, sa: _ }) => {
+                self.jalr(rs, rd);
+                Ok(())
+            },
+            MipsI::Jr(RegisterType { rs, rt: _, rd: _, sa: _ }) => {
+                self.jr(rs);
+                Ok(())
+            },
             MipsI::Lb(ImmediateType { rs, rt, immediate }) => self.lb(rs, rt, immediate, bus),
             MipsI::Lbu(ImmediateType { rs, rt, immediate }) => self.lbu(rs, rt, immediate, bus),
             MipsI::Lh(ImmediateType { rs, rt, immediate }) => self.lh(rs, rt, immediate, bus),
@@ -317,15 +530,15 @@ impl Cpu {
                 Ok(())
             },
             MipsI::Lw(ImmediateType { rs, rt, immediate }) => self.lw(rs, rt, immediate, bus),
-            MipsI::Lwc1(_) => todo!(),
-            MipsI::Lwc2(_) => todo!(),
-            MipsI::Lwc3(_) => todo!(),
+            MipsI::Lwc1(_) => self.check_coprocessor(1),
+            MipsI::Lwc2(ImmediateType { rs, rt, immediate }) => self.lwc2(rs, rt, immediate, bus),
+            MipsI::Lwc3(_) => self.check_coprocessor(3),
             MipsI::Lwl(ImmediateType { rs, rt, immediate }) => self.lwl(rs, rt, immediate, bus),
             MipsI::Lwr(ImmediateType { rs, rt, immediate }) => self.lwr(rs, rt, immediate, bus),
-            MipsI::Mfc0(RegisterType { rs, rt, rd, sa }) => self.mfc0(rt, rd),
-            MipsI::Mfc1(_) => todo!(),
-            MipsI::Mfc2(_) => todo!(),
-            MipsI::Mfc3(_) => todo!(),
+            MipsI::Mfc0(RegisterType { rs: _, rt, rd, sa: _ }) => self.mfc0(rt, rd),
+            MipsI::Mfc1(_) => self.check_coprocessor(1),
+            MipsI::Mfc2(RegisterType { rs: _, rt, rd, sa: _ }) => self.mfc2(rt, rd),
+            MipsI::Mfc3(_) => self.check_coprocessor(3),
             MipsI::Mfhi(RegisterType { rs: _, rt: _, rd, sa: _ }) => {
                 self.mfhi(rd);
                 Ok(())
@@ -334,10 +547,10 @@ impl Cpu {
                 self.mflo(rd);
                 Ok(())
             },
-            MipsI::Mtc0(RegisterType { rs, rt, rd, sa }) => self.mtc0(rt, rd),
-            MipsI::Mtc1(_) => todo!(),
-            MipsI::Mtc2(_) => todo!(),
-            MipsI::Mtc3(_) => todo!(),
+            MipsI::Mtc0(RegisterType { rs: _, rt, rd, sa: _ }) => self.mtc0(rt, rd),
+            MipsI::Mtc1(_) => self.check_coprocessor(1),
+            MipsI::Mtc2(RegisterType { rs: _, rt, rd, sa: _ }) => self.mtc2(rt, rd),
+            MipsI::Mtc3(_) => self.check_coprocessor(3),
             MipsI::Mthi(RegisterType { rs, rt: _, rd: _, sa: _ }) => {
                 self.mthi(rs);
                 Ok(())
@@ -415,9 +628,9 @@ impl Cpu {
                 Ok(())
             },
             MipsI::Sw(ImmediateType { rs, rt, immediate }) => self.sw(rs, rt, immediate, bus),
-            MipsI::Swc1(_) => todo!(),
-            MipsI::Swc2(_) => todo!(),
-            MipsI::Swc3(_) => todo!(),
+            MipsI::Swc1(_) => self.check_coprocessor(1),
+            MipsI::Swc2(ImmediateType { rs, rt, immediate }) => self.swc2(rs, rt, immediate, bus),
+            MipsI::Swc3(_) => self.check_coprocessor(3),
             MipsI::Swl(ImmediateType { rs, rt, immediate }) => self.swl(rs, rt, immediate, bus),
             MipsI::Swr(ImmediateType { rs, rt, immediate }) => self.swr(rs, rt, immediate, bus),
             MipsI::Syscall(RegisterType { rs: _, rt: _, rd: _, sa: _ }) => Err(self.syscall()),
@@ -436,48 +649,230 @@ impl Cpu {
         }
     }
 
-    fn handle_exception(&mut self, _exception: Exception) {
-        todo!()
+    pub fn set_register(&mut self, index: u8, value: u32) {
+        // a write in the load delay slot wins over the load
+        if matches!(self.delayed_load, Some((rt, _)) if rt == index) {
+            self.delayed_load = None;
+        }
+
+        self.register_file[index as usize].write(value);
     }
 
-    fn read_byte(&self, address: u32, bus: &Bus) -> Result<u8, Exception> {
+    fn load_register(&mut self, index: u8, value: u32) {
+        // two loads in a row to the same register, the first one is discarded
+        if matches!(self.delayed_load, Some((rt, _)) if rt == index) {
+            self.delayed_load = None;
+        }
+
+        self.pending_load = Some((index, value));
+    }
+
+    // lwl and lwr merge with the value of a load that is still on its delay
+    // slot, so that a lwl/lwr pair works without a nop between them
+    fn load_bypass(&self, index: u8) -> u32 {
+        match self.delayed_load {
+            Some((rt, value)) if rt == index => value,
+            _ => self.register_file[index as usize].read(),
+        }
+    }
+
+    fn retire_load(&mut self) {
+        if let Some((rt, value)) = self.delayed_load.take() {
+            self.register_file[rt as usize].write(value);
+        }
+
+        self.delayed_load = self.pending_load.take();
+    }
+
+    // cop0 is always usable in kernel mode, there is no cop1 or cop3 on the
+    // psx so when they are marked usable their instructions do nothing
+    fn check_coprocessor(&self, cop: u8) -> Result<(), Exception> {
+        if self.cop0.coprocessor_usable(cop) || (cop == 0 && self.cop0.kernel_mode()) {
+            Ok(())
+        } else {
+            Err(Exception::CoprocessorUnusable(cop))
+        }
+    }
+
+    fn handle_exception(&mut self, exception: Exception) {
+        let (code, bad_vaddr) = match exception {
+            Exception::Reset => {
+                self.reset();
+                return;
+            }
+            Exception::Interrupt => (0x00, None),
+            Exception::AddressErrorLoad(address) => (0x04, Some(address)),
+            Exception::AddressErrorStore(address) => (0x05, Some(address)),
+            Exception::BusErrorInstruction => (0x06, None),
+            Exception::BusErrorData => (0x07, None),
+            Exception::SystemCall => (0x08, None),
+            Exception::Breakpoint | Exception::Debug => (0x09, None),
+            Exception::ReservedInstruction => (0x0a, None),
+            Exception::CoprocessorUnusable(_) => (0x0b, None),
+            Exception::Overflow => (0x0c, None),
+        };
+
+        // on a delay slot EPC points to the branch so it is executed again
+        // when returning from the exception
+        let epc = if self.delay_slot {
+            self.current_pc.wrapping_sub(4)
+        } else {
+            self.current_pc
+        };
+
+        // SR.BEV selects the bootstrap vectors in the bios
+        let handler = match (&exception, self.cop0.bootstrap_vectors()) {
+            (Exception::Debug, false) => 0x80000040,
+            (Exception::Debug, true) => 0xbfc00140,
+            (_, false) => 0x80000080,
+            (_, true) => 0xbfc00180,
+        };
+
+        self.cop0.enter_exception(code, epc, self.delay_slot);
+        if let Exception::CoprocessorUnusable(cop) = exception {
+            self.cop0.cause |= (cop as u32) << 28;
+        }
+        if let Some(address) = bad_vaddr {
+            self.cop0.bad_vaddr = address;
+        }
+
+        self.pc = handler;
+        self.next_pc = handler.wrapping_add(4);
+        self.branch = false;
+    }
+
+    fn reset(&mut self) {
+        self.cop0.reset();
+
+        self.pc = 0xbfc00000;
+        self.next_pc = 0xbfc00004;
+        self.branch = false;
+        self.pending_load = None;
+        self.delayed_load = None;
+    }
+
+    fn icache_enabled(&self) -> bool {
+        self.icache_emulation && self.cache_control & 0x00000800 != 0
+    }
+
+    fn fetch_instruction(&mut self, bus: &mut Bus) -> Result<u32, Exception> {
+        let pc = self.pc;
+        if (pc & 0x00000003) != 0 {
+            return Err(Exception::AddressErrorLoad(pc));
+        }
+
+        // kseg1 is never cached
+        match translate_address(pc) {
+            MemorySpace::Kuseg(address) | MemorySpace::Kseg0(address) if self.icache_enabled() => {
+                let line = &mut self.icache.lines[((pc >> 4) & 0xff) as usize];
+                // kuseg and kseg0 share the lines of the same physical address
+                let tag = pc & 0x1ffff000;
+                let index = (pc >> 2) & 0x00000003;
+
+                if line.tag != tag || line.valid & (1 << index) == 0 {
+                    // a miss fills the line from the missing word to the end
+                    for i in index..4 {
+                        line.data[i as usize] = bus.read_word((address & !0x0000000f) + i * 4)?;
+                    }
+                    line.tag = tag;
+                    line.valid = 0x0f & (0x0f << index);
+                }
+
+                Ok(line.data[index as usize])
+            }
+            address => bus.read_word(address.into_inner()),
+        }
+    }
+
+    fn read_byte(&self, address: u32, bus: &mut Bus) -> Result<u8, Exception> {
         bus.read_byte(translate_address(address).into_inner())
     }
 
-    fn read_halfword(&self, address: u32, bus: &Bus) -> Result<u16, Exception> {
+    fn read_halfword(&self, address: u32, bus: &mut Bus) -> Result<u16, Exception> {
         if (address & 0x00000001) != 0 {
-            return Err(Exception::AddressError);
+            return Err(Exception::AddressErrorLoad(address));
         }
 
         bus.read_halfword(translate_address(address).into_inner())
     }
 
-    fn read_word(&self, address: u32, bus: &Bus) -> Result<u32, Exception> {
+    fn read_word(&self, address: u32, bus: &mut Bus) -> Result<u32, Exception> {
         if (address & 0x00000003) != 0 {
-            return Err(Exception::AddressError);
+            return Err(Exception::AddressErrorLoad(address));
         }
 
-        bus.read_word(translate_address(address).into_inner())
+        match translate_address(address) {
+            MemorySpace::Kseg2(CACHE_CONTROL) => Ok(self.cache_control),
+            address => bus.read_word(address.into_inner()),
+        }
+    }
+
+    // while SR.IsC is set stores to the cached segments only reach the
+    // cache, the bios relies on this to flush it without touching ram
+    fn cache_isolated(&self, address: &MemorySpace) -> bool {
+        self.cop0.isolate_cache()
+            && matches!(address, MemorySpace::Kuseg(_) | MemorySpace::Kseg0(_))
     }
 
-    fn write_byte(&self, address: u32, value: u8, bus: &mut Bus) -> Result<(), Exception> {
-        bus.write_byte(translate_address(address).into_inner(), value)
+    // value is already shifted to the byte lanes of the store
+    fn isolated_store(&mut self, address: u32, value: u32) {
+        if !self.icache_enabled() {
+            return;
+        }
+
+        let line = &mut self.icache.lines[((address >> 4) & 0xff) as usize];
+        if self.cache_control & 0x00000004 != 0 {
+            // tag test mode, the bios flushes the cache with a store per line
+            line.valid = 0;
+        } else {
+            line.data[((address >> 2) & 0x00000003) as usize] = value;
+        }
+    }
+
+    fn write_byte(&mut self, address: u32, value: u8, bus: &mut Bus) -> Result<(), Exception> {
+        let address = translate_address(address);
+        if self.cache_isolated(&address) {
+            let address = address.into_inner();
+            self.isolated_store(address, (value as u32) << ((address & 0x00000003) * 8));
+            return Ok(());
+        }
+
+        bus.write_byte(address.into_inner(), value)
     }
 
-    fn write_halfword(&self, address: u32, value: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn write_halfword(&mut self, address: u32, value: u16, bus: &mut Bus) -> Result<(), Exception> {
         if (address & 0x00000001) != 0 {
-            return Err(Exception::AddressError);
+            return Err(Exception::AddressErrorStore(address));
         }
 
-        bus.write_halfword(translate_address(address).into_inner(), value)
+        let address = translate_address(address);
+        if self.cache_isolated(&address) {
+            let address = address.into_inner();
+            self.isolated_store(address, (value as u32) << ((address & 0x00000003) * 8));
+            return Ok(());
+        }
+
+        bus.write_halfword(address.into_inner(), value)
     }
 
-    fn write_word(&self, address: u32, value: u32, bus: &mut Bus) -> Result<(), Exception> {
+    fn write_word(&mut self, address: u32, value: u32, bus: &mut Bus) -> Result<(), Exception> {
         if (address & 0x00000003) != 0 {
-            return Err(Exception::AddressError);
+            return Err(Exception::AddressErrorStore(address));
+        }
+
+        let address = translate_address(address);
+        if self.cache_isolated(&address) {
+            self.isolated_store(address.into_inner(), value);
+            return Ok(());
         }
 
-        bus.write_word(translate_address(address).into_inner(), value)
+        match address {
+            MemorySpace::Kseg2(CACHE_CONTROL) => {
+                self.cache_control = value;
+                Ok(())
+            }
+            address => bus.write_word(address.into_inner(), value),
+        }
     }
 
     fn add(&mut self, rs: u8, rt: u8, rd: u8) -> Result<(), Exception> {
@@ -485,7 +880,7 @@ impl Cpu {
         let b = self.register_file[rt as usize].read() as i32;
 
         // Overflow is detected for two's complement
-        self.register_file[rd as usize].write(a.checked_add(b).ok_or(Exception::Overflow)? as u32);
+        self.set_register(rd, a.checked_add(b).ok_or(Exception::Overflow)? as u32);
         Ok(())
     }
 
@@ -493,7 +888,8 @@ impl Cpu {
         let a = self.register_file[rs as usize].read() as i32;
 
         // sign extend the immediate to 32 bits
-        self.register_file[rt as usize].write(
+        self.set_register(
+            rt,
             a.checked_add(immediate as i16 as i32)
                 .ok_or(Exception::Overflow)? as u32,
         );
@@ -507,7 +903,7 @@ impl Cpu {
 
         let res = a.wrapping_add(b);
 
-        self.register_file[rt as usize].write(res);
+        self.set_register(rt, res);
     }
 
     fn addu(&mut self, rs: u8, rt: u8, rd: u8) {
@@ -516,112 +912,146 @@ impl Cpu {
 
         let res = a.wrapping_add(b);
 
-        self.register_file[rd as usize].write(res);
+        self.set_register(rd, res);
     }
 
     fn and(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a & b);
+        self.set_register(rd, a & b);
     }
 
     fn andi(&mut self, rs: u8, rt: u8, immediate: u16) {
         let a = self.register_file[rs as usize].read();
         let b = immediate as u32;
 
-        self.register_file[rt as usize].write(a & b);
+        self.set_register(rt, a & b);
     }
 
-    fn bc0f(&mut self, _offset: u16) {
-        todo!()
+    // the coprocessor condition inputs are not connected on the psx and
+    // always read as false
+    fn coprocessor_branch(&mut self, taken: bool, offset: u16) {
+        self.branch = true;
+        if taken {
+            let target = (offset as i16 as i32) << 2;
+            self.next_pc = self.pc.wrapping_add_signed(target);
+        }
     }
 
-    fn bc0t(&mut self, _offset: u16) {
-        todo!()
+    fn bc0f(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(0)?;
+        self.coprocessor_branch(true, offset);
+        Ok(())
     }
 
-    fn bc1f(&mut self, _offset: u16) {
-        todo!()
+    fn bc0t(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(0)?;
+        self.coprocessor_branch(false, offset);
+        Ok(())
     }
 
-    fn bc1t(&mut self, _offset: u16) {
-        todo!()
+    fn bc1f(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(1)?;
+        self.coprocessor_branch(true, offset);
+        Ok(())
     }
 
-    fn bc2f(&mut self, _offset: u16) {
-        todo!()
+    fn bc1t(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(1)?;
+        self.coprocessor_branch(false, offset);
+        Ok(())
     }
 
-    fn bc2t(&mut self, _offset: u16) {
-        todo!()
+    fn bc2f(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        self.coprocessor_branch(true, offset);
+        Ok(())
     }
 
-    fn bc3f(&mut self, _offset: u16) {
-        todo!()
+    fn bc2t(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        self.coprocessor_branch(false, offset);
+        Ok(())
     }
 
-    fn bc3t(&mut self, _offset: u16) {
-        todo!()
+    fn bc3f(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(3)?;
+        self.coprocessor_branch(true, offset);
+        Ok(())
+    }
+
+    fn bc3t(&mut self, offset: u16) -> Result<(), Exception> {
+        self.check_coprocessor(3)?;
+        self.coprocessor_branch(false, offset);
+        Ok(())
     }
 
     fn beq(&mut self, rs: u8, rt: u8, offset: u16) {
+        self.branch = true;
         if self.register_file[rs as usize].read() == self.register_file[rt as usize].read() {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bgez(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         if self.register_file[rs as usize].read() as i32 >= 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bgezal(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         // link register, r31 is loaded with the address of the instruction after the delay slot
-        self.register_file[31].write(self.pc + 8);
+        self.set_register(31, self.next_pc);
         if self.register_file[rs as usize].read() as i32 >= 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bgtz(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         if self.register_file[rs as usize].read() as i32 > 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn blez(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         if self.register_file[rs as usize].read() as i32 <= 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bltz(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         if (self.register_file[rs as usize].read() as i32) < 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bltzal(&mut self, rs: u8, offset: u16) {
+        self.branch = true;
         // link register, r31 is loaded with the address of the instruction after the delay slot
-        self.register_file[31].write(self.pc + 8);
+        self.set_register(31, self.next_pc);
         if (self.register_file[rs as usize].read() as i32) < 0 {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
     fn bne(&mut self, rs: u8, rt: u8, offset: u16) {
+        self.branch = true;
         if self.register_file[rs as usize].read() != self.register_file[rt as usize].read() {
             let target = (offset as i16 as i32) << 2;
-            self.pc = self.pc.wrapping_add_signed(target);
+            self.next_pc = self.pc.wrapping_add_signed(target);
         }
     }
 
@@ -629,6 +1059,26 @@ impl Cpu {
         Exception::Breakpoint
     }
 
+    fn cfc2(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        let value = self.gte.control(rd);
+
+        self.load_register(rt, value);
+        Ok(())
+    }
+
+    fn cop2(&mut self) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        self.gte.command(self.current_instruction & 0x01ffffff);
+        Ok(())
+    }
+
+    fn ctc2(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        self.gte.set_control(rd, self.register_file[rt as usize].read());
+        Ok(())
+    }
+
     fn div(&mut self, rs: u8, rt: u8) {
         let a = self.register_file[rs as usize].read() as i32;
         let b = self.register_file[rt as usize].read() as i32;
@@ -646,43 +1096,31 @@ impl Cpu {
     }
 
     fn j(&mut self, target: u32) {
-        self.pc = (self.pc & 0xf0000000) | (target << 2);
+        self.branch = true;
+        self.next_pc = (self.pc & 0xf0000000) | (target << 2);
     }
 
     fn jal(&mut self, target: u32) {
-        self.register_file[31].write(self.pc + 8);
-        self.pc = (self.pc & 0xf0000000) | (target << 2);
+        self.branch = true;
+        self.set_register(31, self.next_pc);
+        self.next_pc = (self.pc & 0xf0000000) | (target << 2);
     }
 
-    fn jalr(&mut self, rs: u8, rd: u8) -> Result<(), Exception> {
+    // An unaligned target is not trapped here, the exception happens when
+    // fetching from it so EPC and BadVaddr both point to the target
+    fn jalr(&mut self, rs: u8, rd: u8) {
+        self.branch = true;
         let target = self.register_file[rs as usize].read();
 
-        self.register_file[rd as usize].write(self.pc + 8);
-        self.pc = target;
-
-        // TODO: when is this exception trapped?
-        // in this instruction
-        // or when fetching the next
-        if target & 0x00000003 != 0 {
-            Err(Exception::AddressError)
-        } else {
-            Ok(())
-        }
+        self.set_register(rd, self.next_pc);
+        self.next_pc = target;
     }
 
-    fn jr(&mut self, rs: u8) -> Result<(), Exception> {
+    fn jr(&mut self, rs: u8) {
+        self.branch = true;
         let target = self.register_file[rs as usize].read();
 
-        self.pc = target;
-
-        // TODO: when is this exception trapped?
-        // in this instruction
-        // or when fetching the next
-        if target & 0x00000003 != 0 {
-            Err(Exception::AddressError)
-        } else {
-            Ok(())
-        }
+        self.next_pc = target;
     }
 
     fn lb(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
@@ -690,7 +1128,7 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_byte(address, bus)? as i8 as i32 as u32;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
@@ -699,7 +1137,7 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_byte(address, bus)? as u32;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
@@ -708,7 +1146,7 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_halfword(address, bus)? as i16 as i32 as u32;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
@@ -717,12 +1155,12 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_halfword(address, bus)? as u32;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
     fn lui(&mut self, rt: u8, immediate: u16) {
-        self.register_file[rt as usize].write((immediate as u32) << 16);
+        self.set_register(rt, (immediate as u32) << 16);
     }
 
     fn lw(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
@@ -730,7 +1168,17 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_word(address, bus)?;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
+        Ok(())
+    }
+
+    fn lwc2(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        let a = self.register_file[base as usize].read();
+        let address = a.wrapping_add_signed(offset as i16 as i32);
+        let value = self.read_word(address, bus)?;
+
+        self.gte.set_data(rt, value);
         Ok(())
     }
 
@@ -739,7 +1187,9 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_word(address & 0xfffffffc, bus)?;
 
-        let index = address & 0x00000003;
+        // the psx is little endian, an address with the low bits set to 3
+        // loads the whole word
+        let index = !address & 0x00000003;
         let mask = 0xffffffff >> (index * 8);
         let value = (value & mask) << (index * 8);
 
@@ -747,9 +1197,9 @@ impl Cpu {
         let mask = 0xffffffff_u32
             .checked_shr(((!index & 0x00000003) + 1) * 8)
             .unwrap_or(0);
-        let value = value | (self.register_file[rt as usize].read() & mask);
+        let value = value | (self.load_bypass(rt) & mask);
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
@@ -758,34 +1208,52 @@ impl Cpu {
         let address = a.wrapping_add_signed(offset as i16 as i32);
         let value = self.read_word(address & 0xfffffffc, bus)?;
 
-        let index = !address & 0x00000003;
+        let index = address & 0x00000003;
         let mask = 0xffffffff << (index * 8);
         let value = (value & mask) >> (index * 8);
 
         let mask = 0xffffffff_u32
             .checked_shl(((!index & 0x00000003) + 1) * 8)
             .unwrap_or(0);
-        let value = (self.register_file[rt as usize].read() & mask) | value;
+        let value = (self.load_bypass(rt) & mask) | value;
 
-        self.register_file[rt as usize].write(value);
+        self.load_register(rt, value);
         Ok(())
     }
 
     fn mfc0(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
-        self.register_file[rt as usize].write(self.cop0.register_file[rd as usize].read());
+        self.check_coprocessor(0)?;
+        let value = self.cop0.read(rd)?;
+
+        self.load_register(rt, value);
+        Ok(())
+    }
+
+    fn mfc2(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        let value = self.gte.data(rd);
+
+        self.load_register(rt, value);
         Ok(())
     }
 
     fn mfhi(&mut self, rd: u8) {
-        self.register_file[rd as usize].write(self.hi);
+        self.set_register(rd, self.hi);
     }
 
     fn mflo(&mut self, rd: u8) {
-        self.register_file[rd as usize].write(self.lo);
+        self.set_register(rd, self.lo);
     }
 
     fn mtc0(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
-        self.cop0.register_file[rd as usize].write(self.register_file[rt as usize].read());
+        self.check_coprocessor(0)?;
+        self.cop0.write(rd, self.register_file[rt as usize].read());
+        Ok(())
+    }
+
+    fn mtc2(&mut self, rt: u8, rd: u8) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        self.gte.set_data(rd, self.register_file[rt as usize].read());
         Ok(())
     }
 
@@ -819,28 +1287,30 @@ impl Cpu {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(!(a | b));
+        self.set_register(rd, !(a | b));
     }
 
     fn or(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a | b);
+        self.set_register(rd, a | b);
     }
 
     fn ori(&mut self, rs: u8, rt: u8, immediate: u16) {
         let a = self.register_file[rs as usize].read();
         let b = immediate as u32;
 
-        self.register_file[rt as usize].write(a | b);
+        self.set_register(rt, a | b);
     }
 
     fn rfe(&mut self) -> Result<(), Exception> {
-        todo!()
+        self.check_coprocessor(0)?;
+        self.cop0.return_from_exception();
+        Ok(())
     }
 
-    fn sb(&self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn sb(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
         let a = self.register_file[base as usize].read();
         let address = a.wrapping_add_signed(offset as i16 as i32);
 
@@ -849,7 +1319,7 @@ impl Cpu {
         Ok(())
     }
 
-    fn sh(&self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn sh(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
         let a = self.register_file[base as usize].read();
         let address = a.wrapping_add_signed(offset as i16 as i32);
 
@@ -861,75 +1331,75 @@ impl Cpu {
     fn sll(&mut self, rt: u8, rd: u8, sa: u8) {
         let a = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a << sa);
+        self.set_register(rd, a << sa);
     }
 
     fn sllv(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rt as usize].read();
         let sa = self.register_file[rs as usize].read();
 
-        self.register_file[rd as usize].write(a << sa);
+        self.set_register(rd, a << sa);
     }
 
     fn slt(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rs as usize].read() as i32;
         let b = self.register_file[rt as usize].read() as i32;
 
-        self.register_file[rd as usize].write((a < b) as u32);
+        self.set_register(rd, (a < b) as u32);
     }
 
     fn slti(&mut self, rs: u8, rt: u8, immediate: u16) {
         let a = self.register_file[rs as usize].read() as i32;
         let b = immediate as i16 as i32;
 
-        self.register_file[rt as usize].write((a < b) as u32);
+        self.set_register(rt, (a < b) as u32);
     }
 
     fn sltiu(&mut self, rs: u8, rt: u8, immediate: u16) {
         let a = self.register_file[rs as usize].read();
         let b = immediate as u32;
 
-        self.register_file[rt as usize].write((a < b) as u32);
+        self.set_register(rt, (a < b) as u32);
     }
 
     fn sltu(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write((a < b) as u32);
+        self.set_register(rd, (a < b) as u32);
     }
 
     fn sra(&mut self, rt: u8, rd: u8, sa: u8) {
         let a = self.register_file[rt as usize].read() as i32;
 
-        self.register_file[rd as usize].write((a >> sa) as u32);
+        self.set_register(rd, (a >> sa) as u32);
     }
 
     fn srav(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rt as usize].read() as i32;
         let sa = self.register_file[rs as usize].read();
 
-        self.register_file[rd as usize].write((a >> sa) as u32);
+        self.set_register(rd, (a >> sa) as u32);
     }
 
     fn srl(&mut self, rt: u8, rd: u8, sa: u8) {
         let a = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a >> sa);
+        self.set_register(rd, a >> sa);
     }
 
     fn srlv(&mut self, rs: u8, rt: u8, rd: u8) {
         let a = self.register_file[rt as usize].read();
         let sa = self.register_file[rs as usize].read();
 
-        self.register_file[rd as usize].write(a >> sa);
+        self.set_register(rd, a >> sa);
     }
 
     fn sub(&mut self, rs: u8, rt: u8, rd: u8) -> Result<(), Exception> {
         let a = self.register_file[rs as usize].read() as i32;
         let b = self.register_file[rt as usize].read() as i32;
 
-        self.register_file[rd as usize].write(a.checked_sub(b).ok_or(Exception::Overflow)? as u32);
+        self.set_register(rd, a.checked_sub(b).ok_or(Exception::Overflow)? as u32);
 
         Ok(())
     }
@@ -938,10 +1408,10 @@ impl Cpu {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a.wrapping_sub(b));
+        self.set_register(rd, a.wrapping_sub(b));
     }
 
-    fn sw(&self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn sw(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
         let a = self.register_file[base as usize].read();
         let address = a.wrapping_add_signed(offset as i16 as i32);
 
@@ -950,7 +1420,17 @@ impl Cpu {
         Ok(())
     }
 
-    fn swl(&self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn swc2(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+        self.check_coprocessor(2)?;
+        let a = self.register_file[base as usize].read();
+        let address = a.wrapping_add_signed(offset as i16 as i32);
+
+        let value = self.gte.data(rt);
+        self.write_word(address, value, bus)?;
+        Ok(())
+    }
+
+    fn swl(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
         let a = self.register_file[base as usize].read();
         let address = a.wrapping_add_signed(offset as i16 as i32);
 
@@ -966,7 +1446,7 @@ impl Cpu {
         Ok(())
     }
 
-    fn swr(&self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
+    fn swr(&mut self, base: u8, rt: u8, offset: u16, bus: &mut Bus) -> Result<(), Exception> {
         let a = self.register_file[base as usize].read();
         let address = a.wrapping_add_signed(offset as i16 as i32);
 
@@ -987,13 +1467,13 @@ impl Cpu {
         let a = self.register_file[rs as usize].read();
         let b = self.register_file[rt as usize].read();
 
-        self.register_file[rd as usize].write(a ^ b);
+        self.set_register(rd, a ^ b);
     }
 
     fn xori(&mut self, rs: u8, rt: u8, immediate: u16) {
         let a = self.register_file[rs as usize].read();
 
-        self.register_file[rt as usize].write(a ^ immediate as u32);
+        self.set_register(rt, a ^ immediate as u32);
     }
 }
 
@@ -1096,9 +1576,10 @@ mod opcodes {
         cpu.register_file[1].write(1);
         cpu.register_file[2].write(1);
         cpu.pc = 68;
+        cpu.next_pc = 72;
 
         cpu.beq(1, 2, -10i16 as u16);
-        assert_eq!(cpu.pc, 28);
+        assert_eq!(cpu.next_pc, 28);
     }
 
     #[test]
@@ -1107,9 +1588,10 @@ mod opcodes {
         cpu.register_file[1].write(1);
         cpu.register_file[2].write(2);
         cpu.pc = 68;
+        cpu.next_pc = 72;
 
         cpu.beq(1, 2, -10i16 as u16);
-        assert_eq!(cpu.pc, 68);
+        assert_eq!(cpu.next_pc, 72);
     }
 
     #[test]
@@ -1117,9 +1599,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(20);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgez(1, 25);
-        assert_eq!(cpu.pc, 116);
+        assert_eq!(cpu.next_pc, 116);
     }
 
     #[test]
@@ -1127,9 +1610,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(-20i32 as u32);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgez(1, 25);
-        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.next_pc, 20);
     }
 
     #[test]
@@ -1137,10 +1621,11 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(20);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgezal(1, 25);
-        assert_eq!(cpu.pc, 116);
-        assert_eq!(cpu.register_file[31].read(), 24);
+        assert_eq!(cpu.next_pc, 116);
+        assert_eq!(cpu.register_file[31].read(), 20);
     }
 
     #[test]
@@ -1148,10 +1633,11 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(-20i32 as u32);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgezal(1, 25);
-        assert_eq!(cpu.pc, 16);
-        assert_eq!(cpu.register_file[31].read(), 24);
+        assert_eq!(cpu.next_pc, 20);
+        assert_eq!(cpu.register_file[31].read(), 20);
     }
 
     #[test]
@@ -1159,9 +1645,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(20);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgtz(1, 25);
-        assert_eq!(cpu.pc, 116);
+        assert_eq!(cpu.next_pc, 116);
     }
 
     #[test]
@@ -1169,9 +1656,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(0);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bgtz(1, 25);
-        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.next_pc, 20);
     }
 
     #[test]
@@ -1179,9 +1667,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(-10i16 as u32);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.blez(1, 25);
-        assert_eq!(cpu.pc, 116);
+        assert_eq!(cpu.next_pc, 116);
     }
 
     #[test]
@@ -1189,9 +1678,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(20);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.blez(1, 25);
-        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.next_pc, 20);
     }
 
     #[test]
@@ -1199,9 +1689,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(-10i16 as u32);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bltz(1, 25);
-        assert_eq!(cpu.pc, 116);
+        assert_eq!(cpu.next_pc, 116);
     }
 
     #[test]
@@ -1209,9 +1700,10 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(0);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bltz(1, 25);
-        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.next_pc, 20);
     }
 
     #[test]
@@ -1219,10 +1711,11 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(-10i16 as u32);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bltzal(1, 25);
-        assert_eq!(cpu.pc, 116);
-        assert_eq!(cpu.register_file[31].read(), 24);
+        assert_eq!(cpu.next_pc, 116);
+        assert_eq!(cpu.register_file[31].read(), 20);
     }
 
     #[test]
@@ -1230,10 +1723,11 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(0);
         cpu.pc = 16;
+        cpu.next_pc = 20;
 
         cpu.bltzal(1, 25);
-        assert_eq!(cpu.pc, 16);
-        assert_eq!(cpu.register_file[31].read(), 24);
+        assert_eq!(cpu.next_pc, 20);
+        assert_eq!(cpu.register_file[31].read(), 20);
     }
 
     #[test]
@@ -1242,9 +1736,10 @@ mod opcodes {
         cpu.register_file[1].write(1);
         cpu.register_file[2].write(2);
         cpu.pc = 68;
+        cpu.next_pc = 72;
 
         cpu.bne(1, 2, -10i16 as u16);
-        assert_eq!(cpu.pc, 28);
+        assert_eq!(cpu.next_pc, 28);
     }
 
     #[test]
@@ -1253,9 +1748,57 @@ mod opcodes {
         cpu.register_file[1].write(1);
         cpu.register_file[2].write(1);
         cpu.pc = 68;
+        cpu.next_pc = 72;
 
         cpu.bne(1, 2, -10i16 as u16);
-        assert_eq!(cpu.pc, 68);
+        assert_eq!(cpu.next_pc, 72);
+    }
+
+    #[test]
+    fn branch_delay_slot() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        // beq $0, $0, 2
+        bus.write_word(0, 0x10000002).unwrap();
+        // addiu $1, $0, 1
+        bus.write_word(4, 0x24010001).unwrap();
+        // addiu $2, $0, 2
+        bus.write_word(8, 0x24020002).unwrap();
+        // addiu $3, $0, 3
+        bus.write_word(12, 0x24030003).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.next_pc, 12);
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.register_file[1].read(), 1);
+        assert!(cpu.delay_slot);
+        assert_eq!(cpu.pc, 12);
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.register_file[2].read(), 0);
+        assert_eq!(cpu.register_file[3].read(), 3);
+        assert!(!cpu.delay_slot);
+    }
+
+    #[test]
+    fn jump_delay_slot() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        // jal 0x40
+        bus.write_word(0, 0x0c000010).unwrap();
+        // addiu $31, $31, 4
+        bus.write_word(4, 0x27ff0004).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.register_file[31].read(), 12);
     }
 
     #[test]
@@ -1302,19 +1845,21 @@ mod opcodes {
     fn j() {
         let mut cpu = Cpu::new();
         cpu.pc = 0xbfc00000;
+        cpu.next_pc = 0xbfc00004;
 
         cpu.j(0x03f00054);
-        assert_eq!(cpu.pc, 0xbfc00150);
+        assert_eq!(cpu.next_pc, 0xbfc00150);
     }
 
     #[test]
     fn jal() {
         let mut cpu = Cpu::new();
         cpu.pc = 0xbfc00000;
+        cpu.next_pc = 0xbfc00004;
 
         cpu.jal(0x03f00054);
-        assert_eq!(cpu.pc, 0xbfc00150);
-        assert_eq!(cpu.register_file[31].read(), 0xbfc00008);
+        assert_eq!(cpu.next_pc, 0xbfc00150);
+        assert_eq!(cpu.register_file[31].read(), 0xbfc00004);
     }
 
     #[test]
@@ -1322,21 +1867,30 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(0xfffffffc);
         cpu.pc = 0;
+        cpu.next_pc = 4;
 
-        assert_eq!(cpu.jalr(1, 2), Ok(()));
-        assert_eq!(cpu.pc, 0xfffffffc);
-        assert_eq!(cpu.register_file[2].read(), 0x00000008);
+        cpu.jalr(1, 2);
+        assert_eq!(cpu.next_pc, 0xfffffffc);
+        assert_eq!(cpu.register_file[2].read(), 0x00000004);
     }
 
     #[test]
     fn jalr_exception() {
         let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
         cpu.register_file[1].write(0xfffffffd);
         cpu.pc = 0;
+        cpu.next_pc = 4;
 
-        assert_eq!(cpu.jalr(1, 2), Err(Exception::AddressError));
-        assert_eq!(cpu.pc, 0xfffffffd);
-        assert_eq!(cpu.register_file[2].read(), 0x00000008);
+        cpu.jalr(1, 2);
+        assert_eq!(cpu.next_pc, 0xfffffffd);
+        assert_eq!(cpu.register_file[2].read(), 0x00000004);
+
+        cpu.pc = cpu.next_pc;
+        assert!(matches!(
+            cpu.fetch_decode_instruction(&mut bus),
+            Err(Exception::AddressErrorLoad(0xfffffffd))
+        ));
     }
 
     #[test]
@@ -1344,17 +1898,24 @@ mod opcodes {
         let mut cpu = Cpu::new();
         cpu.register_file[1].write(0xfffffffc);
 
-        assert_eq!(cpu.jr(1), Ok(()));
-        assert_eq!(cpu.pc, 0xfffffffc);
+        cpu.jr(1);
+        assert_eq!(cpu.next_pc, 0xfffffffc);
     }
 
     #[test]
     fn jr_exception() {
         let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
         cpu.register_file[1].write(0xfffffffd);
 
-        assert_eq!(cpu.jr(1), Err(Exception::AddressError));
-        assert_eq!(cpu.pc, 0xfffffffd);
+        cpu.jr(1);
+        assert_eq!(cpu.next_pc, 0xfffffffd);
+
+        cpu.pc = cpu.next_pc;
+        assert!(matches!(
+            cpu.fetch_decode_instruction(&mut bus),
+            Err(Exception::AddressErrorLoad(0xfffffffd))
+        ));
     }
 
     #[test]
@@ -1365,7 +1926,7 @@ mod opcodes {
         bus.write_word(0, 0x0000ff00).unwrap();
 
         assert_eq!(cpu.lb(1, 2, 1, &mut bus), Ok(()));
-        assert_eq!(cpu.register_file[2].read(), -1i32 as u32);
+        assert_eq!(cpu.pending_load, Some((2, -1i32 as u32)));
     }
 
     #[test]
@@ -1376,7 +1937,7 @@ mod opcodes {
         bus.write_word(0, 0x00ff0000).unwrap();
 
         cpu.lbu(1, 2, 2, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xff);
+        assert_eq!(cpu.pending_load, Some((2, 0xff)));
     }
 
     #[test]
@@ -1387,7 +1948,7 @@ mod opcodes {
         bus.write_word(0, 0xffff0000).unwrap();
 
         cpu.lh(1, 2, 2, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), -1i32 as u32);
+        assert_eq!(cpu.pending_load, Some((2, -1i32 as u32)));
     }
 
     #[test]
@@ -1398,7 +1959,7 @@ mod opcodes {
         bus.write_word(0, 0x0000ffff).unwrap();
 
         cpu.lhu(1, 2, 0, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffff);
+        assert_eq!(cpu.pending_load, Some((2, 0xffff)));
     }
 
     #[test]
@@ -1417,7 +1978,7 @@ mod opcodes {
         bus.write_word(0, 0xffffffff).unwrap();
 
         cpu.lw(1, 2, 0, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffffffff);
+        assert_eq!(cpu.pending_load, Some((2, 0xffffffff)));
     }
 
     #[test]
@@ -1429,19 +1990,19 @@ mod opcodes {
         bus.write_word(0, 0xffffffff).unwrap();
 
         cpu.lwl(1, 2, 3, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xff696969);
+        assert_eq!(cpu.pending_load, Some((2, 0xffffffff)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwl(1, 2, 2, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffff6969);
+        assert_eq!(cpu.pending_load, Some((2, 0xffffff69)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwl(1, 2, 1, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffffff69);
+        assert_eq!(cpu.pending_load, Some((2, 0xffff6969)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwl(1, 2, 0, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffffffff);
+        assert_eq!(cpu.pending_load, Some((2, 0xff696969)));
     }
 
     #[test]
@@ -1453,19 +2014,112 @@ mod opcodes {
         bus.write_word(4, 0xffffffff).unwrap();
 
         cpu.lwr(1, 2, 7, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0xffffffff);
+        assert_eq!(cpu.pending_load, Some((2, 0x696969ff)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwr(1, 2, 6, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0x69ffffff);
+        assert_eq!(cpu.pending_load, Some((2, 0x6969ffff)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwr(1, 2, 5, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0x6969ffff);
+        assert_eq!(cpu.pending_load, Some((2, 0x69ffffff)));
 
         cpu.register_file[2].write(0x69696969);
         cpu.lwr(1, 2, 4, &mut bus).unwrap();
-        assert_eq!(cpu.register_file[2].read(), 0x696969ff);
+        assert_eq!(cpu.pending_load, Some((2, 0xffffffff)));
+    }
+
+    #[test]
+    fn load_delay_slot() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.register_file[2].write(0x69);
+        bus.write_word(0x10, 0xffffffff).unwrap();
+        // lw $2, 0x10($0)
+        bus.write_word(0, 0x8c020010).unwrap();
+        // addu $3, $2, $0
+        bus.write_word(4, 0x00401821).unwrap();
+        // addu $4, $2, $0
+        bus.write_word(8, 0x00402021).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.register_file[2].read(), 0x69);
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.register_file[3].read(), 0x69);
+        assert_eq!(cpu.register_file[2].read(), 0xffffffff);
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.register_file[4].read(), 0xffffffff);
+    }
+
+    #[test]
+    fn load_delay_slot_same_register() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.register_file[2].write(0x69);
+        bus.write_word(0x10, 0x11111111).unwrap();
+        bus.write_word(0x14, 0x22222222).unwrap();
+        // lw $2, 0x10($0)
+        bus.write_word(0, 0x8c020010).unwrap();
+        // lw $2, 0x14($0)
+        bus.write_word(4, 0x8c020014).unwrap();
+        // addu $3, $2, $0
+        bus.write_word(8, 0x00401821).unwrap();
+        // addu $4, $2, $0
+        bus.write_word(12, 0x00402021).unwrap();
+
+        for _ in 0..4 {
+            cpu.cpu_cycle(&mut bus);
+        }
+        assert_eq!(cpu.register_file[3].read(), 0x69);
+        assert_eq!(cpu.register_file[4].read(), 0x22222222);
+    }
+
+    #[test]
+    fn load_delay_slot_overwritten() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        bus.write_word(0x10, 0xffffffff).unwrap();
+        // lw $2, 0x10($0)
+        bus.write_word(0, 0x8c020010).unwrap();
+        // addiu $2, $0, 1
+        bus.write_word(4, 0x24020001).unwrap();
+        // nop
+        bus.write_word(8, 0x00000000).unwrap();
+
+        for _ in 0..3 {
+            cpu.cpu_cycle(&mut bus);
+        }
+        assert_eq!(cpu.register_file[2].read(), 1);
+    }
+
+    #[test]
+    fn lwr_lwl_pair() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.register_file[2].write(0x69696969);
+        bus.write_word(0x10, 0x44332211).unwrap();
+        bus.write_word(0x14, 0x88776655).unwrap();
+        // lwr $2, 0x11($0)
+        bus.write_word(0, 0x98020011).unwrap();
+        // lwl $2, 0x14($0)
+        bus.write_word(4, 0x88020014).unwrap();
+        // nop
+        bus.write_word(8, 0x00000000).unwrap();
+
+        for _ in 0..3 {
+            cpu.cpu_cycle(&mut bus);
+        }
+        assert_eq!(cpu.register_file[2].read(), 0x55443322);
     }
 
     #[test]
@@ -1601,10 +2255,10 @@ mod opcodes {
 
     #[test]
     fn sh_not_aligned() {
-        let cpu = Cpu::new();
+        let mut cpu = Cpu::new();
         let mut bus = Bus::new(vec![]);
 
-        assert_eq!(cpu.sh(1, 2, 1, &mut bus), Err(Exception::AddressError));
+        assert_eq!(cpu.sh(1, 2, 1, &mut bus), Err(Exception::AddressErrorStore(1)));
     }
 
     #[test]
@@ -1780,10 +2434,30 @@ mod opcodes {
 
     #[test]
     fn sw_not_aligned() {
-        let cpu = Cpu::new();
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+
+        assert_eq!(cpu.sw(1, 2, 3, &mut bus), Err(Exception::AddressErrorStore(3)));
+    }
+
+    #[test]
+    fn sw_isolated_cache() {
+        let mut cpu = Cpu::new();
         let mut bus = Bus::new(vec![]);
+        cpu.cop0.sr |= 0x00010000;
+        cpu.register_file[1].write(0x80000000);
+        cpu.register_file[2].write(0xffff8888);
+        bus.write_word(0, 0x69696969).unwrap();
+
+        cpu.sw(1, 2, 0, &mut bus).unwrap();
+        cpu.sw(0, 2, 4, &mut bus).unwrap();
+        assert_eq!(bus.read_word(0), Ok(0x69696969));
+        assert_eq!(bus.read_word(4), Ok(0));
 
-        assert_eq!(cpu.sw(1, 2, 3, &mut bus), Err(Exception::AddressError));
+        // kseg1 is uncached
+        cpu.register_file[1].write(0xa0000000);
+        cpu.sw(1, 2, 0, &mut bus).unwrap();
+        assert_eq!(bus.read_word(0), Ok(0xffff8888));
     }
 
     #[test]
@@ -1851,3 +2525,449 @@ mod opcodes {
         assert_eq!(cpu.register_file[2].read(), 0x0123ffff);
     }
 }
+
+#[cfg(test)]
+mod exceptions {
+    use super::*;
+
+    #[test]
+    fn address_error() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x00000001;
+        // lw $2, 0x11($0)
+        bus.write_word(0, 0x8c020011).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.next_pc, 0x80000084);
+        assert_eq!(cpu.cop0.bad_vaddr, 0x11);
+        assert_eq!(cpu.cop0.sr, 0x00000004);
+        assert_eq!(cpu.cop0.cause, 0x04 << 2);
+        assert_eq!(cpu.cop0.epc, 0);
+    }
+
+    #[test]
+    fn bus_error() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0;
+        // lw $2, 0($1) with $1 pointing where nothing answers
+        bus.write_word(0, 0x8c220000).unwrap();
+        cpu.register_file[1].write(0x1f900000);
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x07 << 2);
+        assert_eq!(cpu.cop0.epc, 0);
+
+        // the same on a fetch is an instruction bus error
+        cpu.pc = 0x9f900000;
+        cpu.next_pc = 0x9f900004;
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x06 << 2);
+        assert_eq!(cpu.cop0.epc, 0x9f900000);
+    }
+
+    #[test]
+    fn bootstrap_vector() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x00400000;
+        // syscall
+        bus.write_word(0, 0x0000000c).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0xbfc00180);
+        assert_eq!(cpu.cop0.cause, 0x08 << 2);
+    }
+
+    #[test]
+    fn exception_on_delay_slot() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0x10;
+        cpu.next_pc = 0x14;
+        cpu.cop0.sr = 0;
+        cpu.cop0.cause = 0x00000300;
+        // beq $0, $0, 2
+        bus.write_word(0x10, 0x10000002).unwrap();
+        // break
+        bus.write_word(0x14, 0x0000000d).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x80000300 | (0x09 << 2));
+        assert_eq!(cpu.cop0.epc, 0x10);
+    }
+
+    #[test]
+    fn rfe() {
+        let mut cpu = Cpu::new();
+        cpu.cop0.sr = 0x0000002d;
+
+        cpu.handle_exception(Exception::Overflow);
+        assert_eq!(cpu.cop0.sr, 0x00000034);
+
+        cpu.rfe().unwrap();
+        assert_eq!(cpu.cop0.sr, 0x0000003d);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x00000401;
+        // the gpu interrupt request command
+        bus.write_word(0x1f801074, 0x00000002).unwrap();
+        bus.write_word(0x1f801810, 0x1f000000).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x00000400);
+        assert_eq!(cpu.cop0.epc, 0);
+        assert_eq!(cpu.cop0.sr, 0x00000404);
+
+        // acknowledging the interrupt clears Cause.IP2
+        bus.write_word(0x1f801070, 0x00000000).unwrap();
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.cop0.cause & 0x00000400, 0);
+    }
+
+    #[test]
+    fn masked_interrupt() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        // IEc is set but SR.Im2 is not
+        cpu.cop0.sr = 0x00000001;
+        // the gpu interrupt request command
+        bus.write_word(0x1f801074, 0x00000002).unwrap();
+        bus.write_word(0x1f801810, 0x1f000000).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.cop0.cause, 0x00000400);
+
+        // interrupts are disabled
+        cpu.cop0.sr = 0x00000400;
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 8);
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x00000101;
+        cpu.register_file[1].write(0x00000100);
+        // mtc0 $1, $13
+        bus.write_word(0, 0x40816800).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.epc, 4);
+    }
+}
+
+#[cfg(test)]
+mod cop0 {
+    use super::*;
+
+    #[test]
+    fn read_only_registers() {
+        let mut cpu = Cpu::new();
+        cpu.register_file[1].write(0xffffffff);
+
+        cpu.mtc0(1, 15).unwrap();
+        cpu.mtc0(1, 14).unwrap();
+        cpu.mtc0(1, 13).unwrap();
+        assert_eq!(cpu.cop0.read(15), Ok(0x00000002));
+        assert_eq!(cpu.cop0.epc, 0);
+        assert_eq!(cpu.cop0.cause, 0x00000300);
+
+        cpu.mtc0(1, 12).unwrap();
+        assert_eq!(cpu.cop0.sr, 0xf247ff3f);
+    }
+
+    #[test]
+    fn tlb_registers() {
+        let mut cpu = Cpu::new();
+
+        assert_eq!(cpu.mfc0(1, 0), Err(Exception::ReservedInstruction));
+        assert_eq!(cpu.mfc0(1, 10), Err(Exception::ReservedInstruction));
+    }
+
+    #[test]
+    fn user_mode() {
+        let mut cpu = Cpu::new();
+        cpu.cop0.sr = 0x00000002;
+
+        assert_eq!(cpu.mfc0(1, 12), Err(Exception::CoprocessorUnusable(0)));
+        assert_eq!(cpu.rfe(), Err(Exception::CoprocessorUnusable(0)));
+
+        cpu.cop0.sr = 0x10000002;
+        assert_eq!(cpu.mfc0(1, 12), Ok(()));
+    }
+
+    #[test]
+    fn coprocessor_unusable() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0;
+        // mfc1 $1, $2
+        bus.write_word(0, 0x44011000).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x10000000 | (0x0b << 2));
+
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        // mfc2 $1, $2
+        bus.write_word(0, 0x48011000).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 0x80000080);
+        assert_eq!(cpu.cop0.cause, 0x20000000 | (0x0b << 2));
+    }
+
+    #[test]
+    fn missing_coprocessor() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x80000000;
+        // cop3 0x1
+        bus.write_word(0, 0x4e000001).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.cop0.cause, 0);
+    }
+
+    #[test]
+    fn coprocessor_branch() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 16;
+        cpu.next_pc = 20;
+
+        cpu.bc0t(25).unwrap();
+        assert_eq!(cpu.next_pc, 20);
+
+        cpu.bc0f(25).unwrap();
+        assert_eq!(cpu.next_pc, 116);
+    }
+}
+
+#[cfg(test)]
+mod icache {
+    use super::*;
+
+    #[test]
+    fn cached_fetch() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.cache_control = 0x00000800;
+        bus.write_word(0x100, 0x11111111).unwrap();
+        bus.write_word(0x104, 0x22222222).unwrap();
+
+        cpu.pc = 0x80000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+
+        // self modifying code is not seen until the line is flushed
+        bus.write_word(0x100, 0x33333333).unwrap();
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+        cpu.pc = 0x80000104;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+
+        // kseg1 bypasses the cache
+        cpu.pc = 0xa0000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x33333333));
+    }
+
+    #[test]
+    fn physical_tag() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.cache_control = 0x00000800;
+        bus.write_word(0x100, 0x11111111).unwrap();
+
+        cpu.pc = 0x00000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+
+        // kseg0 hits on the line kuseg filled
+        bus.write_word(0x100, 0x22222222).unwrap();
+        cpu.pc = 0x80000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.cache_control = 0x00000800;
+        bus.write_word(0x100, 0x11111111).unwrap();
+        cpu.pc = 0x80000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+        bus.write_word(0x100, 0x22222222).unwrap();
+
+        // another address on the same line keeps it
+        cpu.invalidate_icache(0x80001100, 0x10);
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+        cpu.invalidate_icache(0x800000fe, 4);
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+    }
+
+    #[test]
+    fn partial_line_fill() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.cache_control = 0x00000800;
+        bus.write_word(0x100, 0x11111111).unwrap();
+        bus.write_word(0x108, 0x22222222).unwrap();
+
+        cpu.pc = 0x00000108;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+
+        // words before the missed one were not filled
+        bus.write_word(0x100, 0x33333333).unwrap();
+        cpu.pc = 0x00000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x33333333));
+    }
+
+    #[test]
+    fn cache_disabled() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.register_file[1].write(CACHE_CONTROL);
+        cpu.register_file[2].write(0x00000000);
+        cpu.sw(1, 2, 0, &mut bus).unwrap();
+        bus.write_word(0x100, 0x11111111).unwrap();
+
+        cpu.pc = 0x80000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+
+        bus.write_word(0x100, 0x22222222).unwrap();
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+    }
+
+    #[test]
+    fn isolated_flush() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.register_file[1].write(CACHE_CONTROL);
+        cpu.register_file[2].write(0x00000804);
+        cpu.sw(1, 2, 0, &mut bus).unwrap();
+        assert_eq!(cpu.read_word(CACHE_CONTROL, &mut bus), Ok(0x00000804));
+        bus.write_word(0x100, 0x11111111).unwrap();
+
+        cpu.pc = 0x00000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+        bus.write_word(0x100, 0x22222222).unwrap();
+
+        cpu.cop0.sr |= 0x00010000;
+        cpu.register_file[3].write(0x100);
+        cpu.sw(3, 0, 0, &mut bus).unwrap();
+        assert_eq!(bus.read_word(0x100), Ok(0x22222222));
+
+        cpu.cop0.sr &= !0x00010000;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+    }
+
+    #[test]
+    fn isolated_store() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.enable_icache();
+        cpu.cache_control = 0x00000800;
+        bus.write_word(0x100, 0x11111111).unwrap();
+
+        cpu.pc = 0x00000100;
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x11111111));
+
+        cpu.cop0.sr |= 0x00010000;
+        cpu.register_file[1].write(0x100);
+        cpu.register_file[2].write(0x22222222);
+        cpu.sw(1, 2, 0, &mut bus).unwrap();
+        cpu.cop0.sr &= !0x00010000;
+
+        assert_eq!(bus.read_word(0x100), Ok(0x11111111));
+        assert_eq!(cpu.fetch_instruction(&mut bus), Ok(0x22222222));
+    }
+}
+
+#[cfg(test)]
+mod cop2 {
+    use super::*;
+
+    #[test]
+    fn move_registers() {
+        let mut cpu = Cpu::new();
+        cpu.cop0.sr = 0x40000000;
+        cpu.register_file[1].write(0x12345678);
+
+        cpu.mtc2(1, 0).unwrap();
+        cpu.ctc2(1, 24).unwrap();
+        cpu.mfc2(2, 0).unwrap();
+        assert_eq!(cpu.pending_load, Some((2, 0x12345678)));
+
+        cpu.cfc2(3, 24).unwrap();
+        assert_eq!(cpu.pending_load, Some((3, 0x12345678)));
+    }
+
+    #[test]
+    fn load_store() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.cop0.sr = 0x40000000;
+        cpu.register_file[1].write(0x100);
+        bus.write_word(0x100, 0xfffe0010).unwrap();
+
+        cpu.lwc2(1, 8, 0, &mut bus).unwrap();
+        assert_eq!(cpu.gte.data(8), 0x00000010);
+
+        cpu.swc2(1, 8, 4, &mut bus).unwrap();
+        assert_eq!(bus.read_word(0x104), Ok(0x00000010));
+    }
+
+    #[test]
+    fn command() {
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new(vec![]);
+        cpu.pc = 0;
+        cpu.next_pc = 4;
+        cpu.cop0.sr = 0x40000000;
+        cpu.gte.set_data(12, 0x00000000);
+        cpu.gte.set_data(13, 0x0000000a);
+        cpu.gte.set_data(14, 0x000a0000);
+        // nclip
+        bus.write_word(0, 0x4a000006).unwrap();
+
+        cpu.cpu_cycle(&mut bus);
+        assert_eq!(cpu.gte.data(24), 100);
+    }
+}
@@ -0,0 +1,343 @@
+// BIN/CUE images, a cue sheet listing the bin files and their tracks
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use crate::disc::{Disc, Track, SECTOR_SIZE};
+
+#[derive(Debug, PartialEq)]
+struct CueTrack {
+    number: u8,
+    audio: bool,
+    // sectors of silence that are not in the file
+    pregap: u32,
+    // file sector of INDEX 00, when the pregap is in the file
+    index0: Option<u32>,
+    index1: u32,
+}
+
+#[derive(Debug, PartialEq)]
+struct CueFile {
+    name: String,
+    tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Source {
+    // file index and its first sector
+    File(usize, u32),
+    Silence,
+}
+
+// a run of disc sectors that come from the same place
+#[derive(Debug, PartialEq)]
+struct Region {
+    start: i64,
+    length: u32,
+    source: Source,
+}
+
+pub struct BinCue {
+    files: Vec<File>,
+    regions: Vec<Region>,
+    tracks: Vec<Track>,
+    lead_out: u32,
+}
+
+impl BinCue {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let sheet = std::fs::read_to_string(path)?;
+        let cue_files = parse(&sheet)?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+
+        let mut files = Vec::with_capacity(cue_files.len());
+        let mut sizes = Vec::with_capacity(cue_files.len());
+        for cue_file in &cue_files {
+            let file = File::open(directory.join(&cue_file.name))
+                .map_err(|e| anyhow!("could not open {}: {}", cue_file.name, e))?;
+            sizes.push((file.metadata()?.len() / SECTOR_SIZE as u64) as u32);
+            files.push(file);
+        }
+
+        let (regions, tracks, lead_out) = layout(&cue_files, &sizes)?;
+        Ok(BinCue {
+            files,
+            regions,
+            tracks,
+            lead_out,
+        })
+    }
+
+    // a bin without a cue sheet is a single data track
+    pub fn open_bin(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let sectors = (file.metadata()?.len() / SECTOR_SIZE as u64) as u32;
+
+        Ok(BinCue {
+            files: vec![file],
+            regions: vec![Region {
+                start: 0,
+                length: sectors,
+                source: Source::File(0, 0),
+            }],
+            tracks: vec![Track {
+                number: 1,
+                start: 0,
+                audio: false,
+            }],
+            lead_out: sectors,
+        })
+    }
+}
+
+impl Disc for BinCue {
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.lead_out
+    }
+
+    fn read_sector(&mut self, lba: u32) -> Option<Vec<u8>> {
+        let lba = lba as i64;
+        let region = self
+            .regions
+            .iter()
+            .find(|region| lba >= region.start && lba < region.start + region.length as i64)?;
+
+        let mut sector = vec![0; SECTOR_SIZE];
+        if let Source::File(index, first) = region.source {
+            let file_sector = first as u64 + (lba - region.start) as u64;
+            let file = &mut self.files[index];
+            file.seek(SeekFrom::Start(file_sector * SECTOR_SIZE as u64)).ok()?;
+            file.read_exact(&mut sector).ok()?;
+        }
+        Some(sector)
+    }
+}
+
+fn parse(sheet: &str) -> anyhow::Result<Vec<CueFile>> {
+    let mut files: Vec<CueFile> = vec![];
+
+    for line in sheet.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let command = command.to_ascii_uppercase();
+        let rest = rest.trim();
+
+        match command.as_str() {
+            "FILE" => {
+                // the name can be quoted and have spaces, the type comes last
+                let (name, kind) = match rest.strip_prefix('"') {
+                    Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+                    None => rest.rsplit_once(char::is_whitespace).unwrap_or((rest, "")),
+                };
+                if !kind.trim().eq_ignore_ascii_case("BINARY") {
+                    bail!("unsupported file type in cue sheet: {}", line);
+                }
+                files.push(CueFile {
+                    name: name.to_string(),
+                    tracks: vec![],
+                });
+            }
+            "TRACK" => {
+                let file = files.last_mut().ok_or(anyhow!("TRACK before FILE"))?;
+                let (number, mode) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let audio = match mode.trim().to_ascii_uppercase().as_str() {
+                    "AUDIO" => true,
+                    "MODE1/2352" | "MODE2/2352" => false,
+                    _ => bail!("unsupported track mode: {}", line),
+                };
+                file.tracks.push(CueTrack {
+                    number: number.parse()?,
+                    audio,
+                    pregap: 0,
+                    index0: None,
+                    index1: 0,
+                });
+            }
+            "INDEX" | "PREGAP" => {
+                let track = files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut())
+                    .ok_or(anyhow!("{} before TRACK", command))?;
+                let (number, time) = match command.as_str() {
+                    "PREGAP" => ("", rest),
+                    _ => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+                };
+                let sectors = parse_msf(time.trim())?;
+                match number {
+                    "" => track.pregap = sectors,
+                    "00" | "0" => track.index0 = Some(sectors),
+                    "01" | "1" => track.index1 = sectors,
+                    // the other indexes are just marks inside the track
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if files.iter().all(|file| file.tracks.is_empty()) {
+        bail!("cue sheet has no tracks");
+    }
+    Ok(files)
+}
+
+// mm:ss:ff, without the two second offset
+fn parse_msf(time: &str) -> anyhow::Result<u32> {
+    let parts: Vec<u32> = time.split(':').map(str::parse).collect::<Result<_, _>>()?;
+    match parts[..] {
+        [minute, second, sector] => Ok((minute * 60 + second) * 75 + sector),
+        _ => bail!("invalid time in cue sheet: {}", time),
+    }
+}
+
+// places every file and pregap on the disc, the first track starts at 0
+fn layout(files: &[CueFile], sizes: &[u32]) -> anyhow::Result<(Vec<Region>, Vec<Track>, u32)> {
+    let mut regions = vec![];
+    let mut tracks = vec![];
+    let mut cursor: i64 = 0;
+
+    for (index, (file, &size)) in files.iter().zip(sizes).enumerate() {
+        for (i, track) in file.tracks.iter().enumerate() {
+            if track.pregap > 0 {
+                regions.push(Region {
+                    start: cursor,
+                    length: track.pregap,
+                    source: Source::Silence,
+                });
+                cursor += track.pregap as i64;
+            }
+
+            let begin = track.index0.unwrap_or(track.index1);
+            let end = match file.tracks.get(i + 1) {
+                Some(next) => next.index0.unwrap_or(next.index1),
+                None => size,
+            };
+            if end < begin || track.index1 < begin {
+                bail!("track {} is out of order in the cue sheet", track.number);
+            }
+
+            tracks.push((track.number, cursor + (track.index1 - begin) as i64, track.audio));
+            regions.push(Region {
+                start: cursor,
+                length: end - begin,
+                source: Source::File(index, begin),
+            });
+            cursor += (end - begin) as i64;
+        }
+    }
+
+    let shift = tracks[0].1;
+    for region in &mut regions {
+        region.start -= shift;
+    }
+    let tracks = tracks
+        .into_iter()
+        .map(|(number, start, audio)| Track {
+            number,
+            start: (start - shift) as u32,
+            audio,
+        })
+        .collect();
+
+    Ok((regions, tracks, (cursor - shift) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"FILE "Game (Track 1).bin" BINARY
+  TRACK 01 MODE2/2352
+    INDEX 01 00:00:00
+FILE "Game (Track 2).bin" BINARY
+  TRACK 02 AUDIO
+    INDEX 00 00:00:00
+    INDEX 01 00:02:00
+  TRACK 03 AUDIO
+    PREGAP 00:01:00
+    INDEX 01 00:10:00
+"#;
+
+    #[test]
+    fn parse_sheet() {
+        let files = parse(SHEET).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "Game (Track 1).bin");
+        assert_eq!(
+            files[1].tracks[0],
+            CueTrack {
+                number: 2,
+                audio: true,
+                pregap: 0,
+                index0: Some(0),
+                index1: 150,
+            }
+        );
+        assert_eq!(files[1].tracks[1].pregap, 75);
+        assert_eq!(files[1].tracks[1].index1, 750);
+
+        assert!(parse("FILE \"a.bin\" BINARY\n  TRACK 01 MODE1/2048\n").is_err());
+        assert!(parse("").is_err());
+
+        // the commands can be in any case
+        let files = parse(&SHEET.to_ascii_lowercase()).unwrap();
+        assert_eq!(files[1].tracks[1].pregap, 75);
+        assert_eq!(files[1].tracks[0].index0, Some(0));
+    }
+
+    #[test]
+    fn track_layout() {
+        let files = parse(SHEET).unwrap();
+        let (regions, tracks, lead_out) = layout(&files, &[1000, 1500]).unwrap();
+
+        let starts: Vec<u32> = tracks.iter().map(|track| track.start).collect();
+        // the second file starts with the pregap of track 2
+        assert_eq!(starts, vec![0, 1150, 1000 + 750 + 75]);
+        assert_eq!(lead_out, 1000 + 1500 + 75);
+        assert_eq!(
+            regions[2],
+            Region {
+                start: 1750,
+                length: 75,
+                source: Source::Silence,
+            }
+        );
+        assert_eq!(regions[3].source, Source::File(1, 750));
+    }
+
+    #[test]
+    fn read_sectors() {
+        let directory = std::env::temp_dir().join(format!("psiemu_cue_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut data = vec![0; SECTOR_SIZE * 4];
+        data[SECTOR_SIZE * 3] = 0x33;
+        std::fs::write(directory.join("Game (Track 1).bin"), &data).unwrap();
+        data[SECTOR_SIZE * 3] = 0x44;
+        std::fs::write(directory.join("Game (Track 2).bin"), &data).unwrap();
+        let sheet = [
+            "FILE \"Game (Track 1).bin\" BINARY",
+            "  TRACK 01 MODE2/2352",
+            "    INDEX 01 00:00:00",
+            "FILE \"Game (Track 2).bin\" BINARY",
+            "  TRACK 02 AUDIO",
+            "    PREGAP 00:00:02",
+            "    INDEX 01 00:00:00",
+        ];
+        std::fs::write(directory.join("game.cue"), sheet.join("\n")).unwrap();
+
+        let mut disc = BinCue::open(&directory.join("game.cue")).unwrap();
+        assert_eq!(disc.tracks()[1].start, 6);
+        assert_eq!(disc.lead_out(), 10);
+        assert_eq!(disc.read_sector(3).unwrap()[0], 0x33);
+        assert_eq!(disc.read_sector(4).unwrap(), vec![0; SECTOR_SIZE]);
+        assert_eq!(disc.read_sector(9).unwrap()[0], 0x44);
+        assert_eq!(disc.read_sector(10), None);
+    }
+}
@@ -0,0 +1,84 @@
+// Disc images as the cdrom controller sees them
+
+use std::path::Path;
+
+use anyhow::bail;
+
+use crate::chd::Chd;
+use crate::cue::BinCue;
+use crate::iso::Iso;
+
+pub const SECTOR_SIZE: usize = 2352;
+// sector 0 is at 00:02:00, after the pregap of the first track
+pub const PREGAP: u32 = 150;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub number: u8,
+    // first sector of the track, not counting the pregap
+    pub start: u32,
+    pub audio: bool,
+}
+
+pub trait Disc {
+    fn tracks(&self) -> &[Track];
+
+    // first sector after the last track
+    fn lead_out(&self) -> u32;
+
+    // whole raw sector, with the sync, header and subheader
+    fn read_sector(&mut self, lba: u32) -> Option<Vec<u8>>;
+
+    // the 96 bytes of subchannel data, only some images keep them. the
+    // drive has no GetlocP yet, so nothing reads them
+    #[allow(dead_code)]
+    fn read_subcode(&mut self, _lba: u32) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// minutes, seconds and sectors of an absolute position
+pub fn msf(lba: u32) -> (u8, u8, u8) {
+    let sectors = lba + PREGAP;
+    ((sectors / 75 / 60) as u8, (sectors / 75 % 60) as u8, (sectors % 75) as u8)
+}
+
+pub fn lba(minute: u8, second: u8, sector: u8) -> u32 {
+    let sectors = (minute as u32 * 60 + second as u32) * 75 + sector as u32;
+    sectors.saturating_sub(PREGAP)
+}
+
+pub fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+pub fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// the format is taken from the extension
+pub fn open(path: &Path) -> anyhow::Result<Box<dyn Disc>> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "cue" => Ok(Box::new(BinCue::open(path)?)),
+        "bin" | "img" => Ok(Box::new(BinCue::open_bin(path)?)),
+        "iso" => Ok(Box::new(Iso::open(path)?)),
+        "chd" => Ok(Box::new(Chd::open(path)?)),
+        _ => bail!("unknown disc image format: {}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        assert_eq!(msf(0), (0, 2, 0));
+        assert_eq!(msf(4350), (1, 0, 0));
+        assert_eq!(lba(1, 0, 0), 4350);
+        assert_eq!(lba(0, 2, 74), 74);
+        assert_eq!(binary_to_bcd(59), 0x59);
+        assert_eq!(bcd_to_binary(0x74), 74);
+    }
+}
@@ -0,0 +1,352 @@
+// DMA controller, seven channels sharing DPCR and DICR
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    MdecIn = 0,
+    MdecOut = 1,
+    Gpu = 2,
+    Cdrom = 3,
+    Spu = 4,
+    Pio = 5,
+    // clears the ordering table in ram
+    Otc = 6,
+}
+
+const PORTS: [Port; 7] = [
+    Port::MdecIn,
+    Port::MdecOut,
+    Port::Gpu,
+    Port::Cdrom,
+    Port::Spu,
+    Port::Pio,
+    Port::Otc,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Direction {
+    ToRam,
+    FromRam,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Sync {
+    // the whole transfer at once, started by the trigger bit
+    Manual,
+    // blocks whenever the device asks for them
+    Request,
+    // chain of packets in ram, used for the gpu command lists
+    LinkedList,
+}
+
+#[derive(Clone, Copy)]
+pub struct Channel {
+    madr: u32,
+    bcr: u32,
+    chcr: u32,
+}
+
+impl Channel {
+    const MADR: u32 = 0x0;
+    const BCR: u32 = 0x4;
+    const CHCR: u32 = 0x8;
+
+    // bits 2-7, 11-15, 19, 23, 25-27 and 29 are always zero
+    const CHCR_WRITE_MASK: u32 = 0x71770703;
+    // the ordering table clear always goes backwards, only the start bits
+    // and the unknown bit 30 can be changed
+    const OTC_WRITE_MASK: u32 = 0x51000000;
+    const OTC_FIXED: u32 = 0x00000002;
+
+    fn new() -> Self {
+        Channel {
+            madr: 0,
+            bcr: 0,
+            chcr: 0,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.chcr & 0x00000001 != 0 {
+            Direction::FromRam
+        } else {
+            Direction::ToRam
+        }
+    }
+
+    pub fn step(&self) -> u32 {
+        if self.chcr & 0x00000002 != 0 {
+            (-4i32) as u32
+        } else {
+            4
+        }
+    }
+
+    pub fn sync(&self) -> Sync {
+        match (self.chcr >> 9) & 3 {
+            0 => Sync::Manual,
+            1 => Sync::Request,
+            2 => Sync::LinkedList,
+            // reserved, behaves like the linked list on hardware
+            _ => Sync::LinkedList,
+        }
+    }
+
+    pub fn address(&self) -> u32 {
+        self.madr & 0x00fffffc
+    }
+
+    // number of words to move for the manual and request modes
+    pub fn transfer_size(&self) -> u32 {
+        let size = match self.bcr & 0xffff {
+            0 => 0x10000,
+            size => size,
+        };
+
+        match self.sync() {
+            Sync::Manual => size,
+            Sync::Request => size * (self.bcr >> 16),
+            Sync::LinkedList => 0,
+        }
+    }
+
+    fn active(&self) -> bool {
+        let start = self.chcr & 0x01000000 != 0;
+        match self.sync() {
+            // manual mode also needs the trigger
+            Sync::Manual => start && self.chcr & 0x10000000 != 0,
+            _ => start,
+        }
+    }
+
+    fn finish(&mut self, address: u32) {
+        match self.sync() {
+            // manual mode leaves MADR and BCR untouched
+            Sync::Manual => (),
+            Sync::Request => {
+                self.madr = address & 0x00ffffff;
+                self.bcr &= 0x0000ffff;
+            }
+            Sync::LinkedList => self.madr = address & 0x00ffffff,
+        }
+
+        self.chcr &= !0x11000000;
+    }
+}
+
+pub struct Dma {
+    channels: [Channel; 7],
+    dpcr: u32,
+    dicr: u32,
+    // where MADR stops once the transfer in flight ends
+    ends: [Option<u32>; 7],
+}
+
+impl Dma {
+    const DPCR: u32 = 0x70;
+    const DICR: u32 = 0x74;
+
+    // bits 0-5 are read/write, 15 forces the irq, 16-22 enable each channel
+    // irq and 23 enables them all
+    const DICR_WRITE_MASK: u32 = 0x00ff803f;
+    const DICR_FLAGS: u32 = 0x7f000000;
+
+    pub fn new() -> Self {
+        let mut channels = [Channel::new(); 7];
+        channels[Port::Otc as usize].chcr = Channel::OTC_FIXED;
+
+        Dma {
+            channels,
+            dpcr: 0x07654321,
+            dicr: 0,
+            ends: [None; 7],
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        match offset {
+            0x00..=0x6f => {
+                let channel = &self.channels[(offset >> 4) as usize];
+                match offset & 0xf {
+                    Channel::MADR => channel.madr,
+                    Channel::BCR => channel.bcr,
+                    Channel::CHCR => channel.chcr,
+                    _ => 0,
+                }
+            }
+            Dma::DPCR => self.dpcr,
+            Dma::DICR => self.dicr,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0x00..=0x6f => {
+                let port = PORTS[(offset >> 4) as usize];
+                let channel = &mut self.channels[port as usize];
+                match offset & 0xf {
+                    Channel::MADR => channel.madr = value & 0x00ffffff,
+                    Channel::BCR => channel.bcr = value,
+                    Channel::CHCR if port == Port::Otc => {
+                        channel.chcr = (value & Channel::OTC_WRITE_MASK) | Channel::OTC_FIXED
+                    }
+                    Channel::CHCR => channel.chcr = value & Channel::CHCR_WRITE_MASK,
+                    _ => (),
+                }
+            }
+            Dma::DPCR => self.dpcr = value,
+            Dma::DICR => {
+                // writing one to a flag acknowledges it
+                let flags = self.dicr & Dma::DICR_FLAGS & !value;
+                self.dicr = flags | (value & Dma::DICR_WRITE_MASK);
+                self.update_master_flag();
+            }
+            _ => (),
+        }
+    }
+
+    // the register with a store narrower than a word merged into it, the
+    // DICR flags are left out so the other bytes do not acknowledge them
+    pub fn merge(&self, offset: u32, value: u32, lanes: u32) -> u32 {
+        let mut current = self.read(offset);
+        if offset == Dma::DICR {
+            current &= !Dma::DICR_FLAGS;
+        }
+        (current & !lanes) | value
+    }
+
+    pub fn channel(&self, port: Port) -> Channel {
+        self.channels[port as usize]
+    }
+
+    // next channel that was started and is enabled on DPCR
+    pub fn active_port(&self) -> Option<Port> {
+        PORTS.into_iter().find(|&port| {
+            let enabled = self.dpcr & (0x8 << (port as u32 * 4)) != 0;
+            let in_flight = self.ends[port as usize].is_some();
+            enabled && !in_flight && self.channels[port as usize].active()
+        })
+    }
+
+    // the data already moved, the channel stays busy until complete
+    pub fn transferred(&mut self, port: Port, address: u32) {
+        self.ends[port as usize] = Some(address);
+    }
+
+    pub fn complete(&mut self, port: Port) {
+        if let Some(address) = self.ends[port as usize].take() {
+            self.finish(port, address);
+        }
+    }
+
+    // address is where MADR stopped when the transfer ended
+    pub fn finish(&mut self, port: Port, address: u32) {
+        self.channels[port as usize].finish(address);
+
+        if self.dicr & (0x00010000 << port as u32) != 0 {
+            self.dicr |= 0x01000000 << port as u32;
+        }
+        self.update_master_flag();
+    }
+
+    // DICR bit 31, the interrupt is requested on its rising edge
+    pub fn irq(&self) -> bool {
+        self.dicr & 0x80000000 != 0
+    }
+
+    fn update_master_flag(&mut self) {
+        let force = self.dicr & 0x00008000 != 0;
+        let master_enable = self.dicr & 0x00800000 != 0;
+        let enabled_flags = (self.dicr >> 16) & (self.dicr >> 24) & 0x7f;
+
+        if force || (master_enable && enabled_flags != 0) {
+            self.dicr |= 0x80000000;
+        } else {
+            self.dicr &= !0x80000000;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otc_chcr() {
+        let mut dma = Dma::new();
+
+        assert_eq!(dma.read(0x68), 0x00000002);
+        dma.write(0x68, 0xffffffff);
+        assert_eq!(dma.read(0x68), 0x51000002);
+        dma.write(0x28, 0xffffffff);
+        assert_eq!(dma.read(0x28), 0x71770703);
+    }
+
+    #[test]
+    fn manual_trigger() {
+        let mut dma = Dma::new();
+        dma.write(0x70, 0x00000800);
+
+        dma.write(0x28, 0x01000000);
+        assert_eq!(dma.active_port(), None);
+        dma.write(0x28, 0x11000000);
+        assert_eq!(dma.active_port(), Some(Port::Gpu));
+
+        // disabled on DPCR
+        dma.write(0x70, 0x00000000);
+        assert_eq!(dma.active_port(), None);
+    }
+
+    #[test]
+    fn in_flight() {
+        let mut dma = Dma::new();
+        dma.write(0x70, 0x00000800);
+        dma.write(0x20, 0x00001000);
+        dma.write(0x28, 0x01000401);
+
+        dma.transferred(Port::Gpu, 0x00ffffff);
+        assert_eq!(dma.active_port(), None);
+        assert_eq!(dma.read(0x28), 0x01000401);
+
+        dma.complete(Port::Gpu);
+        assert_eq!(dma.read(0x20), 0x00ffffff);
+        assert_eq!(dma.read(0x28), 0x00000401);
+    }
+
+    #[test]
+    fn request_finish() {
+        let mut dma = Dma::new();
+        dma.write(0x20, 0x00001000);
+        dma.write(0x24, 0x00040010);
+        dma.write(0x28, 0x01000201);
+        let channel = dma.channel(Port::Gpu);
+        assert_eq!(channel.sync(), Sync::Request);
+        assert_eq!(channel.transfer_size(), 0x40);
+
+        dma.finish(Port::Gpu, 0x00001100);
+        assert_eq!(dma.read(0x20), 0x00001100);
+        assert_eq!(dma.read(0x24), 0x00000010);
+        assert_eq!(dma.read(0x28), 0x00000201);
+    }
+
+    #[test]
+    fn interrupt_flags() {
+        let mut dma = Dma::new();
+        dma.write(0x74, 0x00840000);
+
+        dma.finish(Port::Gpu, 0);
+        assert_eq!(dma.read(0x74), 0x84840000);
+        assert!(dma.irq());
+
+        // channel without its irq enabled does not set a flag
+        dma.finish(Port::Spu, 0);
+        assert_eq!(dma.read(0x74), 0x84840000);
+
+        dma.write(0x74, 0x04840000);
+        assert_eq!(dma.read(0x74), 0x00840000);
+        assert!(!dma.irq());
+
+        dma.write(0x74, 0x00008000);
+        assert!(dma.irq());
+    }
+}
@@ -0,0 +1,144 @@
+// PS-X EXE executables, loaded straight into ram instead of booting a disc
+
+use std::path::Path;
+
+use anyhow::bail;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+
+// the bios jumps here to start the shell once the kernel is set up, an
+// executable loaded at that point finds everything ready like a game would
+pub const SHELL_ENTRY: u32 = 0x80030000;
+
+const HEADER_SIZE: usize = 0x800;
+const GP: u8 = 28;
+const SP: u8 = 29;
+const FP: u8 = 30;
+
+#[derive(Debug, PartialEq)]
+pub struct Exe {
+    pc: u32,
+    gp: u32,
+    text_address: u32,
+    text: Vec<u8>,
+    bss_address: u32,
+    bss_size: u32,
+    // base plus offset, the stack is left alone when the base is zero
+    stack: Option<u32>,
+}
+
+impl Exe {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        parse(&std::fs::read(path)?)
+    }
+
+    pub fn load(&self, cpu: &mut Cpu, bus: &mut Bus) {
+        bus.write_ram(self.text_address, &self.text);
+        bus.write_ram(self.bss_address, &vec![0; self.bss_size as usize]);
+        cpu.invalidate_icache(self.text_address, self.text.len() as u32);
+        cpu.invalidate_icache(self.bss_address, self.bss_size);
+
+        cpu.set_register(GP, self.gp);
+        if let Some(stack) = self.stack {
+            cpu.set_register(SP, stack);
+            cpu.set_register(FP, stack);
+        }
+        cpu.jump(self.pc);
+    }
+}
+
+fn parse(data: &[u8]) -> anyhow::Result<Exe> {
+    if data.len() < HEADER_SIZE || &data[0..8] != b"PS-X EXE" {
+        bail!("not a ps-x exe");
+    }
+    let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    // the size in the header is what gets copied, anything after it is left
+    let size = word(0x1c) as usize;
+    if size > 0x200000 || word(0x2c) > 0x200000 {
+        bail!("ps-x exe does not fit in ram");
+    }
+    if data.len() - HEADER_SIZE < size {
+        bail!("ps-x exe is shorter than its header says");
+    }
+    let text = data[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+
+    Ok(Exe {
+        pc: word(0x10),
+        gp: word(0x14),
+        text_address: word(0x18),
+        text,
+        bss_address: word(0x28),
+        bss_size: word(0x2c),
+        stack: match word(0x30) {
+            0 => None,
+            base => Some(base.wrapping_add(word(0x34))),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executable() -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE + 0x10];
+        data[0..8].copy_from_slice(b"PS-X EXE");
+        let fields = [
+            (0x10, 0x80010000),
+            (0x14, 0x8001f000),
+            (0x18, 0x80010000),
+            (0x1c, 0x8),
+            (0x28, 0x80010010),
+            (0x2c, 0x8),
+            (0x30, 0x801ffff0),
+            (0x34, 0x0),
+        ];
+        for (offset, value) in fields {
+            data[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        data[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data
+    }
+
+    #[test]
+    fn header() {
+        let exe = parse(&executable()).unwrap();
+        assert_eq!(exe.pc, 0x80010000);
+        assert_eq!(exe.gp, 0x8001f000);
+        assert_eq!(exe.text_address, 0x80010000);
+        // the file goes on past the size in the header
+        assert_eq!(exe.text, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(exe.stack, Some(0x801ffff0));
+
+        let mut no_stack = executable();
+        no_stack[0x30..0x34].fill(0);
+        assert_eq!(parse(&no_stack).unwrap().stack, None);
+
+        let mut short = executable();
+        short[0x1c..0x20].copy_from_slice(&u32::to_le_bytes(0x20));
+        assert!(parse(&short).is_err());
+        let mut huge = executable();
+        huge[0x1c..0x20].fill(0xff);
+        assert!(parse(&huge).is_err());
+
+        assert!(parse(b"PS-X EXE").is_err());
+        assert!(parse(&[0; HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn load() {
+        let mut bus = Bus::new(vec![0; 0x80000]);
+        let mut cpu = Cpu::new();
+        // left over in the bss from before
+        bus.write_ram(0x80010014, &[0xff; 4]);
+
+        parse(&executable()).unwrap().load(&mut cpu, &mut bus);
+        // the bus takes physical addresses
+        assert_eq!(bus.read_word(0x00010000), Ok(0x04030201));
+        assert_eq!(bus.read_word(0x00010004), Ok(0x08070605));
+        assert_eq!(bus.read_word(0x00010014), Ok(0));
+        assert_eq!(cpu.pc(), 0x80010000);
+    }
+}
@@ -0,0 +1,1600 @@
+// Graphics processing unit, GP0 draws into vram and GP1 controls the display
+
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
+
+// the gpu clock runs at 11/7 of the cpu clock
+const LINE_CYCLES_NTSC: u32 = 3413 * 7;
+const LINE_CYCLES_PAL: u32 = 3406 * 7;
+const LINES_NTSC: u32 = 263;
+const LINES_PAL: u32 = 314;
+// the hblank ends and starts at these gpu cycles of the line when the
+// horizontal range does not give a usable one
+const HBLANK_END: u32 = 0x260;
+const HBLANK_START: u32 = 0xc60;
+
+// offsets added to the color before it is truncated to 15 bits
+const DITHER: [[i32; 4]; 4] = [[-4, 0, -3, 1], [2, -2, 3, -1], [-3, 1, -4, 0], [3, -1, 2, -2]];
+
+#[derive(Clone, Copy)]
+struct Vertex {
+    x: i32,
+    y: i32,
+    // 24 bit color as sent on the command
+    color: u32,
+    u: u8,
+    v: u8,
+}
+
+impl Vertex {
+    fn new(position: u32, color: u32) -> Self {
+        // coordinates are 11 bit signed
+        Vertex {
+            x: ((position as i32) << 21) >> 21,
+            y: ((position as i32) << 5) >> 21,
+            color: color & 0x00ffffff,
+            u: 0,
+            v: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Texture {
+    page_x: u32,
+    page_y: u32,
+    // 0 is 4 bit, 1 is 8 bit and 2 is 15 bit
+    depth: u32,
+    clut_x: u32,
+    clut_y: u32,
+}
+
+// how the pixels of a primitive are shaded
+struct Attributes {
+    semi_transparent: bool,
+    gouraud: bool,
+    texture: Option<Texture>,
+    // the texture is used as it is instead of modulated by the color
+    raw: bool,
+    dither: bool,
+}
+
+// rectangle of vram being transferred from or to the cpu
+struct VramTransfer {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    // pixels already transferred
+    index: u32,
+}
+
+impl VramTransfer {
+    fn new(position: u32, size: u32) -> Self {
+        VramTransfer {
+            x: position & 0x3ff,
+            y: (position >> 16) & 0x1ff,
+            width: ((size & 0xffff).wrapping_sub(1) & 0x3ff) + 1,
+            height: ((size >> 16).wrapping_sub(1) & 0x1ff) + 1,
+            index: 0,
+        }
+    }
+
+    // vram coordinates of the next pixel, wraps around the edges
+    fn next(&mut self) -> (u32, u32) {
+        let x = (self.x + self.index % self.width) & 0x3ff;
+        let y = (self.y + self.index / self.width) & 0x1ff;
+        self.index += 1;
+        (x, y)
+    }
+
+    fn done(&self) -> bool {
+        self.index >= self.width * self.height
+    }
+}
+
+pub struct Gpu {
+    vram: Vec<u16>,
+
+    // words of the GP0 command being received
+    command: Vec<u32>,
+    cpu_to_vram: Option<VramTransfer>,
+    vram_to_cpu: Option<VramTransfer>,
+    gpuread: u32,
+    irq: bool,
+
+    // GP0(E1h) to GP0(E6h), kept raw for GPUSTAT and GP1(10h)
+    draw_mode: u32,
+    texture_window: u32,
+    drawing_area_top_left: u32,
+    drawing_area_bottom_right: u32,
+    drawing_offset: u32,
+    mask_settings: u32,
+
+    // display control
+    display_enabled: bool,
+    dma_direction: u32,
+    display_start: u32,
+    horizontal_range: u32,
+    vertical_range: u32,
+    display_mode: u32,
+    // video timing, in gpu cycles times 7
+    line_cycles: u32,
+    dot_cycles: u32,
+    scanline: u32,
+    odd_field: bool,
+}
+
+// what the video timing went through during a step
+#[derive(Default)]
+pub struct Video {
+    pub dots: u32,
+    // the hblank started or ended
+    pub hblank: Option<bool>,
+    // the vblank started or ended
+    pub vblank: Option<bool>,
+}
+
+// displayed picture as 24 bit rgb
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Gpu {
+    pub fn new() -> Self {
+        let mut gpu = Gpu {
+            vram: vec![0; VRAM_WIDTH * VRAM_HEIGHT],
+            command: Vec::with_capacity(16),
+            cpu_to_vram: None,
+            vram_to_cpu: None,
+            gpuread: 0,
+            irq: false,
+            draw_mode: 0,
+            texture_window: 0,
+            drawing_area_top_left: 0,
+            drawing_area_bottom_right: 0,
+            drawing_offset: 0,
+            mask_settings: 0,
+            display_enabled: false,
+            dma_direction: 0,
+            display_start: 0,
+            horizontal_range: 0,
+            vertical_range: 0,
+            display_mode: 0,
+            line_cycles: 0,
+            dot_cycles: 0,
+            scanline: 0,
+            odd_field: false,
+        };
+        gpu.reset();
+        gpu
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn status(&self) -> u32 {
+        let mut status = self.draw_mode & 0x000007ff;
+        status |= (self.mask_settings & 3) << 11;
+        // the interlace field is always set when not interlacing
+        status |= 1 << 13;
+        status |= ((self.display_mode >> 7) & 1) << 14;
+        status |= ((self.draw_mode >> 11) & 1) << 15;
+        status |= ((self.display_mode >> 6) & 1) << 16;
+        status |= (self.display_mode & 0x3f) << 17;
+        status |= (!self.display_enabled as u32) << 23;
+        status |= (self.irq as u32) << 24;
+
+        // commands run at once so the gpu is always ready
+        let ready_command = self.cpu_to_vram.is_none() && self.command.is_empty();
+        let ready_vram_to_cpu = self.vram_to_cpu.is_some();
+        let ready_dma = self.command.is_empty();
+        status |= (ready_command as u32) << 26;
+        status |= (ready_vram_to_cpu as u32) << 27;
+        status |= (ready_dma as u32) << 28;
+        status |= self.dma_direction << 29;
+
+        let dma_request = match self.dma_direction {
+            0 => false,
+            1 => true,
+            2 => ready_dma,
+            _ => ready_vram_to_cpu,
+        };
+        status |= (dma_request as u32) << 25;
+        status |= (self.odd_line() as u32) << 31;
+
+        status
+    }
+
+    // GPUREAD, pixels of a vram to cpu transfer or the GP1(10h) response
+    pub fn read(&mut self) -> u32 {
+        if let Some(transfer) = &mut self.vram_to_cpu {
+            let mut value = 0;
+            for i in 0..2 {
+                let (x, y) = transfer.next();
+                value |= (self.vram[y as usize * VRAM_WIDTH + x as usize] as u32) << (i * 16);
+            }
+            if transfer.done() {
+                self.vram_to_cpu = None;
+            }
+            self.gpuread = value;
+        }
+
+        self.gpuread
+    }
+
+    pub fn gp0(&mut self, value: u32) {
+        if let Some(transfer) = &mut self.cpu_to_vram {
+            let mut pixels = [None; 2];
+            for (i, pixel) in [value as u16, (value >> 16) as u16].into_iter().enumerate() {
+                pixels[i] = Some((transfer.next(), pixel));
+                // an odd number of pixels leaves the last halfword unused
+                if transfer.done() {
+                    self.cpu_to_vram = None;
+                    break;
+                }
+            }
+            for ((x, y), pixel) in pixels.into_iter().flatten() {
+                self.write_masked(x, y, pixel);
+            }
+            return;
+        }
+
+        self.command.push(value);
+        let opcode = self.command[0] >> 24;
+
+        let complete = match opcode {
+            // polylines end with a 5xxx5xxxh word after the first two vertices
+            0x48..=0x4f | 0x58..=0x5f => {
+                let minimum = if opcode & 0x10 != 0 { 4 } else { 3 };
+                self.command.len() > minimum && value & 0xf000f000 == 0x50005000
+            }
+            _ => self.command.len() >= command_length(opcode),
+        };
+
+        if complete {
+            self.execute_gp0();
+            self.command.clear();
+        }
+    }
+
+    pub fn gp1(&mut self, value: u32) {
+        match value >> 24 {
+            0x00 => self.reset(),
+            0x01 => {
+                self.command.clear();
+                self.cpu_to_vram = None;
+            }
+            0x02 => self.irq = false,
+            0x03 => self.display_enabled = value & 1 == 0,
+            0x04 => self.dma_direction = value & 3,
+            0x05 => self.display_start = value & 0x0007ffff,
+            0x06 => self.horizontal_range = value & 0x00ffffff,
+            0x07 => self.vertical_range = value & 0x000fffff,
+            0x08 => self.display_mode = value & 0xff,
+            0x10..=0x1f => match value & 0x7 {
+                2 => self.gpuread = self.texture_window,
+                3 => self.gpuread = self.drawing_area_top_left,
+                4 => self.gpuread = self.drawing_area_bottom_right,
+                5 => self.gpuread = self.drawing_offset,
+                // gpu version
+                7 => self.gpuread = 2,
+                // the other indexes keep the old value
+                _ => (),
+            },
+            opcode => println!("Unknown gp1 command {:x}", opcode),
+        }
+    }
+
+    // advances the video timing, the timers follow what happened on the way
+    pub fn step(&mut self, cycles: u32) -> Video {
+        let (line_length, lines) = if self.pal() {
+            (LINE_CYCLES_PAL, LINES_PAL)
+        } else {
+            (LINE_CYCLES_NTSC, LINES_NTSC)
+        };
+        let mut video = Video::default();
+        let hblank = self.in_hblank();
+
+        let dot_length = self.dot_divider() * 7;
+        self.dot_cycles += cycles * 11;
+        video.dots = self.dot_cycles / dot_length;
+        self.dot_cycles %= dot_length;
+
+        self.line_cycles += cycles * 11;
+        while self.line_cycles >= line_length {
+            self.line_cycles -= line_length;
+            self.scanline += 1;
+            if self.scanline >= lines {
+                self.scanline = 0;
+                self.odd_field = !self.odd_field;
+            }
+
+            if self.scanline == self.vblank_start().min(lines - 1) {
+                video.vblank = Some(true);
+            } else if self.scanline == (self.vertical_range & 0x3ff).min(lines - 1) {
+                video.vblank = Some(false);
+            }
+        }
+        if self.in_hblank() != hblank {
+            video.hblank = Some(!hblank);
+        }
+
+        video
+    }
+
+    // cpu cycles until the current line ends
+    pub fn cycles_to_next_line(&self) -> u32 {
+        let line_length = if self.pal() { LINE_CYCLES_PAL } else { LINE_CYCLES_NTSC };
+        (line_length - self.line_cycles).div_ceil(11)
+    }
+
+    // the hblank takes the part of the line outside the horizontal range
+    pub fn in_hblank(&self) -> bool {
+        let (end, start) = self.hblank_range();
+        self.line_cycles < end || self.line_cycles >= start
+    }
+
+    // cpu cycles until the hblank starts or ends
+    pub fn cycles_to_hblank_edge(&self) -> u32 {
+        let line_length = if self.pal() { LINE_CYCLES_PAL } else { LINE_CYCLES_NTSC };
+        let (end, start) = self.hblank_range();
+        let edge = if self.line_cycles < end {
+            end
+        } else if self.line_cycles < start {
+            start
+        } else {
+            line_length + end
+        };
+        (edge - self.line_cycles).div_ceil(11)
+    }
+
+    // where the hblank ends and starts, in gpu cycles times 7
+    fn hblank_range(&self) -> (u32, u32) {
+        let line_length = if self.pal() { LINE_CYCLES_PAL } else { LINE_CYCLES_NTSC };
+        let x1 = self.horizontal_range & 0xfff;
+        let x2 = (self.horizontal_range >> 12) & 0xfff;
+        if x1 < x2 && x2 * 7 < line_length {
+            (x1 * 7, x2 * 7)
+        } else {
+            (HBLANK_END * 7, HBLANK_START * 7)
+        }
+    }
+
+    // display area of vram as it would show on the screen
+    pub fn frame(&self) -> Frame {
+        let (width, height) = self.display_size();
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+
+        let start_x = self.display_start & 0x3ff;
+        let start_y = (self.display_start >> 10) & 0x1ff;
+        let depth24 = self.display_mode & 0x10 != 0;
+        let vram = |x: u32, y: u32| {
+            self.vram[((start_y + y) & 0x1ff) as usize * VRAM_WIDTH + (x & 0x3ff) as usize]
+        };
+
+        for y in 0..height {
+            for x in 0..width {
+                if !self.display_enabled {
+                    pixels.extend([0, 0, 0]);
+                } else if depth24 {
+                    // three bytes per pixel packed over the halfwords
+                    let byte = x * 3;
+                    let at = |i: u32| {
+                        let halfword = vram(start_x + (byte + i) / 2, y);
+                        (halfword >> (((byte + i) & 1) * 8)) as u8
+                    };
+                    pixels.extend([at(0), at(1), at(2)]);
+                } else {
+                    let [r, g, b] = channels15(vram(start_x + x, y));
+                    pixels.extend([r, g, b].map(|c| ((c << 3) | (c >> 2)) as u8));
+                }
+            }
+        }
+
+        Frame {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.command.clear();
+        self.cpu_to_vram = None;
+        self.vram_to_cpu = None;
+        self.irq = false;
+        self.draw_mode = 0;
+        self.texture_window = 0;
+        self.drawing_area_top_left = 0;
+        self.drawing_area_bottom_right = 0;
+        self.drawing_offset = 0;
+        self.mask_settings = 0;
+        self.display_enabled = false;
+        self.dma_direction = 0;
+        self.display_start = 0;
+        self.horizontal_range = 0x200 | (0xc00 << 12);
+        self.vertical_range = 0x010 | (0x100 << 10);
+        self.display_mode = 0;
+    }
+
+    fn execute_gp0(&mut self) {
+        let command = self.command[0];
+
+        match command >> 24 {
+            // clear texture cache
+            0x01 => (),
+            0x02 => self.fill_rectangle(),
+            0x1f => self.irq = true,
+            0x20..=0x3f => self.draw_polygon(),
+            0x40..=0x5f => self.draw_line(),
+            0x60..=0x7f => self.draw_rectangle(),
+            0x80..=0x9f => self.copy_rectangle(),
+            0xa0..=0xbf => {
+                self.cpu_to_vram = Some(VramTransfer::new(self.command[1], self.command[2]))
+            }
+            0xc0..=0xdf => {
+                self.vram_to_cpu = Some(VramTransfer::new(self.command[1], self.command[2]))
+            }
+            0xe1 => self.draw_mode = command & 0x00003fff,
+            0xe2 => self.texture_window = command & 0x000fffff,
+            0xe3 => self.drawing_area_top_left = command & 0x000fffff,
+            0xe4 => self.drawing_area_bottom_right = command & 0x000fffff,
+            0xe5 => self.drawing_offset = command & 0x003fffff,
+            0xe6 => self.mask_settings = command & 3,
+            _ => (),
+        }
+    }
+
+    // fills ignore the drawing area, offset and mask
+    fn fill_rectangle(&mut self) {
+        let color = rgb15(self.command[0]);
+        let position = self.command[1];
+        let size = self.command[2];
+
+        let x = position & 0x3f0;
+        let y = (position >> 16) & 0x1ff;
+        let width = ((size & 0x3ff) + 0xf) & !0xf;
+        let height = (size >> 16) & 0x1ff;
+
+        for row in 0..height {
+            for column in 0..width {
+                let x = (x + column) & 0x3ff;
+                let y = (y + row) & 0x1ff;
+                self.vram[y as usize * VRAM_WIDTH + x as usize] = color;
+            }
+        }
+    }
+
+    fn copy_rectangle(&mut self) {
+        let mut source = VramTransfer::new(self.command[1], self.command[3]);
+        let mut destination = VramTransfer::new(self.command[2], self.command[3]);
+
+        while !source.done() {
+            let (sx, sy) = source.next();
+            let (dx, dy) = destination.next();
+            let pixel = self.vram[sy as usize * VRAM_WIDTH + sx as usize];
+            self.write_masked(dx, dy, pixel);
+        }
+    }
+
+    fn draw_polygon(&mut self) {
+        let command = self.command[0];
+        let gouraud = command & 0x10000000 != 0;
+        let quad = command & 0x08000000 != 0;
+        let textured = command & 0x04000000 != 0;
+
+        let mut words = self.command.iter().copied().skip(1);
+        let mut vertices = [Vertex::new(0, 0); 4];
+        let mut color = command;
+        let mut clut = 0;
+        let mut texpage = 0;
+        for (i, vertex) in vertices.iter_mut().take(if quad { 4 } else { 3 }).enumerate() {
+            if gouraud && i > 0 {
+                color = words.next().unwrap();
+            }
+            *vertex = Vertex::new(words.next().unwrap(), color);
+            if textured {
+                let texcoord = words.next().unwrap();
+                vertex.u = texcoord as u8;
+                vertex.v = (texcoord >> 8) as u8;
+                // the clut goes with the first vertex and the page with the second
+                match i {
+                    0 => clut = texcoord >> 16,
+                    1 => texpage = texcoord >> 16,
+                    _ => (),
+                }
+            }
+        }
+
+        // the page of a textured polygon also changes the draw mode
+        if textured {
+            self.draw_mode = (self.draw_mode & !0x000009ff) | (texpage & 0x000009ff);
+        }
+
+        let attributes = self.attributes(command, textured.then_some(clut), gouraud);
+        let offset = self.offset();
+        for vertex in vertices.iter_mut() {
+            vertex.x += offset.0;
+            vertex.y += offset.1;
+        }
+
+        self.draw_triangle([vertices[0], vertices[1], vertices[2]], &attributes);
+        if quad {
+            self.draw_triangle([vertices[1], vertices[2], vertices[3]], &attributes);
+        }
+    }
+
+    fn draw_line(&mut self) {
+        let command = self.command[0];
+        let gouraud = command & 0x10000000 != 0;
+        let polyline = command & 0x08000000 != 0;
+
+        let words = if polyline {
+            // drop the terminator
+            &self.command[1..self.command.len() - 1]
+        } else {
+            &self.command[1..]
+        };
+
+        let mut vertices = Vec::with_capacity(words.len());
+        let mut color = command;
+        let mut words = words.iter().copied();
+        while let Some(word) = words.next() {
+            let position = if gouraud && !vertices.is_empty() {
+                color = word;
+                match words.next() {
+                    Some(position) => position,
+                    None => break,
+                }
+            } else {
+                word
+            };
+            vertices.push(Vertex::new(position, color));
+        }
+
+        let attributes = self.attributes(command, None, gouraud);
+        let offset = self.offset();
+        for segment in vertices.windows(2) {
+            let mut a = segment[0];
+            let mut b = segment[1];
+            a.x += offset.0;
+            a.y += offset.1;
+            b.x += offset.0;
+            b.y += offset.1;
+            self.draw_line_segment(a, b, gouraud, &attributes);
+        }
+    }
+
+    fn draw_rectangle(&mut self) {
+        let command = self.command[0];
+        let textured = command & 0x04000000 != 0;
+
+        let mut vertex = Vertex::new(self.command[1], command);
+        let mut clut = 0;
+        if textured {
+            let texcoord = self.command[2];
+            vertex.u = texcoord as u8;
+            vertex.v = (texcoord >> 8) as u8;
+            clut = texcoord >> 16;
+        }
+
+        let size_word = if textured { 3 } else { 2 };
+        let (width, height) = match (command >> 27) & 3 {
+            0 => {
+                let size = self.command[size_word];
+                ((size & 0x3ff) as i32, ((size >> 16) & 0x1ff) as i32)
+            }
+            1 => (1, 1),
+            2 => (8, 8),
+            _ => (16, 16),
+        };
+
+        // rectangles are never dithered
+        let mut attributes = self.attributes(command, textured.then_some(clut), false);
+        attributes.dither = false;
+
+        // the texture can be flipped, the coordinates go backwards
+        let step_u: i32 = if self.draw_mode & 0x1000 != 0 { -1 } else { 1 };
+        let step_v: i32 = if self.draw_mode & 0x2000 != 0 { -1 } else { 1 };
+
+        let offset = self.offset();
+        let x = vertex.x + offset.0;
+        let y = vertex.y + offset.1;
+        let color = channels(vertex.color);
+
+        for row in 0..height {
+            let v = (vertex.v as i32 + row * step_v) as u8;
+            for column in 0..width {
+                let u = (vertex.u as i32 + column * step_u) as u8;
+                self.plot(x + column, y + row, color, (u, v), &attributes);
+            }
+        }
+    }
+
+    fn draw_triangle(&mut self, mut v: [Vertex; 3], attributes: &Attributes) {
+        let mut area = edge(v[0], v[1], v[2].x, v[2].y);
+        if area == 0 {
+            return;
+        }
+        // keep the vertices in the same winding so the inside is positive
+        if area < 0 {
+            v.swap(1, 2);
+            area = -area;
+        }
+
+        let min_x = v.iter().map(|v| v.x).min().unwrap();
+        let max_x = v.iter().map(|v| v.x).max().unwrap();
+        let min_y = v.iter().map(|v| v.y).min().unwrap();
+        let max_y = v.iter().map(|v| v.y).max().unwrap();
+        // polygons that are too big are not drawn at all
+        if max_x - min_x >= 1024 || max_y - min_y >= 512 {
+            return;
+        }
+
+        let (left, top, right, bottom) = self.drawing_area();
+        let colors = v.map(|v| channels(v.color));
+
+        for y in min_y.max(top)..=max_y.min(bottom) {
+            for x in min_x.max(left)..=max_x.min(right) {
+                let weights = [
+                    edge(v[1], v[2], x, y),
+                    edge(v[2], v[0], x, y),
+                    edge(v[0], v[1], x, y),
+                ];
+                // pixels on the right and bottom edges are not drawn
+                let edges = [(1, 2), (2, 0), (0, 1)];
+                let inside = edges.iter().zip(weights).all(|(&(a, b), w)| {
+                    w > 0 || (w == 0 && top_left(v[a], v[b]))
+                });
+                if !inside {
+                    continue;
+                }
+
+                let color = if attributes.gouraud {
+                    [0, 1, 2].map(|c| {
+                        interpolate(weights, area, [colors[0][c], colors[1][c], colors[2][c]])
+                    })
+                } else {
+                    colors[0]
+                };
+                let u = interpolate(weights, area, v.map(|v| v.u as u32)) as u8;
+                let tv = interpolate(weights, area, v.map(|v| v.v as u32)) as u8;
+
+                self.plot(x, y, color, (u, tv), attributes);
+            }
+        }
+    }
+
+    fn draw_line_segment(
+        &mut self,
+        a: Vertex,
+        b: Vertex,
+        gouraud: bool,
+        attributes: &Attributes,
+    ) {
+        let dx = b.x - a.x;
+        let dy = b.y - a.y;
+        if dx.abs() >= 1024 || dy.abs() >= 512 {
+            return;
+        }
+
+        let start = channels(a.color);
+        let end = if gouraud { channels(b.color) } else { start };
+        let steps = dx.abs().max(dy.abs());
+        if steps == 0 {
+            self.plot(a.x, a.y, start, (0, 0), attributes);
+            return;
+        }
+
+        // 16.16 fixed point, both ends of the line are drawn
+        let step_x = (dx << 16) / steps;
+        let step_y = (dy << 16) / steps;
+        let mut x = (a.x << 16) + 0x8000;
+        let mut y = (a.y << 16) + 0x8000;
+        for i in 0..=steps {
+            let color = [0, 1, 2].map(|c| {
+                let delta = end[c] as i32 - start[c] as i32;
+                (start[c] as i32 + delta * i / steps) as u32
+            });
+            self.plot(x >> 16, y >> 16, color, (0, 0), attributes);
+            x += step_x;
+            y += step_y;
+        }
+    }
+
+    fn attributes(&self, command: u32, clut: Option<u32>, gouraud: bool) -> Attributes {
+        let raw = command & 0x01000000 != 0;
+        let texture = clut.map(|clut| Texture {
+            page_x: (self.draw_mode & 0xf) * 64,
+            page_y: ((self.draw_mode >> 4) & 1) * 256,
+            depth: (self.draw_mode >> 7) & 3,
+            clut_x: (clut & 0x3f) * 16,
+            clut_y: (clut >> 6) & 0x1ff,
+        });
+
+        // only shaded and modulated textures are dithered
+        let dither = self.draw_mode & 0x200 != 0 && (gouraud || (texture.is_some() && !raw));
+
+        Attributes {
+            semi_transparent: command & 0x02000000 != 0,
+            gouraud,
+            texture,
+            raw,
+            dither,
+        }
+    }
+
+    // last stage of every primitive, color has 8 bits per channel
+    fn plot(&mut self, x: i32, y: i32, color: [u32; 3], uv: (u8, u8), attributes: &Attributes) {
+        let (left, top, right, bottom) = self.drawing_area();
+        if x < left || x > right || y < top || y > bottom {
+            return;
+        }
+
+        let index = y as usize * VRAM_WIDTH + x as usize;
+        let background = self.vram[index];
+        if self.mask_settings & 2 != 0 && background & 0x8000 != 0 {
+            return;
+        }
+
+        let (color, semi_transparent, mask) = match attributes.texture {
+            Some(texture) => {
+                let texel = self.texel(&texture, uv.0, uv.1);
+                // black is transparent
+                if texel == 0 {
+                    return;
+                }
+
+                let texel_color = channels15(texel);
+                let color = if attributes.raw {
+                    texel_color.map(|t| t << 3)
+                } else {
+                    // 80h on the vertex color leaves the texture as it is
+                    [0, 1, 2].map(|c| (texel_color[c] * color[c]) >> 4)
+                };
+                // only the texels with bit 15 set are semi-transparent
+                let semi_transparent = attributes.semi_transparent && texel & 0x8000 != 0;
+                (color, semi_transparent, texel & 0x8000)
+            }
+            None => (color, attributes.semi_transparent, 0),
+        };
+
+        let color = if attributes.dither {
+            let offset = DITHER[(y & 3) as usize][(x & 3) as usize];
+            color.map(|c| (c as i32 + offset).clamp(0, 0xff) as u32)
+        } else {
+            color
+        };
+        let mut color = color.map(|c| (c >> 3).min(0x1f));
+
+        if semi_transparent {
+            let back = channels15(background);
+            let mode = (self.draw_mode >> 5) & 3;
+            color = [0, 1, 2].map(|c| {
+                let (b, f) = (back[c] as i32, color[c] as i32);
+                let value = match mode {
+                    0 => (b + f) >> 1,
+                    1 => b + f,
+                    2 => b - f,
+                    _ => b + (f >> 2),
+                };
+                value.clamp(0, 0x1f) as u32
+            });
+        }
+
+        let set_mask = ((self.mask_settings & 1) as u16) << 15;
+        let pixel = color[0] | (color[1] << 5) | (color[2] << 10);
+        self.vram[index] = pixel as u16 | mask | set_mask;
+    }
+
+    fn texel(&self, texture: &Texture, u: u8, v: u8) -> u16 {
+        // the window repeats part of the page
+        let mask_x = (self.texture_window & 0x1f) * 8;
+        let mask_y = ((self.texture_window >> 5) & 0x1f) * 8;
+        let offset_x = ((self.texture_window >> 10) & 0x1f) * 8;
+        let offset_y = ((self.texture_window >> 15) & 0x1f) * 8;
+        let u = (u as u32 & !mask_x) | (offset_x & mask_x);
+        let v = (v as u32 & !mask_y) | (offset_y & mask_y);
+
+        let y = (texture.page_y + v) & 0x1ff;
+        let vram = |x: u32, y: u32| self.vram[y as usize * VRAM_WIDTH + (x & 0x3ff) as usize];
+
+        match texture.depth {
+            0 => {
+                let word = vram(texture.page_x + u / 4, y);
+                let index = (word >> ((u & 3) * 4)) as u32 & 0xf;
+                vram(texture.clut_x + index, texture.clut_y)
+            }
+            1 => {
+                let word = vram(texture.page_x + u / 2, y);
+                let index = (word >> ((u & 1) * 8)) as u32 & 0xff;
+                vram(texture.clut_x + index, texture.clut_y)
+            }
+            _ => vram(texture.page_x + u, y),
+        }
+    }
+
+    // transfers to vram also respect the mask settings
+    fn write_masked(&mut self, x: u32, y: u32, pixel: u16) {
+        let index = y as usize * VRAM_WIDTH + x as usize;
+        if self.mask_settings & 2 != 0 && self.vram[index] & 0x8000 != 0 {
+            return;
+        }
+
+        let set_mask = ((self.mask_settings & 1) as u16) << 15;
+        self.vram[index] = pixel | set_mask;
+    }
+
+    fn pal(&self) -> bool {
+        self.display_mode & 0x08 != 0
+    }
+
+    fn interlaced(&self) -> bool {
+        self.display_mode & 0x24 == 0x24
+    }
+
+    fn vblank_start(&self) -> u32 {
+        (self.vertical_range >> 10) & 0x3ff
+    }
+
+    // outside the vertical range the line is always reported as even
+    fn odd_line(&self) -> bool {
+        let first = self.vertical_range & 0x3ff;
+        if self.scanline < first || self.scanline >= self.vblank_start() {
+            false
+        } else if self.interlaced() {
+            self.odd_field
+        } else {
+            self.scanline & 1 != 0
+        }
+    }
+
+    // gpu cycles per dot
+    fn dot_divider(&self) -> u32 {
+        if self.display_mode & 0x40 != 0 {
+            7
+        } else {
+            [10, 8, 5, 4][(self.display_mode & 3) as usize]
+        }
+    }
+
+    // the horizontal range is in gpu cycles, each dot takes a few of them
+    fn display_size(&self) -> (u32, u32) {
+        let nominal = if self.display_mode & 0x40 != 0 {
+            368
+        } else {
+            [256, 320, 512, 640][(self.display_mode & 3) as usize]
+        };
+        let divider = self.dot_divider();
+        let x1 = self.horizontal_range & 0xfff;
+        let x2 = (self.horizontal_range >> 12) & 0xfff;
+        let width = match (x2.saturating_sub(x1) / divider + 2) & !3 {
+            0 => nominal,
+            width => width.min(VRAM_WIDTH as u32),
+        };
+
+        let y1 = self.vertical_range & 0x3ff;
+        let y2 = self.vertical_range >> 10;
+        let lines = match y2.saturating_sub(y1) {
+            0 => 240,
+            lines => lines,
+        };
+        let height = if self.interlaced() { lines * 2 } else { lines };
+
+        (width, height.min(VRAM_HEIGHT as u32))
+    }
+
+    fn drawing_area(&self) -> (i32, i32, i32, i32) {
+        let top_left = self.drawing_area_top_left;
+        let bottom_right = self.drawing_area_bottom_right;
+        (
+            (top_left & 0x3ff) as i32,
+            ((top_left >> 10) & 0x1ff) as i32,
+            (bottom_right & 0x3ff) as i32,
+            ((bottom_right >> 10) & 0x1ff) as i32,
+        )
+    }
+
+    fn offset(&self) -> (i32, i32) {
+        // 11 bit signed
+        let x = ((self.drawing_offset as i32) << 21) >> 21;
+        let y = ((self.drawing_offset as i32) << 10) >> 21;
+        (x, y)
+    }
+}
+
+// number of words of a GP0 command, including the command itself
+fn command_length(opcode: u32) -> usize {
+    match opcode {
+        0x02 => 3,
+        0x20..=0x3f => {
+            let vertices = if opcode & 0x08 != 0 { 4 } else { 3 };
+            let textured = (opcode & 0x04 != 0) as usize;
+            let gouraud = (opcode & 0x10 != 0) as usize;
+            1 + vertices * (1 + textured) + (vertices - 1) * gouraud
+        }
+        0x40..=0x5f => {
+            let gouraud = (opcode & 0x10 != 0) as usize;
+            3 + gouraud
+        }
+        0x60..=0x7f => {
+            let textured = (opcode & 0x04 != 0) as usize;
+            let variable = (opcode & 0x18 == 0) as usize;
+            2 + textured + variable
+        }
+        0x80..=0x9f => 4,
+        0xa0..=0xdf => 3,
+        _ => 1,
+    }
+}
+
+fn channels(color: u32) -> [u32; 3] {
+    [color & 0xff, (color >> 8) & 0xff, (color >> 16) & 0xff]
+}
+
+fn channels15(pixel: u16) -> [u32; 3] {
+    let pixel = pixel as u32;
+    [pixel & 0x1f, (pixel >> 5) & 0x1f, (pixel >> 10) & 0x1f]
+}
+
+// barycentric interpolation of a vertex attribute, rounded to nearest
+fn interpolate(weights: [i32; 3], area: i32, values: [u32; 3]) -> u32 {
+    let sum: i64 = weights.iter().zip(values).map(|(&w, v)| w as i64 * v as i64).sum();
+    ((sum + area as i64 / 2) / area as i64) as u32
+}
+
+fn rgb15(color: u32) -> u16 {
+    let r = (color >> 3) & 0x1f;
+    let g = (color >> 11) & 0x1f;
+    let b = (color >> 19) & 0x1f;
+    (r | (g << 5) | (b << 10)) as u16
+}
+
+// positive when (x, y) is on the inside of the edge a-b
+fn edge(a: Vertex, b: Vertex, x: i32, y: i32) -> i32 {
+    (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)
+}
+
+fn top_left(a: Vertex, b: Vertex) -> bool {
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+    dy < 0 || (dy == 0 && dx > 0)
+}
+
+#[cfg(test)]
+mod commands {
+    use super::*;
+
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> u16 {
+        gpu.vram[y * VRAM_WIDTH + x]
+    }
+
+    fn full_drawing_area(gpu: &mut Gpu) {
+        gpu.gp0(0xe3000000);
+        gpu.gp0(0xe4000000 | (511 << 10) | 1023);
+    }
+
+    #[test]
+    fn fill_rectangle() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0x020000ff);
+        gpu.gp0(0x00020013);
+        gpu.gp0(0x00020001);
+
+        // x is rounded down and the width up to 16 pixels
+        assert_eq!(pixel(&gpu, 0x10, 2), 0x001f);
+        assert_eq!(pixel(&gpu, 0x1f, 3), 0x001f);
+        assert_eq!(pixel(&gpu, 0x20, 2), 0);
+        assert_eq!(pixel(&gpu, 0x10, 4), 0);
+    }
+
+    #[test]
+    fn cpu_to_vram() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0xa0000000);
+        gpu.gp0(0x00050003);
+        gpu.gp0(0x00020003);
+        gpu.gp0(0x22221111);
+        gpu.gp0(0x44443333);
+        gpu.gp0(0x66665555);
+        assert_eq!(gpu.status() & 0x04000000, 0x04000000);
+
+        assert_eq!(pixel(&gpu, 3, 5), 0x1111);
+        assert_eq!(pixel(&gpu, 5, 5), 0x3333);
+        assert_eq!(pixel(&gpu, 3, 6), 0x4444);
+        assert_eq!(pixel(&gpu, 5, 6), 0x6666);
+
+        // back to the cpu through GPUREAD
+        gpu.gp0(0xc0000000);
+        gpu.gp0(0x00050004);
+        gpu.gp0(0x00020002);
+        assert_eq!(gpu.status() & 0x08000000, 0x08000000);
+        assert_eq!(gpu.read(), 0x33332222);
+        assert_eq!(gpu.read(), 0x66665555);
+        assert_eq!(gpu.status() & 0x08000000, 0);
+    }
+
+    #[test]
+    fn copy_rectangle() {
+        let mut gpu = Gpu::new();
+        gpu.vram[0] = 0x1234;
+        gpu.vram[1] = 0x5678;
+        gpu.gp0(0x80000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x01000200);
+        gpu.gp0(0x00010002);
+
+        assert_eq!(pixel(&gpu, 0x200, 0x100), 0x1234);
+        assert_eq!(pixel(&gpu, 0x201, 0x100), 0x5678);
+    }
+
+    #[test]
+    fn triangle() {
+        let mut gpu = Gpu::new();
+        full_drawing_area(&mut gpu);
+        gpu.gp0(0x200000ff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x0000000a);
+        gpu.gp0(0x000a0000);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x001f);
+        assert_eq!(pixel(&gpu, 9, 0), 0x001f);
+        assert_eq!(pixel(&gpu, 0, 9), 0x001f);
+        assert_eq!(pixel(&gpu, 4, 4), 0x001f);
+        // the right and bottom edges are left out
+        assert_eq!(pixel(&gpu, 10, 0), 0);
+        assert_eq!(pixel(&gpu, 0, 10), 0);
+        assert_eq!(pixel(&gpu, 5, 5), 0);
+    }
+
+    #[test]
+    fn quad_with_offset() {
+        let mut gpu = Gpu::new();
+        full_drawing_area(&mut gpu);
+        gpu.gp0(0xe5000000 | (20 << 11) | 10);
+        gpu.gp0(0x28ff0000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00000004);
+        gpu.gp0(0x00040000);
+        gpu.gp0(0x00040004);
+
+        assert_eq!(pixel(&gpu, 10, 20), 0x7c00);
+        assert_eq!(pixel(&gpu, 13, 23), 0x7c00);
+        assert_eq!(pixel(&gpu, 14, 23), 0);
+        assert_eq!(pixel(&gpu, 9, 20), 0);
+    }
+
+    #[test]
+    fn rectangle_clipped() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0xe3000000 | (2 << 10) | 2);
+        gpu.gp0(0xe4000000 | (5 << 10) | 5);
+        gpu.gp0(0x7000ff00);
+        gpu.gp0(0x00000000);
+
+        assert_eq!(pixel(&gpu, 2, 2), 0x03e0);
+        assert_eq!(pixel(&gpu, 5, 5), 0x03e0);
+        assert_eq!(pixel(&gpu, 1, 1), 0);
+        assert_eq!(pixel(&gpu, 6, 6), 0);
+    }
+
+    #[test]
+    fn line() {
+        let mut gpu = Gpu::new();
+        full_drawing_area(&mut gpu);
+        gpu.gp0(0x40ffffff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00020004);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x7fff);
+        assert_eq!(pixel(&gpu, 2, 1), 0x7fff);
+        assert_eq!(pixel(&gpu, 4, 2), 0x7fff);
+    }
+
+    #[test]
+    fn polyline() {
+        let mut gpu = Gpu::new();
+        full_drawing_area(&mut gpu);
+        gpu.gp0(0x48ffffff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00000004);
+        gpu.gp0(0x00040004);
+        assert_eq!(gpu.status() & 0x04000000, 0);
+        gpu.gp0(0x55555555);
+
+        assert_eq!(gpu.status() & 0x04000000, 0x04000000);
+        assert_eq!(pixel(&gpu, 2, 0), 0x7fff);
+        assert_eq!(pixel(&gpu, 4, 2), 0x7fff);
+    }
+
+    #[test]
+    fn status() {
+        let mut gpu = Gpu::new();
+        assert_eq!(gpu.status(), 0x14802000);
+
+        gpu.gp0(0xe100020f);
+        gpu.gp0(0xe6000003);
+        gpu.gp1(0x03000000);
+        gpu.gp1(0x04000002);
+        gpu.gp1(0x08000029);
+        assert_eq!(gpu.status(), 0x56523a0f);
+    }
+
+    #[test]
+    fn gpu_info() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0xe5001234);
+        gpu.gp1(0x10000005);
+        assert_eq!(gpu.read(), 0x00001234);
+        gpu.gp1(0x10000007);
+        assert_eq!(gpu.read(), 0x00000002);
+    }
+
+    #[test]
+    fn irq() {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0x1f000000);
+        assert!(gpu.irq());
+        assert_eq!(gpu.status() & 0x01000000, 0x01000000);
+
+        gpu.gp1(0x02000000);
+        assert!(!gpu.irq());
+    }
+}
+
+#[cfg(test)]
+mod shading {
+    use super::*;
+
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> u16 {
+        gpu.vram[y * VRAM_WIDTH + x]
+    }
+
+    fn setup() -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.gp0(0xe3000000);
+        gpu.gp0(0xe4000000 | (511 << 10) | 1023);
+        gpu
+    }
+
+    fn upload(gpu: &mut Gpu, x: u32, y: u32, width: u32, pixels: &[u16]) {
+        let height = pixels.len() as u32 / width;
+        gpu.gp0(0xa0000000);
+        gpu.gp0((y << 16) | x);
+        gpu.gp0((height << 16) | width);
+        for pair in pixels.chunks(2) {
+            let high = pair.get(1).copied().unwrap_or(0) as u32;
+            gpu.gp0(pair[0] as u32 | (high << 16));
+        }
+    }
+
+    // compares a rectangle of vram at the origin against a dump in tests/golden,
+    // rows of hex halfwords worked out from the drawing rules and never
+    // written back from the renderer
+    fn golden(gpu: &Gpu, name: &str, width: usize, height: usize) {
+        let path = format!("{}/tests/golden/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let expected = std::fs::read_to_string(&path).unwrap();
+        let rows: Vec<&str> = expected.lines().collect();
+        assert_eq!(rows.len(), height, "{}", path);
+
+        for (y, row) in rows.iter().enumerate() {
+            let values: Vec<u16> = row
+                .split_whitespace()
+                .map(|value| u16::from_str_radix(value, 16).unwrap())
+                .collect();
+            assert_eq!(values.len(), width, "{} row {}", path, y);
+            for (x, &value) in values.iter().enumerate() {
+                assert_eq!(pixel(gpu, x, y), value, "{} at ({}, {})", path, x, y);
+            }
+        }
+    }
+
+    // 4 bit texture on page x 64, clut of four colors at (0, 256)
+    fn texture_4bit(gpu: &mut Gpu) {
+        upload(gpu, 0, 256, 4, &[0x0000, 0x001f, 0x03e0, 0xfc00]);
+        upload(gpu, 64, 0, 2, &[0x3210, 0x0123, 0x0101, 0x2222]);
+        gpu.gp0(0xe1000001);
+    }
+
+    #[test]
+    fn gouraud_triangle() {
+        let mut gpu = setup();
+        gpu.gp0(0x300000ff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x0000ff00);
+        gpu.gp0(0x00000010);
+        gpu.gp0(0x00ff0000);
+        gpu.gp0(0x00100000);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x001f);
+        // halfway between red and green
+        assert_eq!(pixel(&gpu, 8, 0), 0x0210);
+        assert_eq!(pixel(&gpu, 0, 15), 0x7402);
+    }
+
+    #[test]
+    fn raw_texture_4bit() {
+        let mut gpu = setup();
+        texture_4bit(&mut gpu);
+        gpu.vram[11 * VRAM_WIDTH + 11] = 0x7777;
+        // raw textured rectangle of 4x2 at (10, 10)
+        gpu.gp0(0x65000000);
+        gpu.gp0(0x000a000a);
+        gpu.gp0(0x40000000);
+        gpu.gp0(0x00020004);
+
+        assert_eq!(pixel(&gpu, 10, 10), 0x0000);
+        assert_eq!(pixel(&gpu, 11, 10), 0x001f);
+        assert_eq!(pixel(&gpu, 12, 10), 0x03e0);
+        assert_eq!(pixel(&gpu, 13, 10), 0xfc00);
+        assert_eq!(pixel(&gpu, 10, 11), 0x001f);
+        // index 0 is black and transparent
+        assert_eq!(pixel(&gpu, 11, 11), 0x7777);
+    }
+
+    #[test]
+    fn modulated_texture() {
+        let mut gpu = setup();
+        upload(&mut gpu, 64, 0, 2, &[0x7fff, 0x4210]);
+        gpu.gp0(0xe1000101);
+
+        // 80h leaves the texture unchanged
+        gpu.gp0(0x64808080);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+        assert_eq!(pixel(&gpu, 0, 0), 0x7fff);
+        assert_eq!(pixel(&gpu, 1, 0), 0x4210);
+
+        gpu.gp0(0x64404040);
+        gpu.gp0(0x00010000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+        assert_eq!(pixel(&gpu, 0, 1), 0x3def);
+        assert_eq!(pixel(&gpu, 1, 1), 0x2108);
+
+        // and ffh doubles it, saturating
+        gpu.gp0(0x64ffffff);
+        gpu.gp0(0x00020000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+        assert_eq!(pixel(&gpu, 0, 2), 0x7fff);
+        assert_eq!(pixel(&gpu, 1, 2), 0x7fff);
+    }
+
+    #[test]
+    fn texture_8bit() {
+        let mut gpu = setup();
+        let mut clut = vec![0; 256];
+        clut[0x12] = 0x1234;
+        clut[0xab] = 0x4321;
+        upload(&mut gpu, 0, 256, 256, &clut);
+        upload(&mut gpu, 64, 0, 2, &[0xab12, 0x0000]);
+        gpu.gp0(0xe1000081);
+
+        gpu.gp0(0x65000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x40000000);
+        gpu.gp0(0x00010002);
+        assert_eq!(pixel(&gpu, 0, 0), 0x1234);
+        assert_eq!(pixel(&gpu, 1, 0), 0x4321);
+    }
+
+    #[test]
+    fn texture_window() {
+        let mut gpu = setup();
+        texture_4bit(&mut gpu);
+        // u from 8 wraps back to the first 8 texels
+        gpu.gp0(0xe2000001);
+        gpu.gp0(0x65000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x40000008);
+        gpu.gp0(0x00010004);
+
+        assert_eq!(pixel(&gpu, 1, 0), 0x001f);
+        assert_eq!(pixel(&gpu, 2, 0), 0x03e0);
+        assert_eq!(pixel(&gpu, 3, 0), 0xfc00);
+
+        // and without the window it reads outside the texture
+        gpu.gp0(0xe2000000);
+        gpu.gp0(0x65000000);
+        gpu.gp0(0x00010000);
+        gpu.gp0(0x40000008);
+        gpu.gp0(0x00010004);
+        assert_eq!(pixel(&gpu, 1, 1), 0x0000);
+    }
+
+    #[test]
+    fn rectangle_flip() {
+        let mut gpu = setup();
+        texture_4bit(&mut gpu);
+        gpu.gp0(0xe1001001);
+        gpu.gp0(0x65000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x40000003);
+        gpu.gp0(0x00010004);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0xfc00);
+        assert_eq!(pixel(&gpu, 1, 0), 0x03e0);
+        assert_eq!(pixel(&gpu, 2, 0), 0x001f);
+        assert_eq!(pixel(&gpu, 3, 0), 0x0000);
+    }
+
+    #[test]
+    fn semi_transparency() {
+        let mut gpu = setup();
+        for (mode, expected) in [(0, 12), (1, 24), (2, 8), (3, 18)] {
+            gpu.vram[0] = 16;
+            gpu.gp0(0xe1000000 | (mode << 5));
+            gpu.gp0(0x6a000040);
+            gpu.gp0(0x00000000);
+
+            assert_eq!(pixel(&gpu, 0, 0), expected, "mode {}", mode);
+        }
+
+        // opaque primitives replace the background
+        gpu.vram[0] = 16;
+        gpu.gp0(0x68000040);
+        gpu.gp0(0x00000000);
+        assert_eq!(pixel(&gpu, 0, 0), 8);
+    }
+
+    #[test]
+    fn semi_transparent_texels() {
+        let mut gpu = setup();
+        upload(&mut gpu, 64, 0, 2, &[0x8008, 0x0008]);
+        gpu.gp0(0xe1000121);
+        gpu.vram[VRAM_WIDTH * 4] = 16;
+        gpu.vram[VRAM_WIDTH * 4 + 1] = 16;
+
+        // only the texel with bit 15 set is blended
+        gpu.gp0(0x67000000);
+        gpu.gp0(0x00040000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+        assert_eq!(pixel(&gpu, 0, 4), 0x8018);
+        assert_eq!(pixel(&gpu, 1, 4), 0x0008);
+    }
+
+    #[test]
+    fn mask_bit() {
+        let mut gpu = setup();
+        gpu.vram[1] = 0x8000;
+        gpu.gp0(0xe6000003);
+        gpu.gp0(0x600000ff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x801f);
+        assert_eq!(pixel(&gpu, 1, 0), 0x8000);
+
+        // transfers check the mask as well
+        upload(&mut gpu, 0, 0, 2, &[0x1111, 0x2222]);
+        assert_eq!(pixel(&gpu, 0, 0), 0x801f);
+
+        gpu.gp0(0xe6000000);
+        upload(&mut gpu, 0, 0, 2, &[0x1111, 0x2222]);
+        assert_eq!(pixel(&gpu, 0, 0), 0x1111);
+    }
+
+    #[test]
+    fn dithering() {
+        let mut gpu = setup();
+        gpu.gp0(0xe1000200);
+        gpu.gp0(0x38808080);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00808080);
+        gpu.gp0(0x00000010);
+        gpu.gp0(0x00808080);
+        gpu.gp0(0x00100000);
+        gpu.gp0(0x00808080);
+        gpu.gp0(0x00100010);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x3def);
+        assert_eq!(pixel(&gpu, 3, 0), 0x4210);
+        assert_eq!(pixel(&gpu, 1, 1), 0x3def);
+        assert_eq!(pixel(&gpu, 2, 1), 0x4210);
+
+        // flat polygons are not dithered
+        gpu.gp0(0x28808080);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00000010);
+        gpu.gp0(0x00100000);
+        gpu.gp0(0x00100010);
+        assert_eq!(pixel(&gpu, 0, 0), 0x4210);
+    }
+
+    #[test]
+    fn shaded_line() {
+        let mut gpu = setup();
+        gpu.gp0(0x50000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00ffffff);
+        gpu.gp0(0x003f003f);
+
+        assert_eq!(pixel(&gpu, 0, 0), 0x0000);
+        // a third of the way is 85, 10 in five bits
+        assert_eq!(pixel(&gpu, 21, 21), 0x294a);
+        assert_eq!(pixel(&gpu, 63, 63), 0x7fff);
+        assert_eq!(pixel(&gpu, 21, 20), 0x0000);
+    }
+
+    #[test]
+    fn textured_quad() {
+        let mut gpu = setup();
+        texture_4bit(&mut gpu);
+        // 4x2 quad with a texel per pixel, u from 8 wraps back by the window
+        gpu.gp0(0xe2000001);
+        gpu.gp0(0x2c808080);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x40000008);
+        gpu.gp0(0x00000004);
+        gpu.gp0(0x0001000c);
+        gpu.gp0(0x00020000);
+        gpu.gp0(0x00000208);
+        gpu.gp0(0x00020004);
+        gpu.gp0(0x0000020c);
+
+        // indices 0 1 2 3 on the first row and 1 0 1 0 on the second
+        assert_eq!(pixel(&gpu, 1, 0), 0x001f);
+        assert_eq!(pixel(&gpu, 2, 0), 0x03e0);
+        assert_eq!(pixel(&gpu, 3, 0), 0xfc00);
+        assert_eq!(pixel(&gpu, 0, 1), 0x001f);
+        assert_eq!(pixel(&gpu, 2, 1), 0x001f);
+        // index 0 is transparent, and the right edge is not drawn
+        assert_eq!(pixel(&gpu, 0, 0), 0x0000);
+        assert_eq!(pixel(&gpu, 1, 1), 0x0000);
+        assert_eq!(pixel(&gpu, 4, 0), 0x0000);
+        // and nothing past the quad, the bottom edge is not drawn either
+        golden(&gpu, "textured", 8, 3);
+    }
+
+    #[test]
+    fn blended_triangles() {
+        let mut gpu = setup();
+        // 8 on every channel behind, and 16 on the triangles
+        gpu.gp0(0x02404040);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00100040);
+
+        for (mode, expected) in [(0, 0x318c), (1, 0x6318), (2, 0x0000), (3, 0x318c)] {
+            let x = mode * 16;
+            gpu.gp0(0xe1000000 | (mode << 5));
+            gpu.gp0(0x32808080);
+            gpu.gp0(x);
+            gpu.gp0(0x00808080);
+            gpu.gp0(x + 16);
+            gpu.gp0(0x00808080);
+            gpu.gp0(0x00100000 | x);
+
+            let x = x as usize;
+            assert_eq!(pixel(&gpu, x + 1, 1), expected, "mode {}", mode);
+            assert_eq!(pixel(&gpu, x + 15, 15), 0x2108, "mode {}", mode);
+        }
+
+        // the four triangles cover the pixels left of the diagonal, x + y < 16
+        golden(&gpu, "blending", 64, 16);
+    }
+
+    #[test]
+    fn polygon_texpage() {
+        let mut gpu = setup();
+        texture_4bit(&mut gpu);
+        gpu.gp0(0xe1000000);
+
+        // textured triangle taking page 1 and semi-transparency mode 1
+        gpu.gp0(0x25000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x40000000);
+        gpu.gp0(0x00000004);
+        gpu.gp0(0x00210004);
+        gpu.gp0(0x00040000);
+        gpu.gp0(0x00000400);
+
+        assert_eq!(gpu.status() & 0x1ff, 0x021);
+        assert_eq!(pixel(&gpu, 1, 0), 0x001f);
+    }
+}
+
+#[cfg(test)]
+mod display {
+    use super::*;
+
+    #[test]
+    fn frame_15bit() {
+        let mut gpu = Gpu::new();
+        gpu.gp1(0x03000000);
+        gpu.gp1(0x05000000 | (8 << 10) | 16);
+        // 320 wide, 2560 cycles and 10 lines
+        gpu.gp1(0x06000000 | ((0x260 + 2560) << 12) | 0x260);
+        gpu.gp1(0x07000000 | (26 << 10) | 16);
+        gpu.gp1(0x08000001);
+        gpu.vram[8 * VRAM_WIDTH + 16] = 0x7c1f;
+        gpu.vram[9 * VRAM_WIDTH + 17] = 0x03e0;
+
+        let frame = gpu.frame();
+        assert_eq!((frame.width, frame.height), (320, 10));
+        assert_eq!(frame.pixels[0..3], [0xff, 0x00, 0xff]);
+        let second_line = (320 + 1) * 3;
+        assert_eq!(frame.pixels[second_line..second_line + 3], [0x00, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn frame_24bit() {
+        let mut gpu = Gpu::new();
+        gpu.gp1(0x03000000);
+        gpu.gp1(0x08000010);
+        gpu.vram[0] = 0x2211;
+        gpu.vram[1] = 0x4433;
+        gpu.vram[2] = 0x6655;
+
+        let frame = gpu.frame();
+        assert_eq!((frame.width, frame.height), (256, 240));
+        assert_eq!(frame.pixels[0..6], [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    }
+
+    #[test]
+    fn display_disabled() {
+        let mut gpu = Gpu::new();
+        gpu.vram[0] = 0x7fff;
+
+        assert!(gpu.frame().pixels.iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn vblank() {
+        let mut gpu = Gpu::new();
+        let frame_cycles = LINE_CYCLES_NTSC * LINES_NTSC / 11;
+
+        let mut vblanks = 0;
+        let mut hblanks = [0, 0];
+        for _ in 0..frame_cycles * 3 / 100 {
+            let video = gpu.step(100);
+            vblanks += (video.vblank == Some(true)) as u32;
+            if let Some(active) = video.hblank {
+                hblanks[active as usize] += 1;
+            }
+        }
+        assert_eq!(vblanks, 3);
+        // the last line stopped inside its hblank, the first one started in one
+        assert_eq!(hblanks, [LINES_NTSC * 3, LINES_NTSC * 3]);
+
+        // odd lines only show inside the vertical range
+        assert_eq!(gpu.scanline, 262);
+        assert_eq!(gpu.status() >> 31, 0);
+        gpu.scanline = 17;
+        assert_eq!(gpu.status() >> 31, 1);
+
+        let line = gpu.cycles_to_next_line();
+        gpu.step(line - 1);
+        assert_eq!(gpu.scanline, 17);
+        gpu.step(1);
+        assert_eq!(gpu.scanline, 18);
+    }
+
+    #[test]
+    fn hblank() {
+        let mut gpu = Gpu::new();
+        // from gpu cycle 0x200 to 0xa00, the cpu cycles round up
+        gpu.gp1(0x06000000 | 0x200 | (0xa00 << 12));
+        assert!(gpu.in_hblank());
+        assert_eq!(gpu.cycles_to_hblank_edge(), 326);
+
+        assert_eq!(gpu.step(325).hblank, None);
+        assert_eq!(gpu.step(1).hblank, Some(false));
+        assert!(!gpu.in_hblank());
+        // 0x800 gpu cycles later, less the part of a cycle already gone
+        assert_eq!(gpu.cycles_to_hblank_edge(), 1304);
+        assert_eq!(gpu.step(1304).hblank, Some(true));
+        // through the end of the line to the next start of the range
+        assert_eq!(gpu.cycles_to_hblank_edge(), 868);
+
+        // a range that ends before it starts falls back to the usual one
+        gpu.gp1(0x06000000 | 0x800 | (0x100 << 12));
+        assert_eq!(gpu.hblank_range(), (HBLANK_END * 7, HBLANK_START * 7));
+    }
+}
@@ -0,0 +1,1119 @@
+// Geometry Transformation Engine, coprocessor 2
+
+// matrices and vectors used by the commands
+const ROTATION: usize = 0;
+const LIGHT: usize = 1;
+const COLOR: usize = 2;
+// MVMVA can select a matrix that does not exist and gets garbage
+const GARBAGE: usize = 3;
+
+const TRANSLATION: usize = 0;
+const BACKGROUND_COLOR: usize = 1;
+const FAR_COLOR: usize = 2;
+const ZERO: usize = 3;
+
+// bits of FLAG that also set the error bit 31
+const FLAG_ERROR_MASK: u32 = 0x7f87e000;
+
+// Reciprocal table used by the division of the perspective transformation
+const UNR_TABLE: [u8; 0x101] = unr_table();
+
+const fn unr_table() -> [u8; 0x101] {
+    let mut table = [0; 0x101];
+    let mut i = 0;
+    while i < 0x101 {
+        let value = (0x40000 / (i as i32 + 0x100) + 1) / 2 - 0x101;
+        table[i] = if value > 0 { value as u8 } else { 0 };
+        i += 1;
+    }
+    table
+}
+
+#[derive(Clone, Copy)]
+struct Config {
+    // results are shifted right by 12 when the sf bit is set
+    shift: u8,
+    // IR1-3 saturate to 0 instead of -8000h
+    lm: bool,
+}
+
+impl Config {
+    fn from_command(command: u32) -> Self {
+        Config {
+            shift: if command & 0x00080000 != 0 { 12 } else { 0 },
+            lm: command & 0x00000400 != 0,
+        }
+    }
+}
+
+pub struct Gte {
+    // control registers
+    matrices: [[[i16; 3]; 3]; 3],
+    control_vectors: [[i32; 3]; 4],
+    ofx: i32,
+    ofy: i32,
+    h: u16,
+    dqa: i16,
+    dqb: i32,
+    zsf3: i16,
+    zsf4: i16,
+    flag: u32,
+
+    // data registers
+    v: [[i16; 3]; 3],
+    rgbc: [u8; 4],
+    otz: u16,
+    ir: [i16; 4],
+    xy_fifo: [(i16, i16); 3],
+    z_fifo: [u16; 4],
+    rgb_fifo: [[u8; 4]; 3],
+    res1: u32,
+    mac: [i32; 4],
+    lzcs: u32,
+    lzcr: u8,
+}
+
+impl Gte {
+    pub fn new() -> Self {
+        Gte {
+            matrices: [[[0; 3]; 3]; 3],
+            control_vectors: [[0; 3]; 4],
+            ofx: 0,
+            ofy: 0,
+            h: 0,
+            dqa: 0,
+            dqb: 0,
+            zsf3: 0,
+            zsf4: 0,
+            flag: 0,
+            v: [[0; 3]; 3],
+            rgbc: [0; 4],
+            otz: 0,
+            ir: [0; 4],
+            xy_fifo: [(0, 0); 3],
+            z_fifo: [0; 4],
+            rgb_fifo: [[0; 4]; 3],
+            res1: 0,
+            mac: [0; 4],
+            lzcs: 0,
+            lzcr: 32,
+        }
+    }
+
+    pub fn data(&self, reg: u8) -> u32 {
+        match reg {
+            0 | 2 | 4 => {
+                let v = self.v[reg as usize / 2];
+                pack(v[0], v[1])
+            }
+            // the 16 bit registers are sign extended
+            1 | 3 | 5 => self.v[reg as usize / 2][2] as i32 as u32,
+            6 => u32::from_le_bytes(self.rgbc),
+            7 => self.otz as u32,
+            8..=11 => self.ir[reg as usize - 8] as i32 as u32,
+            12..=14 => {
+                let (x, y) = self.xy_fifo[reg as usize - 12];
+                pack(x, y)
+            }
+            // SXYP mirrors SXY2 on reads
+            15 => {
+                let (x, y) = self.xy_fifo[2];
+                pack(x, y)
+            }
+            16..=19 => self.z_fifo[reg as usize - 16] as u32,
+            20..=22 => u32::from_le_bytes(self.rgb_fifo[reg as usize - 20]),
+            23 => self.res1,
+            24..=27 => self.mac[reg as usize - 24] as u32,
+            // IRGB and ORGB read the IR registers as a 15 bit color
+            28 | 29 => {
+                let r = (self.ir[1] >> 7).clamp(0, 0x1f) as u32;
+                let g = (self.ir[2] >> 7).clamp(0, 0x1f) as u32;
+                let b = (self.ir[3] >> 7).clamp(0, 0x1f) as u32;
+                r | (g << 5) | (b << 10)
+            }
+            30 => self.lzcs,
+            31 => self.lzcr as u32,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_data(&mut self, reg: u8, value: u32) {
+        match reg {
+            0 | 2 | 4 => {
+                let v = &mut self.v[reg as usize / 2];
+                v[0] = value as i16;
+                v[1] = (value >> 16) as i16;
+            }
+            1 | 3 | 5 => self.v[reg as usize / 2][2] = value as i16,
+            6 => self.rgbc = value.to_le_bytes(),
+            7 => self.otz = value as u16,
+            8..=11 => self.ir[reg as usize - 8] = value as i16,
+            12..=14 => self.xy_fifo[reg as usize - 12] = (value as i16, (value >> 16) as i16),
+            // writing SXYP pushes onto the screen coordinates fifo
+            15 => self.push_xy(value as i16, (value >> 16) as i16),
+            16..=19 => self.z_fifo[reg as usize - 16] = value as u16,
+            20..=22 => self.rgb_fifo[reg as usize - 20] = value.to_le_bytes(),
+            23 => self.res1 = value,
+            24..=27 => self.mac[reg as usize - 24] = value as i32,
+            28 => {
+                self.ir[1] = ((value & 0x1f) << 7) as i16;
+                self.ir[2] = (((value >> 5) & 0x1f) << 7) as i16;
+                self.ir[3] = (((value >> 10) & 0x1f) << 7) as i16;
+            }
+            // ORGB is read-only
+            29 => (),
+            30 => {
+                self.lzcs = value;
+                // counts leading ones for negative values
+                self.lzcr = if (value as i32) < 0 {
+                    value.leading_ones() as u8
+                } else {
+                    value.leading_zeros() as u8
+                };
+            }
+            // LZCR is read-only
+            31 => (),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn control(&self, reg: u8) -> u32 {
+        match reg {
+            0..=4 => self.matrix_register(ROTATION, reg),
+            5..=7 => self.control_vectors[TRANSLATION][reg as usize - 5] as u32,
+            8..=12 => self.matrix_register(LIGHT, reg - 8),
+            13..=15 => self.control_vectors[BACKGROUND_COLOR][reg as usize - 13] as u32,
+            16..=20 => self.matrix_register(COLOR, reg - 16),
+            21..=23 => self.control_vectors[FAR_COLOR][reg as usize - 21] as u32,
+            24 => self.ofx as u32,
+            25 => self.ofy as u32,
+            // H is unsigned but the hardware sign extends it on reads
+            26 => self.h as i16 as i32 as u32,
+            27 => self.dqa as i32 as u32,
+            28 => self.dqb as u32,
+            29 => self.zsf3 as i32 as u32,
+            30 => self.zsf4 as i32 as u32,
+            31 => self.flag,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_control(&mut self, reg: u8, value: u32) {
+        match reg {
+            0..=4 => self.set_matrix_register(ROTATION, reg, value),
+            5..=7 => self.control_vectors[TRANSLATION][reg as usize - 5] = value as i32,
+            8..=12 => self.set_matrix_register(LIGHT, reg - 8, value),
+            13..=15 => self.control_vectors[BACKGROUND_COLOR][reg as usize - 13] = value as i32,
+            16..=20 => self.set_matrix_register(COLOR, reg - 16, value),
+            21..=23 => self.control_vectors[FAR_COLOR][reg as usize - 21] = value as i32,
+            24 => self.ofx = value as i32,
+            25 => self.ofy = value as i32,
+            26 => self.h = value as u16,
+            27 => self.dqa = value as i16,
+            28 => self.dqb = value as i32,
+            29 => self.zsf3 = value as i16,
+            30 => self.zsf4 = value as i16,
+            31 => {
+                self.flag = value & 0x7ffff000;
+                self.update_error_flag();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // the matrices are packed two elements per register, the last one alone
+    fn matrix_register(&self, matrix: usize, index: u8) -> u32 {
+        let m = &self.matrices[matrix];
+        match index {
+            0 => pack(m[0][0], m[0][1]),
+            1 => pack(m[0][2], m[1][0]),
+            2 => pack(m[1][1], m[1][2]),
+            3 => pack(m[2][0], m[2][1]),
+            4 => m[2][2] as i32 as u32,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_matrix_register(&mut self, matrix: usize, index: u8, value: u32) {
+        let m = &mut self.matrices[matrix];
+        let (low, high) = (value as i16, (value >> 16) as i16);
+        match index {
+            0 => (m[0][0], m[0][1]) = (low, high),
+            1 => (m[0][2], m[1][0]) = (low, high),
+            2 => (m[1][1], m[1][2]) = (low, high),
+            3 => (m[2][0], m[2][1]) = (low, high),
+            4 => m[2][2] = low,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn command(&mut self, command: u32) {
+        let config = Config::from_command(command);
+        self.flag = 0;
+
+        match command & 0x3f {
+            0x01 => self.rtps(config),
+            0x06 => self.nclip(),
+            0x0c => self.op(config),
+            0x10 => self.dpcs(config),
+            0x11 => self.intpl(config),
+            0x12 => self.mvmva(config, command),
+            0x13 => self.ncds(config),
+            0x14 => self.cdp(config),
+            0x16 => self.ncdt(config),
+            0x1b => self.nccs(config),
+            0x1c => self.cc(config),
+            0x1e => self.ncs(config),
+            0x20 => self.nct(config),
+            0x28 => self.sqr(config),
+            0x29 => self.dcpl(config),
+            0x2a => self.dpct(config),
+            0x2d => self.avsz3(),
+            0x2e => self.avsz4(),
+            0x30 => self.rtpt(config),
+            0x3d => self.gpf(config),
+            0x3e => self.gpl(config),
+            0x3f => self.ncct(config),
+            opcode => println!("Unknown gte command {:x}", opcode),
+        }
+
+        self.update_error_flag();
+    }
+
+    fn rtps(&mut self, config: Config) {
+        let div = self.rotate_translate_perspective(config, 0);
+        self.depth_cue_factor(div);
+    }
+
+    fn rtpt(&mut self, config: Config) {
+        self.rotate_translate_perspective(config, 0);
+        self.rotate_translate_perspective(config, 1);
+        let div = self.rotate_translate_perspective(config, 2);
+        self.depth_cue_factor(div);
+    }
+
+    fn nclip(&mut self) {
+        let [(x0, y0), (x1, y1), (x2, y2)] = self.xy_fifo.map(|(x, y)| (x as i64, y as i64));
+
+        let value = x0 * y1 + x1 * y2 + x2 * y0 - x0 * y2 - x1 * y0 - x2 * y1;
+        self.mac[0] = self.check_mac0(value) as i32;
+    }
+
+    // outer product of IR and the diagonal of the rotation matrix
+    fn op(&mut self, config: Config) {
+        let d = [
+            self.matrices[ROTATION][0][0] as i64,
+            self.matrices[ROTATION][1][1] as i64,
+            self.matrices[ROTATION][2][2] as i64,
+        ];
+        let ir = [self.ir[1] as i64, self.ir[2] as i64, self.ir[3] as i64];
+
+        let values = [
+            ir[2] * d[1] - ir[1] * d[2],
+            ir[0] * d[2] - ir[2] * d[0],
+            ir[1] * d[0] - ir[0] * d[1],
+        ];
+        for (i, value) in values.into_iter().enumerate() {
+            self.set_mac(i + 1, value, config.shift);
+        }
+        self.mac_to_ir(config);
+    }
+
+    fn dpcs(&mut self, config: Config) {
+        let color = [
+            (self.rgbc[0] as i64) << 16,
+            (self.rgbc[1] as i64) << 16,
+            (self.rgbc[2] as i64) << 16,
+        ];
+        self.interpolate_far_color(config, color);
+    }
+
+    fn dpct(&mut self, config: Config) {
+        // every step takes the oldest entry, the fifo moves after each push
+        for _ in 0..3 {
+            let color = [
+                (self.rgb_fifo[0][0] as i64) << 16,
+                (self.rgb_fifo[0][1] as i64) << 16,
+                (self.rgb_fifo[0][2] as i64) << 16,
+            ];
+            self.interpolate_far_color(config, color);
+        }
+    }
+
+    fn intpl(&mut self, config: Config) {
+        let color = [
+            (self.ir[1] as i64) << 12,
+            (self.ir[2] as i64) << 12,
+            (self.ir[3] as i64) << 12,
+        ];
+        self.interpolate_far_color(config, color);
+    }
+
+    fn mvmva(&mut self, config: Config, command: u32) {
+        let matrix = ((command >> 17) & 3) as usize;
+        let vector = match (command >> 15) & 3 {
+            3 => [self.ir[1], self.ir[2], self.ir[3]],
+            v => self.v[v as usize],
+        };
+        let translation = ((command >> 13) & 3) as usize;
+
+        if translation == FAR_COLOR {
+            self.multiply_far_color_bugged(config, matrix, vector);
+        } else {
+            self.multiply_matrix_by_vector(config, matrix, vector, translation);
+        }
+    }
+
+    fn ncds(&mut self, config: Config) {
+        self.normal_color_depth_cue(config, 0);
+    }
+
+    fn ncdt(&mut self, config: Config) {
+        for vertex in 0..3 {
+            self.normal_color_depth_cue(config, vertex);
+        }
+    }
+
+    fn cdp(&mut self, config: Config) {
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_by_vector(config, COLOR, ir, BACKGROUND_COLOR);
+
+        let color = self.color_times_ir();
+        self.interpolate_far_color(config, color);
+    }
+
+    fn nccs(&mut self, config: Config) {
+        self.normal_color_color(config, 0);
+    }
+
+    fn ncct(&mut self, config: Config) {
+        for vertex in 0..3 {
+            self.normal_color_color(config, vertex);
+        }
+    }
+
+    fn cc(&mut self, config: Config) {
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_by_vector(config, COLOR, ir, BACKGROUND_COLOR);
+
+        let color = self.color_times_ir();
+        for (i, value) in color.into_iter().enumerate() {
+            self.set_mac(i + 1, value, config.shift);
+        }
+        self.mac_to_ir(config);
+        self.push_color();
+    }
+
+    fn ncs(&mut self, config: Config) {
+        self.normal_color(config, 0);
+    }
+
+    fn nct(&mut self, config: Config) {
+        for vertex in 0..3 {
+            self.normal_color(config, vertex);
+        }
+    }
+
+    fn sqr(&mut self, config: Config) {
+        for i in 1..4 {
+            let ir = self.ir[i] as i64;
+            self.set_mac(i, ir * ir, config.shift);
+        }
+        self.mac_to_ir(config);
+    }
+
+    fn dcpl(&mut self, config: Config) {
+        let color = self.color_times_ir();
+        self.interpolate_far_color(config, color);
+    }
+
+    fn avsz3(&mut self) {
+        let sum = self.z_fifo[1] as i64 + self.z_fifo[2] as i64 + self.z_fifo[3] as i64;
+        self.average_z(self.zsf3, sum);
+    }
+
+    fn avsz4(&mut self) {
+        let sum = self.z_fifo.iter().map(|&z| z as i64).sum();
+        self.average_z(self.zsf4, sum);
+    }
+
+    // general purpose interpolation
+    fn gpf(&mut self, config: Config) {
+        let ir0 = self.ir[0] as i64;
+        for i in 1..4 {
+            let value = self.ir[i] as i64 * ir0;
+            self.set_mac(i, value, config.shift);
+        }
+        self.mac_to_ir(config);
+        self.push_color();
+    }
+
+    fn gpl(&mut self, config: Config) {
+        let ir0 = self.ir[0] as i64;
+        for i in 1..4 {
+            let mac = (self.mac[i] as i64) << config.shift;
+            let value = self.check_mac(i, mac + self.ir[i] as i64 * ir0);
+            self.set_mac(i, value, config.shift);
+        }
+        self.mac_to_ir(config);
+        self.push_color();
+    }
+
+    // Transforms a vertex to screen coordinates and returns the result of the
+    // perspective division, used for the depth cueing of the last vertex
+    fn rotate_translate_perspective(&mut self, config: Config, vertex: usize) -> u32 {
+        let v = self.v[vertex];
+        let m = self.matrices[ROTATION];
+        let mut z = 0;
+
+        for (r, row) in m.iter().enumerate() {
+            let mut sum = self.control_vector(TRANSLATION, r) << 12;
+            for (&m, &v) in row.iter().zip(&v) {
+                sum = self.check_mac(r + 1, sum + m as i64 * v as i64);
+            }
+            self.set_mac(r + 1, sum, config.shift);
+            z = sum;
+        }
+
+        self.ir[1] = self.saturate_ir(1, self.mac[1], config.lm);
+        self.ir[2] = self.saturate_ir(2, self.mac[2], config.lm);
+
+        // the IR3 saturation flag is checked against the depth before the
+        // shift is applied, the value itself uses MAC3 as usual
+        let z = z >> 12;
+        let min = if config.lm { 0 } else { -0x8000 };
+        self.ir[3] = self.mac[3].clamp(min, 0x7fff) as i16;
+        if !(-0x8000..=0x7fff).contains(&z) {
+            self.set_flag(22);
+        }
+
+        let sz3 = if z < 0 {
+            self.set_flag(18);
+            0
+        } else if z > 0xffff {
+            self.set_flag(18);
+            0xffff
+        } else {
+            z as u16
+        };
+        self.push_z(sz3);
+
+        let div = self.divide() as i64;
+
+        let x = self.check_mac0(div * self.ir[1] as i64 + self.ofx as i64) >> 16;
+        let y = self.check_mac0(div * self.ir[2] as i64 + self.ofy as i64) >> 16;
+        let x = self.saturate_screen(14, x);
+        let y = self.saturate_screen(13, y);
+        self.push_xy(x, y);
+
+        div as u32
+    }
+
+    fn depth_cue_factor(&mut self, div: u32) {
+        let depth = self.check_mac0(div as i64 * self.dqa as i64 + self.dqb as i64);
+        self.mac[0] = depth as i32;
+
+        let ir0 = depth >> 12;
+        self.ir[0] = if ir0 < 0 {
+            self.set_flag(12);
+            0
+        } else if ir0 > 0x1000 {
+            self.set_flag(12);
+            0x1000
+        } else {
+            ir0 as i16
+        };
+    }
+
+    // Unsigned Newton-Raphson division of H by SZ3
+    fn divide(&mut self) -> u32 {
+        let h = self.h as u32;
+        let sz3 = self.z_fifo[3] as u32;
+
+        if h < sz3 * 2 {
+            let z = (sz3 as u16).leading_zeros();
+            let n = (h << z) as u64;
+            let d = (sz3 << z) as u64;
+            let u = UNR_TABLE[((d - 0x7fc0) >> 7) as usize] as u64 + 0x101;
+            let d = (0x2000080 - d * u) >> 8;
+            let d = (0x0000080 + d * u) >> 8;
+            (((n * d) + 0x8000) >> 16).min(0x1ffff) as u32
+        } else {
+            self.set_flag(17);
+            0x1ffff
+        }
+    }
+
+    fn normal_color(&mut self, config: Config, vertex: usize) {
+        self.light(config, vertex);
+        self.push_color();
+    }
+
+    fn normal_color_color(&mut self, config: Config, vertex: usize) {
+        self.light(config, vertex);
+
+        let color = self.color_times_ir();
+        for (i, value) in color.into_iter().enumerate() {
+            self.set_mac(i + 1, value, config.shift);
+        }
+        self.mac_to_ir(config);
+        self.push_color();
+    }
+
+    fn normal_color_depth_cue(&mut self, config: Config, vertex: usize) {
+        self.light(config, vertex);
+
+        let color = self.color_times_ir();
+        self.interpolate_far_color(config, color);
+    }
+
+    // [IR] = [MAC] = (LLM * V) SAR sf, then [IR] = [MAC] = (BK * 1000h + LCM * IR) SAR sf
+    fn light(&mut self, config: Config, vertex: usize) {
+        self.multiply_matrix_by_vector(config, LIGHT, self.v[vertex], ZERO);
+
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_by_vector(config, COLOR, ir, BACKGROUND_COLOR);
+    }
+
+    // [R * IR1, G * IR2, B * IR3] SHL 4, before any shift
+    fn color_times_ir(&mut self) -> [i64; 3] {
+        let mut color = [0; 3];
+        for (i, value) in color.iter_mut().enumerate() {
+            let product = ((self.rgbc[i] as i64) * (self.ir[i + 1] as i64)) << 4;
+            *value = self.check_mac(i + 1, product);
+        }
+        color
+    }
+
+    // [MAC] = color + (FC - color) * IR0, then shifted and pushed to the color fifo
+    fn interpolate_far_color(&mut self, config: Config, color: [i64; 3]) {
+        for (i, &value) in color.iter().enumerate() {
+            let far = (self.control_vectors[FAR_COLOR][i] as i64) << 12;
+            let difference = self.check_mac(i + 1, far - value) >> config.shift;
+            // lm is ignored on this step
+            self.ir[i + 1] = self.saturate_ir(i + 1, difference as i32, false);
+        }
+
+        let ir0 = self.ir[0] as i64;
+        for (i, &value) in color.iter().enumerate() {
+            let value = self.check_mac(i + 1, self.ir[i + 1] as i64 * ir0 + value);
+            self.set_mac(i + 1, value, config.shift);
+        }
+
+        self.mac_to_ir(config);
+        self.push_color();
+    }
+
+    // [MAC] = (T * 1000h + M * V) SAR sf, [IR] = [MAC]
+    fn multiply_matrix_by_vector(
+        &mut self,
+        config: Config,
+        matrix: usize,
+        v: [i16; 3],
+        translation: usize,
+    ) {
+        let m = self.matrix(matrix);
+
+        for (r, row) in m.iter().enumerate() {
+            let mut sum = self.control_vector(translation, r) << 12;
+            for (&m, &v) in row.iter().zip(&v) {
+                sum = self.check_mac(r + 1, sum + m as i64 * v as i64);
+            }
+            self.set_mac(r + 1, sum, config.shift);
+        }
+
+        self.mac_to_ir(config);
+    }
+
+    // With the far color as the translation vector MVMVA is broken, the first
+    // column is only used to compute the flags and then discarded
+    fn multiply_far_color_bugged(&mut self, config: Config, matrix: usize, v: [i16; 3]) {
+        let m = self.matrix(matrix);
+
+        for (r, row) in m.iter().enumerate() {
+            let far = self.control_vector(FAR_COLOR, r) << 12;
+            let discarded = self.check_mac(r + 1, far + row[0] as i64 * v[0] as i64);
+            self.saturate_ir(r + 1, (discarded >> config.shift) as i32, config.lm);
+
+            let sum = self.check_mac(r + 1, row[1] as i64 * v[1] as i64);
+            let sum = self.check_mac(r + 1, sum + row[2] as i64 * v[2] as i64);
+            self.set_mac(r + 1, sum, config.shift);
+        }
+
+        self.mac_to_ir(config);
+    }
+
+    fn matrix(&self, matrix: usize) -> [[i16; 3]; 3] {
+        if matrix == GARBAGE {
+            let r = (self.rgbc[0] as i16) << 4;
+            let rt = &self.matrices[ROTATION];
+            [
+                [-r, r, self.ir[0]],
+                [rt[0][2], rt[0][2], rt[0][2]],
+                [rt[1][1], rt[1][1], rt[1][1]],
+            ]
+        } else {
+            self.matrices[matrix]
+        }
+    }
+
+    fn control_vector(&self, vector: usize, index: usize) -> i64 {
+        self.control_vectors[vector][index] as i64
+    }
+
+    fn average_z(&mut self, factor: i16, sum: i64) {
+        let value = self.check_mac0(factor as i64 * sum);
+        self.mac[0] = value as i32;
+
+        let otz = value >> 12;
+        self.otz = if otz < 0 {
+            self.set_flag(18);
+            0
+        } else if otz > 0xffff {
+            self.set_flag(18);
+            0xffff
+        } else {
+            otz as u16
+        };
+    }
+
+    fn set_flag(&mut self, bit: u8) {
+        self.flag |= 1 << bit;
+    }
+
+    fn update_error_flag(&mut self) {
+        if self.flag & FLAG_ERROR_MASK != 0 {
+            self.flag |= 0x80000000;
+        }
+    }
+
+    // MAC1-3 keep 44 bits on the intermediate results of a command
+    fn check_mac(&mut self, index: usize, value: i64) -> i64 {
+        if value > 0x7ffffffffff {
+            self.set_flag(31 - index as u8);
+        } else if value < -0x80000000000 {
+            self.set_flag(28 - index as u8);
+        }
+
+        (value << 20) >> 20
+    }
+
+    fn check_mac0(&mut self, value: i64) -> i64 {
+        if value > 0x7fffffff {
+            self.set_flag(16);
+        } else if value < -0x80000000 {
+            self.set_flag(15);
+        }
+
+        value
+    }
+
+    fn set_mac(&mut self, index: usize, value: i64, shift: u8) {
+        let value = self.check_mac(index, value);
+        self.mac[index] = (value >> shift) as i32;
+    }
+
+    fn saturate_ir(&mut self, index: usize, value: i32, lm: bool) -> i16 {
+        let min = if lm { 0 } else { -0x8000 };
+
+        if value < min {
+            self.set_flag(25 - index as u8);
+            min as i16
+        } else if value > 0x7fff {
+            self.set_flag(25 - index as u8);
+            0x7fff
+        } else {
+            value as i16
+        }
+    }
+
+    fn mac_to_ir(&mut self, config: Config) {
+        for i in 1..4 {
+            self.ir[i] = self.saturate_ir(i, self.mac[i], config.lm);
+        }
+    }
+
+    fn saturate_screen(&mut self, bit: u8, value: i64) -> i16 {
+        if value < -0x400 {
+            self.set_flag(bit);
+            -0x400
+        } else if value > 0x3ff {
+            self.set_flag(bit);
+            0x3ff
+        } else {
+            value as i16
+        }
+    }
+
+    fn push_xy(&mut self, x: i16, y: i16) {
+        self.xy_fifo[0] = self.xy_fifo[1];
+        self.xy_fifo[1] = self.xy_fifo[2];
+        self.xy_fifo[2] = (x, y);
+    }
+
+    fn push_z(&mut self, z: u16) {
+        self.z_fifo[0] = self.z_fifo[1];
+        self.z_fifo[1] = self.z_fifo[2];
+        self.z_fifo[2] = self.z_fifo[3];
+        self.z_fifo[3] = z;
+    }
+
+    // [MAC1 / 16, MAC2 / 16, MAC3 / 16, CODE]
+    fn push_color(&mut self) {
+        let mut color = [0; 4];
+        for (i, value) in color.iter_mut().take(3).enumerate() {
+            let c = self.mac[i + 1] >> 4;
+            *value = if c < 0 {
+                self.set_flag(21 - i as u8);
+                0
+            } else if c > 0xff {
+                self.set_flag(21 - i as u8);
+                0xff
+            } else {
+                c as u8
+            };
+        }
+        color[3] = self.rgbc[3];
+
+        self.rgb_fifo[0] = self.rgb_fifo[1];
+        self.rgb_fifo[1] = self.rgb_fifo[2];
+        self.rgb_fifo[2] = color;
+    }
+}
+
+fn pack(low: i16, high: i16) -> u32 {
+    (low as u16 as u32) | ((high as u16 as u32) << 16)
+}
+
+#[cfg(test)]
+mod registers {
+    use super::*;
+
+    #[test]
+    fn sign_extension() {
+        let mut gte = Gte::new();
+
+        gte.set_data(1, 0x00008000);
+        gte.set_data(7, 0xffff8000);
+        gte.set_control(26, 0x00008000);
+        assert_eq!(gte.data(1), 0xffff8000);
+        assert_eq!(gte.data(7), 0x00008000);
+        assert_eq!(gte.control(26), 0xffff8000);
+    }
+
+    #[test]
+    fn sxyp() {
+        let mut gte = Gte::new();
+
+        gte.set_data(15, 0x00010002);
+        gte.set_data(15, 0x00030004);
+        assert_eq!(gte.data(13), 0x00010002);
+        assert_eq!(gte.data(14), 0x00030004);
+        assert_eq!(gte.data(15), 0x00030004);
+    }
+
+    #[test]
+    fn irgb_orgb() {
+        let mut gte = Gte::new();
+
+        gte.set_data(28, 0x00007c1f);
+        assert_eq!(gte.data(9), 0x00000f80);
+        assert_eq!(gte.data(10), 0x00000000);
+        assert_eq!(gte.data(11), 0x00000f80);
+
+        gte.set_data(9, 0xffffffff);
+        gte.set_data(10, 0x00007fff);
+        assert_eq!(gte.data(29), 0x00007fe0);
+    }
+
+    #[test]
+    fn lzcs() {
+        let mut gte = Gte::new();
+
+        gte.set_data(30, 0x00ffffff);
+        assert_eq!(gte.data(31), 8);
+        gte.set_data(30, 0xfff00000);
+        assert_eq!(gte.data(31), 12);
+        gte.set_data(30, 0x00000000);
+        assert_eq!(gte.data(31), 32);
+    }
+
+    #[test]
+    fn flag() {
+        let mut gte = Gte::new();
+
+        gte.set_control(31, 0xffffffff);
+        assert_eq!(gte.control(31), 0xfffff000);
+        gte.set_control(31, 0x00001000);
+        assert_eq!(gte.control(31), 0x00001000);
+    }
+}
+
+#[cfg(test)]
+mod commands {
+    use super::*;
+
+    fn identity(gte: &mut Gte) {
+        gte.set_control(0, 0x00001000);
+        gte.set_control(2, 0x00001000);
+        gte.set_control(4, 0x00001000);
+    }
+
+    #[test]
+    fn unr_table() {
+        assert_eq!(UNR_TABLE[0x00], 0xff);
+        assert_eq!(UNR_TABLE[0xf4], 0x05);
+        assert_eq!(UNR_TABLE[0x100], 0x00);
+    }
+
+    #[test]
+    fn rtps() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(24, 160 << 16);
+        gte.set_control(25, 120 << 16);
+        gte.set_control(26, 1000);
+        gte.set_control(28, 0x00800000);
+        gte.set_data(0, 0x0014000a);
+        gte.set_data(1, 2000);
+
+        gte.command(0x00080001);
+        assert_eq!(gte.data(25), 10);
+        assert_eq!(gte.data(26), 20);
+        assert_eq!(gte.data(27), 2000);
+        assert_eq!(gte.data(19), 2000);
+        assert_eq!(gte.data(14), (130 << 16) | 165);
+        assert_eq!(gte.data(24), 0x00800000);
+        assert_eq!(gte.data(8), 0x800);
+        assert_eq!(gte.control(31), 0);
+    }
+
+    #[test]
+    fn rtps_divide_overflow() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(26, 1000);
+        gte.set_data(0, 0x0000000a);
+        gte.set_data(1, 100);
+
+        gte.command(0x00080001);
+        assert_eq!(gte.data(14), 19);
+        assert_eq!(gte.control(31), 0x80020000);
+    }
+
+    #[test]
+    fn rtpt() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(26, 1000);
+        gte.set_data(0, 0x00000000);
+        gte.set_data(1, 1000);
+        gte.set_data(2, 0x00000000);
+        gte.set_data(3, 2000);
+        gte.set_data(4, 0x00000000);
+        gte.set_data(5, 4000);
+
+        gte.command(0x00080030);
+        assert_eq!(gte.data(17), 1000);
+        assert_eq!(gte.data(18), 2000);
+        assert_eq!(gte.data(19), 4000);
+    }
+
+    #[test]
+    fn screen_saturation() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(26, 1000);
+        gte.set_data(0, 0x7fff8000);
+        gte.set_data(1, 1000);
+
+        gte.command(0x00080001);
+        assert_eq!(gte.data(14), 0x03fffc00);
+        assert_eq!(gte.control(31), 0x80006000);
+    }
+
+    #[test]
+    fn nclip() {
+        let mut gte = Gte::new();
+        gte.set_data(12, 0x00000000);
+        gte.set_data(13, 0x0000000a);
+        gte.set_data(14, 0x000a0000);
+
+        gte.command(0x00000006);
+        assert_eq!(gte.data(24), 100);
+    }
+
+    #[test]
+    fn avsz3() {
+        let mut gte = Gte::new();
+        gte.set_control(29, 0x555);
+        gte.set_data(17, 300);
+        gte.set_data(18, 300);
+        gte.set_data(19, 300);
+
+        gte.command(0x0000002d);
+        assert_eq!(gte.data(24), 0x555 * 900);
+        assert_eq!(gte.data(7), 299);
+    }
+
+    #[test]
+    fn avsz4() {
+        let mut gte = Gte::new();
+        gte.set_control(30, 0x400);
+        gte.set_data(16, 100);
+        gte.set_data(17, 200);
+        gte.set_data(18, 300);
+        gte.set_data(19, 400);
+
+        gte.command(0x0000002e);
+        assert_eq!(gte.data(7), 250);
+    }
+
+    #[test]
+    fn sqr() {
+        let mut gte = Gte::new();
+        gte.set_data(9, 2);
+        gte.set_data(10, 3);
+        gte.set_data(11, 0xfffffffc);
+
+        gte.command(0x00000028);
+        assert_eq!(gte.data(25), 4);
+        assert_eq!(gte.data(26), 9);
+        assert_eq!(gte.data(27), 16);
+        assert_eq!(gte.data(11), 16);
+    }
+
+    #[test]
+    fn op() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_data(9, 0x1000);
+        gte.set_data(10, 0x2000);
+        gte.set_data(11, 0x3000);
+
+        gte.command(0x0008000c);
+        assert_eq!(gte.data(25), 0x3000 - 0x2000);
+        assert_eq!(gte.data(26), -0x2000i32 as u32);
+        assert_eq!(gte.data(27), 0x2000 - 0x1000);
+    }
+
+    #[test]
+    fn mvmva() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(5, 1);
+        gte.set_control(6, 2);
+        gte.set_control(7, 3);
+        gte.set_data(2, 0x00200010);
+        gte.set_data(3, 0x00000030);
+
+        // rotation * V1 + translation
+        gte.command(0x00088012);
+        assert_eq!(gte.data(25), 0x11);
+        assert_eq!(gte.data(26), 0x22);
+        assert_eq!(gte.data(27), 0x33);
+
+        // rotation * IR, no translation, lm clamps the negative value
+        gte.set_data(9, 0xffffffff);
+        gte.command(0x0009e412);
+        assert_eq!(gte.data(25), 0xffffffff);
+        assert_eq!(gte.data(9), 0);
+        assert_eq!(gte.control(31), 0x81000000);
+    }
+
+    #[test]
+    fn mvmva_far_color() {
+        let mut gte = Gte::new();
+        identity(&mut gte);
+        gte.set_control(21, 0x100);
+        gte.set_data(0, 0x00020001);
+        gte.set_data(1, 0x00000003);
+
+        // the far color and first column are discarded
+        gte.command(0x00084012);
+        assert_eq!(gte.data(25), 0);
+        assert_eq!(gte.data(26), 2);
+        assert_eq!(gte.data(27), 3);
+    }
+
+    #[test]
+    fn ncs() {
+        let mut gte = Gte::new();
+        // light matrix takes the first component to all the colors
+        gte.set_control(8, 0x00001000);
+        gte.set_control(9, 0x10000000);
+        gte.set_control(11, 0x00001000);
+        // color matrix is the identity
+        gte.set_control(16, 0x00001000);
+        gte.set_control(18, 0x00001000);
+        gte.set_control(20, 0x00001000);
+        gte.set_control(15, 0x10);
+        gte.set_data(6, 0xab000000);
+        gte.set_data(0, 0x00000800);
+
+        gte.command(0x0008041e);
+        assert_eq!(gte.data(22), 0xab818080);
+        assert_eq!(gte.data(9), 0x800);
+        assert_eq!(gte.data(10), 0x800);
+        assert_eq!(gte.data(11), 0x810);
+    }
+
+    #[test]
+    fn color_saturation() {
+        let mut gte = Gte::new();
+        gte.set_data(9, 0x7fff);
+        gte.set_data(10, 0);
+        gte.set_data(11, 0xffffffff);
+        gte.set_data(8, 0x1000);
+
+        gte.command(0x0008003d);
+        assert_eq!(gte.data(22), 0x000000ff);
+        // color saturation does not set the error bit
+        assert_eq!(gte.control(31), 0x00280000);
+    }
+
+    #[test]
+    fn dpcs() {
+        let mut gte = Gte::new();
+        gte.set_data(6, 0x12204080);
+        gte.set_control(21, 0x00000ff0);
+        gte.set_control(22, 0x00000ff0);
+        gte.set_control(23, 0x00000ff0);
+        gte.set_data(8, 0x800);
+
+        gte.command(0x00080010);
+        assert_eq!(gte.data(22), 0x128f9fbf);
+    }
+
+    #[test]
+    fn dpct() {
+        let mut gte = Gte::new();
+        gte.set_data(20, 0x00000010);
+        gte.set_data(21, 0x00000020);
+        gte.set_data(22, 0x00000030);
+        gte.set_data(6, 0x34000000);
+
+        gte.command(0x0008002a);
+        assert_eq!(gte.data(20), 0x34000010);
+        assert_eq!(gte.data(21), 0x34000020);
+        assert_eq!(gte.data(22), 0x34000030);
+    }
+
+    #[test]
+    fn gpl() {
+        let mut gte = Gte::new();
+        gte.set_data(25, 0x100);
+        gte.set_data(26, 0x200);
+        gte.set_data(27, 0x300);
+        gte.set_data(9, 0x100);
+        gte.set_data(10, 0x100);
+        gte.set_data(11, 0x100);
+        gte.set_data(8, 0x800);
+
+        gte.command(0x0008003e);
+        assert_eq!(gte.data(25), 0x180);
+        assert_eq!(gte.data(26), 0x280);
+        assert_eq!(gte.data(27), 0x380);
+        assert_eq!(gte.data(22), 0x00382818);
+    }
+}
@@ -0,0 +1,105 @@
+// Interrupt controller, I_STAT and I_MASK
+
+// bit of each interrupt line in I_STAT and I_MASK
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Vblank = 0,
+    Gpu = 1,
+    Cdrom = 2,
+    Dma = 3,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+    // shared by the controllers and memory cards
+    Controller = 7,
+    // no serial port or lightgun is emulated yet, nothing raises these
+    #[allow(dead_code)]
+    Sio = 8,
+    Spu = 9,
+    #[allow(dead_code)]
+    Lightpen = 10,
+}
+
+pub struct InterruptController {
+    status: u16,
+    mask: u16,
+}
+
+impl InterruptController {
+    const I_STAT: u32 = 0;
+    const I_MASK: u32 = 4;
+
+    // bits 11-15 are not used
+    const WRITE_MASK: u16 = 0x07ff;
+
+    pub fn new() -> Self {
+        InterruptController { status: 0, mask: 0 }
+    }
+
+    // the line goes up, stays set on I_STAT until acknowledged
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.status |= 1 << interrupt as u16;
+    }
+
+    // drives COP0 Cause.IP2
+    pub fn pending(&self) -> bool {
+        self.status & self.mask != 0
+    }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        match offset {
+            InterruptController::I_STAT => self.status as u32,
+            InterruptController::I_MASK => self.mask as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        let value = value as u16 & InterruptController::WRITE_MASK;
+        match offset {
+            // writing zero to a bit acknowledges it, ones leave it unchanged
+            InterruptController::I_STAT => self.status &= value,
+            InterruptController::I_MASK => self.mask = value,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acknowledge() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::Vblank);
+        interrupts.request(Interrupt::Dma);
+        assert_eq!(interrupts.read(0), 0x00000009);
+
+        interrupts.write(0, 0xfffffffe);
+        assert_eq!(interrupts.read(0), 0x00000008);
+    }
+
+    #[test]
+    fn sio_and_lightpen() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::Sio);
+        assert_eq!(interrupts.read(0), 0x00000100);
+        interrupts.request(Interrupt::Lightpen);
+        assert_eq!(interrupts.read(0), 0x00000500);
+    }
+
+    #[test]
+    fn mask() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::Cdrom);
+        assert!(!interrupts.pending());
+
+        interrupts.write(4, 0xffff0004);
+        assert_eq!(interrupts.read(4), 0x00000004);
+        assert!(interrupts.pending());
+
+        interrupts.write(0, 0);
+        assert!(!interrupts.pending());
+    }
+}
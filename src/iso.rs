@@ -0,0 +1,88 @@
+// ISO images, only the 2048 bytes of user data of each sector
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::disc::{self, binary_to_bcd, Disc, Track, SECTOR_SIZE};
+
+const DATA_SIZE: usize = 2048;
+
+pub struct Iso {
+    file: File,
+    sectors: u32,
+    tracks: Vec<Track>,
+}
+
+impl Iso {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let sectors = (file.metadata()?.len() / DATA_SIZE as u64) as u32;
+
+        Ok(Iso {
+            file,
+            sectors,
+            tracks: vec![Track {
+                number: 1,
+                start: 0,
+                audio: false,
+            }],
+        })
+    }
+}
+
+impl Disc for Iso {
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.sectors
+    }
+
+    // the rest of the sector is made up as mode 2 form 1, the edc and ecc
+    // are left as zero since the controller does not check them
+    fn read_sector(&mut self, lba: u32) -> Option<Vec<u8>> {
+        if lba >= self.sectors {
+            return None;
+        }
+
+        let mut sector = vec![0; SECTOR_SIZE];
+        sector[1..11].fill(0xff);
+        let (minute, second, frame) = disc::msf(lba);
+        sector[12] = binary_to_bcd(minute);
+        sector[13] = binary_to_bcd(second);
+        sector[14] = binary_to_bcd(frame);
+        sector[15] = 2;
+        // the subheader is repeated, submode says data
+        sector[18] = 0x08;
+        sector[22] = 0x08;
+
+        self.file.seek(SeekFrom::Start(lba as u64 * DATA_SIZE as u64)).ok()?;
+        self.file.read_exact(&mut sector[24..24 + DATA_SIZE]).ok()?;
+        Some(sector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_sector() {
+        let path = std::env::temp_dir().join(format!("psiemu_test_{}.iso", std::process::id()));
+        let mut data = vec![0; DATA_SIZE * 20];
+        data[DATA_SIZE * 16] = 0x01;
+        data[DATA_SIZE * 16 + 1..DATA_SIZE * 16 + 6].copy_from_slice(b"CD001");
+        std::fs::write(&path, &data).unwrap();
+
+        let mut iso = Iso::open(&path).unwrap();
+        assert_eq!(iso.lead_out(), 20);
+        let sector = iso.read_sector(16).unwrap();
+        assert_eq!(sector[0..12], [0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 0]);
+        // 00:02:16 in bcd, mode 2
+        assert_eq!(sector[12..16], [0x00, 0x02, 0x16, 0x02]);
+        assert_eq!(sector[24..30], *b"\x01CD001");
+        assert_eq!(iso.read_sector(20), None);
+    }
+}
@@ -1,26 +1,239 @@
+use std::fs::File;
+use std::io::Write;
+
 use bus::Bus;
 use clap::Parser;
+use controller::{Controller, Kind, Motors};
 use cpu::Cpu;
+use exe::Exe;
+use memcard::MemoryCard;
+use screenshot::FrameDump;
+use wav::WavWriter;
 
 mod bus;
+mod cdrom;
+mod chd;
+mod controller;
 mod cpu;
+mod cue;
+mod disc;
+mod dma;
+mod exe;
+mod gpu;
+mod gte;
+mod interrupt;
+mod iso;
+mod memcard;
+mod saves;
+mod scheduler;
+mod screenshot;
+mod sio;
+mod spu;
+mod timers;
+mod wav;
+mod xa;
 
 #[derive(clap::Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[arg(long, required = true)]
+    bios: Option<std::path::PathBuf>,
+    /// Memory card in the first slot, a raw 128 KiB .mcd image that is created
+    /// formatted if it does not exist
+    #[arg(long)]
+    memcard1: Option<std::path::PathBuf>,
+    /// Memory card in the second slot
+    #[arg(long)]
+    memcard2: Option<std::path::PathBuf>,
+    /// Disc image to put in the drive, a cue sheet, bin, iso or chd
+    #[arg(long)]
+    disc: Option<std::path::PathBuf>,
+    /// PS-X EXE to run once the bios has booted, instead of the shell
+    #[arg(long)]
+    exe: Option<std::path::PathBuf>,
+    /// Emulate the cpu instruction cache
+    #[arg(long)]
+    icache: bool,
+    /// Write the displayed frames as png files to this directory
+    #[arg(long)]
+    dump_dir: Option<std::path::PathBuf>,
+    /// Only dump one frame out of every this many
+    #[arg(long, requires = "dump_dir")]
+    dump_every: Option<u32>,
+    /// Frame numbers to dump, separated by commas
+    #[arg(long, requires = "dump_dir", value_delimiter = ',')]
+    dump_frames: Vec<u32>,
+    /// Write the sound output to this wav file, 44.1 kHz stereo
+    #[arg(long)]
+    wav: Option<std::path::PathBuf>,
+    /// Plug a DualShock in the first slot instead of a digital pad
     #[arg(long)]
-    bios: std::path::PathBuf,
+    dualshock: bool,
+    /// Buttons to press on the first pad, as button:frame or button:frame:frames to hold
+    /// it longer than one frame, separated by commas
+    #[arg(long, value_delimiter = ',', value_parser = parse_press)]
+    press: Vec<Press>,
+    /// Write the speed of the motors of the first pad to this file whenever it changes, one
+    /// line of frame number, small motor and large motor, from 0 to 255
+    #[arg(long)]
+    rumble: Option<std::path::PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Look into memory card images and move saves in and out of them
+    #[command(subcommand)]
+    Card(Card),
+}
+
+// the images can be raw .mcd, .gme or .vmp, read by their contents and
+// written by their extension, .vmp signed for the psp and the ps3
+#[derive(clap::Subcommand, Debug)]
+enum Card {
+    /// List the saves on a card
+    List { image: std::path::PathBuf },
+    /// Write the save that starts at a block, from 1 to 15, to a .mcs file
+    Export {
+        image: std::path::PathBuf,
+        block: usize,
+        save: std::path::PathBuf,
+    },
+    /// Copy a save from a .mcs file to the first free blocks of a card
+    Import {
+        image: std::path::PathBuf,
+        save: std::path::PathBuf,
+    },
+    /// Convert a card to another format
+    Convert {
+        input: std::path::PathBuf,
+        output: std::path::PathBuf,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Press {
+    button: u16,
+    frame: u32,
+    frames: u32,
+}
+
+impl Press {
+    fn held(&self, frame: u32) -> bool {
+        (self.frame..self.frame.saturating_add(self.frames)).contains(&frame)
+    }
+}
+
+fn parse_press(text: &str) -> Result<Press, String> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or_default();
+    let button = controller::button(name).ok_or(format!("unknown button {name}"))?;
+    let mut number = |default: Option<u32>| match (parts.next(), default) {
+        (Some(number), _) => number.parse().map_err(|_| format!("bad frame number {number}")),
+        (None, Some(default)) => Ok(default),
+        (None, None) => Err("missing frame number".to_string()),
+    };
+    let frame = number(None)?;
+    let frames = number(Some(1))?;
+    Ok(Press {
+        button,
+        frame,
+        frames,
+    })
+}
+
+fn run_card(command: Card) -> anyhow::Result<()> {
+    match command {
+        Card::List { image } => {
+            let card = saves::read(&image)?;
+            println!("Block  Product     Name      Blocks  Icon frames  Title");
+            for save in saves::saves(&card) {
+                println!(
+                    "{:>5}  {:<10}  {:<8}  {:>6}  {:>11}  {}",
+                    save.block, save.product, save.name, save.blocks, save.icon_frames, save.title
+                );
+            }
+            println!("{} blocks free", saves::free_blocks(&card));
+        }
+        Card::Export { image, block, save } => {
+            let card = saves::read(&image)?;
+            std::fs::write(save, saves::export(&card, block)?)?;
+        }
+        Card::Import { image, save } => {
+            let mut card = saves::read(&image)?;
+            let block = saves::import(&mut card, &std::fs::read(save)?)?;
+            saves::write(&image, &card)?;
+            println!("Imported at block {}", block);
+        }
+        Card::Convert { input, output } => saves::write(&output, &saves::read(&input)?)?,
+    }
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let args =  Args::parse();
+    if let Some(Command::Card(command)) = args.command {
+        return run_card(command);
+    }
 
-    let bios = std::fs::read(args.bios)?;
+    // only the subcommands go without it
+    let bios = std::fs::read(args.bios.expect("--bios is required"))?;
     
     let mut cpu = Cpu::new();
+    if args.icache {
+        cpu.enable_icache();
+    }
     let mut bus = Bus::new(bios);
+    if let Some(path) = args.disc {
+        bus.insert_disc(disc::open(&path)?);
+    }
+    for (slot, path) in [args.memcard1, args.memcard2].into_iter().enumerate() {
+        if let Some(path) = path {
+            bus.insert_memory_card(slot, MemoryCard::open(&path)?);
+        }
+    }
+    let mut exe = args.exe.as_deref().map(Exe::open).transpose()?;
+    if args.dualshock {
+        *bus.controller(0) = Controller::new(Kind::DualShock);
+    }
+
+    let dump = match args.dump_dir {
+        Some(directory) => Some(FrameDump::new(directory, args.dump_every, args.dump_frames)?),
+        None => None,
+    };
+    let mut wav = args.wav.as_deref().map(WavWriter::new).transpose()?;
+    let mut rumble = args.rumble.as_deref().map(File::create).transpose()?;
+    let mut frame = 0;
+    let mut motors = Motors::default();
 
     loop {
+        if cpu.pc() == exe::SHELL_ENTRY {
+            if let Some(exe) = exe.take() {
+                exe.load(&mut cpu, &mut bus);
+            }
+        }
         cpu.cpu_cycle(&mut bus);
+
+        // one cycle per instruction, the memory accesses add their wait states
+        if bus.tick(1) {
+            frame += 1;
+            if let Some(dump) = dump.as_ref().filter(|dump| dump.wants(frame)) {
+                dump.write(frame, &bus.gpu().frame())?;
+            }
+            // the samples are taken even without a file so they do not pile up
+            let samples = bus.take_audio();
+            if let Some(wav) = wav.as_mut() {
+                wav.write(&samples)?;
+            }
+
+            let pad = bus.controller(0);
+            let held = args.press.iter().filter(|press| press.held(frame));
+            pad.set_buttons(held.fold(0, |buttons, press| buttons | press.button));
+            if let Some(file) = rumble.as_mut().filter(|_| pad.motors() != motors) {
+                motors = pad.motors();
+                writeln!(file, "{} {} {}", frame, motors.small, motors.large)?;
+            }
+        }
     }
 }
@@ -0,0 +1,295 @@
+// Memory cards, 128 KiB of flash in frames of 128 bytes that the joypad port
+// reads and writes one frame per command, kept in a raw .mcd image
+
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::bail;
+
+pub const CARD_SIZE: usize = 128 * 1024;
+pub const FRAME_SIZE: usize = 128;
+const FRAMES: u16 = (CARD_SIZE / FRAME_SIZE) as u16;
+
+// flag bit set from power on until the first write
+const FLAG_FRESH: u8 = 0x08;
+
+// end bytes of the reads and writes
+const GOOD: u8 = 0x47;
+const BAD_CHECKSUM: u8 = 0x4e;
+const BAD_SECTOR: u8 = 0xff;
+
+pub struct MemoryCard {
+    data: Vec<u8>,
+    // the image the written frames go to
+    file: Option<File>,
+    flag: u8,
+
+    // byte of the command in progress, after the address byte
+    step: usize,
+    command: u8,
+    // the card answers some bytes with the one it got before
+    last: u8,
+    address: u16,
+    frame: [u8; FRAME_SIZE],
+    checksum: u8,
+}
+
+impl MemoryCard {
+    pub fn new(data: Vec<u8>, file: Option<File>) -> Self {
+        MemoryCard {
+            data,
+            file,
+            flag: FLAG_FRESH,
+            step: 0,
+            command: 0,
+            last: 0,
+            address: 0,
+            frame: [0; FRAME_SIZE],
+            checksum: 0,
+        }
+    }
+
+    // a missing image is created with a freshly formatted card
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            std::fs::write(path, formatted())?;
+        }
+        let data = std::fs::read(path)?;
+        if data.len() != CARD_SIZE {
+            bail!("{} is not a 128 KiB memory card image", path.display());
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(MemoryCard::new(data, Some(file)))
+    }
+
+    pub fn end_transfer(&mut self) {
+        self.step = 0;
+    }
+
+    // like the controllers, every byte but the last is acknowledged
+    pub fn transfer(&mut self, byte: u8) -> (u8, bool) {
+        let step = self.step;
+        self.step += 1;
+        let last = std::mem::replace(&mut self.last, byte);
+
+        if step == 0 {
+            return (0xff, true);
+        }
+        if step == 1 {
+            self.command = byte;
+            let known = matches!(byte, b'R' | b'W' | b'S');
+            return (self.flag, known);
+        }
+        match self.command {
+            b'R' => self.read(step, byte, last),
+            b'W' => self.write(step, byte, last),
+            _ => self.get_id(step),
+        }
+    }
+
+    fn read(&mut self, step: usize, byte: u8, last: u8) -> (u8, bool) {
+        match step {
+            2 => (0x5a, true),
+            3 => (0x5d, true),
+            4 => (0x00, true),
+            5 => {
+                self.address = u16::from_be_bytes([last, byte]);
+                (last, true)
+            }
+            6 => (0x5c, true),
+            7 => (0x5d, true),
+            // a frame past the end is answered with 0xffff and nothing else
+            8 | 9 if self.address >= FRAMES => (0xff, step == 8),
+            8 => (self.address.to_be_bytes()[0], true),
+            9 => {
+                let offset = self.address as usize * FRAME_SIZE;
+                self.frame.copy_from_slice(&self.data[offset..offset + FRAME_SIZE]);
+                self.checksum = checksum(self.address, &self.frame);
+                (self.address.to_be_bytes()[1], true)
+            }
+            10..=137 => (self.frame[step - 10], true),
+            138 => (self.checksum, true),
+            _ => (GOOD, false),
+        }
+    }
+
+    fn write(&mut self, step: usize, byte: u8, last: u8) -> (u8, bool) {
+        match step {
+            2 => (0x5a, true),
+            3 => (0x5d, true),
+            4 => (0x00, true),
+            5 => {
+                self.address = u16::from_be_bytes([last, byte]);
+                (last, true)
+            }
+            6..=133 => {
+                self.frame[step - 6] = byte;
+                (last, true)
+            }
+            134 => {
+                self.checksum = byte;
+                (last, true)
+            }
+            135 => (0x5c, true),
+            136 => (0x5d, true),
+            _ => {
+                let end = if self.address >= FRAMES {
+                    BAD_SECTOR
+                } else if checksum(self.address, &self.frame) != self.checksum {
+                    BAD_CHECKSUM
+                } else {
+                    self.save_frame();
+                    GOOD
+                };
+                (end, false)
+            }
+        }
+    }
+
+    fn get_id(&mut self, step: usize) -> (u8, bool) {
+        // the size, 1024 frames of 128 bytes
+        const ID: [u8; 8] = [0x5a, 0x5d, 0x5c, 0x5d, 0x04, 0x00, 0x00, 0x80];
+        match ID.get(step - 2) {
+            Some(&value) => (value, step - 2 < ID.len() - 1),
+            None => (0xff, false),
+        }
+    }
+
+    fn save_frame(&mut self) {
+        let offset = self.address as usize * FRAME_SIZE;
+        self.data[offset..offset + FRAME_SIZE].copy_from_slice(&self.frame);
+        self.flag &= !FLAG_FRESH;
+
+        if let Some(file) = self.file.as_mut() {
+            let saved = file
+                .seek(SeekFrom::Start(offset as u64))
+                .and_then(|_| file.write_all(&self.frame));
+            if let Err(error) = saved {
+                println!("Could not save the memory card: {}", error);
+            }
+        }
+    }
+}
+
+fn checksum(address: u16, frame: &[u8]) -> u8 {
+    let [high, low] = address.to_be_bytes();
+    frame.iter().fold(high ^ low, |checksum, byte| checksum ^ byte)
+}
+
+// an empty card as the bios formats it: the "MC" header, 15 free directory
+// entries, an empty list of broken frames and a copy of the header at the
+// end of the first block. every frame of the first block ends in the xor of
+// its other bytes
+pub fn formatted() -> Vec<u8> {
+    let mut data = vec![0; CARD_SIZE];
+    data[0..2].copy_from_slice(b"MC");
+    for frame in data[FRAME_SIZE..16 * FRAME_SIZE].chunks_mut(FRAME_SIZE) {
+        frame[0] = 0xa0;
+        frame[8..10].fill(0xff);
+    }
+    for frame in data[16 * FRAME_SIZE..36 * FRAME_SIZE].chunks_mut(FRAME_SIZE) {
+        frame[0..4].fill(0xff);
+        frame[8..10].fill(0xff);
+    }
+    data.copy_within(0..FRAME_SIZE, 63 * FRAME_SIZE);
+
+    data[..64 * FRAME_SIZE].chunks_mut(FRAME_SIZE).for_each(set_checksum);
+    data
+}
+
+// the last byte of a frame of the first block is the xor of the others
+pub fn set_checksum(frame: &mut [u8]) {
+    frame[FRAME_SIZE - 1] = frame[..FRAME_SIZE - 1].iter().fold(0, |xor, byte| xor ^ byte);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(card: &mut MemoryCard, bytes: &[u8]) -> Vec<(u8, bool)> {
+        card.end_transfer();
+        card.transfer(0x81);
+        bytes.iter().map(|&byte| card.transfer(byte)).collect()
+    }
+
+    fn read_frame(card: &mut MemoryCard, address: u16) -> Vec<(u8, bool)> {
+        let [high, low] = address.to_be_bytes();
+        let mut bytes = vec![b'R', 0x00, 0x00, high, low];
+        bytes.resize(5 + 4 + 128 + 2, 0);
+        exchange(card, &bytes)
+    }
+
+    fn write_frame(card: &mut MemoryCard, address: u16, frame: &[u8], sum: u8) -> (u8, bool) {
+        let [high, low] = address.to_be_bytes();
+        let mut bytes = vec![b'W', 0x00, 0x00, high, low];
+        bytes.extend(frame);
+        bytes.extend([sum, 0x00, 0x00, 0x00]);
+        *exchange(card, &bytes).last().unwrap()
+    }
+
+    #[test]
+    fn format() {
+        let data = formatted();
+        assert_eq!(data[0..2], *b"MC");
+        assert_eq!(data[0x7f], 0x0e);
+        assert_eq!(data[0x80..0x84], [0xa0, 0, 0, 0]);
+        assert_eq!(data[0xff], 0xa0);
+        assert_eq!(data[0x800..0x804], [0xff; 4]);
+        assert_eq!(data[0x87f], 0x00);
+        assert_eq!(data[0x1f80..0x2000], data[0..0x80]);
+        assert!(data[0x2000..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn read() {
+        let mut data = formatted();
+        data[0x2080..0x2100].fill(0x11);
+        let mut card = MemoryCard::new(data, None);
+
+        let reply = read_frame(&mut card, 0x41);
+        let bytes: Vec<u8> = reply.iter().map(|&(byte, _)| byte).collect();
+        assert_eq!(bytes[0..9], [FLAG_FRESH, 0x5a, 0x5d, 0x00, 0x00, 0x5c, 0x5d, 0x00, 0x41]);
+        assert!(bytes[9..137].iter().all(|&byte| byte == 0x11));
+        // the address goes into the checksum, the even number of data bytes cancel out
+        assert_eq!(bytes[137..], [0x41, GOOD]);
+        assert!(reply[..138].iter().all(|&(_, ack)| ack));
+        assert!(!reply[138].1);
+
+        // past the last frame
+        let reply = read_frame(&mut card, 0x400);
+        assert_eq!(reply[7..9], [(0xff, true), (0xff, false)]);
+    }
+
+    #[test]
+    fn write() {
+        let path = std::env::temp_dir().join(format!("psiemu_write_{}.mcd", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut card = MemoryCard::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), formatted());
+
+        let frame = [0x22; FRAME_SIZE];
+        assert_eq!(write_frame(&mut card, 0x0100, &frame, 0x00), (BAD_CHECKSUM, false));
+        assert_eq!(write_frame(&mut card, 0x0400, &frame, 0x04), (BAD_SECTOR, false));
+        assert_eq!(card.flag, FLAG_FRESH);
+        assert_eq!(write_frame(&mut card, 0x0100, &frame, 0x01), (GOOD, false));
+        assert_eq!(card.flag, 0x00);
+
+        // it is in the image right away
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data[0x8000..0x8080], frame);
+        assert_eq!(data[0x7f80..0x8000], [0; FRAME_SIZE]);
+        assert_eq!(MemoryCard::open(&path).unwrap().data, data);
+    }
+
+    #[test]
+    fn get_id() {
+        let mut card = MemoryCard::new(formatted(), None);
+        let reply = exchange(&mut card, &[b'S', 0, 0, 0, 0, 0, 0, 0, 0]);
+        let bytes: Vec<u8> = reply.iter().map(|&(byte, _)| byte).collect();
+        assert_eq!(bytes, [FLAG_FRESH, 0x5a, 0x5d, 0x5c, 0x5d, 0x04, 0x00, 0x00, 0x80]);
+        assert!(!reply[8].1);
+
+        assert_eq!(exchange(&mut card, b"X"), [(FLAG_FRESH, false)]);
+    }
+}
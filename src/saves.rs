@@ -0,0 +1,447 @@
+// Saves on memory card images, listed from the directory in the first block
+// and the title frame of each save, moved around as .mcs files, and the
+// card itself converted between raw, .gme and signed .vmp images
+
+use std::path::Path;
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use anyhow::bail;
+use sha1::{Digest, Sha1};
+
+use crate::memcard::{self, CARD_SIZE, FRAME_SIZE};
+
+const BLOCK_SIZE: usize = 8192;
+// blocks for saves, the first one holds the directory
+const BLOCKS: usize = 15;
+
+// the directory entries, 0xa_ is free or a deleted save
+const FIRST: u8 = 0x51;
+const MIDDLE: u8 = 0x52;
+const LAST: u8 = 0x53;
+const NO_BLOCK: u16 = 0xffff;
+
+// dexdrive images start with the directory states and a comment per save
+const GME_MAGIC: &[u8] = b"123-456-STD";
+const GME_HEADER: usize = 0xf40;
+// psp and ps3 images, signed with an hmac-sha1 of the whole image. its key
+// comes from the seed, run through aes with the key and iv of the psp
+const VMP_MAGIC: &[u8] = b"\0PMV";
+const VMP_HEADER: usize = 0x80;
+const VMP_SEED: usize = 0x0c;
+const VMP_SIGNATURE: usize = 0x20;
+const VMP_KEY: [u8; 16] = [
+    0xab, 0x5a, 0xbc, 0x9f, 0xc1, 0xf4, 0x9d, 0xe6, 0xa0, 0x51, 0xdb, 0xae, 0xfa, 0x51, 0x88, 0x59,
+];
+const VMP_IV: [u8; 16] = [
+    0xb3, 0x0f, 0xfe, 0xed, 0xb7, 0xdc, 0x5e, 0xb7, 0x13, 0x3d, 0xa6, 0x0d, 0x1b, 0x6b, 0x2c, 0xdc,
+];
+
+// the first row of shift-jis, punctuation and symbols, with the full width
+// forms of ascii turned into ascii
+const SYMBOLS: &str = " 、。,.・:;?!゛゜´`¨^￣_ヽヾゝゞ〃仝々〆〇ー―-/\\~∥|…‥''\"\"()〔〕[]{}〈〉《》\
+    「」『』【】+-±×÷=≠<>≦≧∞∴♂♀°′″℃￥$￠￡%#&*@§☆★○●◎◇◆□■△▲▽▼※〒→←↑↓〓";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Raw,
+    Gme,
+    Vmp,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Save {
+    // the first block of the save, from 1 to 15
+    pub block: usize,
+    pub region: String,
+    pub product: String,
+    pub name: String,
+    pub blocks: usize,
+    pub icon_frames: usize,
+    pub title: String,
+}
+
+// a card image in any of the formats, as the raw 128 KiB
+pub fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    let card = match format_of(&data) {
+        Format::Raw => &data[..],
+        Format::Gme => &data[GME_HEADER.min(data.len())..],
+        Format::Vmp => &data[VMP_HEADER.min(data.len())..],
+    };
+    if card.len() != CARD_SIZE {
+        bail!("{} is not a memory card image", path.display());
+    }
+    Ok(card.to_vec())
+}
+
+// the format comes from the extension, anything else is written raw
+pub fn write(path: &Path, card: &[u8]) -> anyhow::Result<()> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let format = match extension.map(|extension| extension.to_ascii_lowercase()).as_deref() {
+        Some("gme") => Format::Gme,
+        Some("vmp") => Format::Vmp,
+        _ => Format::Raw,
+    };
+    std::fs::write(path, encode(card, format)?)?;
+    Ok(())
+}
+
+fn format_of(data: &[u8]) -> Format {
+    if data.starts_with(GME_MAGIC) {
+        Format::Gme
+    } else if data.starts_with(VMP_MAGIC) {
+        Format::Vmp
+    } else {
+        Format::Raw
+    }
+}
+
+fn encode(card: &[u8], format: Format) -> anyhow::Result<Vec<u8>> {
+    let mut header = match format {
+        Format::Raw => vec![],
+        Format::Gme => {
+            let mut header = vec![0; GME_HEADER];
+            header[..GME_MAGIC.len()].copy_from_slice(GME_MAGIC);
+            header[18] = 0x01;
+            header[20] = 0x01;
+            header[21] = b'M';
+            // the state and next block of every entry, the comments stay empty
+            for block in 0..BLOCKS {
+                let entry = entry(card, block + 1);
+                header[22 + block] = entry[0];
+                header[38 + block] = entry[8];
+            }
+            header
+        }
+        Format::Vmp => {
+            let mut header = vec![0; VMP_HEADER];
+            header[..VMP_MAGIC.len()].copy_from_slice(VMP_MAGIC);
+            header[4..8].copy_from_slice(&(VMP_HEADER as u32).to_le_bytes());
+            header
+        }
+    };
+    header.extend(card);
+    if format == Format::Vmp {
+        let signature = vmp_signature(&header);
+        header[VMP_SIGNATURE..VMP_SIGNATURE + 20].copy_from_slice(&signature);
+    }
+    Ok(header)
+}
+
+// the signature is taken with its own place in the header zeroed
+fn vmp_signature(image: &[u8]) -> [u8; 20] {
+    let cipher = Aes128::new(&VMP_KEY.into());
+    let seed = &image[VMP_SEED..VMP_SEED + 20];
+
+    // the first 16 bytes of the seed decrypted, then the last 4 against
+    // the start of the first 16 encrypted
+    let mut salt = [0; 64];
+    let mut block = <[u8; 16]>::try_from(&seed[..16]).unwrap().into();
+    cipher.decrypt_block(&mut block);
+    for i in 0..16 {
+        salt[i] = block[i] ^ VMP_IV[i];
+    }
+    let mut block = <[u8; 16]>::try_from(&seed[..16]).unwrap().into();
+    cipher.encrypt_block(&mut block);
+    for i in 0..4 {
+        salt[16 + i] = block[i] ^ seed[16 + i];
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(salt.map(|byte| byte ^ 0x36));
+    inner.update(&image[..VMP_SIGNATURE]);
+    inner.update([0; 20]);
+    inner.update(&image[VMP_SIGNATURE + 20..]);
+    let mut outer = Sha1::new();
+    outer.update(salt.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// the directory entry of a block
+fn entry(card: &[u8], block: usize) -> &[u8] {
+    &card[block * FRAME_SIZE..(block + 1) * FRAME_SIZE]
+}
+
+fn is_free(card: &[u8], block: usize) -> bool {
+    entry(card, block)[0] & 0xf0 == 0xa0
+}
+
+pub fn free_blocks(card: &[u8]) -> usize {
+    (1..=BLOCKS).filter(|&block| is_free(card, block)).count()
+}
+
+// the blocks of the save starting at a block, a broken chain ends early
+fn chain(card: &[u8], first: usize) -> Vec<usize> {
+    let mut blocks = vec![first];
+    let mut block = first;
+    while blocks.len() < BLOCKS {
+        let entry = entry(card, block);
+        let next = u16::from_le_bytes([entry[8], entry[9]]);
+        if next == NO_BLOCK || next as usize >= BLOCKS || entry[0] == LAST {
+            break;
+        }
+        block = next as usize + 1;
+        blocks.push(block);
+    }
+    blocks
+}
+
+pub fn saves(card: &[u8]) -> Vec<Save> {
+    let mut saves = vec![];
+    for block in (1..=BLOCKS).filter(|&block| entry(card, block)[0] == FIRST) {
+        // region, product code and the name the game gave it
+        let file_name = &entry(card, block)[0x0a..0x1e];
+        let (region, rest) = file_name.split_at(2);
+        let (product, name) = rest.split_at(10);
+
+        let title_frame = &card[block * BLOCK_SIZE..block * BLOCK_SIZE + FRAME_SIZE];
+        let (icon_frames, title) = match &title_frame[0..2] {
+            b"SC" => (
+                match title_frame[2] {
+                    flag @ 0x11..=0x13 => (flag - 0x10) as usize,
+                    _ => 0,
+                },
+                shift_jis(&title_frame[4..0x44]),
+            ),
+            _ => (0, String::new()),
+        };
+
+        saves.push(Save {
+            block,
+            region: text(region),
+            product: text(product),
+            name: text(name),
+            blocks: chain(card, block).len(),
+            icon_frames,
+            title,
+        });
+    }
+    saves
+}
+
+// a .mcs file is the directory entry of the first block and the blocks of
+// the save
+pub fn export(card: &[u8], block: usize) -> anyhow::Result<Vec<u8>> {
+    if !(1..=BLOCKS).contains(&block) || entry(card, block)[0] != FIRST {
+        bail!("No save starts at block {}", block);
+    }
+    let mut save = entry(card, block).to_vec();
+    save[8..10].copy_from_slice(&NO_BLOCK.to_le_bytes());
+    memcard::set_checksum(&mut save);
+    for block in chain(card, block) {
+        save.extend(&card[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]);
+    }
+    Ok(save)
+}
+
+// into the first free blocks, returns where the save starts
+pub fn import(card: &mut [u8], save: &[u8]) -> anyhow::Result<usize> {
+    if save.len() < FRAME_SIZE + BLOCK_SIZE
+        || !(save.len() - FRAME_SIZE).is_multiple_of(BLOCK_SIZE)
+        || save[0] != FIRST
+    {
+        bail!("Not a .mcs save");
+    }
+    let file_name = &save[0x0a..0x1e];
+    let taken = |block| {
+        let entry = entry(card, block);
+        entry[0] == FIRST && entry[0x0a..0x1e] == *file_name
+    };
+    if (1..=BLOCKS).any(taken) {
+        bail!("The card already has a save named {}", text(file_name));
+    }
+    let count = (save.len() - FRAME_SIZE) / BLOCK_SIZE;
+    let free = (1..=BLOCKS).filter(|&block| is_free(card, block));
+    let blocks: Vec<usize> = free.take(count).collect();
+    if blocks.len() < count {
+        bail!("The save needs {} blocks and only {} are free", count, blocks.len());
+    }
+
+    for (index, &block) in blocks.iter().enumerate() {
+        // the rest of the blocks only have their state and the next one
+        let mut entry = [0; FRAME_SIZE];
+        match index {
+            0 => entry.copy_from_slice(&save[..FRAME_SIZE]),
+            _ if index == count - 1 => entry[0] = LAST,
+            _ => entry[0] = MIDDLE,
+        }
+        let next = blocks.get(index + 1).map_or(NO_BLOCK, |&next| (next - 1) as u16);
+        entry[8..10].copy_from_slice(&next.to_le_bytes());
+        memcard::set_checksum(&mut entry);
+        card[block * FRAME_SIZE..(block + 1) * FRAME_SIZE].copy_from_slice(&entry);
+
+        let data = &save[FRAME_SIZE + index * BLOCK_SIZE..FRAME_SIZE + (index + 1) * BLOCK_SIZE];
+        card[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].copy_from_slice(data);
+    }
+    Ok(blocks[0])
+}
+
+// ascii up to the first zero
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// the titles are mostly full width letters, kana and symbols, the kanji would
+// need the whole table and come out as '?'
+fn shift_jis(bytes: &[u8]) -> String {
+    let mut title = String::new();
+    let mut bytes = bytes.iter().copied().take_while(|&byte| byte != 0);
+    while let Some(byte) = bytes.next() {
+        let single = match byte {
+            0x20..=0x7e => Some(byte as char),
+            // half width katakana
+            0xa1..=0xdf => char::from_u32(0xff61 + (byte - 0xa1) as u32),
+            0x81..=0x9f | 0xe0..=0xef => None,
+            _ => Some('?'),
+        };
+        if let Some(single) = single {
+            title.push(single);
+            continue;
+        }
+        let Some(low) = bytes.next() else {
+            break;
+        };
+        let index = low.wrapping_sub(if low < 0x80 { 0x40 } else { 0x41 }) as u32;
+        let character = match (byte, low) {
+            (0x81, 0x40..=0xac) if low != 0x7f => SYMBOLS.chars().nth(index as usize),
+            (0x82, 0x4f..=0x58) => char::from_u32('0' as u32 + (low - 0x4f) as u32),
+            (0x82, 0x60..=0x79) => char::from_u32('A' as u32 + (low - 0x60) as u32),
+            (0x82, 0x81..=0x9a) => char::from_u32('a' as u32 + (low - 0x81) as u32),
+            (0x82, 0x9f..=0xf1) => char::from_u32(0x3041 + (low - 0x9f) as u32),
+            (0x83, 0x40..=0x96) if low != 0x7f => char::from_u32(0x30a1 + index),
+            _ => None,
+        };
+        title.push(character.unwrap_or('?'));
+    }
+    title.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a save of some blocks, each filled with its number
+    fn save(name: &str, title: &[u8], blocks: usize) -> Vec<u8> {
+        let mut save = vec![0; FRAME_SIZE];
+        save[0] = FIRST;
+        save[4..8].copy_from_slice(&((blocks * BLOCK_SIZE) as u32).to_le_bytes());
+        save[8..10].copy_from_slice(&NO_BLOCK.to_le_bytes());
+        save[0x0a..0x0a + name.len()].copy_from_slice(name.as_bytes());
+        memcard::set_checksum(&mut save);
+        for block in 0..blocks {
+            save.extend([block as u8; BLOCK_SIZE]);
+        }
+        save[FRAME_SIZE..FRAME_SIZE + 4].copy_from_slice(&[b'S', b'C', 0x13, 0x01]);
+        save[FRAME_SIZE + 4..FRAME_SIZE + 4 + title.len()].copy_from_slice(title);
+        save[FRAME_SIZE + 4 + title.len()..FRAME_SIZE + 0x44].fill(0);
+        save
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(SYMBOLS.chars().count(), 108);
+        // full width "SPYRO 2", a space, then "セーブ" and a kanji
+        let title = [
+            0x82, 0x72, 0x82, 0x6f, 0x82, 0x78, 0x82, 0x71, 0x82, 0x6e, 0x81, 0x40, 0x82, 0x51,
+            0x81, 0x40, 0x83, 0x5a, 0x81, 0x5b, 0x83, 0x75, 0x88, 0x9f, 0x81, 0x40, 0x00, 0x82,
+        ];
+        assert_eq!(shift_jis(&title), "SPYRO 2 セーブ?");
+        assert_eq!(shift_jis(b"Crash (1)\xb1"), "Crash (1)ｱ");
+    }
+
+    #[test]
+    fn import_and_list() {
+        let mut card = memcard::formatted();
+        let first = save("BASCUS-94163SPYRO", b"\x82\x60 save", 1);
+        let second = save("BISLPS-00001GAME0", b"Game", 3);
+        assert_eq!(import(&mut card, &first).unwrap(), 1);
+        assert_eq!(import(&mut card, &second).unwrap(), 2);
+        assert_eq!(free_blocks(&card), 11);
+
+        let list = saves(&card);
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list[1],
+            Save {
+                block: 2,
+                region: "BI".to_string(),
+                product: "SLPS-00001".to_string(),
+                name: "GAME0".to_string(),
+                blocks: 3,
+                icon_frames: 3,
+                title: "Game".to_string(),
+            }
+        );
+        assert_eq!(list[0].title, "A save");
+
+        // linked through the directory, with the checksums the bios checks
+        assert_eq!(entry(&card, 2)[8..10], [0x02, 0x00]);
+        assert_eq!(entry(&card, 3)[0..2], [MIDDLE, 0x00]);
+        assert_eq!(entry(&card, 4)[0..10], [LAST, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        for block in 1..=BLOCKS {
+            let entry = entry(&card, block);
+            assert_eq!(entry.iter().fold(0, |xor, byte| xor ^ byte), 0);
+        }
+        assert_eq!(card[4 * BLOCK_SIZE + 100], 0x02);
+
+        // the parts of the name are split before they are decoded
+        let mut odd = save("", b"Odd", 1);
+        odd[0x0a..0x10].copy_from_slice(b"\xe9ISLP\xe9");
+        memcard::set_checksum(&mut odd[..FRAME_SIZE]);
+        assert_eq!(import(&mut card, &odd).unwrap(), 5);
+        let list = saves(&card);
+        assert_eq!(list[2].region, "\u{fffd}I");
+        assert_eq!(list[2].product, "SLP\u{fffd}");
+
+        // the same save cannot go in twice, nor a save that does not fit
+        assert!(import(&mut card, &second).is_err());
+        assert!(import(&mut card, &save("BISLPS-00002BIG", b"", 12)).is_err());
+    }
+
+    #[test]
+    fn export_save() {
+        let mut card = memcard::formatted();
+        let mcs = save("BESLES-00001DATA", b"Data", 2);
+        import(&mut card, &save("BESLES-00002ONE", b"One", 1)).unwrap();
+        import(&mut card, &mcs).unwrap();
+        assert_eq!(export(&card, 2).unwrap(), mcs);
+        assert!(export(&card, 3).is_err());
+        assert!(export(&card, 16).is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let mut card = memcard::formatted();
+        import(&mut card, &save("BASCUS-00001A", b"A", 2)).unwrap();
+
+        let gme = encode(&card, Format::Gme).unwrap();
+        assert_eq!(gme.len(), GME_HEADER + CARD_SIZE);
+        assert_eq!(gme[21..25], [b'M', FIRST, LAST, 0xa0]);
+        assert_eq!(gme[38..41], [0x01, 0xff, 0xff]);
+        // the signature was checked against a separate hmac-sha1 and aes, the
+        // seed is left empty
+        let vmp = encode(&card, Format::Vmp).unwrap();
+        assert_eq!(vmp.len(), VMP_HEADER + CARD_SIZE);
+        assert_eq!(vmp[..12], [0x00, b'P', b'M', b'V', 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(vmp[VMP_SEED..VMP_SIGNATURE], [0; 20]);
+        let signature = [
+            0x66, 0x9f, 0xbc, 0x33, 0x18, 0x62, 0xea, 0x52, 0x5b, 0x21, 0x43, 0x1f, 0x33, 0x46,
+            0xc0, 0x9b, 0x47, 0x31, 0x32, 0xc4,
+        ];
+        assert_eq!(vmp[VMP_SIGNATURE..VMP_SIGNATURE + 20], signature);
+        assert!(vmp[VMP_SIGNATURE + 20..VMP_HEADER].iter().all(|&byte| byte == 0));
+        assert_eq!(vmp[VMP_HEADER..], card);
+
+        let path = std::env::temp_dir().join(format!("psiemu_formats_{}.gme", std::process::id()));
+        std::fs::write(&path, &gme).unwrap();
+        assert_eq!(read(&path).unwrap(), card);
+        write(&path.with_extension("vmp"), &card).unwrap();
+        assert_eq!(std::fs::read(path.with_extension("vmp")).unwrap(), vmp);
+        assert_eq!(read(&path.with_extension("vmp")).unwrap(), card);
+        std::fs::write(&path, &gme[..1000]).unwrap();
+        assert!(read(&path).is_err());
+    }
+}
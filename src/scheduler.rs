@@ -0,0 +1,97 @@
+// Cycle counter and the device events waiting on it
+
+use crate::dma::Port;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // start or end of the hblank, or of a gpu scanline
+    Hblank,
+    // next point where a timer raises its irq
+    Timers,
+    // end of a dma transfer
+    Dma(Port),
+    // next response or sector of the cdrom drive
+    Cdrom,
+    // next output sample of the spu
+    Spu,
+    // end of a byte on the joypad port, or its /ack
+    Sio,
+}
+
+pub struct Scheduler {
+    cycles: u64,
+    // only a handful of events are ever waiting, a list is enough
+    events: Vec<(u64, Event)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            cycles: 0,
+            events: Vec::with_capacity(16),
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    // an event that was already waiting is moved to the new time
+    pub fn schedule(&mut self, event: Event, delay: u32) {
+        self.events.retain(|&(_, waiting)| waiting != event);
+        self.events.push((self.cycles + delay as u64, event));
+    }
+
+    // earliest event that is due, in the order they were scheduled on ties
+    pub fn next(&mut self) -> Option<Event> {
+        let (index, &(time, event)) = self
+            .events
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (time, _))| *time)?;
+
+        if time > self.cycles {
+            return None;
+        }
+        self.events.remove(index);
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timers, 10);
+        scheduler.schedule(Event::Hblank, 5);
+        scheduler.schedule(Event::Dma(Port::Gpu), 5);
+
+        assert_eq!(scheduler.next(), None);
+        scheduler.advance(10);
+        assert_eq!(scheduler.next(), Some(Event::Hblank));
+        assert_eq!(scheduler.next(), Some(Event::Dma(Port::Gpu)));
+        assert_eq!(scheduler.next(), Some(Event::Timers));
+        assert_eq!(scheduler.next(), None);
+    }
+
+    #[test]
+    fn reschedule() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timers, 10);
+        scheduler.advance(5);
+        scheduler.schedule(Event::Timers, 10);
+
+        scheduler.advance(5);
+        assert_eq!(scheduler.next(), None);
+        scheduler.advance(5);
+        assert_eq!(scheduler.next(), Some(Event::Timers));
+        assert_eq!(scheduler.next(), None);
+    }
+}
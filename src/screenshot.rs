@@ -0,0 +1,90 @@
+// Dumps the displayed frames to png files
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use crate::gpu::Frame;
+
+pub struct FrameDump {
+    directory: PathBuf,
+    every: Option<u32>,
+    frames: Vec<u32>,
+}
+
+impl FrameDump {
+    pub fn new(directory: PathBuf, every: Option<u32>, frames: Vec<u32>) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(FrameDump {
+            directory,
+            every,
+            frames,
+        })
+    }
+
+    // with no frames given every one of them is dumped
+    pub fn wants(&self, frame: u32) -> bool {
+        let on_interval = match self.every {
+            Some(every) => every != 0 && frame.is_multiple_of(every),
+            None => self.frames.is_empty(),
+        };
+        on_interval || self.frames.contains(&frame)
+    }
+
+    pub fn write(&self, number: u32, frame: &Frame) -> anyhow::Result<()> {
+        let path = self.directory.join(format!("frame_{:06}.png", number));
+        let file = BufWriter::new(File::create(path)?);
+
+        let mut encoder = png::Encoder::new(file, frame.width, frame.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&frame.pixels)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule() {
+        let name = format!("psiemu_schedule_{}", std::process::id());
+        let directory = std::env::temp_dir().join(name);
+
+        let all = FrameDump::new(directory.clone(), None, vec![]).unwrap();
+        assert!(all.wants(1) && all.wants(2));
+
+        let every = FrameDump::new(directory.clone(), Some(10), vec![3]).unwrap();
+        assert!(every.wants(3));
+        assert!(!every.wants(5));
+        assert!(every.wants(20));
+
+        let some = FrameDump::new(directory, None, vec![7, 9]).unwrap();
+        assert!(some.wants(7) && some.wants(9));
+        assert!(!some.wants(8));
+    }
+
+    #[test]
+    fn png_file() {
+        let name = format!("psiemu_png_file_{}", std::process::id());
+        let directory = std::env::temp_dir().join(name);
+        let dump = FrameDump::new(directory.clone(), None, vec![]).unwrap();
+        let frame = Frame {
+            width: 2,
+            height: 1,
+            pixels: vec![0xff, 0, 0, 0, 0, 0xff],
+        };
+        dump.write(42, &frame).unwrap();
+
+        let file = File::open(directory.join("frame_000042.png")).unwrap();
+        let mut reader = png::Decoder::new(file).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(pixels, frame.pixels);
+    }
+}
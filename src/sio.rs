@@ -0,0 +1,304 @@
+// Serial port 0, the joypad port where the controllers and memory cards
+// sit, one of each on every slot. bytes go out one at a time and the device
+// answers with one of its own, then pulses /ack if it has more to say
+
+use crate::controller::{Controller, Kind};
+use crate::memcard::MemoryCard;
+
+// cpu cycles from the end of a byte to the /ack of the controller, the
+// memory cards are faster
+const ACK_CYCLES: u32 = 338;
+const CARD_ACK_CYCLES: u32 = 170;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transfer {
+    Idle,
+    // the byte is on the wire, the answer arrives at the end
+    Sending,
+    // the answer arrived and /ack is coming
+    WaitingAck,
+}
+
+// who answers the bytes, from the address byte until /joy goes high again
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Device {
+    Controller,
+    MemoryCard,
+}
+
+pub struct Sio {
+    controllers: [Controller; 2],
+    cards: [Option<MemoryCard>; 2],
+    selected: Option<Device>,
+    // byte being sent and the one that came back
+    tx: u8,
+    rx: Option<u8>,
+    mode: u16,
+    control: u16,
+    baud: u16,
+    // /ack was pulled low since the last byte
+    ack: bool,
+    irq: bool,
+    transfer: Transfer,
+    cycles: u32,
+}
+
+impl Sio {
+    // digital pads on both slots until something else is plugged in, and no
+    // memory cards
+    pub fn new() -> Self {
+        Sio {
+            controllers: [Controller::new(Kind::Digital), Controller::new(Kind::Digital)],
+            cards: [None, None],
+            selected: None,
+            tx: 0,
+            rx: None,
+            mode: 0,
+            control: 0,
+            baud: 0,
+            ack: false,
+            irq: false,
+            transfer: Transfer::Idle,
+            cycles: 0,
+        }
+    }
+
+    pub fn controller(&mut self, slot: usize) -> &mut Controller {
+        &mut self.controllers[slot]
+    }
+
+    pub fn insert_memory_card(&mut self, slot: usize, card: MemoryCard) {
+        self.cards[slot] = Some(card);
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            // a single byte comes out at any width
+            0x0..=0x3 => self.rx.take().unwrap_or(0xff) as u32,
+            0x4..=0x7 => self.status() >> ((offset & 3) * 8),
+            0x8..=0x9 => (self.mode >> ((offset & 1) * 8)) as u32,
+            0xa..=0xb => (self.control >> ((offset & 1) * 8)) as u32,
+            0xe..=0xf => (self.baud >> ((offset & 1) * 8)) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0x0 => {
+                self.tx = value as u8;
+                self.ack = false;
+                self.transfer = Transfer::Sending;
+                // 8 bits at the reload of the baud rate timer each
+                self.cycles = (self.baud as u32 * 8).max(1);
+            }
+            0x8 => self.mode = value as u16,
+            0xa => self.write_control(value as u16),
+            0xe => self.baud = value as u16,
+            _ => println!("Write to unknown sio register {:x}", offset),
+        }
+    }
+
+    fn write_control(&mut self, value: u16) {
+        // /joy went high or the slot changed, the device lets go
+        if value & 0x02 == 0 || (value ^ self.control) & 0x2000 != 0 {
+            self.deselect();
+        }
+        // acknowledge and reset are not kept
+        self.control = value & !0x50;
+
+        if value & 0x10 != 0 {
+            self.irq = false;
+        }
+        if value & 0x40 != 0 {
+            self.mode = 0;
+            self.control = 0;
+            self.baud = 0;
+            self.rx = None;
+            self.ack = false;
+            self.irq = false;
+            self.transfer = Transfer::Idle;
+            self.deselect();
+        }
+    }
+
+    fn deselect(&mut self) {
+        self.selected = None;
+        for controller in self.controllers.iter_mut() {
+            controller.end_transfer();
+        }
+        for card in self.cards.iter_mut().flatten() {
+            card.end_transfer();
+        }
+    }
+
+    fn status(&self) -> u32 {
+        // the transmit buffer is always free, a write starts at once
+        let mut status = 0x01;
+        status |= (self.rx.is_some() as u32) << 1;
+        status |= ((self.transfer != Transfer::Sending) as u32) << 2;
+        status |= (self.ack as u32) << 7;
+        status |= (self.irq as u32) << 9;
+        status
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut left = cycles;
+        while self.transfer != Transfer::Idle && self.cycles <= left {
+            left -= self.cycles;
+            self.cycles = 0;
+            self.fire();
+        }
+        if self.transfer != Transfer::Idle {
+            self.cycles -= left;
+        }
+    }
+
+    pub fn cycles_until_event(&self) -> Option<u32> {
+        (self.transfer != Transfer::Idle).then_some(self.cycles)
+    }
+
+    fn fire(&mut self) {
+        match self.transfer {
+            Transfer::Sending => {
+                let (value, ack) = self.exchange();
+                self.rx = Some(value);
+                if ack {
+                    self.transfer = Transfer::WaitingAck;
+                    self.cycles = match self.selected {
+                        Some(Device::MemoryCard) => CARD_ACK_CYCLES,
+                        _ => ACK_CYCLES,
+                    };
+                } else {
+                    self.transfer = Transfer::Idle;
+                    self.deselect();
+                }
+            }
+            Transfer::WaitingAck => {
+                self.transfer = Transfer::Idle;
+                self.ack = true;
+                if self.control & 0x1000 != 0 {
+                    self.irq = true;
+                }
+            }
+            Transfer::Idle => (),
+        }
+    }
+
+    // nothing drives the line when no device answers, it reads as 0xff
+    fn exchange(&mut self) -> (u8, bool) {
+        if self.control & 0x02 == 0 {
+            return (0xff, false);
+        }
+        let slot = ((self.control >> 13) & 1) as usize;
+        match (self.selected, self.tx) {
+            (Some(Device::Controller), byte) | (None, byte @ 0x01) => {
+                self.selected = Some(Device::Controller);
+                self.controllers[slot].transfer(byte)
+            }
+            (Some(Device::MemoryCard), byte) | (None, byte @ 0x81) => {
+                match self.cards[slot].as_mut() {
+                    Some(card) => {
+                        self.selected = Some(Device::MemoryCard);
+                        card.transfer(byte)
+                    }
+                    None => (0xff, false),
+                }
+            }
+            (None, _) => (0xff, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::button;
+    use crate::memcard;
+
+    fn send(sio: &mut Sio, byte: u8) -> (u8, bool) {
+        sio.write(0x0, byte as u32);
+        sio.step(sio.cycles_until_event().unwrap());
+        let value = sio.read(0x0) as u8;
+        let ack = sio.transfer == Transfer::WaitingAck;
+        if ack {
+            sio.step(sio.cycles_until_event().unwrap());
+        }
+        (value, ack)
+    }
+
+    #[test]
+    fn read_pad() {
+        let mut sio = Sio::new();
+        sio.controller(0).set_buttons(button("circle").unwrap());
+        sio.write(0xe, 0x88);
+        sio.write(0xa, 0x1003);
+
+        sio.write(0x0, 0x01);
+        assert_eq!(sio.status() & 0x06, 0x00);
+        sio.step(0x88 * 8);
+        assert_eq!(sio.status() & 0x06, 0x06);
+        assert_eq!(sio.read(0x0), 0xff);
+        assert!(!sio.irq());
+        sio.step(ACK_CYCLES);
+        assert!(sio.irq());
+        assert_eq!(sio.status() & 0x280, 0x280);
+        sio.write(0xa, 0x1013);
+        assert!(!sio.irq());
+
+        let replies: Vec<(u8, bool)> = [0x42, 0x00, 0x00, 0x00]
+            .into_iter()
+            .map(|byte| send(&mut sio, byte))
+            .collect();
+        assert_eq!(replies, [(0x41, true), (0x5a, true), (0xff, true), (0xdf, false)]);
+    }
+
+    #[test]
+    fn slots() {
+        let mut sio = Sio::new();
+        // nothing answers without /joy low, or on an unknown address
+        sio.write(0xa, 0x0001);
+        assert_eq!(send(&mut sio, 0x01), (0xff, false));
+        sio.write(0xa, 0x0003);
+        assert_eq!(send(&mut sio, 0x99), (0xff, false));
+
+        // the second slot, then raising /joy ends the transfer
+        sio.write(0xa, 0x2003);
+        sio.controller(1).set_buttons(button("up").unwrap());
+        assert_eq!(send(&mut sio, 0x01), (0xff, true));
+        assert_eq!(send(&mut sio, 0x42), (0x41, true));
+        sio.write(0xa, 0x0000);
+        sio.write(0xa, 0x2003);
+        assert_eq!(send(&mut sio, 0x42), (0xff, false));
+        assert_eq!(send(&mut sio, 0x01), (0xff, true));
+        assert_eq!(send(&mut sio, 0x42), (0x41, true));
+        assert_eq!(send(&mut sio, 0x00), (0x5a, true));
+        assert_eq!(send(&mut sio, 0x00), (0xef, true));
+    }
+
+    #[test]
+    fn memory_card() {
+        let mut sio = Sio::new();
+        sio.write(0xa, 0x2003);
+        // no card in the second slot yet
+        assert_eq!(send(&mut sio, 0x81), (0xff, false));
+
+        sio.insert_memory_card(1, MemoryCard::new(memcard::formatted(), None));
+        sio.write(0xa, 0x0000);
+        sio.write(0xa, 0x2003);
+        assert_eq!(send(&mut sio, 0x81), (0xff, true));
+        let replies: Vec<u8> = [b'S', 0, 0, 0, 0, 0, 0, 0, 0]
+            .into_iter()
+            .map(|byte| send(&mut sio, byte).0)
+            .collect();
+        assert_eq!(replies, [0x08, 0x5a, 0x5d, 0x5c, 0x5d, 0x04, 0x00, 0x00, 0x80]);
+
+        // the pad on the same slot still answers its own address
+        assert_eq!(send(&mut sio, 0x01), (0xff, true));
+        assert_eq!(send(&mut sio, 0x42), (0x41, true));
+    }
+}
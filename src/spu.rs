@@ -0,0 +1,1027 @@
+// Sound processing unit, 24 adpcm voices playing from their own 512 KiB of
+// sound ram, mixed with the cd audio and the reverb into a 44.1 kHz stereo
+// output
+
+use std::collections::VecDeque;
+
+// cpu cycles between two output samples
+pub const SAMPLE_CYCLES: u32 = 768;
+
+const RAM_SIZE: usize = 512 * 1024;
+const VOICES: usize = 24;
+const FIFO_SIZE: usize = 32;
+
+// register offsets from 0x1f801c00, the voices come first with 16 bytes
+// each, then the control registers
+const MAIN_VOLUME: u32 = 0x180;
+const REVERB_VOLUME: u32 = 0x184;
+const KEY_ON: u32 = 0x188;
+const KEY_OFF: u32 = 0x18c;
+const PITCH_MODULATION: u32 = 0x190;
+const NOISE: u32 = 0x194;
+const REVERB_MODE: u32 = 0x198;
+const ENDX: u32 = 0x19c;
+const REVERB_BASE: u32 = 0x1a2;
+const IRQ_ADDRESS: u32 = 0x1a4;
+const TRANSFER_ADDRESS: u32 = 0x1a6;
+const TRANSFER_FIFO: u32 = 0x1a8;
+const CONTROL: u32 = 0x1aa;
+const STATUS: u32 = 0x1ae;
+const CD_VOLUME: u32 = 0x1b0;
+const CURRENT_VOLUME: u32 = 0x1b8;
+const REVERB: u32 = 0x1c0;
+// current volume of each voice, left and right
+const VOICE_VOLUMES: u32 = 0x200;
+
+// adpcm filters, the weights of the last two samples in 1/64
+const FILTERS: [(i32, i32); 5] = [(0, 0), (60, 0), (115, -52), (98, -55), (122, -60)];
+
+// how an envelope or a volume sweep moves, the step is 0-3 for "+7..+4"
+// when increasing and "-8..-5" when decreasing
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    exponential: bool,
+    decrease: bool,
+    shift: u32,
+    step: u32,
+}
+
+impl Ramp {
+    // the counter holds the samples left before the next step
+    fn advance(&self, level: i16, counter: &mut u32) -> i16 {
+        if *counter > 0 {
+            *counter -= 1;
+            return level;
+        }
+
+        let step = match self.decrease {
+            true => -8 + self.step as i32,
+            false => 7 - self.step as i32,
+        };
+        let mut cycles = 1 << self.shift.saturating_sub(11);
+        let mut step = step << 11u32.saturating_sub(self.shift);
+        if self.exponential && !self.decrease && level > 0x6000 {
+            cycles *= 4;
+        }
+        if self.exponential && self.decrease {
+            step = (step * level as i32) >> 15;
+        }
+
+        *counter = cycles - 1;
+        (level as i32 + step).clamp(0, 0x7fff) as i16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    phase: Phase,
+    level: i16,
+    counter: u32,
+}
+
+impl Envelope {
+    fn tick(&mut self, adsr: u32) {
+        let ramp = match self.phase {
+            Phase::Attack => Ramp {
+                exponential: adsr & 0x8000 != 0,
+                decrease: false,
+                shift: (adsr >> 10) & 0x1f,
+                step: (adsr >> 8) & 3,
+            },
+            Phase::Decay => Ramp {
+                exponential: true,
+                decrease: true,
+                shift: (adsr >> 4) & 0xf,
+                step: 0,
+            },
+            Phase::Sustain => Ramp {
+                exponential: adsr & 0x80000000 != 0,
+                decrease: adsr & 0x40000000 != 0,
+                shift: (adsr >> 24) & 0x1f,
+                step: (adsr >> 22) & 3,
+            },
+            Phase::Release => Ramp {
+                exponential: adsr & 0x00200000 != 0,
+                decrease: true,
+                shift: (adsr >> 16) & 0x1f,
+                step: 0,
+            },
+            Phase::Off => return,
+        };
+        self.level = ramp.advance(self.level, &mut self.counter);
+
+        let sustain_level = ((adsr & 0xf) as i32 + 1) * 0x800;
+        let next = match self.phase {
+            Phase::Attack if self.level == 0x7fff => Phase::Decay,
+            Phase::Decay if self.level as i32 <= sustain_level => Phase::Sustain,
+            Phase::Release if self.level == 0 => Phase::Off,
+            phase => phase,
+        };
+        if next != self.phase {
+            self.phase = next;
+            self.counter = 0;
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = Phase::Attack;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    fn key_off(&mut self) {
+        if self.phase != Phase::Off {
+            self.phase = Phase::Release;
+            self.counter = 0;
+        }
+    }
+}
+
+// a fixed volume, or a sweep when bit 15 is set
+#[derive(Debug, Clone, Copy, Default)]
+struct Volume {
+    register: u16,
+    level: i16,
+    counter: u32,
+}
+
+impl Volume {
+    fn write(&mut self, value: u16) {
+        self.register = value;
+        self.counter = 0;
+        if value & 0x8000 == 0 {
+            self.level = (value << 1) as i16;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.register & 0x8000 == 0 {
+            return;
+        }
+
+        let ramp = Ramp {
+            exponential: self.register & 0x4000 != 0,
+            decrease: self.register & 0x2000 != 0,
+            shift: (self.register as u32 >> 2) & 0x1f,
+            step: self.register as u32 & 3,
+        };
+        let level = ramp.advance(self.level.saturating_abs(), &mut self.counter);
+        // the negative phase sweeps an inverted volume
+        self.level = match self.register & 0x1000 {
+            0 => level,
+            _ => -level,
+        };
+    }
+
+    fn apply(&self, sample: i32) -> i32 {
+        (sample * self.level as i32) >> 15
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    volume: [Volume; 2],
+    pitch: u16,
+    // in units of 8 bytes like every address of the spu
+    start: u16,
+    adsr: u32,
+    repeat: u16,
+
+    // block being played, in bytes
+    address: u32,
+    // bits 12 and up are the sample in the block, the rest is the fraction
+    counter: u32,
+    flags: u8,
+    samples: [i16; 28],
+    // last two decoded samples, for the filters
+    history: [i16; 2],
+    // last sample of the previous block, to interpolate with
+    previous: i16,
+    envelope: Envelope,
+    // after the envelope, it modulates the pitch of the next voice
+    output: i16,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Voice {
+            volume: [Volume::default(); 2],
+            pitch: 0,
+            start: 0,
+            adsr: 0,
+            repeat: 0,
+            address: 0,
+            counter: 0,
+            flags: 0,
+            samples: [0; 28],
+            history: [0; 2],
+            previous: 0,
+            envelope: Envelope {
+                phase: Phase::Off,
+                level: 0,
+                counter: 0,
+            },
+            output: 0,
+        }
+    }
+
+    // 16 bytes, a header with the shift and filter, the loop flags and
+    // then 28 samples of 4 bits
+    fn decode_block(&mut self, ram: &[u8]) {
+        let block: [u8; 16] = std::array::from_fn(|i| ram[(self.address as usize + i) % RAM_SIZE]);
+        let shift = match block[0] & 0xf {
+            shift @ 0..=12 => shift,
+            // the reserved shifts act like 9
+            _ => 9,
+        };
+        let (weight_old, weight_older) = FILTERS[((block[0] >> 4) & 7).min(4) as usize];
+        self.flags = block[1];
+        if self.flags & 0x04 != 0 {
+            self.repeat = (self.address / 8) as u16;
+        }
+
+        for (i, sample) in self.samples.iter_mut().enumerate() {
+            let nibble = (block[2 + i / 2] >> ((i & 1) * 4)) as i32;
+            let value = ((nibble << 28) >> 16) >> shift;
+            let [old, older] = self.history.map(|sample| sample as i32);
+            let value = value + ((old * weight_old + older * weight_older + 32) >> 6);
+            *sample = value.clamp(-0x8000, 0x7fff) as i16;
+            self.history = [*sample, self.history[0]];
+        }
+    }
+
+    // linear interpolation between the sample before and the current one,
+    // the hardware uses a 4 point gaussian filter instead
+    fn interpolate(&self) -> i16 {
+        let index = (self.counter >> 12) as usize;
+        let before = match index {
+            0 => self.previous,
+            _ => self.samples[index - 1],
+        } as i32;
+        let fraction = (self.counter & 0xfff) as i32;
+        (before + (((self.samples[index] as i32 - before) * fraction) >> 12)) as i16
+    }
+
+    fn key_on(&mut self) {
+        self.address = self.start as u32 * 8;
+        self.counter = 0;
+        self.history = [0; 2];
+        self.previous = 0;
+        self.envelope.key_on();
+    }
+}
+
+// pseudo random generator the voices in noise mode play instead
+struct Noise {
+    level: u16,
+    timer: i32,
+}
+
+impl Noise {
+    fn tick(&mut self, control: u16) -> i16 {
+        let shift = (control >> 10) & 0xf;
+        let step = ((control >> 8) & 3) as i32 + 4;
+        let level = self.level;
+        let parity = (level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10);
+
+        self.timer -= step;
+        if self.timer < 0 {
+            self.level = (self.level << 1) | ((parity ^ 1) & 1);
+            self.timer += 0x20000 >> shift;
+            if self.timer < 0 {
+                self.timer += 0x20000 >> shift;
+            }
+        }
+        self.level as i16
+    }
+}
+
+// the reverb registers, from 0x1c0, plus its output volume and work area.
+// every address is in units of 8 bytes relative to the moving buffer
+// address, and the pairs are left and right
+#[derive(Debug, Default)]
+struct Reverb {
+    apf_offset: [u32; 2],
+    iir: i16,
+    comb_volume: [i16; 4],
+    wall: i16,
+    apf_volume: [i16; 2],
+    same: [u32; 2],
+    comb: [[u32; 2]; 4],
+    same_source: [u32; 2],
+    diff: [u32; 2],
+    diff_source: [u32; 2],
+    apf: [[u32; 2]; 2],
+    input_volume: [i16; 2],
+    output_volume: [i16; 2],
+    // start of the work area, it goes up to the end of the ram
+    base: u32,
+    address: u32,
+    // it runs at 22.05 kHz, the input of every other sample waits here
+    pending: Option<[i32; 2]>,
+    output: [i32; 2],
+}
+
+impl Reverb {
+    fn write(&mut self, offset: u32, value: u16) {
+        let channel = ((offset >> 1) & 1) as usize;
+        let address = value as u32 * 8;
+        match offset {
+            0x00 | 0x02 => self.apf_offset[channel] = address,
+            0x04 => self.iir = value as i16,
+            0x06..=0x0c => self.comb_volume[((offset - 0x06) >> 1) as usize] = value as i16,
+            0x0e => self.wall = value as i16,
+            0x10 | 0x12 => self.apf_volume[channel] = value as i16,
+            0x14 | 0x16 => self.same[channel] = address,
+            0x18..=0x1e => self.comb[((offset - 0x18) >> 2) as usize][channel] = address,
+            0x20 | 0x22 => self.same_source[channel] = address,
+            0x24 | 0x26 => self.diff[channel] = address,
+            0x28..=0x2e => self.comb[((offset - 0x20) >> 2) as usize][channel] = address,
+            0x30 | 0x32 => self.diff_source[channel] = address,
+            0x34..=0x3a => self.apf[((offset - 0x34) >> 2) as usize][channel] = address,
+            _ => self.input_volume[channel] = value as i16,
+        }
+    }
+
+    fn set_base(&mut self, value: u16) {
+        self.base = value as u32 * 8;
+        self.address = self.base;
+    }
+
+    // byte address in the work area, the offset is added and back is taken
+    // away, both in bytes
+    fn ram_address(&self, offset: u32, back: u32) -> usize {
+        let size = RAM_SIZE as u32 - self.base;
+        let relative = self.address - self.base + offset % size + size - back;
+        (self.base + relative % size) as usize
+    }
+}
+
+pub struct Spu {
+    ram: Vec<u8>,
+    voices: [Voice; VOICES],
+    // what was last written, for the registers that read back as is
+    registers: [u16; 0x200],
+    main_volume: [Volume; 2],
+    pitch_modulation: u32,
+    noise_mode: u32,
+    // voices that reached a block with the loop end flag
+    endx: u32,
+    noise: Noise,
+    // voices that go into the reverb
+    reverb_mode: u32,
+    reverb: Reverb,
+    cd_volume: [i16; 2],
+    // position in the capture buffers, in samples
+    capture: u32,
+    control: u16,
+    irq_address: u32,
+    irq: bool,
+    transfer_address: u32,
+    fifo: VecDeque<u16>,
+    // interleaved left and right, until the frontend takes them
+    output: Vec<i16>,
+}
+
+impl Spu {
+    pub fn new() -> Self {
+        Spu {
+            ram: vec![0; RAM_SIZE],
+            voices: [Voice::new(); VOICES],
+            registers: [0; 0x200],
+            main_volume: [Volume::default(); 2],
+            pitch_modulation: 0,
+            noise_mode: 0,
+            endx: 0,
+            noise: Noise { level: 0, timer: 0 },
+            reverb_mode: 0,
+            reverb: Reverb::default(),
+            cd_volume: [0; 2],
+            capture: 0,
+            control: 0,
+            irq_address: 0,
+            irq: false,
+            transfer_address: 0,
+            fifo: VecDeque::with_capacity(FIFO_SIZE),
+            output: Vec::with_capacity(2048),
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn read(&self, offset: u32) -> u16 {
+        match offset {
+            0x000..=0x17f => {
+                let voice = &self.voices[(offset >> 4) as usize];
+                match offset & 0xf {
+                    0xc => voice.envelope.level as u16,
+                    0xe => voice.repeat,
+                    _ => self.registers[(offset >> 1) as usize],
+                }
+            }
+            ENDX => self.endx as u16,
+            0x19e => (self.endx >> 16) as u16,
+            STATUS => self.status(),
+            CURRENT_VOLUME => self.main_volume[0].level as u16,
+            0x1ba => self.main_volume[1].level as u16,
+            VOICE_VOLUMES..=0x25f => {
+                let voice = &self.voices[((offset - VOICE_VOLUMES) >> 2) as usize];
+                voice.volume[((offset >> 1) & 1) as usize].level as u16
+            }
+            _ => self.registers[(offset >> 1) as usize],
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u16) {
+        self.registers[(offset >> 1) as usize] = value;
+
+        match offset {
+            0x000..=0x17f => {
+                let voice = &mut self.voices[(offset >> 4) as usize];
+                match offset & 0xf {
+                    0x0 => voice.volume[0].write(value),
+                    0x2 => voice.volume[1].write(value),
+                    0x4 => voice.pitch = value,
+                    0x6 => voice.start = value,
+                    0x8 => voice.adsr = (voice.adsr & 0xffff0000) | value as u32,
+                    0xa => voice.adsr = (voice.adsr & 0x0000ffff) | (value as u32) << 16,
+                    0xc => voice.envelope.level = value as i16,
+                    _ => voice.repeat = value,
+                }
+            }
+            MAIN_VOLUME => self.main_volume[0].write(value),
+            0x182 => self.main_volume[1].write(value),
+            REVERB_VOLUME | 0x186 => {
+                self.reverb.output_volume[((offset >> 1) & 1) as usize] = value as i16;
+            }
+            KEY_ON | 0x18a => self.key_on(half(offset, value)),
+            KEY_OFF | 0x18e => {
+                let bits = half(offset, value);
+                for voice in (0..VOICES).filter(|voice| bits & (1 << voice) != 0) {
+                    self.voices[voice].envelope.key_off();
+                }
+            }
+            // voice 0 has nothing to be modulated by
+            PITCH_MODULATION | 0x192 => set_half(&mut self.pitch_modulation, offset, value & !1),
+            NOISE | 0x196 => set_half(&mut self.noise_mode, offset, value),
+            REVERB_MODE | 0x19a => set_half(&mut self.reverb_mode, offset, value),
+            REVERB_BASE => self.reverb.set_base(value),
+            IRQ_ADDRESS => self.irq_address = value as u32 * 8,
+            TRANSFER_ADDRESS => self.transfer_address = value as u32 * 8,
+            TRANSFER_FIFO if self.fifo.len() < FIFO_SIZE => self.fifo.push_back(value),
+            CD_VOLUME | 0x1b2 => self.cd_volume[((offset >> 1) & 1) as usize] = value as i16,
+            REVERB..=0x1ff => self.reverb.write(offset - REVERB, value),
+            CONTROL => {
+                self.control = value;
+                // clearing the enable bit acknowledges the irq
+                if value & 0x40 == 0 {
+                    self.irq = false;
+                }
+                // manual write sends what was queued on the fifo
+                if (value >> 4) & 3 == 1 {
+                    while let Some(value) = self.fifo.pop_front() {
+                        self.write_ram(value);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // the dma moves a word as two halfwords, low one first
+    pub fn dma_write(&mut self, value: u32) {
+        self.write_ram(value as u16);
+        self.write_ram((value >> 16) as u16);
+    }
+
+    pub fn dma_read(&mut self) -> u32 {
+        let low = self.read_ram() as u32;
+        let high = self.read_ram() as u32;
+        low | high << 16
+    }
+
+    // one output sample, with the cd audio that came in for it
+    pub fn clock(&mut self, cd: [i16; 2]) {
+        let noise = self.noise.tick(self.control);
+        let mut mix = [0; 2];
+        let mut reverb_input = [0; 2];
+
+        for index in 0..VOICES {
+            let modulator = self.voices[index.saturating_sub(1)].output;
+            let voice = &mut self.voices[index];
+
+            let sample = match self.noise_mode & (1 << index) {
+                0 => voice.interpolate(),
+                _ => noise,
+            };
+            voice.envelope.tick(voice.adsr);
+            voice.output = ((sample as i32 * voice.envelope.level as i32) >> 15) as i16;
+            for channel in 0..2 {
+                voice.volume[channel].tick();
+                let output = voice.volume[channel].apply(voice.output as i32);
+                mix[channel] += output;
+                if self.reverb_mode & (1 << index) != 0 {
+                    reverb_input[channel] += output;
+                }
+            }
+
+            let mut step = voice.pitch as u32;
+            if self.pitch_modulation & (1 << index) != 0 {
+                let factor = modulator as i32 + 0x8000;
+                step = ((step as i16 as i32 * factor) >> 15) as u32 & 0xffff;
+            }
+            voice.counter += step.min(0x4000);
+
+            if voice.counter >= 28 << 12 {
+                voice.counter -= 28 << 12;
+                voice.previous = voice.samples[27];
+                if voice.flags & 0x01 != 0 {
+                    self.endx |= 1 << index;
+                    voice.address = voice.repeat as u32 * 8;
+                    // without the repeat flag the voice stops there
+                    if voice.flags & 0x02 == 0 {
+                        voice.envelope.phase = Phase::Release;
+                        voice.envelope.level = 0;
+                    }
+                } else {
+                    voice.address = (voice.address + 16) % RAM_SIZE as u32;
+                }
+                self.start_block(index);
+            }
+        }
+        // muting silences the voices but not the cd
+        if self.control & 0x4000 == 0 {
+            mix = [0; 2];
+        }
+
+        let cd = [0, 1].map(|channel| (cd[channel] as i32 * self.cd_volume[channel] as i32) >> 15);
+        self.capture(cd);
+        if self.control & 0x01 != 0 {
+            for channel in 0..2 {
+                mix[channel] += cd[channel];
+                if self.control & 0x04 != 0 {
+                    reverb_input[channel] += cd[channel];
+                }
+            }
+        }
+
+        let reverb = self.reverb(reverb_input.map(saturate));
+        for channel in 0..2 {
+            self.main_volume[channel].tick();
+            let level = match self.control & 0x8000 {
+                0 => 0,
+                _ => self.main_volume[channel].apply(saturate(mix[channel] + reverb[channel])),
+            };
+            self.output.push(saturate(level) as i16);
+        }
+    }
+
+    // the hardware resamples the reverb input and output with 39 taps
+    // filters, here the two samples are averaged and the output is held
+    fn reverb(&mut self, input: [i32; 2]) -> [i32; 2] {
+        let Some(pending) = self.reverb.pending.take() else {
+            self.reverb.pending = Some(input);
+            return self.reverb.output;
+        };
+        let input = [0, 1].map(|channel| (pending[channel] + input[channel]) / 2);
+
+        for channel in 0..2 {
+            let other = 1 - channel;
+            let reverb = &self.reverb;
+            let input = volume(input[channel], reverb.input_volume[channel]);
+            let wall = reverb.wall;
+
+            let same = reverb.same[channel];
+            let reflected = volume(self.reverb_read(reverb.same_source[channel], 0), wall);
+            let last = self.reverb_read(same, 2);
+            let value = volume(input + reflected - last, reverb.iir) + last;
+            self.reverb_write(same, value);
+
+            let reverb = &self.reverb;
+            let diff = reverb.diff[channel];
+            let reflected = volume(self.reverb_read(reverb.diff_source[other], 0), wall);
+            let last = self.reverb_read(diff, 2);
+            let value = volume(input + reflected - last, reverb.iir) + last;
+            self.reverb_write(diff, value);
+
+            let reverb = &self.reverb;
+            let mut output = (0..4)
+                .map(|comb| {
+                    let value = self.reverb_read(reverb.comb[comb][channel], 0);
+                    volume(value, reverb.comb_volume[comb])
+                })
+                .sum::<i32>();
+
+            // the two all pass filters
+            for stage in 0..2 {
+                let reverb = &self.reverb;
+                let (address, apf_volume) = (reverb.apf[stage][channel], reverb.apf_volume[stage]);
+                let delayed = self.reverb_read(address, reverb.apf_offset[stage]);
+                output = saturate(output - volume(delayed, apf_volume));
+                self.reverb_write(address, output);
+                output = saturate(volume(output, apf_volume) + delayed);
+            }
+            self.reverb.output[channel] = volume(output, self.reverb.output_volume[channel]);
+        }
+
+        self.reverb.address = ((self.reverb.address + 2) & 0x7fffe).max(self.reverb.base);
+        self.reverb.output
+    }
+
+    fn reverb_read(&self, offset: u32, back: u32) -> i32 {
+        let address = self.reverb.ram_address(offset, back);
+        i16::from_le_bytes([self.ram[address], self.ram[address + 1]]) as i32
+    }
+
+    // the work area only changes with the reverb enabled
+    fn reverb_write(&mut self, offset: u32, value: i32) {
+        if self.control & 0x80 == 0 {
+            return;
+        }
+        let address = self.reverb.ram_address(offset, 0);
+        let bytes = (saturate(value) as i16).to_le_bytes();
+        self.ram[address..address + 2].copy_from_slice(&bytes);
+    }
+
+    // the cd audio and voices 1 and 3 are written to the first 4 KiB of
+    // the ram, in buffers of 512 samples
+    fn capture(&mut self, cd: [i32; 2]) {
+        let outputs = [cd[0], cd[1], self.voices[1].output as i32, self.voices[3].output as i32];
+        for (buffer, output) in outputs.into_iter().enumerate() {
+            let address = buffer * 0x400 + self.capture as usize * 2;
+            let bytes = (saturate(output) as i16).to_le_bytes();
+            self.ram[address..address + 2].copy_from_slice(&bytes);
+        }
+        self.capture = (self.capture + 1) % 0x200;
+    }
+
+    fn key_on(&mut self, bits: u32) {
+        for index in (0..VOICES).filter(|voice| bits & (1 << voice) != 0) {
+            self.voices[index].key_on();
+            self.endx &= !(1 << index);
+            self.start_block(index);
+        }
+    }
+
+    fn start_block(&mut self, index: usize) {
+        let address = self.voices[index].address;
+        self.check_irq(address, 16);
+        self.voices[index].decode_block(&self.ram);
+    }
+
+    // the irq goes off when the irq address is read or written
+    fn check_irq(&mut self, address: u32, length: u32) {
+        if self.control & 0x40 != 0 && self.irq_address.wrapping_sub(address) < length {
+            self.irq = true;
+        }
+    }
+
+    fn write_ram(&mut self, value: u16) {
+        let address = self.transfer_address;
+        self.check_irq(address, 2);
+        self.ram[address as usize..address as usize + 2].copy_from_slice(&value.to_le_bytes());
+        self.transfer_address = (address + 2) % RAM_SIZE as u32;
+    }
+
+    fn read_ram(&mut self) -> u16 {
+        let address = self.transfer_address;
+        self.check_irq(address, 2);
+        let bytes = &self.ram[address as usize..address as usize + 2];
+        let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        self.transfer_address = (address + 2) % RAM_SIZE as u32;
+        value
+    }
+
+    fn status(&self) -> u16 {
+        let mode = self.control & 0x3f;
+        let mut status = mode;
+        if self.irq {
+            status |= 0x40;
+        }
+        // which half of the capture buffers is being written
+        if self.capture >= 0x100 {
+            status |= 0x0800;
+        }
+        // the dma request follows the transfer mode
+        status |= match mode >> 4 {
+            2 => 0x0180,
+            3 => 0x0280,
+            _ => 0,
+        };
+        status
+    }
+}
+
+fn saturate(value: i32) -> i32 {
+    value.clamp(-0x8000, 0x7fff)
+}
+
+// signed volumes where 0x8000 is -1.0
+fn volume(value: i32, volume: i16) -> i32 {
+    (value * volume as i32) >> 15
+}
+
+// the 32 bit registers are split in two halfwords
+fn half(offset: u32, value: u16) -> u32 {
+    (value as u32) << ((offset & 2) * 8)
+}
+
+fn set_half(register: &mut u32, offset: u32, value: u16) {
+    let shift = (offset & 2) * 8;
+    *register = (*register & !(0xffff << shift)) | half(offset, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_block(spu: &mut Spu, address: usize, header: u8, flags: u8, nibble: u8) {
+        spu.ram[address] = header;
+        spu.ram[address + 1] = flags;
+        spu.ram[address + 2..address + 16].fill(nibble << 4 | nibble);
+    }
+
+    // linear attack of +0x3800 a sample, then the slowest decay down to a
+    // sustain level of 0x8000 so it ends at once, and a sustain that barely moves
+    const ADSR: u32 = 0x1f00_00ff;
+
+    fn play(spu: &mut Spu, voice: usize, start: u16) {
+        let base = voice as u32 * 16;
+        spu.write(base + 0x4, 0x1000);
+        spu.write(base + 0x6, start);
+        spu.write(base + 0x8, ADSR as u16);
+        spu.write(base + 0xa, (ADSR >> 16) as u16);
+        spu.write(KEY_ON, 1 << voice);
+    }
+
+    #[test]
+    fn adpcm_block() {
+        let mut spu = Spu::new();
+        // shift 0, filter 0 keeps the nibbles as the top 4 bits
+        spu.ram[0..2].copy_from_slice(&[0x00, 0x00]);
+        spu.ram[2] = 0x87;
+        spu.voices[0].decode_block(&spu.ram);
+        assert_eq!(spu.voices[0].samples[0..3], [0x7000, -0x8000, 0]);
+
+        // shift 12 and filter 1, every sample adds 60/64 of the last one
+        write_block(&mut spu, 16, 0x1c, 0, 1);
+        spu.voices[0].address = 16;
+        spu.voices[0].history = [0; 2];
+        spu.voices[0].decode_block(&spu.ram);
+        assert_eq!(spu.voices[0].samples[0..5], [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn envelope() {
+        let mut envelope = Envelope {
+            phase: Phase::Off,
+            level: 0,
+            counter: 0,
+        };
+        envelope.key_on();
+        let levels: Vec<i16> = (0..3)
+            .map(|_| {
+                envelope.tick(ADSR);
+                envelope.level
+            })
+            .collect();
+        assert_eq!(levels, [0x3800, 0x7000, 0x7fff]);
+        assert_eq!(envelope.phase, Phase::Decay);
+        envelope.tick(ADSR);
+        assert_eq!(envelope.phase, Phase::Sustain);
+
+        // release shift 11 and linear, -8 every sample
+        envelope.key_off();
+        envelope.level = 16;
+        envelope.tick(0x000b_0000);
+        assert_eq!((envelope.phase, envelope.level), (Phase::Release, 8));
+        envelope.tick(0x000b_0000);
+        assert_eq!((envelope.phase, envelope.level), (Phase::Off, 0));
+
+        // exponential attack is four times slower above 0x6000
+        let ramp = Ramp {
+            exponential: true,
+            decrease: false,
+            shift: 12,
+            step: 0,
+        };
+        let mut counter = 0;
+        ramp.advance(0x7000, &mut counter);
+        assert_eq!(counter, 7);
+    }
+
+    #[test]
+    fn loops() {
+        let mut spu = Spu::new();
+        write_block(&mut spu, 0x1000, 0x0c, 0x04, 1);
+        write_block(&mut spu, 0x1010, 0x0c, 0x03, 2);
+        write_block(&mut spu, 0x2000, 0x0c, 0x01, 3);
+        play(&mut spu, 0, 0x1000 / 8);
+        play(&mut spu, 1, 0x2000 / 8);
+        spu.write(0x1e, 0x1000 / 8);
+
+        for _ in 0..28 {
+            spu.clock([0; 2]);
+        }
+        // voice 1 ended without the repeat flag, it goes silent
+        assert_eq!(spu.read(ENDX), 0x2);
+        assert_eq!(spu.voices[1].envelope.level, 0);
+        assert_eq!(spu.voices[1].address, 0x1000);
+        assert_eq!(spu.voices[0].address, 0x1010);
+
+        for _ in 0..28 {
+            spu.clock([0; 2]);
+        }
+        // voice 0 goes back to its loop start
+        assert_eq!(spu.read(ENDX), 0x3);
+        assert_eq!(spu.voices[0].address, 0x1000);
+        assert_eq!(spu.read(0xe), 0x1000 / 8);
+        assert_ne!(spu.voices[0].envelope.phase, Phase::Release);
+
+        spu.write(KEY_ON, 0x2);
+        assert_eq!(spu.read(ENDX), 0x1);
+        spu.write(KEY_OFF, 0x1);
+        assert_eq!(spu.voices[0].envelope.phase, Phase::Release);
+    }
+
+    #[test]
+    fn mixing() {
+        let mut spu = Spu::new();
+        // every sample is 0x4000
+        write_block(&mut spu, 0x1000, 0x00, 0x03, 4);
+        spu.write(0x0, 0x3fff);
+        spu.write(0x2, 0x2000);
+        spu.write(MAIN_VOLUME, 0x3fff);
+        spu.write(MAIN_VOLUME + 2, 0x3fff);
+        spu.write(CONTROL, 0xc000);
+        play(&mut spu, 0, 0x1000 / 8);
+
+        for _ in 0..4 {
+            spu.clock([0; 2]);
+        }
+        let samples = spu.take_samples();
+        // the first sample interpolates from silence
+        assert_eq!(samples[0..2], [0, 0]);
+        // full envelope, both voice and main volume close to 1.0
+        assert_eq!(samples[4..6], [0x3ffd, 0x1ffe]);
+        assert!(spu.take_samples().is_empty());
+
+        // muted, the voices keep playing
+        spu.write(CONTROL, 0x8000);
+        spu.clock([0; 2]);
+        assert_eq!(spu.take_samples(), [0, 0]);
+        assert_eq!(spu.voices[0].counter, 0x5000);
+        assert_eq!(spu.read(VOICE_VOLUMES), 0x7ffe);
+    }
+
+    #[test]
+    fn cd_audio() {
+        let mut spu = Spu::new();
+        spu.write(MAIN_VOLUME, 0x3fff);
+        spu.write(MAIN_VOLUME + 2, 0x3fff);
+        spu.write(CD_VOLUME, 0x4000);
+        spu.write(CD_VOLUME + 2, 0x8000);
+        spu.write(CONTROL, 0xc001);
+
+        let input = [[0x1000, 0x1000], [-0x8000, 0x7fff], [0x7fff, -0x8000]];
+        for cd in input {
+            spu.clock(cd);
+        }
+        // half volume on the left and inverted on the right
+        let reference = [0x07ff, -0x1000, -0x3fff, -0x7ffe, 0x3ffe, 0x7ffd];
+        assert_eq!(spu.take_samples(), reference);
+        assert_eq!(spu.ram[0..6], [0x00, 0x08, 0x00, 0xc0, 0xff, 0x3f]);
+        assert_eq!(spu.ram[0x400..0x402], [0x00, 0xf0]);
+
+        // muting leaves the cd alone, but it can be turned off
+        spu.write(CONTROL, 0x8001);
+        spu.clock([0x1000, 0x1000]);
+        spu.write(CONTROL, 0xc000);
+        spu.clock([0x1000, 0x1000]);
+        assert_eq!(spu.take_samples(), [0x07ff, -0x1000, 0, 0]);
+    }
+
+    #[test]
+    fn reverb() {
+        let mut spu = Spu::new();
+        // halves for the input, the iir, the first comb and the output, the
+        // all pass filters at -1 with no delay so that both together keep the
+        // value, and no wall reflections. every buffer is 32 bytes from the
+        // next so none of them meet in the few steps taken
+        let registers = [
+            0x0000, 0x0000, 0x4000, 0x4000, 0x0000, 0x0000, 0x0000, 0x0000, 0x8000, 0x8000,
+            0x0000, 0x0004, 0x0000, 0x0004, 0x0000, 0x0000, 0x0000, 0x0000, 0x0008, 0x000c,
+            0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0010, 0x0014, 0x0018, 0x001c,
+            0x4000, 0x4000,
+        ];
+        for (i, value) in registers.into_iter().enumerate() {
+            spu.write(REVERB + i as u32 * 2, value);
+        }
+        spu.write(REVERB_BASE, 0xffc0);
+        spu.write(REVERB_VOLUME, 0x4000);
+        spu.write(REVERB_VOLUME + 2, 0x4000);
+        spu.write(MAIN_VOLUME, 0x2000);
+        spu.write(MAIN_VOLUME + 2, 0x2000);
+        spu.write(CD_VOLUME, 0x4000);
+        spu.write(CD_VOLUME + 2, 0x4000);
+        // cd audio on and into the reverb, which is enabled
+        spu.write(CONTROL, 0xc085);
+
+        // worked out by hand from the formulas in the nocash psx specs. the cd
+        // volume halves 0x4000 and 0x2000, the reverb takes the average of two
+        // steps and halves it again, and the same side buffer gets
+        // (input - last) / 2 + last from an empty one, 2048 and 1024. the comb
+        // halves that, the filters pass it and the output volume halves it
+        // again, 512 and 256, held for the step after. with no more input
+        // every reverb step halves the buffer. the main volume halves the mix
+        // of the cd and the reverb
+        let input = [[0x4000, 0x2000], [0x4000, 0x2000], [0, 0], [0, 0], [0, 0], [0, 0]];
+        for cd in input {
+            spu.clock(cd);
+        }
+        let reference = [4096, 2048, 4352, 2176, 256, 128, 128, 64, 128, 64, 64, 32];
+        assert_eq!(spu.take_samples(), reference);
+        assert_eq!(spu.ram[0x7fe00..0x7fe02], 2048i16.to_le_bytes());
+
+        // with the reverb off the work area is left as it was. the output
+        // still runs, the last one is held and then the comb reads a slot that
+        // was never written
+        let work_area = spu.ram[0x7fe00..].to_vec();
+        spu.write(CONTROL, 0xc005);
+        spu.clock([0x4000, 0x2000]);
+        spu.clock([0x4000, 0x2000]);
+        assert_eq!(spu.ram[0x7fe00..], work_area);
+        assert_eq!(spu.take_samples(), [4096 + 64, 2048 + 32, 4096, 2048]);
+    }
+
+    #[test]
+    fn noise_and_modulation() {
+        let mut spu = Spu::new();
+        write_block(&mut spu, 0x1000, 0x00, 0x03, 4);
+        for voice in 0..3 {
+            play(&mut spu, voice, 0x1000 / 8);
+        }
+        // the fastest noise clock steps every sample
+        spu.write(CONTROL, 0x3c00);
+        spu.write(NOISE, 0x4);
+        // the first voice outputs 0x3fff, which is a factor of about 1.5
+        spu.write(PITCH_MODULATION, 0x3);
+        assert_eq!(spu.pitch_modulation, 0x2);
+
+        for _ in 0..4 {
+            spu.clock([0; 2]);
+        }
+        assert_eq!(spu.noise.level, 0xf);
+        assert_eq!(spu.voices[0].counter, 0x4000);
+        assert!(spu.voices[1].counter > 0x4000);
+        // the noise goes through the envelope like any sample
+        assert_eq!(spu.voices[2].output, 0xe);
+    }
+
+    #[test]
+    fn transfers() {
+        let mut spu = Spu::new();
+        spu.write(TRANSFER_ADDRESS, 0x100);
+        spu.write(TRANSFER_FIFO, 0x1234);
+        spu.write(TRANSFER_FIFO, 0x5678);
+        assert_eq!(spu.ram[0x800], 0);
+        // manual write mode sends the fifo
+        spu.write(CONTROL, 0x0010);
+        assert_eq!(spu.ram[0x800..0x804], [0x34, 0x12, 0x78, 0x56]);
+
+        spu.write(CONTROL, 0x0020);
+        assert_eq!(spu.read(STATUS), 0x01a0);
+        spu.dma_write(0xcafebabe);
+        spu.write(TRANSFER_ADDRESS, 0x100);
+        assert_eq!(spu.dma_read(), 0x56781234);
+        assert_eq!(spu.dma_read(), 0xcafebabe);
+
+        // the irq address is hit by the transfer
+        spu.write(IRQ_ADDRESS, 0x101);
+        spu.write(TRANSFER_ADDRESS, 0x100);
+        spu.write(CONTROL, 0x0060);
+        spu.dma_write(0);
+        spu.dma_write(0);
+        assert!(!spu.irq());
+        spu.dma_write(0);
+        assert!(spu.irq());
+        assert_eq!(spu.read(STATUS) & 0x40, 0x40);
+        spu.write(CONTROL, 0x0020);
+        assert!(!spu.irq());
+    }
+}
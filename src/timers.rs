@@ -0,0 +1,518 @@
+// Root counters, three 16 bit timers with their own clock sources
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Clock {
+    System,
+    // only timer 0
+    Dot,
+    // only timer 1
+    Hblank,
+    // only timer 2
+    System8,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    index: usize,
+    counter: u16,
+    mode: u16,
+    target: u16,
+    // timer 0 syncs to the hblank and timer 1 to the vblank
+    blank: bool,
+    // sync mode 3 waits for the first blank
+    started: bool,
+    // one-shot irqs only fire once until the mode is written
+    fired: bool,
+    requested: bool,
+    // system clock cycles left over by the /8 divider
+    divider: u32,
+}
+
+impl Timer {
+    const COUNTER: u32 = 0x0;
+    const MODE: u32 = 0x4;
+    const TARGET: u32 = 0x8;
+
+    // bits 10-12 are read-only
+    const MODE_WRITE_MASK: u16 = 0x03ff;
+    const REACHED: u16 = 0x1800;
+
+    fn new(index: usize) -> Self {
+        Timer {
+            index,
+            counter: 0,
+            mode: 0x0400,
+            target: 0,
+            blank: false,
+            started: false,
+            fired: false,
+            requested: false,
+            divider: 0,
+        }
+    }
+
+    fn clock(&self) -> Clock {
+        match (self.index, (self.mode >> 8) & 3) {
+            (0, 1 | 3) => Clock::Dot,
+            (1, 1 | 3) => Clock::Hblank,
+            (2, 2 | 3) => Clock::System8,
+            _ => Clock::System,
+        }
+    }
+
+    fn sync_mode(&self) -> Option<u16> {
+        (self.mode & 1 != 0).then_some((self.mode >> 1) & 3)
+    }
+
+    fn running(&self) -> bool {
+        match (self.index, self.sync_mode()) {
+            (_, None) => true,
+            // timer 2 just stops on modes 0 and 3
+            (2, Some(sync)) => sync == 1 || sync == 2,
+            (_, Some(0)) => !self.blank,
+            (_, Some(1)) => true,
+            (_, Some(2)) => self.blank,
+            (_, Some(_)) => self.started,
+        }
+    }
+
+    fn set_blank(&mut self, active: bool) {
+        let entering = active && !self.blank;
+        self.blank = active;
+        if !entering || self.index == 2 {
+            return;
+        }
+
+        match self.sync_mode() {
+            Some(1 | 2) => self.counter = 0,
+            Some(3) => self.started = true,
+            _ => (),
+        }
+    }
+
+    // only the clocks driven by the cpu can be known in advance
+    fn cycles_until_irq(&self) -> Option<u32> {
+        let repeat = self.mode & 0x0040 != 0;
+        if !self.running() || (self.fired && !repeat) {
+            return None;
+        }
+
+        let mut ticks = None;
+        if self.mode & 0x0010 != 0 {
+            let reset = self.mode & 0x0008 != 0;
+            ticks = Some(match self.target.wrapping_sub(self.counter) {
+                0 if reset => self.target as u32 + 1,
+                0 => 0x10000,
+                distance => distance as u32,
+            });
+        }
+        if self.mode & 0x0020 != 0 {
+            let distance = match 0xffff - self.counter {
+                0 => 0x10000,
+                distance => distance as u32,
+            };
+            ticks = Some(ticks.map_or(distance, |ticks: u32| ticks.min(distance)));
+        }
+
+        match self.clock() {
+            Clock::System => ticks,
+            Clock::System8 => ticks.map(|ticks| ticks * 8 - self.divider),
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self, clock: Clock, ticks: u32) {
+        if self.clock() != clock || !self.running() {
+            return;
+        }
+
+        for _ in 0..ticks {
+            self.increment();
+        }
+    }
+
+    fn increment(&mut self) {
+        // the counter wraps on the tick after it reached the target
+        if self.mode & 0x0008 != 0 && self.counter == self.target {
+            self.counter = 0;
+        } else {
+            self.counter = self.counter.wrapping_add(1);
+        }
+
+        if self.counter == self.target {
+            self.mode |= 0x0800;
+            if self.mode & 0x0010 != 0 {
+                self.interrupt();
+            }
+        }
+        if self.counter == 0xffff {
+            self.mode |= 0x1000;
+            if self.mode & 0x0020 != 0 {
+                self.interrupt();
+            }
+        }
+    }
+
+    fn interrupt(&mut self) {
+        let repeat = self.mode & 0x0040 != 0;
+        if self.fired && !repeat {
+            return;
+        }
+        self.fired = true;
+
+        // bit 10 is low while the irq is requested
+        if self.mode & 0x0080 != 0 {
+            self.mode ^= 0x0400;
+            if self.mode & 0x0400 == 0 {
+                self.requested = true;
+            }
+        } else {
+            // the pulse is short enough that bit 10 reads back as one
+            self.requested = true;
+        }
+    }
+
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            Timer::COUNTER => self.counter as u32,
+            Timer::MODE => {
+                let mode = self.mode;
+                // the reached flags are cleared by reading them
+                self.mode &= !Timer::REACHED;
+                mode as u32
+            }
+            Timer::TARGET => self.target as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            Timer::COUNTER => self.counter = value as u16,
+            Timer::MODE => {
+                self.mode = (self.mode & Timer::REACHED)
+                    | (value as u16 & Timer::MODE_WRITE_MASK)
+                    | 0x0400;
+                self.counter = 0;
+                self.started = false;
+                self.fired = false;
+            }
+            Timer::TARGET => self.target = value as u16,
+            _ => (),
+        }
+    }
+}
+
+pub struct Timers {
+    timers: [Timer; 3],
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers {
+            timers: [Timer::new(0), Timer::new(1), Timer::new(2)],
+        }
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        match self.timers.get_mut((offset >> 4) as usize) {
+            Some(timer) => timer.read(offset & 0xf),
+            None => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        if let Some(timer) = self.timers.get_mut((offset >> 4) as usize) {
+            timer.write(offset & 0xf, value);
+        }
+    }
+
+    // cpu clock cycles
+    pub fn step(&mut self, cycles: u32) {
+        for timer in &mut self.timers {
+            timer.tick(Clock::System, cycles);
+        }
+
+        let timer = &mut self.timers[2];
+        timer.divider += cycles;
+        let ticks = timer.divider / 8;
+        timer.divider %= 8;
+        timer.tick(Clock::System8, ticks);
+    }
+
+    pub fn dot_clock(&mut self, dots: u32) {
+        self.timers[0].tick(Clock::Dot, dots);
+    }
+
+    // timer 1 counts the hblanks as they start
+    pub fn hblank(&mut self, active: bool) {
+        if active && !self.timers[0].blank {
+            self.timers[1].tick(Clock::Hblank, 1);
+        }
+        self.timers[0].set_blank(active);
+    }
+
+    pub fn vblank(&mut self, active: bool) {
+        self.timers[1].set_blank(active);
+    }
+
+    pub fn cycles_until_irq(&self) -> Option<u32> {
+        self.timers.iter().filter_map(Timer::cycles_until_irq).min()
+    }
+
+    // whether the timer asked for an interrupt since the last call
+    pub fn take_irq(&mut self, index: usize) -> bool {
+        std::mem::take(&mut self.timers[index].requested)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_clock() {
+        let mut timers = Timers::new();
+        timers.step(10);
+        assert_eq!(timers.read(0x00), 10);
+        assert_eq!(timers.read(0x10), 10);
+        assert_eq!(timers.read(0x20), 10);
+
+        // writing the mode resets the counter
+        timers.write(0x04, 0);
+        assert_eq!(timers.read(0x00), 0);
+        assert_eq!(timers.read(0x04), 0x0400);
+    }
+
+    #[test]
+    fn clock_sources() {
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0100);
+        timers.write(0x14, 0x0100);
+        timers.write(0x24, 0x0200);
+
+        timers.step(20);
+        timers.dot_clock(3);
+        for active in [true, false, true, false] {
+            timers.hblank(active);
+        }
+        assert_eq!(timers.read(0x00), 3);
+        assert_eq!(timers.read(0x10), 2);
+        assert_eq!(timers.read(0x20), 2);
+
+        // the divider keeps the cycles left over
+        timers.step(4);
+        assert_eq!(timers.read(0x20), 3);
+    }
+
+    #[test]
+    fn reset_at_target() {
+        let mut timers = Timers::new();
+        timers.write(0x08, 4);
+        timers.write(0x04, 0x0008);
+
+        timers.step(4);
+        assert_eq!(timers.read(0x00), 4);
+        timers.step(1);
+        assert_eq!(timers.read(0x00), 0);
+        assert_eq!(timers.read(0x04), 0x0c08);
+        // reading cleared the reached flag
+        assert_eq!(timers.read(0x04), 0x0408);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0020);
+        timers.write(0x00, 0xfffe);
+
+        timers.step(1);
+        assert_eq!(timers.read(0x04), 0x1420);
+        assert!(timers.take_irq(0));
+        timers.step(1);
+        assert_eq!(timers.read(0x00), 0);
+    }
+
+    #[test]
+    fn irq_once() {
+        let mut timers = Timers::new();
+        timers.write(0x18, 2);
+        timers.write(0x14, 0x0018);
+
+        timers.step(2);
+        assert!(timers.take_irq(1));
+        assert!(!timers.take_irq(1));
+        timers.step(3);
+        assert!(!timers.take_irq(1));
+
+        // until the mode is written again
+        timers.write(0x14, 0x0018);
+        timers.step(2);
+        assert!(timers.take_irq(1));
+    }
+
+    #[test]
+    fn irq_repeat() {
+        let mut timers = Timers::new();
+        timers.write(0x18, 2);
+        timers.write(0x14, 0x0058);
+
+        timers.step(2);
+        assert!(timers.take_irq(1));
+        timers.step(3);
+        assert!(timers.take_irq(1));
+        assert_eq!(timers.read(0x14) & 0x0400, 0x0400);
+    }
+
+    #[test]
+    fn irq_toggle() {
+        let mut timers = Timers::new();
+        timers.write(0x28, 2);
+        timers.write(0x24, 0x00d8);
+
+        timers.step(2);
+        assert!(timers.take_irq(2));
+        assert_eq!(timers.read(0x24) & 0x0400, 0);
+
+        // bit 10 goes back up without an irq
+        timers.step(3);
+        assert!(!timers.take_irq(2));
+        assert_eq!(timers.read(0x24) & 0x0400, 0x0400);
+
+        timers.step(3);
+        assert!(timers.take_irq(2));
+    }
+
+    #[test]
+    fn next_irq() {
+        let mut timers = Timers::new();
+        assert_eq!(timers.cycles_until_irq(), None);
+
+        timers.write(0x08, 100);
+        timers.write(0x04, 0x0010);
+        timers.write(0x28, 100);
+        timers.write(0x24, 0x0210);
+        timers.step(4);
+        assert_eq!(timers.cycles_until_irq(), Some(96));
+
+        timers.write(0x04, 0x0000);
+        assert_eq!(timers.cycles_until_irq(), Some(100 * 8 - 4));
+    }
+
+    #[test]
+    fn sync_pause_during_blank() {
+        let mut timers = Timers::new();
+        timers.write(0x14, 0x0001);
+
+        timers.step(5);
+        timers.vblank(true);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 5);
+        timers.vblank(false);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 10);
+    }
+
+    #[test]
+    fn sync_reset_at_blank() {
+        let mut timers = Timers::new();
+        timers.write(0x14, 0x0003);
+
+        timers.step(5);
+        timers.vblank(true);
+        assert_eq!(timers.read(0x10), 0);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 5);
+    }
+
+    #[test]
+    fn sync_only_during_blank() {
+        let mut timers = Timers::new();
+        timers.write(0x14, 0x0005);
+
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 0);
+        timers.vblank(true);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 5);
+        timers.vblank(false);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 5);
+    }
+
+    #[test]
+    fn sync_wait_for_blank() {
+        let mut timers = Timers::new();
+        timers.write(0x14, 0x0007);
+
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 0);
+        timers.vblank(true);
+        timers.vblank(false);
+        timers.step(5);
+        assert_eq!(timers.read(0x10), 5);
+    }
+
+    #[test]
+    fn timer2_sync() {
+        let mut timers = Timers::new();
+        timers.write(0x24, 0x0001);
+        timers.step(5);
+        assert_eq!(timers.read(0x20), 0);
+
+        timers.write(0x24, 0x0003);
+        timers.step(5);
+        assert_eq!(timers.read(0x20), 5);
+    }
+
+    // timer 0 against an hblank of 5 cycles after 10 cycles of picture
+    fn hblank_line(timers: &mut Timers) -> [u32; 3] {
+        timers.step(10);
+        let before = timers.read(0x00);
+        timers.hblank(true);
+        let entered = timers.read(0x00);
+        timers.step(5);
+        timers.hblank(false);
+        [before, entered, timers.read(0x00)]
+    }
+
+    #[test]
+    fn hblank_sync_modes() {
+        // paused during the hblank
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0001);
+        assert_eq!(hblank_line(&mut timers), [10, 10, 10]);
+        assert_eq!(hblank_line(&mut timers), [20, 20, 20]);
+
+        // reset as the hblank starts, counting through it
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0003);
+        assert_eq!(hblank_line(&mut timers), [10, 0, 5]);
+        assert_eq!(hblank_line(&mut timers), [15, 0, 5]);
+
+        // reset as it starts and only counting inside it
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0005);
+        assert_eq!(hblank_line(&mut timers), [0, 0, 5]);
+        assert_eq!(hblank_line(&mut timers), [5, 0, 5]);
+
+        // stopped until the first hblank, then free running
+        let mut timers = Timers::new();
+        timers.write(0x04, 0x0007);
+        assert_eq!(hblank_line(&mut timers), [0, 0, 5]);
+        assert_eq!(hblank_line(&mut timers), [15, 15, 20]);
+    }
+
+    #[test]
+    fn hblank_clock() {
+        // timer 1 counts the hblanks as they start
+        let mut timers = Timers::new();
+        timers.write(0x14, 0x0100);
+        timers.hblank(true);
+        timers.hblank(true);
+        assert_eq!(timers.read(0x10), 1);
+        timers.hblank(false);
+        timers.hblank(true);
+        assert_eq!(timers.read(0x10), 2);
+    }
+}
@@ -0,0 +1,81 @@
+// Writes the sound output to a wav file, 16 bit stereo at 44.1 kHz
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u16 = 2;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    // bytes of samples written so far
+    length: u32,
+}
+
+impl WavWriter {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header(0))?;
+
+        Ok(WavWriter { file, length: 0 })
+    }
+
+    // the sizes in the header are kept up to date so that the file is
+    // complete whenever the emulator is closed
+    pub fn write(&mut self, samples: &[i16]) -> anyhow::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.file.write_all(&bytes)?;
+        self.length += bytes.len() as u32;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header(self.length))?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+fn header(length: u32) -> [u8; 44] {
+    let block_align = CHANNELS * 2;
+    let mut header = [0; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(36 + length).to_le_bytes());
+    header[8..16].copy_from_slice(b"WAVEfmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // pcm
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&CHANNELS.to_le_bytes());
+    header[24..28].copy_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header[28..32].copy_from_slice(&(SAMPLE_RATE * block_align as u32).to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&16u16.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&length.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_file() {
+        let path = std::env::temp_dir().join(format!("psiemu_test_{}.wav", std::process::id()));
+        let mut wav = WavWriter::new(&path).unwrap();
+        wav.write(&[1, -1]).unwrap();
+        wav.write(&[0x1234, 0x5678]).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(data[0..4], *b"RIFF");
+        assert_eq!(data[4..8], 44u32.to_le_bytes());
+        assert_eq!(data[24..28], 44100u32.to_le_bytes());
+        assert_eq!(data[40..44], 8u32.to_le_bytes());
+        assert_eq!(data[44..], [0x01, 0x00, 0xff, 0xff, 0x34, 0x12, 0x78, 0x56]);
+    }
+}
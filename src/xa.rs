@@ -0,0 +1,178 @@
+// XA-ADPCM audio sectors, decoded and resampled to the 44.1 kHz of the spu
+
+use std::collections::VecDeque;
+
+const GROUPS: usize = 18;
+const GROUP_SIZE: usize = 128;
+const SAMPLES: usize = 28;
+
+// same filters as the spu adpcm, without the fifth one
+const FILTERS: [(i32, i32); 4] = [(0, 0), (60, 0), (115, -52), (98, -55)];
+
+// the coding info byte of the subheader
+#[derive(Debug, Clone, Copy)]
+struct Coding {
+    stereo: bool,
+    half_rate: bool,
+    eight_bit: bool,
+}
+
+pub struct XaDecoder {
+    // last two samples of each channel, for the filters
+    history: [[i16; 2]; 2],
+    // last input of the resampler, and where the next output falls between
+    // it and the next input in 1/7
+    previous: [i16; 2],
+    phase: u32,
+}
+
+impl XaDecoder {
+    pub fn new() -> Self {
+        XaDecoder {
+            history: [[0; 2]; 2],
+            previous: [0; 2],
+            phase: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = XaDecoder::new();
+    }
+
+    // a whole sector, from the sync, into stereo samples at 44.1 kHz
+    pub fn decode(&mut self, sector: &[u8], output: &mut VecDeque<[i16; 2]>) {
+        let coding = Coding {
+            stereo: sector[19] & 0x01 != 0,
+            half_rate: sector[19] & 0x04 != 0,
+            eight_bit: sector[19] & 0x10 != 0,
+        };
+
+        let mut channels = [vec![], vec![]];
+        for group in sector[24..24 + GROUPS * GROUP_SIZE].chunks(GROUP_SIZE) {
+            let blocks = if coding.eight_bit { 4 } else { 8 };
+            for block in 0..blocks {
+                // stereo has the left channel in the even blocks
+                let channel = (coding.stereo && block & 1 != 0) as usize;
+                let samples = self.decode_block(group, block, coding);
+                channels[channel].extend(samples);
+            }
+        }
+
+        let [left, right] = channels;
+        let inputs: Vec<[i16; 2]> = match coding.stereo {
+            true => left.into_iter().zip(right).map(|(left, right)| [left, right]).collect(),
+            false => left.into_iter().map(|sample| [sample; 2]).collect(),
+        };
+        self.resample(&inputs, coding, output);
+    }
+
+    fn decode_block(&mut self, group: &[u8], block: usize, coding: Coding) -> [i16; SAMPLES] {
+        let header = group[4 + block];
+        let shift = match header & 0xf {
+            shift @ 0..=12 => shift,
+            _ => 9,
+        };
+        let (weight_old, weight_older) = FILTERS[((header >> 4) & 3) as usize];
+        let channel = (coding.stereo && block & 1 != 0) as usize;
+        let history = &mut self.history[channel];
+
+        std::array::from_fn(|i| {
+            let value = match coding.eight_bit {
+                true => (group[16 + i * 4 + block] as i16) << 8,
+                false => {
+                    let byte = group[16 + i * 4 + block / 2];
+                    (((byte >> ((block & 1) * 4)) & 0xf) as i16) << 12
+                }
+            };
+            let [old, older] = history.map(|sample| sample as i32);
+            let filtered = (old * weight_old + older * weight_older + 32) >> 6;
+            let value = (value >> shift) as i32 + filtered;
+            let sample = value.clamp(-0x8000, 0x7fff) as i16;
+            *history = [sample, history[0]];
+            sample
+        })
+    }
+
+    // 37.8 kHz gives 7 samples for every 6 and 18.9 kHz 7 for every 3,
+    // linear interpolation where the hardware has a zigzag filter
+    fn resample(&mut self, inputs: &[[i16; 2]], coding: Coding, output: &mut VecDeque<[i16; 2]>) {
+        let step = if coding.half_rate { 3 } else { 6 };
+        for &input in inputs {
+            while self.phase < 7 {
+                let phase = self.phase as i32;
+                output.push_back([0, 1].map(|channel| {
+                    let previous = self.previous[channel] as i32;
+                    (previous + (input[channel] as i32 - previous) * phase / 7) as i16
+                }));
+                self.phase += step;
+            }
+            self.phase -= 7;
+            self.previous = input;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sector(coding: u8, header: u8, data: u8) -> Vec<u8> {
+        let mut sector = vec![0; 2352];
+        sector[16..20].copy_from_slice(&[1, 0, 0x64, coding]);
+        for group in sector[24..24 + GROUPS * GROUP_SIZE].chunks_mut(GROUP_SIZE) {
+            group[0..16].fill(header);
+            group[16..].fill(data);
+        }
+        sector
+    }
+
+    #[test]
+    fn mono() {
+        let mut decoder = XaDecoder::new();
+        let mut output = VecDeque::new();
+        // every sample is 0x1000
+        decoder.decode(&sector(0x00, 0x00, 0x11), &mut output);
+
+        assert_eq!(output.len(), 4032 * 7 / 6);
+        let first: Vec<[i16; 2]> = output.range(0..3).copied().collect();
+        assert_eq!(first, [[0; 2], [3510; 2], [4096; 2]]);
+        assert_eq!(output.back(), Some(&[4096; 2]));
+    }
+
+    #[test]
+    fn stereo_half_rate() {
+        let mut decoder = XaDecoder::new();
+        let mut output = VecDeque::new();
+        // 8 bit, with 0x1000 on the left and -0x1000 on the right
+        let mut sector = sector(0x15, 0x00, 0x10);
+        for group in sector[24..24 + GROUPS * GROUP_SIZE].chunks_mut(GROUP_SIZE) {
+            for sample in group[16..].chunks_mut(4) {
+                sample.copy_from_slice(&[0x10, 0xf0, 0x10, 0xf0]);
+            }
+        }
+        decoder.decode(&sector, &mut output);
+
+        assert_eq!(output.len(), 1008 * 7 / 3);
+        let first: Vec<[i16; 2]> = output.range(0..4).copied().collect();
+        assert_eq!(first, [[0, 0], [1755, -1755], [3510, -3510], [4096, -4096]]);
+    }
+
+    #[test]
+    fn filters() {
+        let mut decoder = XaDecoder::new();
+        let mut output = VecDeque::new();
+        // shift 8 and filter 1, every sample adds 60/64 of the last one
+        decoder.decode(&sector(0x00, 0x18, 0x11), &mut output);
+        assert_eq!(decoder.history[0], [249, 249]);
+
+        // the history carries over to the next sector
+        let mut group = [0; GROUP_SIZE];
+        group[0..16].fill(0x18);
+        let coding = Coding {
+            stereo: false,
+            half_rate: false,
+            eight_bit: false,
+        };
+        assert_eq!(decoder.decode_block(&group, 0, coding)[0..3], [233, 218, 204]);
+    }
+}